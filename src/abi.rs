@@ -0,0 +1,279 @@
+use std::path::{Path, PathBuf};
+
+use cargo::core::Workspace;
+use cargo::Config;
+use cargo_util::paths::{create_dir_all, read, read_bytes, write};
+use clap::ArgMatches;
+use semver::Version;
+
+use crate::build::{warn, CPackage};
+
+/// The symbols exported by the shared library, sorted
+fn exported_symbols(shared_lib: &Path) -> anyhow::Result<Vec<String>> {
+    use object::read::Object;
+
+    let data = read_bytes(shared_lib)?;
+    let file = object::read::File::parse(&*data)
+        .map_err(|e| anyhow::anyhow!("Cannot parse {}: {}", shared_lib.display(), e))?;
+    let exports = file.exports().map_err(|e| {
+        anyhow::anyhow!("Cannot read the exports of {}: {}", shared_lib.display(), e)
+    })?;
+
+    let mut symbols: Vec<_> = exports
+        .iter()
+        .map(|e| {
+            let name = String::from_utf8_lossy(e.name());
+            // Mach-O symbols have a leading underscore
+            match name.strip_prefix('_') {
+                Some(name) if file.format() == object::BinaryFormat::MachO => name.to_string(),
+                _ => name.into_owned(),
+            }
+        })
+        .collect();
+    symbols.sort();
+    symbols.dedup();
+    Ok(symbols)
+}
+
+/// A stored list of exported symbols, with the library version it was taken from
+struct Baseline {
+    version: Version,
+    symbols: Vec<String>,
+}
+
+impl Baseline {
+    fn parse(path: &Path) -> anyhow::Result<Self> {
+        let contents = read(path)?;
+        let mut lines = contents.lines();
+        let version = lines
+            .next()
+            .and_then(|l| l.strip_prefix("# "))
+            .and_then(|l| l.split_whitespace().nth(1))
+            .and_then(|v| Version::parse(v).ok())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{} does not start with a `# <name> <version>` line",
+                    path.display()
+                )
+            })?;
+        let symbols = lines
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(String::from)
+            .collect();
+
+        Ok(Baseline { version, symbols })
+    }
+
+    fn render(name: &str, version: &Version, symbols: &[String]) -> String {
+        let mut contents = format!("# {name} {version}\n");
+        for symbol in symbols {
+            contents.push_str(symbol);
+            contents.push('\n');
+        }
+        contents
+    }
+}
+
+/// Whether `current` may break the ABI of `baseline`: a new major version, or a new minor
+/// version for the 0.x versions
+fn is_breaking_bump(baseline: &Version, current: &Version) -> bool {
+    if baseline.major == 0 && current.major == 0 {
+        current.minor > baseline.minor
+    } else {
+        current.major > baseline.major
+    }
+}
+
+/// Run `abidiff` on the previous and the current library, true if it reports an
+/// incompatible change
+fn abidiff(config: &Config, previous: &Path, current: &Path) -> anyhow::Result<bool> {
+    // The exit status is a bit field, see the abidiff manual
+    const ABIDIFF_ERROR: i32 = 1;
+    const ABIDIFF_USAGE_ERROR: i32 = 2;
+    const ABIDIFF_ABI_INCOMPATIBLE_CHANGE: i32 = 8;
+
+    let mut cmd =
+        std::process::Command::new(std::env::var_os("ABIDIFF").unwrap_or_else(|| "abidiff".into()));
+    cmd.arg(previous).arg(current);
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {:?}: {}", cmd, e))?;
+    let status = out.status.code().unwrap_or(ABIDIFF_ERROR);
+    if status & (ABIDIFF_ERROR | ABIDIFF_USAGE_ERROR) != 0 {
+        anyhow::bail!(
+            "Command failed {:?}: {}",
+            cmd,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    if status != 0 {
+        config.shell().print_ansi_stdout(&out.stdout)?;
+    }
+
+    Ok(status & ABIDIFF_ABI_INCOMPATIBLE_CHANGE != 0)
+}
+
+/// Compare the ABI of the freshly built shared libraries against their baseline
+///
+/// Removing exported symbols, or an incompatible change reported by `abidiff`, requires a
+/// breaking version bump from the version the baseline was taken from.
+pub fn cabi_check(ws: &Workspace, args: &ArgMatches, packages: &[CPackage]) -> anyhow::Result<()> {
+    let config = ws.config();
+    let update = args.get_flag("update-baseline");
+    let mut failures = Vec::new();
+
+    for pkg in packages {
+        let library = &pkg.capi_config.library;
+        let name = &library.name;
+        let shared_lib = match &pkg.build_targets.shared_lib {
+            Some(shared_lib) => shared_lib,
+            None => anyhow::bail!("the ABI check of {name} requires the cdylib library type"),
+        };
+
+        let baseline_path = args
+            .get_one::<PathBuf>("baseline")
+            .cloned()
+            .unwrap_or_else(|| pkg.root_path.join("abi").join(format!("{name}.symbols")));
+        let symbols = exported_symbols(shared_lib)?;
+
+        if update {
+            config.shell().status("Writing", baseline_path.display())?;
+            create_dir_all(baseline_path.parent().unwrap())?;
+            write(
+                &baseline_path,
+                Baseline::render(name, &library.version, &symbols),
+            )?;
+            continue;
+        }
+
+        if !baseline_path.is_file() {
+            anyhow::bail!(
+                "there is no ABI baseline for {name} in {}, create it with --update-baseline",
+                baseline_path.display()
+            );
+        }
+        let baseline = Baseline::parse(&baseline_path)?;
+        let breaking = is_breaking_bump(&baseline.version, &library.version);
+
+        config.shell().status(
+            "Checking",
+            format!(
+                "ABI of {name} {} against {}",
+                library.version, baseline.version
+            ),
+        )?;
+
+        let removed: Vec<_> = baseline
+            .symbols
+            .iter()
+            .filter(|s| !symbols.contains(s))
+            .collect();
+        let added: Vec<_> = symbols
+            .iter()
+            .filter(|s| !baseline.symbols.contains(s))
+            .collect();
+
+        let join = |symbols: &[&String]| {
+            symbols
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        if !added.is_empty() {
+            config.shell().status("Added", join(&added))?;
+        }
+        if !added.is_empty() && library.version == baseline.version {
+            warn(
+                config,
+                format!(
+                    "{name} exports new symbols but its version is still {}",
+                    library.version
+                ),
+            )?;
+        }
+        let mut broken = Vec::new();
+        if !removed.is_empty() {
+            if breaking {
+                config.shell().status("Removed", join(&removed))?;
+            } else {
+                broken.push(format!("no longer exports {}", join(&removed)));
+            }
+        }
+        if let Some(previous) = args.get_one::<PathBuf>("abidiff") {
+            if abidiff(config, previous, shared_lib)? && !breaking {
+                broken.push(format!(
+                    "has incompatible changes from {} according to abidiff",
+                    previous.display()
+                ));
+            }
+        }
+        if !broken.is_empty() {
+            failures.push(format!(
+                "{name} {} {}, which requires a breaking version bump from {}",
+                library.version,
+                broken.join(" and "),
+                baseline.version
+            ));
+        }
+    }
+
+    if !failures.is_empty() {
+        anyhow::bail!("{}", failures.join("\n"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+
+    use super::Baseline;
+
+    #[test]
+    fn baseline() {
+        let dir = std::env::temp_dir().join(format!("cargo-c-abi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("foo.symbols");
+
+        let symbols = vec!["foo_free".to_string(), "foo_new".to_string()];
+        let rendered = Baseline::render("foo", &Version::new(1, 2, 3), &symbols);
+        assert_eq!(rendered, "# foo 1.2.3\nfoo_free\nfoo_new\n");
+
+        // The blank lines and the comments are skipped
+        std::fs::write(
+            &path,
+            format!("{rendered}\n# removed in 1.3\n  foo_old  \n"),
+        )
+        .unwrap();
+        let baseline = Baseline::parse(&path).unwrap();
+        assert_eq!(baseline.version, Version::new(1, 2, 3));
+        assert_eq!(baseline.symbols, ["foo_free", "foo_new", "foo_old"]);
+
+        std::fs::write(&path, "foo_free\nfoo_new\n").unwrap();
+        let err = Baseline::parse(&path).err().unwrap();
+        assert!(err
+            .to_string()
+            .ends_with("does not start with a `# <name> <version>` line"));
+
+        std::fs::write(&path, "# foo one\n").unwrap();
+        assert!(Baseline::parse(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn is_breaking_bump() {
+        let v = |v: &str| Version::parse(v).unwrap();
+        assert!(super::is_breaking_bump(&v("1.2.3"), &v("2.0.0")));
+        assert!(!super::is_breaking_bump(&v("1.2.3"), &v("1.3.0")));
+        assert!(!super::is_breaking_bump(&v("1.2.3"), &v("1.2.4")));
+        assert!(super::is_breaking_bump(&v("0.2.3"), &v("0.3.0")));
+        assert!(!super::is_breaking_bump(&v("0.2.3"), &v("0.2.4")));
+        assert!(super::is_breaking_bump(&v("0.2.3"), &v("1.0.0")));
+        assert!(!super::is_breaking_bump(&v("2.0.0"), &v("1.9.0")));
+    }
+}
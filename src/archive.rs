@@ -0,0 +1,399 @@
+use std::path::{Path, PathBuf};
+
+use cargo::core::Workspace;
+use cargo_util::paths::{create_dir_all, read_bytes, remove_dir_all, write};
+use clap::ArgMatches;
+
+use crate::build::{warn, CApiConfig, CPackage};
+use crate::build_targets::BuildTargets;
+use crate::install::cinstall;
+use crate::nuget::{add_dir, xml_escape, Zip};
+use crate::target::Target;
+
+/// The top level entries of the staging directory, e.g. `usr`
+pub(crate) fn top_level(staging: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut entries: Vec<_> = std::fs::read_dir(staging)?
+        .flatten()
+        .map(|e| (e.file_name().to_string_lossy().into_owned(), e.path()))
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
+
+fn tar_gz(staging: &Path) -> anyhow::Result<Vec<u8>> {
+    let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    // The versioned links of the shared library are kept as links
+    builder.follow_symlinks(false);
+    for (name, path) in top_level(staging)? {
+        if path.is_dir() {
+            builder.append_dir_all(&name, &path)?;
+        } else {
+            builder.append_path_with_name(&path, &name)?;
+        }
+    }
+    Ok(builder.into_inner()?.finish()?)
+}
+
+fn zip(staging: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut zip = Zip::default();
+    // zip has no links, the files they point to are stored in their place
+    for (name, path) in top_level(staging)? {
+        if path.is_dir() {
+            add_dir(&mut zip, &path, &name)?;
+        } else {
+            zip.add(&name, &read_bytes(&path)?)?;
+        }
+    }
+    zip.finish()
+}
+
+/// The CMSIS condition matching the cores a thumb target runs on
+fn cmsis_cores(target: &Target) -> Option<Vec<(&'static str, &'static str)>> {
+    let hf = target.abi == "eabihf";
+    let fpu = if hf { "FPU" } else { "NO_FPU" };
+    let triple = target.triple.as_str();
+    let cores = if triple.starts_with("thumbv6m-") {
+        vec![("Cortex-M0", "NO_FPU"), ("Cortex-M0+", "NO_FPU")]
+    } else if triple.starts_with("thumbv7m-") {
+        vec![("Cortex-M3", "NO_FPU")]
+    } else if triple.starts_with("thumbv7em-") {
+        vec![("Cortex-M4", fpu), ("Cortex-M7", fpu)]
+    } else if triple.starts_with("thumbv8m.base-") {
+        vec![("Cortex-M23", "NO_FPU")]
+    } else if triple.starts_with("thumbv8m.main-") {
+        vec![("Cortex-M33", fpu), ("Cortex-M35P", fpu)]
+    } else {
+        return None;
+    };
+    Some(cores)
+}
+
+/// The `.pdsc` description of a pack holding one component per target, `url` being the
+/// page the pack is published on
+fn pdsc(capi_config: &CApiConfig, url: Option<&str>, targets: &[&BuildTargets]) -> String {
+    let name = pack_name(&capi_config.library.name);
+    let version = &capi_config.library.version;
+    let description = match capi_config.pkg_config.description.as_str() {
+        "" => xml_escape(&capi_config.library.name),
+        description => xml_escape(description),
+    };
+
+    let includedir = Path::new("include").join(&capi_config.header.subdirectory);
+    let includedir = includedir
+        .ancestors()
+        .nth(capi_config.pkg_config.strip_include_path_components)
+        .unwrap_or_else(|| Path::new("include"))
+        .display()
+        .to_string();
+    // An empty subdirectory leaves a trailing separator
+    let includedir = includedir.trim_end_matches('/');
+
+    let mut conditions = String::new();
+    let mut components = String::new();
+    for build_targets in targets {
+        let target = &build_targets.target;
+        let static_lib = match &build_targets.static_lib {
+            Some(static_lib) => static_lib.file_name().unwrap().to_string_lossy(),
+            None => continue,
+        };
+
+        let condition = match cmsis_cores(target) {
+            Some(cores) => {
+                let accept: String = cores
+                    .iter()
+                    .map(|(core, fpu)| format!("\n      <accept Dcore=\"{core}\" Dfpu=\"{fpu}\"/>"))
+                    .collect();
+                conditions += &format!(
+                    "\n    <condition id=\"{triple}\">{accept}\n    </condition>",
+                    triple = target.triple
+                );
+                format!(" condition=\"{}\"", target.triple)
+            }
+            None => String::new(),
+        };
+
+        let header = build_targets
+            .include
+            .as_ref()
+            .map(|h| {
+                let path = Path::new(&includedir).join(h.file_name().unwrap());
+                format!(
+                    "\n        <file category=\"header\" name=\"{}\"/>",
+                    path.display()
+                )
+            })
+            .unwrap_or_default();
+
+        components += &format!(
+            "
+    <component Cclass=\"Rust\" Cgroup=\"{name}\" Csub=\"{triple}\" Cversion=\"{version}\"{condition}>
+      <description>{description}</description>
+      <files>
+        <file category=\"include\" name=\"{includedir}/\"/>{header}
+        <file category=\"library\" name=\"lib/{triple}/{static_lib}\"/>
+      </files>
+    </component>",
+            triple = target.triple,
+        );
+    }
+
+    let url = url
+        .map(|url| format!("\n  <url>{}</url>", xml_escape(url)))
+        .unwrap_or_default();
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<!-- Generated by cargo-c -->
+<package schemaVersion=\"1.7.7\" xmlns:xs=\"http://www.w3.org/2001/XMLSchema-instance\" xs:noNamespaceSchemaLocation=\"https://raw.githubusercontent.com/Open-CMSIS-Pack/Open-CMSIS-Pack-Spec/v1.7.7/schema/PACK.xsd\">
+  <vendor>{vendor}</vendor>
+  <name>{name}</name>
+  <description>{description}</description>{url}
+  <releases>
+    <release version=\"{version}\"/>
+  </releases>
+  <conditions>{conditions}
+  </conditions>
+  <components>{components}
+  </components>
+</package>
+",
+        vendor = xml_escape(&pack_name(&capi_config.pack.vendor)),
+    )
+}
+
+/// The pack names allow only letters, digits, `_` and `-`
+fn pack_name(name: &str) -> String {
+    name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")
+}
+
+/// Assemble the static libraries of every target and the headers in a CMSIS-Pack,
+/// `<vendor>.<name>.<version>.pack`
+fn cmsis_pack(ws: &Workspace, packages: &[CPackage], output_dir: &Path) -> anyhow::Result<()> {
+    let mut names: Vec<_> = packages
+        .iter()
+        .map(|p| &p.capi_config.library.name)
+        .collect();
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        let pkgs: Vec<_> = packages
+            .iter()
+            .filter(|p| &p.capi_config.library.name == name && p.build_targets.static_lib.is_some())
+            .collect();
+        if pkgs.is_empty() {
+            anyhow::bail!("A CMSIS-Pack needs the static library of {name}, it was not built");
+        }
+        for pkg in &pkgs {
+            if cmsis_cores(&pkg.build_targets.target).is_none() {
+                warn(
+                    ws.config(),
+                    format!(
+                        "{} is not a Cortex-M target, its component of the pack has no condition",
+                        pkg.build_targets.target.triple
+                    ),
+                )?;
+            }
+        }
+
+        let capi_config = &pkgs[0].capi_config;
+        let base = format!(
+            "{}.{}",
+            pack_name(&capi_config.pack.vendor),
+            pack_name(&capi_config.library.name)
+        );
+        let file_name = format!("{base}.{}.pack", capi_config.library.version);
+        ws.config().shell().status("Packaging", &file_name)?;
+
+        let mut zip = Zip::default();
+        let metadata = ws
+            .members()
+            .find(|m| m.root() == pkgs[0].root_path)
+            .map(|m| m.manifest().metadata());
+        let url = metadata.and_then(|m| m.homepage.as_deref().or(m.repository.as_deref()));
+        let targets: Vec<_> = pkgs.iter().map(|p| &p.build_targets).collect();
+        zip.add(
+            &format!("{base}.pdsc"),
+            pdsc(capi_config, url, &targets).as_bytes(),
+        )?;
+        // The headers are the same for every target
+        let include = pkgs[0].build_targets.pc.parent().unwrap().join("include");
+        if include.is_dir() {
+            add_dir(&mut zip, &include, "include")?;
+        }
+        for pkg in &pkgs {
+            let static_lib = pkg.build_targets.static_lib.as_ref().unwrap();
+            zip.add(
+                &format!(
+                    "lib/{}/{}",
+                    pkg.build_targets.target.triple,
+                    static_lib.file_name().unwrap().to_string_lossy()
+                ),
+                &read_bytes(static_lib)?,
+            )?;
+        }
+
+        create_dir_all(output_dir)?;
+        let pack = output_dir.join(file_name);
+        write(&pack, zip.finish()?)?;
+        ws.config().shell().status("Writing", pack.display())?;
+    }
+
+    Ok(())
+}
+
+/// The one line summary of the distribution packages, the first line of the pkg-config
+/// description or else the library name
+pub(crate) fn package_summary(capi_config: &CApiConfig) -> String {
+    match capi_config.pkg_config.description.trim() {
+        "" => capi_config.library.name.clone(),
+        description => description.lines().next().unwrap().to_string(),
+    }
+}
+
+/// Install the package in a staging directory, `runtime` holding the shared library and
+/// the data files and `devel` the rest, for the packaging tools splitting them likewise
+///
+/// The install manifest is left out, the package managers keep their own lists.
+pub(crate) fn stage_split_install(
+    ws: &Workspace,
+    pkg: &mut CPackage,
+) -> anyhow::Result<(PathBuf, PathBuf, PathBuf)> {
+    let root_output = pkg.build_targets.pc.parent().unwrap().to_path_buf();
+    let staging = root_output.join("cpackage");
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+    let (runtime, devel) = (staging.join("runtime"), staging.join("devel"));
+
+    let paths = &mut pkg.install_paths;
+    paths.destdir = Some(devel.clone());
+    paths.devel_prefix = None;
+    paths.runtime_prefix = Some(runtime.clone());
+    cinstall(ws, std::slice::from_mut(pkg))?;
+
+    let libdir = pkg
+        .install_paths
+        .libdir
+        .strip_prefix("/")
+        .unwrap_or(&pkg.install_paths.libdir);
+    for tree in [&runtime, &devel] {
+        let manifests = tree.join(libdir).join("cargo-c");
+        if manifests.exists() {
+            remove_dir_all(&manifests)?;
+        }
+    }
+
+    Ok((staging, runtime, devel))
+}
+
+/// Install each package in a staging directory and archive the installed tree as
+/// `<name>-<version>-<target>.tar.gz` or `.zip`, or assemble a CMSIS-Pack, the Debian
+/// packages or the rpms
+///
+/// The archive holds the tree below the staging directory, the prefix included, as
+/// `cargo cinstall --destdir` would have laid it out.
+pub fn cpackage(
+    ws: &Workspace,
+    args: &ArgMatches,
+    packages: &mut [CPackage],
+) -> anyhow::Result<()> {
+    let format = args.get_one::<String>("format").unwrap();
+    let output_dir = args
+        .get_one::<PathBuf>("output-dir")
+        .cloned()
+        .unwrap_or_else(|| ws.target_dir().into_path_unlocked());
+
+    if format == "pack" {
+        return cmsis_pack(ws, packages, &output_dir);
+    }
+    if format == "deb" || format == "rpm" {
+        for pkg in packages.iter_mut() {
+            match format.as_str() {
+                "deb" => crate::deb::deb_packages(ws, pkg, &output_dir)?,
+                _ => crate::rpm::rpm_packages(ws, pkg, &output_dir, args.get_flag("spec-only"))?,
+            }
+        }
+        return Ok(());
+    }
+
+    for pkg in packages.iter_mut() {
+        let root_output = pkg.build_targets.pc.parent().unwrap().to_path_buf();
+        let staging = root_output.join("cpackage");
+        if staging.exists() {
+            remove_dir_all(&staging)?;
+        }
+
+        let paths = &mut pkg.install_paths;
+        paths.destdir = Some(staging.clone());
+        paths.devel_prefix = None;
+        paths.runtime_prefix = None;
+        cinstall(ws, std::slice::from_mut(pkg))?;
+
+        let archive_name = format!(
+            "{}-{}-{}.{format}",
+            pkg.capi_config.library.name,
+            pkg.capi_config.library.version,
+            pkg.build_targets.target.triple
+        );
+        ws.config().shell().status("Packaging", &archive_name)?;
+        let contents = match format.as_str() {
+            "zip" => zip(&staging)?,
+            _ => tar_gz(&staging)?,
+        };
+        remove_dir_all(&staging)?;
+
+        create_dir_all(&output_dir)?;
+        let archive = output_dir.join(archive_name);
+        write(&archive, contents)?;
+        ws.config().shell().status("Writing", archive.display())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::build_targets::BuildTargets;
+    use crate::target::Target;
+
+    #[test]
+    fn pdsc() {
+        let capi_config = crate::build::test_capi_config();
+        let build_targets = |triple: &str| {
+            let target = Target::new(triple).unwrap();
+            let root_output = Path::new("/build").join(triple);
+            BuildTargets::new("foo", &target, &root_output, &["staticlib"], &capi_config).unwrap()
+        };
+        let m4 = build_targets("thumbv7em-none-eabihf");
+        let m0 = build_targets("thumbv6m-none-eabi");
+
+        let pdsc = super::pdsc(&capi_config, Some("https://example.com/foo"), &[&m4, &m0]);
+        assert!(pdsc.contains("  <name>foo</name>\n  <description>foo</description>\n  <url>https://example.com/foo</url>\n"));
+        assert!(pdsc.contains(
+            "
+    <condition id=\"thumbv7em-none-eabihf\">
+      <accept Dcore=\"Cortex-M4\" Dfpu=\"FPU\"/>
+      <accept Dcore=\"Cortex-M7\" Dfpu=\"FPU\"/>
+    </condition>"
+        ));
+        assert!(pdsc.contains(
+            "
+    <component Cclass=\"Rust\" Cgroup=\"foo\" Csub=\"thumbv6m-none-eabi\" Cversion=\"0.1.0\" condition=\"thumbv6m-none-eabi\">
+      <description>foo</description>
+      <files>
+        <file category=\"include\" name=\"include/\"/>
+        <file category=\"header\" name=\"include/foo.h\"/>
+        <file category=\"library\" name=\"lib/thumbv6m-none-eabi/libfoo.a\"/>
+      </files>
+    </component>"
+        ));
+
+        // Without a homepage nor a repository there is no url to publish
+        let pdsc = super::pdsc(&capi_config, None, &[&m4]);
+        assert!(!pdsc.contains("<url>"));
+    }
+}
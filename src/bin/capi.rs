@@ -1,7 +1,16 @@
-use cargo_c::build::{cbuild, ctest};
+use cargo_c::abi::cabi_check;
+use cargo_c::archive::cpackage;
+use cargo_c::build::{cbuild, check_single_target, check_warnings, ctest};
 use cargo_c::cli::*;
 use cargo_c::config::*;
-use cargo_c::install::cinstall;
+use cargo_c::doctor::cdoctor;
+use cargo_c::init::{cimport_autotools, cinit};
+use cargo_c::install::{cinstall, cprint_default_prefix, cuninstall};
+use cargo_c::meson::cmeson;
+use cargo_c::nuget::cnuget;
+use cargo_c::packaging::cpackaging_template;
+use cargo_c::vcpkg::cvcpkg;
+use cargo_c::watch::watch;
 
 use cargo::util::command_prelude::flag;
 use cargo::util::command_prelude::ArgMatchesExt;
@@ -15,7 +24,20 @@ fn main() -> CliResult {
 
     let cli_build = subcommand_build("build", "Build the crate C-API");
     let cli_install = subcommand_install("install", "Install the crate C-API");
+    let cli_uninstall = subcommand_uninstall("uninstall", "Uninstall the crate C-API");
+    let cli_package = subcommand_package(
+        "package",
+        "Package the installed crate C-API in a binary archive",
+    );
     let cli_test = subcommand_test("test");
+    let cli_doctor = subcommand_doctor("doctor");
+    let cli_abi_check = subcommand_abi_check("abi-check");
+    let cli_init = subcommand_init("init");
+    let cli_import_autotools = subcommand_import_autotools("import-autotools");
+    let cli_packaging_template = subcommand_packaging_template("packaging-template");
+    let cli_nuget = subcommand_nuget("nuget");
+    let cli_meson = subcommand_meson("meson");
+    let cli_vcpkg = subcommand_vcpkg("vcpkg");
 
     let mut app = clap::command!()
         .dont_collapse_args_in_usage(true)
@@ -27,16 +49,65 @@ fn main() -> CliResult {
                 .arg(flag("version", "Print version info and exit").short('V'))
                 .subcommand(cli_build)
                 .subcommand(cli_install)
-                .subcommand(cli_test),
+                .subcommand(cli_uninstall)
+                .subcommand(cli_package)
+                .subcommand(cli_test)
+                .subcommand(cli_doctor)
+                .subcommand(cli_abi_check)
+                .subcommand(cli_init)
+                .subcommand(cli_import_autotools)
+                .subcommand(cli_packaging_template)
+                .subcommand(cli_nuget)
+                .subcommand(cli_meson)
+                .subcommand(cli_vcpkg),
         );
 
-    let args = app.clone().get_matches();
+    let args = get_matches_with_preset(&app)?;
 
     let (cmd, subcommand_args, default_profile) = match args.subcommand() {
         Some(("capi", args)) => match args.subcommand() {
             Some(("build", args)) => ("build", args, "dev"),
             Some(("test", args)) => ("test", args, "dev"),
             Some(("install", args)) => ("install", args, "release"),
+            Some(("package", args)) => ("package", args, "release"),
+            Some(("abi-check", args)) => ("abi-check", args, "release"),
+            Some(("packaging-template", args)) => ("packaging-template", args, "release"),
+            Some(("uninstall", args)) => {
+                config_configure(&mut config, args)?;
+                let ws = args.workspace(&config)?;
+                cuninstall(&ws, args)?;
+                check_warnings(args)?;
+                return Ok(());
+            }
+            Some(("doctor", args)) => {
+                config_configure(&mut config, args)?;
+                let ws = args.workspace(&config)?;
+                cdoctor(&ws, args)?;
+                return Ok(());
+            }
+            Some(("init", args)) => {
+                cinit(&config, args)?;
+                return Ok(());
+            }
+            Some(("import-autotools", args)) => {
+                cimport_autotools(&config, args)?;
+                return Ok(());
+            }
+            Some(("nuget", args)) => {
+                let ws = args.workspace(&config)?;
+                cnuget(&ws, args)?;
+                return Ok(());
+            }
+            Some(("meson", args)) => {
+                let ws = args.workspace(&config)?;
+                cmeson(&ws, args)?;
+                return Ok(());
+            }
+            Some(("vcpkg", args)) => {
+                let ws = args.workspace(&config)?;
+                cvcpkg(&ws, args)?;
+                return Ok(());
+            }
             Some((cmd, args)) => {
                 return run_cargo_fallback(cmd, args);
             }
@@ -56,19 +127,69 @@ fn main() -> CliResult {
     };
 
     if subcommand_args.flag("version") {
-        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        print_version(subcommand_args)?;
         return Ok(());
     }
 
     config_configure(&mut config, subcommand_args)?;
 
+    if cmd == "install" && subcommand_args.flag("print-default-prefix") {
+        let ws = subcommand_args.workspace(&config)?;
+        cprint_default_prefix(&ws, subcommand_args)?;
+        return Ok(());
+    }
+
+    if subcommand_args.flag("watch") {
+        return Ok(watch(&config, subcommand_args, |ws| {
+            if cmd == "install" {
+                check_single_target(ws, &config, subcommand_args, "cinstall")?;
+            }
+            let (mut packages, _) = cbuild(ws, &config, subcommand_args, default_profile)?;
+            if cmd == "install" {
+                cinstall(ws, &packages)?;
+            }
+            if cmd == "package" {
+                cpackage(ws, subcommand_args, &mut packages)?;
+            }
+            if cmd == "abi-check" {
+                cabi_check(ws, subcommand_args, &packages)?;
+            }
+            Ok(())
+        })?);
+    }
+
     let mut ws = subcommand_args.workspace(&config)?;
 
-    let (packages, compile_opts) = cbuild(&mut ws, &config, subcommand_args, default_profile)?;
+    match cmd {
+        "install" => check_single_target(&ws, &config, subcommand_args, "cinstall")?,
+        "test" => check_single_target(&ws, &config, subcommand_args, "ctest")?,
+        _ => {}
+    }
+
+    let (mut packages, compile_opts) = cbuild(&mut ws, &config, subcommand_args, default_profile)?;
+
+    // Denied before anything is written in the prefix
+    check_warnings(subcommand_args)?;
 
     if cmd == "install" {
         cinstall(&ws, &packages)?;
-    } else if cmd == "test" {
+    }
+
+    if cmd == "package" {
+        cpackage(&ws, subcommand_args, &mut packages)?;
+    }
+
+    if cmd == "abi-check" {
+        cabi_check(&ws, subcommand_args, &packages)?;
+    }
+
+    if cmd == "packaging-template" {
+        cpackaging_template(&ws, subcommand_args, &mut packages)?;
+    }
+
+    check_warnings(subcommand_args)?;
+
+    if cmd == "test" {
         ctest(&ws, &config, subcommand_args, &packages, compile_opts)?;
     }
 
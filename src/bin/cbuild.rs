@@ -3,9 +3,12 @@ use cargo::CliResult;
 use cargo::Config;
 
 use cargo_c::build::*;
+use cargo_c::cli::get_matches_with_preset;
+use cargo_c::cli::print_version;
 use cargo_c::cli::run_cargo_fallback;
 use cargo_c::cli::subcommand_build;
 use cargo_c::config::*;
+use cargo_c::watch::watch;
 
 fn main() -> CliResult {
     let mut config = Config::default()?;
@@ -16,7 +19,7 @@ fn main() -> CliResult {
         .allow_external_subcommands(true)
         .subcommand(subcommand);
 
-    let args = app.clone().get_matches();
+    let args = get_matches_with_preset(&app)?;
 
     let subcommand_args = match args.subcommand() {
         Some(("cbuild", args)) => args,
@@ -31,15 +34,23 @@ fn main() -> CliResult {
     };
 
     if subcommand_args.flag("version") {
-        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        print_version(subcommand_args)?;
         return Ok(());
     }
 
     config_configure(&mut config, subcommand_args)?;
 
+    if subcommand_args.flag("watch") {
+        return Ok(watch(&config, subcommand_args, |ws| {
+            cbuild(ws, &config, subcommand_args, "dev").map(|_| ())
+        })?);
+    }
+
     let mut ws = subcommand_args.workspace(&config)?;
 
     let _ = cbuild(&mut ws, &config, subcommand_args, "dev")?;
 
+    check_warnings(subcommand_args)?;
+
     Ok(())
 }
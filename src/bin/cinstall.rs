@@ -2,11 +2,14 @@ use cargo::util::command_prelude::ArgMatchesExt;
 use cargo::CliResult;
 use cargo::Config;
 
-use cargo_c::build::cbuild;
+use cargo_c::build::{cbuild, check_single_target, check_warnings};
+use cargo_c::cli::get_matches_with_preset;
+use cargo_c::cli::print_version;
 use cargo_c::cli::run_cargo_fallback;
 use cargo_c::cli::subcommand_install;
 use cargo_c::config::config_configure;
-use cargo_c::install::cinstall;
+use cargo_c::install::{cinstall, cprint_default_prefix};
+use cargo_c::watch::watch;
 
 fn main() -> CliResult {
     let mut config = Config::default()?;
@@ -17,7 +20,7 @@ fn main() -> CliResult {
         .allow_external_subcommands(true)
         .subcommand(subcommand);
 
-    let args = app.clone().get_matches();
+    let args = get_matches_with_preset(&app)?;
 
     let subcommand_args = match args.subcommand() {
         Some(("cinstall", args)) => args,
@@ -32,17 +35,38 @@ fn main() -> CliResult {
     };
 
     if subcommand_args.flag("version") {
-        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        print_version(subcommand_args)?;
         return Ok(());
     }
 
     config_configure(&mut config, subcommand_args)?;
 
+    if subcommand_args.flag("print-default-prefix") {
+        let ws = subcommand_args.workspace(&config)?;
+        cprint_default_prefix(&ws, subcommand_args)?;
+        return Ok(());
+    }
+
+    if subcommand_args.flag("watch") {
+        return Ok(watch(&config, subcommand_args, |ws| {
+            check_single_target(ws, &config, subcommand_args, "cinstall")?;
+            let (packages, _) = cbuild(ws, &config, subcommand_args, "release")?;
+            cinstall(ws, &packages)
+        })?);
+    }
+
     let mut ws = subcommand_args.workspace(&config)?;
 
+    check_single_target(&ws, &config, subcommand_args, "cinstall")?;
+
     let (packages, _) = cbuild(&mut ws, &config, subcommand_args, "release")?;
 
+    // Denied before anything is written in the prefix
+    check_warnings(subcommand_args)?;
+
     cinstall(&ws, &packages)?;
 
+    check_warnings(subcommand_args)?;
+
     Ok(())
 }
@@ -0,0 +1,57 @@
+use cargo::util::command_prelude::ArgMatchesExt;
+use cargo::CliResult;
+use cargo::Config;
+
+use cargo_c::archive::cpackage;
+use cargo_c::build::{cbuild, check_warnings};
+use cargo_c::cli::get_matches_with_preset;
+use cargo_c::cli::print_version;
+use cargo_c::cli::run_cargo_fallback;
+use cargo_c::cli::subcommand_package;
+use cargo_c::config::config_configure;
+
+fn main() -> CliResult {
+    let mut config = Config::default()?;
+
+    let subcommand = subcommand_package(
+        "cpackage",
+        "Package the installed crate C-API in a binary archive",
+    );
+    let mut app = clap::command!()
+        .dont_collapse_args_in_usage(true)
+        .allow_external_subcommands(true)
+        .subcommand(subcommand);
+
+    let args = get_matches_with_preset(&app)?;
+
+    let subcommand_args = match args.subcommand() {
+        Some(("cpackage", args)) => args,
+        Some((cmd, args)) => {
+            return run_cargo_fallback(cmd, args);
+        }
+        _ => {
+            // No subcommand provided.
+            app.print_help()?;
+            return Ok(());
+        }
+    };
+
+    if subcommand_args.flag("version") {
+        print_version(subcommand_args)?;
+        return Ok(());
+    }
+
+    config_configure(&mut config, subcommand_args)?;
+
+    let mut ws = subcommand_args.workspace(&config)?;
+
+    let (mut packages, _) = cbuild(&mut ws, &config, subcommand_args, "release")?;
+
+    check_warnings(subcommand_args)?;
+
+    cpackage(&ws, subcommand_args, &mut packages)?;
+
+    check_warnings(subcommand_args)?;
+
+    Ok(())
+}
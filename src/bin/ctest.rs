@@ -3,6 +3,8 @@ use cargo::CliResult;
 use cargo::Config;
 
 use cargo_c::build::*;
+use cargo_c::cli::get_matches_with_preset;
+use cargo_c::cli::print_version;
 use cargo_c::cli::run_cargo_fallback;
 use cargo_c::cli::subcommand_test;
 use cargo_c::config::*;
@@ -17,7 +19,7 @@ fn main() -> CliResult {
         .allow_external_subcommands(true)
         .subcommand(subcommand);
 
-    let args = app.clone().get_matches();
+    let args = get_matches_with_preset(&app)?;
 
     let subcommand_args = match args.subcommand() {
         Some(("ctest", args)) => args,
@@ -32,7 +34,7 @@ fn main() -> CliResult {
     };
 
     if subcommand_args.flag("version") {
-        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        print_version(subcommand_args)?;
         return Ok(());
     }
 
@@ -40,7 +42,11 @@ fn main() -> CliResult {
 
     let mut ws = subcommand_args.workspace(&config)?;
 
+    check_single_target(&ws, &config, subcommand_args, "ctest")?;
+
     let (packages, compile_opts) = cbuild(&mut ws, &config, subcommand_args, "dev")?;
 
+    check_warnings(subcommand_args)?;
+
     ctest(&ws, &config, subcommand_args, &packages, compile_opts)
 }
@@ -0,0 +1,50 @@
+use cargo::util::command_prelude::ArgMatchesExt;
+use cargo::CliResult;
+use cargo::Config;
+
+use cargo_c::build::check_warnings;
+use cargo_c::cli::get_matches_with_preset;
+use cargo_c::cli::print_version;
+use cargo_c::cli::run_cargo_fallback;
+use cargo_c::cli::subcommand_uninstall;
+use cargo_c::config::config_configure;
+use cargo_c::install::cuninstall;
+
+fn main() -> CliResult {
+    let mut config = Config::default()?;
+
+    let subcommand = subcommand_uninstall("cuninstall", "Uninstall the crate C-API");
+    let mut app = clap::command!()
+        .dont_collapse_args_in_usage(true)
+        .allow_external_subcommands(true)
+        .subcommand(subcommand);
+
+    let args = get_matches_with_preset(&app)?;
+
+    let subcommand_args = match args.subcommand() {
+        Some(("cuninstall", args)) => args,
+        Some((cmd, args)) => {
+            return run_cargo_fallback(cmd, args);
+        }
+        _ => {
+            // No subcommand provided.
+            app.print_help()?;
+            return Ok(());
+        }
+    };
+
+    if subcommand_args.flag("version") {
+        print_version(subcommand_args)?;
+        return Ok(());
+    }
+
+    config_configure(&mut config, subcommand_args)?;
+
+    let ws = subcommand_args.workspace(&config)?;
+
+    cuninstall(&ws, subcommand_args)?;
+
+    check_warnings(subcommand_args)?;
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Read, Write};
-use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use cargo::core::compiler::unit_graph::UnitDep;
@@ -17,11 +17,23 @@ use cargo::{CliResult, Config};
 use cargo_util::paths::{copy, create, create_dir_all, open, read, read_bytes, write};
 use semver::Version;
 
-use crate::build_targets::BuildTargets;
-use crate::install::InstallPaths;
+use crate::build_targets::{rustc_shared_lib, BuildTargets};
+use crate::install::{substitute_template, InstallPaths};
 use crate::pkg_config_gen::PkgConfig;
 use crate::target;
 
+/// The `<NAME>_MAJOR`, `<NAME>_MINOR` and `<NAME>_PATCH` macros of the header, the name
+/// made a C identifier
+fn version_macros(name: &str, version: &Version) -> String {
+    let prefix = name
+        .to_uppercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    format!(
+        "\n#define {0}_MAJOR {1}\n#define {0}_MINOR {2}\n#define {0}_PATCH {3}\n",
+        prefix, version.major, version.minor, version.patch
+    )
+}
+
 /// Build the C header
 fn build_include_file(
     ws: &Workspace,
@@ -41,14 +53,7 @@ fn build_include_file(
     // TODO: map the errors
     let mut config = cbindgen::Config::from_root_or_default(crate_path);
     let warning = config.autogen_warning.unwrap_or_default();
-    let version_info = format!(
-        "\n#define {0}_MAJOR {1}\n#define {0}_MINOR {2}\n#define {0}_PATCH {3}\n",
-        name.to_uppercase(),
-        version.major,
-        version.minor,
-        version.patch
-    );
-    config.autogen_warning = Some(warning + &version_info);
+    config.autogen_warning = Some(warning + &version_macros(name, version));
     cbindgen::Builder::new()
         .with_crate(crate_path)
         .with_config(config)
@@ -59,6 +64,102 @@ fn build_include_file(
     Ok(())
 }
 
+/// Build the Python module loading the library through cffi
+/// `dll` is the file name of the DLL when building for Windows, the MSVC naming is assumed
+/// otherwise
+fn build_python_module(
+    ws: &Workspace,
+    capi_config: &CApiConfig,
+    root_output: &Path,
+    root_path: &Path,
+    dll: Option<&str>,
+) -> anyhow::Result<()> {
+    ws.config()
+        .shell()
+        .status("Building", "Python module using cbindgen")?;
+
+    // cffi only parses plain declarations
+    let mut config = cbindgen::Config::from_root_or_default(root_path);
+    config.language = cbindgen::Language::C;
+    config.cpp_compat = false;
+    config.no_includes = true;
+    config.includes.clear();
+    config.sys_includes.clear();
+    config.after_includes = None;
+    config.header = None;
+    config.trailer = None;
+    config.autogen_warning = None;
+    config.include_guard = None;
+    config.pragma_once = false;
+    config.include_version = false;
+    config.documentation = false;
+
+    let bindings = cbindgen::Builder::new()
+        .with_crate(root_path)
+        .with_config(config)
+        .generate()
+        .map_err(|e| anyhow::anyhow!("Cannot generate the cffi declarations: {}", e))?;
+    let mut declarations = Vec::new();
+    bindings.write(&mut declarations);
+    let cdef = String::from_utf8(declarations)?
+        .lines()
+        .filter(|l| !l.starts_with('#') || l.starts_with("#define "))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let library = &capi_config.library;
+    let lib_name = &library.name;
+    let so_name = if library.versioning {
+        format!("lib{lib_name}.so.{}", library.version.major)
+    } else {
+        format!("lib{lib_name}.so")
+    };
+    let dll = match dll {
+        Some(dll) => dll.to_string(),
+        None => format!("{}.dll", library.dll_name.as_deref().unwrap_or(lib_name)),
+    };
+    let env_var = lib_name
+        .to_uppercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+
+    let module = format!(
+        r#"# Generated by cargo-c, do not edit
+import ctypes.util
+import os
+import sys
+
+from cffi import FFI
+
+ffi = FFI()
+ffi.cdef("""
+{cdef}
+""")
+
+
+def _library_path():
+    path = os.environ.get("{env_var}_LIBRARY")
+    if path:
+        return path
+    path = ctypes.util.find_library("{lib_name}")
+    if path:
+        return path
+    if sys.platform == "win32":
+        return "{dll}"
+    if sys.platform == "darwin":
+        return "lib{lib_name}.dylib"
+    return "{so_name}"
+
+
+lib = ffi.dlopen(_library_path())
+"#
+    );
+
+    write(
+        root_output.join(format!("{}.py", capi_config.python.module)),
+        module,
+    )
+}
+
 /// Copy the pre-built C header from the asset directory to the root_dir
 fn copy_prebuilt_include_file(
     ws: &Workspace,
@@ -78,6 +179,109 @@ fn copy_prebuilt_include_file(
     Ok(())
 }
 
+/// Build the header defining the runtime directories as macros
+fn build_runtime_dirs_header(
+    ws: &Workspace,
+    capi_config: &CApiConfig,
+    install_paths: &InstallPaths,
+    root_output: &Path,
+) -> anyhow::Result<()> {
+    let header_name = match capi_config.runtime_dirs.header_name() {
+        Some(header_name) => header_name,
+        None => return Ok(()),
+    };
+
+    ws.config()
+        .shell()
+        .status("Building", "runtime directories header")?;
+
+    let prefix = capi_config
+        .library
+        .name
+        .to_uppercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let guard = header_name
+        .to_string_lossy()
+        .to_uppercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+
+    let mut buf =
+        format!("/* Generated by cargo-c, do not edit */\n#ifndef {guard}\n#define {guard}\n\n");
+    for (name, path) in capi_config.runtime_dirs.dirs.iter() {
+        let path = install_paths.libdir.join(path);
+        buf.push_str(&format!(
+            "#define {prefix}_{} \"{}\"\n",
+            name.to_uppercase(),
+            path.display().to_string().replace('\\', "\\\\")
+        ));
+    }
+    buf.push_str(&format!("\n#endif /* {guard} */\n"));
+
+    write(root_output.join(header_name), buf)
+}
+
+/// Build the Swift package manifest and the module map
+fn build_swift_package(
+    ws: &Workspace,
+    capi_config: &CApiConfig,
+    install_paths: &InstallPaths,
+    root_output: &Path,
+) -> anyhow::Result<()> {
+    let swift = &capi_config.swift;
+    if !swift.enabled {
+        return Ok(());
+    }
+
+    ws.config().shell().status("Building", "Swift package")?;
+
+    let module = &swift.module;
+    let package_dir = root_output.join("swift");
+    let target = match &swift.xcframework {
+        Some(xcframework) => format!(".binaryTarget(name: \"{module}\", path: \"{xcframework}\")"),
+        None => format!(
+            ".systemLibrary(name: \"{module}\", pkgConfig: \"{}\")",
+            capi_config.pkg_config.filename
+        ),
+    };
+    let manifest = format!(
+        "// swift-tools-version:5.3
+// Generated by cargo-c, do not edit
+import PackageDescription
+
+let package = Package(
+    name: \"{module}\",
+    products: [
+        .library(name: \"{module}\", targets: [\"{module}\"]),
+    ],
+    targets: [
+        {target},
+    ]
+)
+"
+    );
+    create_dir_all(&package_dir)?;
+    write(package_dir.join("Package.swift"), manifest)?;
+
+    if swift.xcframework.is_none() {
+        let mut header_name = PathBuf::from(&capi_config.header.name);
+        header_name.set_extension("h");
+        let header = install_paths
+            .includedir
+            .join(&capi_config.header.subdirectory)
+            .join(header_name);
+        let modulemap = format!(
+            "module {module} [system] {{\n    header \"{}\"\n    link \"{}\"\n    export *\n}}\n",
+            header.display().to_string().replace('\\', "\\\\"),
+            capi_config.library.name
+        );
+        let module_dir = package_dir.join("Sources").join(module);
+        create_dir_all(&module_dir)?;
+        write(module_dir.join("module.modulemap"), modulemap)?;
+    }
+
+    Ok(())
+}
+
 fn build_pc_file(name: &str, root_output: &Path, pc: &PkgConfig) -> anyhow::Result<()> {
     let pc_path = root_output.join(format!("{name}.pc"));
     let buf = pc.render();
@@ -101,33 +305,450 @@ fn build_pc_files(
     )
 }
 
-fn patch_target(
-    pkg: &mut Package,
-    libkinds: &[&str],
+/// The environment variables pointing at the artifacts in the build tree, e.g. `FOO_C_LIB_DIR`,
+/// for the Rust tests building C code against the library
+fn artifact_env(
+    capi_config: &CApiConfig,
+    build_targets: &BuildTargets,
+    static_libs: &str,
+) -> Vec<(String, String)> {
+    let prefix = capi_config
+        .library
+        .name
+        .to_uppercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let root_output = build_targets.pc.parent().unwrap();
+
+    let mut env = vec![
+        ("INCLUDE_DIR", root_output.join("include")),
+        ("LIB_DIR", root_output.to_path_buf()),
+    ];
+    env.extend(build_targets.static_lib.clone().map(|l| ("STATIC_LIB", l)));
+    env.extend(build_targets.shared_lib.clone().map(|l| ("SHARED_LIB", l)));
+
+    env.into_iter()
+        .map(|(name, path)| (name, path.display().to_string()))
+        .chain(std::iter::once((
+            "NATIVE_STATIC_LIBS",
+            static_libs.trim().to_string(),
+        )))
+        .map(|(name, value)| (format!("{prefix}_C_{name}"), value))
+        .collect()
+}
+
+/// Write the artifact environment variables as `<filename>.env` in the build tree
+fn build_env_file(
+    ws: &Workspace,
     capi_config: &CApiConfig,
+    build_targets: &BuildTargets,
+    root_output: &Path,
+    static_libs: &str,
 ) -> anyhow::Result<()> {
-    use cargo::core::compiler::CrateType;
+    ws.config()
+        .shell()
+        .verbose(|s| s.status("Building", "artifacts environment file"))?;
 
-    let manifest = pkg.manifest_mut();
-    let targets = manifest.targets_mut();
+    let env: String = artifact_env(capi_config, build_targets, static_libs)
+        .iter()
+        .map(|(name, value)| format!("{name}={value}\n"))
+        .collect();
+    write(
+        root_output.join(format!("{}.env", capi_config.pkg_config.filename)),
+        env,
+    )
+}
+
+/// Write the flags a C consumer needs to use the library from the build tree, as json and as
+/// a `.clangd` fragment, so the editors can pick up the generated header before installing
+fn build_compile_flags_files(
+    ws: &Workspace,
+    filename: &str,
+    root_output: &Path,
+    pc: &PkgConfig,
+) -> anyhow::Result<()> {
+    ws.config()
+        .shell()
+        .verbose(|s| s.status("Building", "compiler flags for the C consumers"))?;
+
+    let flags = pc.uninstalled(root_output).expanded_cflags();
+    let include_dirs: Vec<_> = flags.iter().filter_map(|f| f.strip_prefix("-I")).collect();
+    let defines: Vec<_> = flags.iter().filter_map(|f| f.strip_prefix("-D")).collect();
+
+    let json = serde_json::json!({
+        "name": filename,
+        "include_dirs": include_dirs,
+        "defines": defines,
+        "flags": flags,
+    });
+    write(
+        root_output.join(format!("{filename}-compile-flags.json")),
+        serde_json::to_string_pretty(&json)? + "\n",
+    )?;
+
+    let clangd = format!(
+        "# Generated by cargo-c, add it to the .clangd of the C consumer\nCompileFlags:\n  Add: [{}]\n",
+        flags
+            .iter()
+            .map(|f| serde_json::to_string(f).unwrap())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    write(root_output.join(format!("{filename}.clangd")), clangd)
+}
+
+/// The name of the artifacts as produced by rustc
+fn artifact_name(name: &str) -> String {
+    name.replace(['-', '.'], "_")
+}
+
+/// The separate debug information emitted next to the shared library with this profile
+///
+/// The MSVC linker writes a PDB unless the library is stripped, the Apple targets get a
+/// `.dSYM` bundle only with `split-debuginfo = "packed"`. The other targets keep the debug
+/// information in the library itself.
+fn debug_info_artifact(
+    profile: &cargo::core::profiles::Profile,
+    min_size: bool,
+    target: &target::Target,
+    root_output: &Path,
+    name: &str,
+) -> Option<PathBuf> {
+    use cargo::core::profiles::Strip;
+
+    let artifact = artifact_name(name);
+    let stripped = min_size || profile.strip != Strip::None;
+    let debuginfo = profile.debuginfo.unwrap_or(0) > 0;
+    match (target.os.as_str(), target.env.as_str()) {
+        ("windows", "msvc") if !stripped => Some(root_output.join(format!("{artifact}.pdb"))),
+        ("macos", _) | ("ios", _)
+            if debuginfo && profile.split_debuginfo.as_deref() == Some("packed") =>
+        {
+            Some(root_output.join(format!("lib{artifact}.dylib.dSYM")))
+        }
+        _ => None,
+    }
+}
+
+/// Number of warnings emitted by cargo-c itself
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Emit a cargo-c diagnostic, turned into an error by `--deny-warnings`
+pub(crate) fn warn(config: &Config, message: impl std::fmt::Display) -> anyhow::Result<()> {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+    config.shell().warn(message)
+}
+
+/// Fail if any cargo-c warning was emitted and `--deny-warnings` is set
+pub fn check_warnings(args: &ArgMatches) -> anyhow::Result<()> {
+    let count = WARNINGS.load(Ordering::Relaxed);
+    if count > 0 && args.get_flag("deny_warnings") {
+        anyhow::bail!(
+            "cargo-c emitted {} warning{}, denied by --deny-warnings",
+            count,
+            if count == 1 { "" } else { "s" }
+        );
+    }
+    Ok(())
+}
+
+/// The keys understood in each table of `package.metadata.capi`
+const CAPI_KEYS: &[(&str, &[&str])] = &[
+    (
+        "",
+        &[
+            "min_version",
+            "name_style",
+            "header_name",
+            "header",
+            "pkg_config",
+            "library",
+            "install",
+            "runtime_dirs",
+            "env",
+            "def",
+            "swift",
+            "python",
+            "js",
+            "licenses",
+            "pack",
+            "target",
+            "preset",
+        ],
+    ),
+    (
+        "header",
+        &["name", "subdirectory", "generation", "enabled", "layout"],
+    ),
+    (
+        "pkg_config",
+        &[
+            "name",
+            "filename",
+            "description",
+            "version",
+            "requires",
+            "requires_private",
+            "dependencies",
+            "strip_include_path_components",
+            "include_cflags",
+            "requires_sys_versions",
+            "check_requires",
+            "install",
+            "modules",
+            "libs_private",
+            "variables",
+        ],
+    ),
+    (
+        "library",
+        &[
+            "name",
+            "version",
+            "install_subdir",
+            "versioning",
+            "rustflags",
+            "max_glibc_version",
+            "macos_deployment_target",
+            "dll_name",
+            "dll_prefix",
+            "dll_suffix",
+            "dll_version",
+            "codesign_identity",
+            "allocator",
+            "init",
+            "fini",
+            "features",
+            "parallel_installable",
+            "linker_script",
+            "symbol_version",
+            "framework",
+            "bundle_identifier",
+            "types",
+            "prefix",
+            "suffix",
+            "plugin",
+            "version_resource",
+            "manifest",
+            "stamp",
+            "reproducible",
+        ],
+    ),
+    (
+        "install",
+        &[
+            "include",
+            "data",
+            "systemd",
+            "udev",
+            "dbus",
+            "man",
+            "doc",
+            "bin",
+            "debug_info",
+            "rlib",
+        ],
+    ),
+    ("runtime_dirs", &["dirs", "header"]),
+    (
+        "def",
+        &[
+            "symbols",
+            "exports",
+            "exports_file",
+            "kill_at",
+            "stdcall_alias",
+            "install",
+        ],
+    ),
+    ("swift", &["enabled", "module", "xcframework"]),
+    ("python", &["enabled", "module"]),
+    ("js", &["enabled"]),
+    ("licenses", &["deny"]),
+    ("pack", &["vendor"]),
+];
+
+/// Warn about the metadata keys cargo-c ignores, usually misspelled ones
+fn check_capi_keys(config: &Config, pkg: &Package) -> anyhow::Result<()> {
+    let manifest = read(pkg.manifest_path())?.parse::<toml::Value>()?;
+    let capi = match manifest
+        .get("package")
+        .and_then(|v| v.get("metadata"))
+        .and_then(|v| v.get("capi"))
+    {
+        Some(capi) => capi,
+        None => return Ok(()),
+    };
+
+    check_capi_table_keys(config, pkg, capi, "package.metadata.capi")?;
+
+    for (spec, overrides) in capi
+        .get("target")
+        .and_then(|v| v.as_table())
+        .into_iter()
+        .flatten()
+    {
+        check_capi_table_keys(
+            config,
+            pkg,
+            overrides,
+            &format!("package.metadata.capi.target.'{spec}'"),
+        )?;
+    }
+
+    for (name, preset) in capi
+        .get("preset")
+        .and_then(|v| v.as_table())
+        .into_iter()
+        .flatten()
+    {
+        let mut preset = preset.clone();
+        if let Some(table) = preset.as_table_mut() {
+            table.remove("args");
+        }
+        check_capi_table_keys(
+            config,
+            pkg,
+            &preset,
+            &format!("package.metadata.capi.preset.{name}"),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn check_capi_table_keys(
+    config: &Config,
+    pkg: &Package,
+    capi: &toml::Value,
+    prefix: &str,
+) -> anyhow::Result<()> {
+    for (section, keys) in CAPI_KEYS {
+        let table = if section.is_empty() {
+            capi.as_table()
+        } else {
+            capi.get(section).and_then(|v| v.as_table())
+        };
+        for key in table.into_iter().flat_map(|t| t.keys()) {
+            if !keys.contains(&key.as_str()) {
+                let path = if section.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{section}.{key}")
+                };
+                warn(
+                    config,
+                    format!(
+                        "unknown key {prefix}.{path} in {}",
+                        pkg.manifest_path().display()
+                    ),
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The `module` library type is an unversioned shared library, loaded with dlopen only, the
+/// plugins are always built as such
+fn apply_library_kinds(capi_config: &mut CApiConfig, libkinds: &[&str]) {
+    if libkinds.contains(&"module") || capi_config.library.plugin.is_some() {
+        capi_config.library.module = true;
+        capi_config.library.versioning = false;
+    }
+}
+
+fn lib_crate_types(libkinds: &[&str]) -> Vec<cargo::core::compiler::CrateType> {
+    use cargo::core::compiler::CrateType;
 
     let mut kinds: Vec<_> = libkinds
         .iter()
         .map(|&kind| match kind {
             "staticlib" => CrateType::Staticlib,
-            "cdylib" => CrateType::Cdylib,
+            "cdylib" | "module" => CrateType::Cdylib,
             _ => unreachable!(),
         })
         .collect();
 
     kinds.push(CrateType::Lib);
 
+    kinds
+}
+
+fn patch_target(
+    config: &Config,
+    pkg: &mut Package,
+    libkinds: &[&str],
+    capi_config: &CApiConfig,
+) -> anyhow::Result<()> {
+    let pkg_name = pkg.name();
+    let manifest = pkg.manifest_mut();
+    let targets = manifest.targets_mut();
+
+    let kinds = lib_crate_types(libkinds);
+
     for target in targets.iter_mut() {
+        if target.is_lib() {
+            check_crate_types(config, &pkg_name, target.rustc_crate_types(), &kinds)?;
+            target.set_kind(TargetKind::Lib(kinds.clone()));
+            target.set_name(&capi_config.library.name.replace('.', "_"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Build only some of the library types, when they are built with different features
+fn set_lib_kinds(pkg: &mut Package, libkinds: &[&str]) {
+    let kinds = lib_crate_types(libkinds);
+    for target in pkg.manifest_mut().targets_mut().iter_mut() {
         if target.is_lib() {
             target.set_kind(TargetKind::Lib(kinds.clone()));
-            target.set_name(&capi_config.library.name);
         }
     }
+}
+
+/// Explain how the crate types set in the manifest relate to the ones cargo-c builds
+fn check_crate_types(
+    config: &Config,
+    pkg_name: &str,
+    declared: Vec<cargo::core::compiler::CrateType>,
+    kinds: &[cargo::core::compiler::CrateType],
+) -> anyhow::Result<()> {
+    use cargo::core::compiler::CrateType;
+
+    if declared.contains(&CrateType::ProcMacro) {
+        anyhow::bail!("{pkg_name} is a proc-macro crate, it cannot be built as a C library");
+    }
+
+    if declared.contains(&CrateType::Dylib) {
+        warn(
+            config,
+            format!(
+                "the `dylib` crate-type of {pkg_name} is not built by cargo-c, \
+             the C shared library is the `cdylib`"
+            ),
+        )?;
+    }
+
+    // Without an explicit crate-type only the rlib is declared
+    let explicit = declared != [CrateType::Lib];
+    let missing: Vec<_> = kinds
+        .iter()
+        .filter(|k| !matches!(k, CrateType::Lib) && !declared.contains(k))
+        .map(|k| format!("`{k}`"))
+        .collect();
+
+    if explicit && !missing.is_empty() {
+        warn(config, format!(
+            "the lib target of {pkg_name} lacks the {} crate-type, cargo-c adds it for this \
+             build but a plain `cargo build` does not.\n\
+             Remove `crate-type` from the `[lib]` section of Cargo.toml, or declare all of them:\n\n\
+             [lib]\n\
+             crate-type = [\"lib\", \"staticlib\", \"cdylib\"]\n",
+            missing.join(" and ")
+        ))?;
+    }
 
     Ok(())
 }
@@ -157,10 +778,15 @@ fn build_def_file(
 
         dumpbin
             .arg("/EXPORTS")
-            .arg(targetdir.join(format!("{}.dll", name.replace('-', "_"))));
+            .arg(targetdir.join(format!("{}.dll", artifact_name(name))));
         dumpbin.arg(format!("/OUT:{}", txt_path.to_str().unwrap()));
 
-        let out = dumpbin.output()?;
+        let out = match dumpbin.output() {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return build_def_file_builtin(ws, name, targetdir);
+            }
+            out => out?,
+        };
         if out.status.success() {
             let txt_file = open(txt_path)?;
             let buf_reader = BufReader::new(txt_file);
@@ -202,12 +828,172 @@ fn build_def_file(
     }
 }
 
+/// Build the def file reading the export table of the dll directly,
+/// used when dumpbin is not available
+fn build_def_file_builtin(ws: &Workspace, name: &str, targetdir: &Path) -> anyhow::Result<()> {
+    use object::read::Object;
+
+    ws.config()
+        .shell()
+        .status("Building", ".def file using the builtin generator")?;
+
+    let dll_path = targetdir.join(format!("{}.dll", artifact_name(name)));
+    let data = read_bytes(&dll_path)?;
+    let dll = object::read::File::parse(&*data)
+        .map_err(|e| anyhow::anyhow!("Cannot parse {}: {}", dll_path.display(), e))?;
+
+    let mut def_file = create(targetdir.join(format!("{name}.def")))?;
+    writeln!(def_file, "EXPORTS")?;
+    for export in dll
+        .exports()
+        .map_err(|e| anyhow::anyhow!("Cannot read the exports of {}: {}", dll_path.display(), e))?
+    {
+        writeln!(def_file, "\t{}", String::from_utf8_lossy(export.name()))?;
+    }
+
+    Ok(())
+}
+
+/// The name of an export without the `@N` stdcall suffix
+fn undecorated(name: &str) -> &str {
+    match name.rsplit_once('@') {
+        Some((base, size)) if !base.is_empty() && size.parse::<u16>().is_ok() => base,
+        _ => name,
+    }
+}
+
+/// Apply the per-symbol ordinals, attributes and stdcall decoration to the exports of the
+/// .def file, and drop the ones left out of the `def.exports` allowlist
+fn apply_def_symbols(
+    ws: &Workspace,
+    def_config: &DefCApiConfig,
+    target: &target::Target,
+    def_path: &Path,
+) -> anyhow::Result<()> {
+    if def_config.symbols.is_empty() && def_config.exports.is_none() {
+        return Ok(());
+    }
+
+    let (def, warnings) = def_with_symbols(&read(def_path)?, def_config, target);
+    for warning in warnings {
+        warn(ws.config(), warning)?;
+    }
+    write(def_path, def)
+}
+
+/// The .def file with the `def.symbols` and `def.exports` applied, and the warnings about
+/// the symbols they list that the library does not export
+fn def_with_symbols(
+    def: &str,
+    def_config: &DefCApiConfig,
+    target: &target::Target,
+) -> (String, Vec<String>) {
+    let x86 = target.arch == "x86";
+    let allowed = |name: &str| match &def_config.exports {
+        Some(exports) => {
+            exports.iter().any(|e| e == name)
+                || def_config
+                    .symbols
+                    .iter()
+                    .any(|s| s.aliases.iter().any(|a| a == name))
+        }
+        None => true,
+    };
+
+    let mut found = Vec::new();
+    let mut exported = Vec::new();
+    let mut in_exports = false;
+    let mut out = String::new();
+
+    for line in def.lines() {
+        let trimmed = line.trim();
+        if trimmed.eq_ignore_ascii_case("EXPORTS") {
+            in_exports = true;
+        } else if in_exports && !trimmed.is_empty() && !trimmed.starts_with(';') {
+            let mut tokens = trimmed.split_whitespace();
+            let entry = tokens.next().unwrap();
+            // `name=internal_name`, the exported name is the first one
+            let name = entry.split('=').next().unwrap();
+            if !allowed(undecorated(name)) {
+                continue;
+            }
+            exported.push(undecorated(name).to_string());
+            if let Some(symbol) = def_config
+                .symbols
+                .iter()
+                .find(|s| s.name == undecorated(name))
+            {
+                let mut entry = entry.to_string();
+                match symbol.stdcall {
+                    // The DLL exports it undecorated, the import library maps the `_name@N`
+                    // referenced by the `__stdcall` declarations to it
+                    Some(size) if x86 && undecorated(name) == name => {
+                        if def_config.stdcall_alias && target.env == "msvc" {
+                            let private = if symbol.private { " PRIVATE" } else { "" };
+                            out.push_str(&format!("\t{entry}{private}\n"));
+                        }
+                        entry = match entry.split_once('=') {
+                            Some((name, internal)) => format!("{name}@{size}={internal}"),
+                            None => format!("{entry}@{size}"),
+                        };
+                    }
+                    _ => {}
+                }
+                let mut export = format!("\t{entry}");
+                if let Some(ordinal) = symbol.ordinal {
+                    export.push_str(&format!(" @{ordinal}"));
+                    if symbol.noname {
+                        export.push_str(" NONAME");
+                    }
+                }
+                if tokens.any(|t| t == "DATA") {
+                    export.push_str(" DATA");
+                }
+                if symbol.private {
+                    export.push_str(" PRIVATE");
+                }
+                out.push_str(&export);
+                out.push('\n');
+                found.push(symbol.name.clone());
+                continue;
+            }
+        } else if !trimmed.is_empty() {
+            in_exports = false;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    let mut warnings = Vec::new();
+    for symbol in def_config.symbols.iter() {
+        if !found.contains(&symbol.name) {
+            warnings.push(format!(
+                "def.symbols.{} is not exported by the library",
+                symbol.name
+            ));
+        }
+    }
+    for name in def_config.exports.iter().flatten() {
+        if !exported.contains(name) {
+            warnings.push(format!(
+                "def.exports lists {name}, which the library does not export"
+            ));
+        }
+    }
+
+    (out, warnings)
+}
+
 /// Build import library for windows-gnu
+#[allow(clippy::too_many_arguments)]
 fn build_implib_file(
     ws: &Workspace,
     name: &str,
+    dll_name: &str,
+    def_config: &DefCApiConfig,
     target: &target::Target,
     targetdir: &Path,
+    implib: &Path,
     dlltool: &Path,
 ) -> anyhow::Result<()> {
     let os = &target.os;
@@ -224,21 +1010,33 @@ fn build_implib_file(
                 "x86_64" => "i386:x86-64",
                 "x86" => "i386",
                 "aarch64" => "arm64",
+                "arm" => "arm",
                 _ => unimplemented!("Windows support for {} is not implemented yet.", arch),
             };
 
             let mut dlltool_command =
                 std::process::Command::new(dlltool.to_str().unwrap_or("dlltool"));
             dlltool_command.arg("-m").arg(binutils_arch);
-            dlltool_command.arg("-D").arg(format!("{name}.dll"));
-            dlltool_command
-                .arg("-l")
-                .arg(targetdir.join(format!("{name}.dll.a")));
+            dlltool_command.arg("-D").arg(dll_name);
+            dlltool_command.arg("-l").arg(implib);
             dlltool_command
                 .arg("-d")
                 .arg(targetdir.join(format!("{name}.def")));
+            if arch == "x86" && def_config.kill_at {
+                dlltool_command.arg("--kill-at");
+            }
+            if arch == "x86" && def_config.stdcall_alias {
+                dlltool_command.arg("--add-stdcall-alias");
+            }
 
-            let out = dlltool_command.output()?;
+            let out = match dlltool_command.output() {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return build_implib_file_builtin(
+                        ws, name, dll_name, def_config, target, targetdir, implib,
+                    );
+                }
+                out => out?,
+            };
             if out.status.success() {
                 Ok(())
             } else {
@@ -246,14 +1044,15 @@ fn build_implib_file(
             }
         } else {
             ws.config().shell().status("Building", "implib using lib")?;
-            let target_str = format!("{}-pc-windows-msvc", &target.arch);
-            let mut lib = match cc::windows_registry::find(&target_str, "lib.exe") {
+            let mut lib = match cc::windows_registry::find(&target.triple, "lib.exe") {
                 Some(command) => command,
                 None => std::process::Command::new("lib"),
             };
             let lib_arch = match arch.as_str() {
                 "x86_64" => "X64",
                 "x86" => "IX86",
+                "aarch64" => "ARM64",
+                "arm" => "ARM",
                 _ => unimplemented!("Windows support for {} is not implemented yet.", arch),
             };
             lib.arg(format!(
@@ -261,13 +1060,17 @@ fn build_implib_file(
                 targetdir.join(format!("{name}.def")).display()
             ));
             lib.arg(format!("/MACHINE:{lib_arch}"));
-            lib.arg(format!("/NAME:{name}.dll"));
-            lib.arg(format!(
-                "/OUT:{}",
-                targetdir.join(format!("{name}.dll.lib")).display()
-            ));
-
-            let out = lib.output()?;
+            lib.arg(format!("/NAME:{dll_name}"));
+            lib.arg(format!("/OUT:{}", implib.display()));
+
+            let out = match lib.output() {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return build_implib_file_builtin(
+                        ws, name, dll_name, def_config, target, targetdir, implib,
+                    );
+                }
+                out => out?,
+            };
             if out.status.success() {
                 Ok(())
             } else {
@@ -279,12 +1082,73 @@ fn build_implib_file(
     }
 }
 
+/// Build the import library without relying on dlltool or lib.exe
+fn build_implib_file_builtin(
+    ws: &Workspace,
+    name: &str,
+    dll_name: &str,
+    def_config: &DefCApiConfig,
+    target: &target::Target,
+    targetdir: &Path,
+    implib_path: &Path,
+) -> anyhow::Result<()> {
+    use implib::{Flavor, ImportLibrary, MachineType};
+
+    ws.config()
+        .shell()
+        .status("Building", "implib using the builtin generator")?;
+
+    let stdcall = def_config.kill_at
+        || def_config.stdcall_alias
+        || def_config.symbols.iter().any(|s| s.stdcall.is_some());
+    if target.arch == "x86" && stdcall {
+        warn(
+            ws.config(),
+            "the builtin import library generator does not handle the stdcall decoration, \
+             install dlltool or lib.exe so the 32-bit consumers link correctly",
+        )?;
+    }
+
+    let machine = match target.arch.as_str() {
+        "x86_64" => MachineType::AMD64,
+        "x86" => MachineType::I386,
+        "aarch64" => MachineType::ARM64,
+        "arm" => MachineType::ARMNT,
+        arch => anyhow::bail!("Windows support for {} is not implemented yet.", arch),
+    };
+    let flavor = if target.env == "msvc" {
+        Flavor::Msvc
+    } else {
+        Flavor::Gnu
+    };
+
+    // The import library takes the dll name from the LIBRARY statement
+    let mut def = read(&targetdir.join(format!("{name}.def")))?;
+    if !def
+        .lines()
+        .any(|l| l.trim_start().to_ascii_uppercase().starts_with("LIBRARY"))
+    {
+        def = format!("LIBRARY {dll_name}\n{def}");
+    }
+
+    let implib = ImportLibrary::new(&def, machine, flavor)
+        .map_err(|e| anyhow::anyhow!("Cannot parse the .def file: {}", e))?;
+    let mut implib_file = create(implib_path)?;
+    implib.write_to(&mut implib_file)?;
+
+    Ok(())
+}
+
 #[derive(Debug)]
 struct FingerPrint {
     id: PackageId,
     root_output: PathBuf,
     build_targets: BuildTargets,
     install_paths: InstallPaths,
+    /// The C-API configuration the generated files are derived from
+    config: String,
+    /// The libraries as produced by rustc, when they are renamed afterwards
+    artifacts: Vec<PathBuf>,
     static_libs: String,
 }
 
@@ -300,12 +1164,15 @@ impl FingerPrint {
         root_output: &Path,
         build_targets: &BuildTargets,
         install_paths: &InstallPaths,
+        capi_config: &CApiConfig,
     ) -> Self {
         Self {
             id: id.to_owned(),
             root_output: root_output.to_owned(),
             build_targets: build_targets.clone(),
             install_paths: install_paths.clone(),
+            config: format!("{capi_config:?}"),
+            artifacts: Vec::new(),
             static_libs: String::new(),
         }
     }
@@ -316,13 +1183,18 @@ impl FingerPrint {
 
         let mut hasher = DefaultHasher::new();
         self.install_paths.hash(&mut hasher);
+        self.config.hash(&mut hasher);
 
-        let mut paths: Vec<&PathBuf> = Vec::new();
+        // A missing generated file, e.g. removed by hand, invalidates the cache as well
+        let mut paths: Vec<&PathBuf> = vec![&self.build_targets.pc];
+        paths.extend(&self.build_targets.pc_modules);
         if let Some(include) = &self.build_targets.include {
             paths.push(include);
         }
+        paths.extend(&self.build_targets.def);
         paths.extend(&self.build_targets.static_lib);
         paths.extend(&self.build_targets.shared_lib);
+        paths.extend(&self.artifacts);
 
         for path in paths.iter() {
             if let Ok(buf) = read_bytes(path) {
@@ -375,20 +1247,375 @@ impl FingerPrint {
     }
 }
 
-#[derive(Debug)]
-pub struct CApiConfig {
-    pub header: HeaderCApiConfig,
-    pub pkg_config: PkgConfigCApiConfig,
-    pub library: LibraryCApiConfig,
-    pub install: InstallCApiConfig,
+/// Built artifacts shared by the builds of several workspaces or CI jobs, stored by the hash
+/// of everything they are derived from
+struct ArtifactCache {
+    dir: PathBuf,
+    target_dir: PathBuf,
+    /// The inputs shared by the members: toolchain, target, profile, features and sources
+    inputs: String,
 }
 
-#[derive(Debug)]
-pub struct HeaderCApiConfig {
-    pub name: String,
-    pub subdirectory: String,
-    pub generation: bool,
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ArtifactCacheEntry {
+    /// The files, relative to the target directory
+    files: Vec<PathBuf>,
+    /// The OUT_DIR of the build script, relative to the target directory
+    out_dir: Option<PathBuf>,
+}
+
+impl ArtifactCache {
+    fn new(ws: &Workspace, dir: &Path, inputs: String) -> anyhow::Result<Self> {
+        let mut sources = cargo_util::Sha256::new();
+        // The path packages, the members and the path dependencies, are identified by their
+        // files, the other ones by their source
+        let (packages, resolve) = ops::resolve_ws(ws)?;
+        let mut path_ids: Vec<_> = resolve
+            .iter()
+            .filter(|id| id.source_id().is_path())
+            .collect();
+        path_ids.sort();
+        for pkg in packages.get_many(path_ids)? {
+            let path_source = cargo::sources::PathSource::new(
+                pkg.root(),
+                pkg.package_id().source_id(),
+                ws.config(),
+            );
+            for file in path_source.list_files(pkg)? {
+                let name = file.strip_prefix(ws.root()).unwrap_or(&file);
+                sources.update(name.to_string_lossy().as_bytes());
+                sources.update_path(&file)?;
+            }
+        }
+        if let Ok(lock) = read_bytes(&ws.root().join("Cargo.lock")) {
+            sources.update(&lock);
+        }
+
+        Ok(ArtifactCache {
+            dir: dir.to_owned(),
+            target_dir: ws.target_dir().into_path_unlocked(),
+            inputs: format!("{inputs}\n{}", sources.finish_hex()),
+        })
+    }
+
+    fn key(&self, cpkg: &CPackage) -> String {
+        let finger_print = &cpkg.finger_print;
+        let id = finger_print.id;
+        let source = if id.source_id().is_path() {
+            String::new()
+        } else {
+            id.source_id().to_string()
+        };
+
+        cargo_util::Sha256::new()
+            .update(env!("CARGO_PKG_VERSION").as_bytes())
+            .update(self.inputs.as_bytes())
+            .update(format!("{} {} {source}", id.name(), id.version()).as_bytes())
+            .update(finger_print.config.as_bytes())
+            .update(format!("{:?}", finger_print.install_paths).as_bytes())
+            .finish_hex()
+    }
+
+    /// The directory of the entry for `key` matching the current values of the environment
+    /// variables its build scripts read
+    fn entry_dir(&self, key: &str) -> PathBuf {
+        let key_dir = self.dir.join(key);
+        let names = read(&key_dir.join(ARTIFACT_CACHE_ENV_NAMES)).unwrap_or_default();
+        key_dir.join(env_hash(names.lines()))
+    }
+
+    /// Copy the files of the entry back in the target directory
+    fn restore(&self, key: &str) -> anyhow::Result<Option<ArtifactCacheEntry>> {
+        let entry_dir = self.entry_dir(key);
+        let entry: ArtifactCacheEntry = match read(&entry_dir.join("entry.toml")) {
+            Ok(entry) => toml::de::from_str(&entry)?,
+            Err(_) => return Ok(None),
+        };
+
+        for file in entry.files.iter() {
+            let (from, to) = (
+                entry_dir.join("files").join(file),
+                self.target_dir.join(file),
+            );
+            create_dir_all(to.parent().unwrap())?;
+            if from.is_dir() {
+                crate::install::copy_dir(&from, &to)?;
+            } else if is_uninstalled_pc(file) {
+                let target_dir = self.target_dir.display().to_string();
+                write(
+                    to,
+                    read(&from)?.replace(TARGET_DIR_PLACEHOLDER, &target_dir),
+                )?;
+            } else {
+                copy(from, to)?;
+            }
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Store the files of the target directory in a new entry, in place once complete so
+    /// that concurrent builds never see a partial one
+    ///
+    /// The entry is keyed by the values of `env_names` as well, the environment variables the
+    /// build scripts declared with `rerun-if-env-changed`.
+    fn store(
+        &self,
+        key: &str,
+        files: &[PathBuf],
+        out_dir: Option<&Path>,
+        env_names: &[String],
+    ) -> anyhow::Result<()> {
+        let key_dir = self.dir.join(key);
+        let names_file = key_dir.join(ARTIFACT_CACHE_ENV_NAMES);
+        let mut names: Vec<String> = read(&names_file)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .chain(env_names.iter().cloned())
+            .collect();
+        names.sort();
+        names.dedup();
+        create_dir_all(&key_dir)?;
+        write(&names_file, names.join("\n"))?;
+
+        let entry_dir = key_dir.join(env_hash(names.iter().map(String::as_str)));
+        if entry_dir.exists() {
+            return Ok(());
+        }
+        let partial = key_dir.join(format!("partial-{}", std::process::id()));
+
+        let mut entry = ArtifactCacheEntry {
+            files: Vec::new(),
+            out_dir: out_dir.and_then(|dir| Some(dir.strip_prefix(&self.target_dir).ok()?.into())),
+        };
+        for file in files {
+            let relative = match file.strip_prefix(&self.target_dir) {
+                Ok(relative) if file.exists() && !entry.files.iter().any(|f| f == relative) => {
+                    relative.to_path_buf()
+                }
+                _ => continue,
+            };
+            let to = partial.join("files").join(&relative);
+            create_dir_all(to.parent().unwrap())?;
+            if file.is_dir() {
+                crate::install::copy_dir(file, &to)?;
+            } else if is_uninstalled_pc(file) {
+                let target_dir = self.target_dir.display().to_string();
+                write(to, read(file)?.replace(&target_dir, TARGET_DIR_PLACEHOLDER))?;
+            } else {
+                copy(file, to)?;
+            }
+            entry.files.push(relative);
+        }
+        write(partial.join("entry.toml"), toml::ser::to_string(&entry)?)?;
+
+        if std::fs::rename(&partial, &entry_dir).is_err() {
+            // Another build stored the same entry in the meantime
+            std::fs::remove_dir_all(&partial)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The uninstalled pkg-config files point to the target directory they are in, it differs
+/// between the workspaces sharing the artifact cache
+const TARGET_DIR_PLACEHOLDER: &str = "@CARGO_C_TARGET_DIR@";
+
+/// The file of an artifact cache key listing the environment variables its entries depend on
+const ARTIFACT_CACHE_ENV_NAMES: &str = "env-names";
+
+/// The hash of the current values of the environment variables
+fn env_hash<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    let mut hash = cargo_util::Sha256::new();
+    for name in names.filter(|name| !name.is_empty()) {
+        hash.update(format!("{name}={:?}\n", std::env::var_os(name)).as_bytes());
+    }
+    hash.finish_hex()
+}
+
+/// The environment variables the build scripts run for the target declared they read, with
+/// `cargo:rerun-if-env-changed`
+fn build_script_env_names(root_output: &Path) -> Vec<String> {
+    let mut names = Vec::new();
+    let outputs = std::fs::read_dir(root_output.join("build"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| read(&entry.path().join("output")).ok());
+    for output in outputs {
+        names.extend(
+            output
+                .lines()
+                .filter_map(|line| line.strip_prefix("cargo:rerun-if-env-changed="))
+                .map(|name| name.trim().to_string()),
+        );
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn is_uninstalled_pc(path: &Path) -> bool {
+    path.to_string_lossy().ends_with("-uninstalled.pc")
+}
+
+/// The files of the target directory a member produces, as cached by the ArtifactCache
+fn cached_files(cpkg: &CPackage) -> Vec<PathBuf> {
+    let build_targets = &cpkg.build_targets;
+    let finger_print = &cpkg.finger_print;
+    let mut files = vec![finger_print.path()];
+    for pc in std::iter::once(&build_targets.pc).chain(&build_targets.pc_modules) {
+        let mut uninstalled = pc.clone();
+        uninstalled.set_file_name(format!(
+            "{}-uninstalled.pc",
+            pc.file_stem().unwrap().to_string_lossy()
+        ));
+        files.push(pc.clone());
+        files.push(uninstalled);
+    }
+    files.extend(
+        build_targets
+            .include
+            .iter()
+            .chain(&build_targets.static_lib)
+            .chain(&build_targets.shared_lib)
+            .chain(&build_targets.impl_lib)
+            .chain(&build_targets.def)
+            .chain(&build_targets.debug_info)
+            .chain(&build_targets.rustlib)
+            .chain(&build_targets.bins)
+            .chain(&finger_print.artifacts)
+            .cloned(),
+    );
+    let extra = &build_targets.extra;
+    files.extend(
+        extra
+            .include
+            .iter()
+            .chain(extra.non_include())
+            .map(|(from, _)| from.clone()),
+    );
+    files
+}
+
+#[derive(Debug)]
+pub struct CApiConfig {
+    pub header: HeaderCApiConfig,
+    pub pkg_config: PkgConfigCApiConfig,
+    pub library: LibraryCApiConfig,
+    pub install: InstallCApiConfig,
+    pub runtime_dirs: RuntimeDirsCApiConfig,
+    /// Environment variables set while building through cargo-c
+    pub env: Vec<(String, String)>,
+    pub def: DefCApiConfig,
+    pub swift: SwiftCApiConfig,
+    pub python: PythonCApiConfig,
+    pub js: JsCApiConfig,
+    pub licenses: LicensesCApiConfig,
+    pub pack: PackCApiConfig,
+    /// The `[lints.rust] unexpected_cfgs` check-cfg entries, registered with the ones of cargo-c
+    pub check_cfg: Vec<String>,
+}
+
+/// Licenses of the crates compiled into the library
+#[derive(Debug, Default)]
+pub struct LicensesCApiConfig {
+    /// Glob patterns of the SPDX identifiers that must not end up in the library
+    pub deny: Vec<String>,
+}
+
+/// CMSIS-Pack of the static library
+#[derive(Debug, Default)]
+pub struct PackCApiConfig {
+    /// The pack vendor, the first author or else the library name by default
+    pub vendor: String,
+}
+
+/// JavaScript loader and TypeScript declarations for the wasm library
+#[derive(Debug, Default)]
+pub struct JsCApiConfig {
+    pub enabled: bool,
+}
+
+/// Python module declaring the library API to cffi
+#[derive(Debug, Default)]
+pub struct PythonCApiConfig {
+    pub enabled: bool,
+    /// Name of the module, without the `.py` extension
+    pub module: String,
+}
+
+/// Swift package wrapping the library, installed in `datarootdir/swift`
+#[derive(Debug, Default)]
+pub struct SwiftCApiConfig {
+    pub enabled: bool,
+    /// Name of the clang module and of the Swift target
+    pub module: String,
+    /// Declare a binaryTarget pointing at this xcframework instead of a systemLibrary
+    pub xcframework: Option<String>,
+}
+
+impl SwiftCApiConfig {
+    /// Generated files, relative to the swift package directory
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files = vec![PathBuf::from("Package.swift")];
+        if self.xcframework.is_none() {
+            files.push(
+                Path::new("Sources")
+                    .join(&self.module)
+                    .join("module.modulemap"),
+            );
+        }
+        files
+    }
+}
+
+/// Tweaks to the exports of the Windows module-definition file
+#[derive(Debug, Default)]
+pub struct DefCApiConfig {
+    pub symbols: Vec<DefSymbol>,
+    /// The only symbols exported by the DLL and its import library, all the ones rustc
+    /// exports when unset
+    pub exports: Option<Vec<String>>,
+    /// 32-bit x86 windows-gnu: export the stdcall symbols undecorated from the DLL and
+    /// look them up undecorated from the import library
+    pub kill_at: bool,
+    /// 32-bit x86: import the stdcall symbols under their undecorated name as well
+    pub stdcall_alias: bool,
+    /// Install the .def file next to the import library, true by default
+    pub install: bool,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DefSymbol {
+    #[serde(skip)]
+    pub name: String,
+    pub ordinal: Option<u16>,
+    /// Export only by ordinal, requires `ordinal`
+    #[serde(default)]
+    pub noname: bool,
+    /// Keep the symbol out of the import library
+    #[serde(default)]
+    pub private: bool,
+    /// Other names the symbol is exported under
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Size of the arguments of a `__stdcall` function, the `@N` of its decorated name
+    /// on 32-bit x86
+    pub stdcall: Option<u16>,
+}
+
+#[derive(Debug)]
+pub struct HeaderCApiConfig {
+    pub name: String,
+    pub subdirectory: String,
+    pub generation: bool,
     pub enabled: bool,
+    /// The header asserting the layouts of the types, relative to the package root
+    pub layout: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -400,6 +1627,34 @@ pub struct PkgConfigCApiConfig {
     pub requires: Option<String>,
     pub requires_private: Option<String>,
     pub strip_include_path_components: usize,
+    /// Add the include subdirectories of the installed headers to the Cflags
+    pub include_cflags: bool,
+    /// Constrain the `Requires` entries without a version to the one the `-sys` crates
+    /// require in their `system-deps` metadata
+    pub requires_sys_versions: bool,
+    /// Check that pkg-config finds the `Requires` entries at build time
+    pub check_requires: bool,
+    /// Whether the pkg-config file is installed, not by default on bare metal
+    pub install: bool,
+    /// Additional pkg-config files for the optional parts of the library
+    pub modules: Vec<PkgConfigModule>,
+    /// Linker flags appended to `Libs.private`
+    pub libs_private: Option<String>,
+    /// Additional variables, as name and value
+    pub variables: Vec<(String, String)>,
+}
+
+/// A pkg-config file written next to the main one, from the same build
+#[derive(Debug)]
+pub struct PkgConfigModule {
+    pub filename: String,
+    pub name: String,
+    pub description: Option<String>,
+    /// The main pkg-config file by default
+    pub requires: Option<String>,
+    pub requires_private: Option<String>,
+    pub libs: Option<String>,
+    pub cflags: Option<String>,
 }
 
 #[derive(Debug)]
@@ -409,12 +1664,142 @@ pub struct LibraryCApiConfig {
     pub install_subdir: Option<String>,
     pub versioning: bool,
     pub rustflags: Vec<String>,
+    /// Maximum glibc symbol version the shared library may depend on
+    pub max_glibc_version: Option<String>,
+    /// Minimum macOS version the library supports
+    pub macos_deployment_target: Option<String>,
+    /// File name of the Windows DLL, without extension, if it differs from the library name
+    pub dll_name: Option<String>,
+    /// File name prefix of the Windows DLL, `lib` for windows-gnu and none for windows-msvc
+    /// by default
+    pub dll_prefix: Option<String>,
+    /// File name suffix of the Windows DLL
+    pub dll_suffix: Option<String>,
+    /// Append the major version to the DLL name, e.g. `libfoo-2.dll`
+    pub dll_version: bool,
+    /// Identity used to sign the installed macOS library, ad-hoc signing is used otherwise
+    pub codesign_identity: Option<String>,
+    pub allocator: Option<Allocator>,
+    /// Exported function run when the shared library is loaded
+    pub init: Option<String>,
+    /// Exported function run when the shared library is unloaded
+    pub fini: Option<String>,
+    /// Features enabled only when building the static library
+    pub staticlib_features: Vec<String>,
+    /// Features enabled only when building the shared library
+    pub cdylib_features: Vec<String>,
+    /// Install a GNU ld script fragment linking the static library on bare metal
+    pub linker_script: bool,
+    /// Version node of the symbols exported by the ELF shared library, e.g. `FOO_1.0`
+    pub symbol_version: Option<String>,
+    /// Ship the shared library as a `<name>.framework` bundle, only on macOS and iOS
+    pub framework: bool,
+    /// `CFBundleIdentifier` of the framework, the library name by default
+    pub bundle_identifier: Option<String>,
+    /// Library types built when `--library-type` is not passed
+    pub types: Option<Vec<String>>,
+    /// File name prefix of the shared library, replacing the one of the target
+    pub prefix: Option<String>,
+    /// File name suffix of the shared library, replacing the one of the target
+    pub suffix: Option<String>,
+    /// The shared library is a module loaded with dlopen, built with the `module` library type
+    pub module: bool,
+    /// The library is a plugin of another package of the workspace
+    pub plugin: Option<Plugin>,
+    /// Link a VERSIONINFO resource into the Windows DLL
+    pub version_resource: bool,
+    /// Side-by-side manifest embedded in the Windows DLL
+    pub manifest: Option<DllManifest>,
+    /// Record the crate, its version and the cargo-c version in the shared library
+    pub stamp: bool,
+    /// Build a static library not depending on the build machine: the archive members have
+    /// no timestamps nor owners and the debug info has no absolute paths
+    pub reproducible: bool,
+}
+
+/// The manifest of the DLL, generated or provided by the package
+#[derive(Clone, Debug)]
+pub enum DllManifest {
+    Generated,
+    /// Relative to the package root
+    File(PathBuf),
+}
+
+/// The package a plugin extends and the runtime directory of that package it is installed in
+#[derive(Debug, Clone)]
+pub struct Plugin {
+    pub package: String,
+    pub dir: String,
+}
+
+/// Global allocator the library is built with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Allocator {
+    /// The host malloc, verified once built
+    System,
+    /// The allocator selected by a feature of the crate
+    Feature(String),
+}
+
+/// Install-relative directories the library looks up at runtime, e.g. to dlopen plugins
+#[derive(Debug, Default)]
+pub struct RuntimeDirsCApiConfig {
+    /// Variable name and path relative to `libdir`
+    pub dirs: Vec<(String, String)>,
+    /// Name of the header defining the absolute paths as macros
+    pub header: Option<String>,
+}
+
+impl RuntimeDirsCApiConfig {
+    fn header_name(&self) -> Option<PathBuf> {
+        self.header.as_ref().map(|name| {
+            let mut header_name = PathBuf::from(name);
+            header_name.set_extension("h");
+            header_name
+        })
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct InstallCApiConfig {
     pub include: Vec<InstallTarget>,
+    /// The include subdirectory of the asset and generated headers if it differs from the
+    /// generated header one
+    pub include_subdirectory: Option<String>,
     pub data: Vec<InstallTarget>,
+    /// Data installed in the well-known destinations of `{datarootdir}`
+    pub dataroot: Vec<InstallTarget>,
+    pub systemd: Vec<InstallTarget>,
+    pub udev: Vec<InstallTarget>,
+    pub dbus: Vec<InstallTarget>,
+    /// Manual pages, installed in the `{mandir}/man<section>` matching their extension
+    pub man: Vec<InstallTarget>,
+    /// Install the manual pages gzip compressed
+    pub man_compress: bool,
+    /// Documentation installed in `{docdir}`
+    pub doc: Vec<InstallTarget>,
+    /// The `[[bin]]` targets built with the library and installed in `{bindir}`
+    pub bin: Vec<String>,
+    /// Install the separate debug information next to the library, not by default for the
+    /// UWP targets as the Store packages do not ship it
+    pub debug_info: bool,
+    /// Install the rlib and the ones of its dependencies in `{libdir}/rustlib`, for the
+    /// consumers of the Rust crate
+    pub rlib: bool,
+}
+
+impl InstallCApiConfig {
+    /// All the install targets that are not headers
+    pub fn non_include(&self) -> impl Iterator<Item = &InstallTarget> {
+        self.data
+            .iter()
+            .chain(self.dataroot.iter())
+            .chain(self.systemd.iter())
+            .chain(self.udev.iter())
+            .chain(self.dbus.iter())
+            .chain(self.man.iter())
+            .chain(self.doc.iter())
+    }
 }
 
 #[derive(Debug)]
@@ -423,7 +1808,21 @@ pub enum InstallTarget {
     Generated(InstallTargetPaths),
 }
 
-#[derive(Clone, Debug)]
+impl InstallTarget {
+    pub fn paths(&self) -> &InstallTargetPaths {
+        match self {
+            InstallTarget::Asset(paths) | InstallTarget::Generated(paths) => paths,
+        }
+    }
+
+    pub fn paths_mut(&mut self) -> &mut InstallTargetPaths {
+        match self {
+            InstallTarget::Asset(paths) | InstallTarget::Generated(paths) => paths,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct InstallTargetPaths {
     /// pattern to feed to glob::glob()
     ///
@@ -433,6 +1832,30 @@ pub struct InstallTargetPaths {
     /// The path to be joined to the canonical directory to install the files discovered by the
     /// glob, e.g. `{includedir}/{to}` for includes.
     pub to: String,
+    /// Substitute the `@VAR@` placeholders in the files at install time, a trailing `.in`
+    /// extension is removed from the installed file name.
+    pub template: bool,
+    /// Well-known data destination, `to` is then relative to `{datarootdir}/{destination}`
+    pub destination: Option<String>,
+    /// patterns matched against the file path relative to the glob base and the file name,
+    /// the matching files are not installed
+    pub exclude: Vec<String>,
+}
+
+/// Map the well-known data destinations to their path in `{datarootdir}`
+fn well_known_destination(name: &str) -> anyhow::Result<&'static str> {
+    let path = match name {
+        "completions.bash" => "bash-completion/completions",
+        "completions.zsh" => "zsh/site-functions",
+        "completions.fish" => "fish/vendor_completions.d",
+        "applications" => "applications",
+        "icons" => "icons/hicolor",
+        "metainfo" => "metainfo",
+        "mime" => "mime/packages",
+        _ => anyhow::bail!("Unknown data destination {}", name),
+    };
+
+    Ok(path)
 }
 
 impl InstallTargetPaths {
@@ -441,14 +1864,35 @@ impl InstallTargetPaths {
             .get("from")
             .and_then(|v| v.as_str())
             .ok_or_else(|| anyhow::anyhow!("a from field is required"))?;
-        let to = value
-            .get("to")
+        let destination = value
+            .get("destination")
             .and_then(|v| v.as_str())
-            .unwrap_or(default_to);
+            .map(String::from);
+        let to = value.get("to").and_then(|v| v.as_str());
+        let to = if let Some(destination) = &destination {
+            Path::new(well_known_destination(destination)?)
+                .join(to.unwrap_or(""))
+                .to_str()
+                .unwrap()
+                .to_string()
+        } else {
+            to.unwrap_or(default_to).to_string()
+        };
+        let template = value
+            .get("template")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let exclude = value
+            .get("exclude")
+            .map(|v| v.clone().try_into())
+            .unwrap_or_else(|| Ok(Vec::new()))?;
 
         Ok(InstallTargetPaths {
             from: from.to_string(),
-            to: to.to_string(),
+            to,
+            template,
+            destination,
+            exclude,
         })
     }
 
@@ -467,11 +1911,26 @@ impl InstallTargetPaths {
         };
         let pattern = pattern.to_str().unwrap();
         let to = PathBuf::from(&self.to);
+        let template = self.template;
+        let exclude = self
+            .exclude
+            .iter()
+            .map(|p| glob::Pattern::new(p))
+            .collect::<Result<Vec<_>, _>>()?;
         let g = glob::glob(pattern)?.filter_map(move |p| {
             if let Ok(p) = p {
-                if p.is_file() {
+                let relative = p.strip_prefix(&base_pattern).unwrap();
+                let excluded = exclude.iter().any(|e| {
+                    e.matches_path(relative)
+                        || p.file_name()
+                            .map_or(false, |n| e.matches_path(Path::new(n)))
+                });
+                if p.is_file() && !excluded {
+                    let mut to = to.join(relative);
                     let from = p;
-                    let to = to.join(from.strip_prefix(&base_pattern).unwrap());
+                    if template && to.extension() == Some(std::ffi::OsStr::new("in")) {
+                        to.set_extension("");
+                    }
                     Some((from, to))
                 } else {
                     None
@@ -485,8 +1944,70 @@ impl InstallTargetPaths {
     }
 }
 
-fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
-    let name = &pkg
+/// Overlay the tables of `package.metadata.capi.target` whose triple or `cfg()` expression
+/// matches the target, in the order of their keys
+///
+/// Nested tables are merged, any other value replaces the one set before.
+fn merge(base: &mut toml::Value, overrides: &toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+/// The preset selected with `--preset`, if the command has the option
+pub(crate) fn selected_preset(args: &ArgMatches) -> Option<&str> {
+    args.try_get_one::<String>("preset")
+        .ok()
+        .flatten()
+        .map(String::as_str)
+}
+
+/// Merge the tables of the preset over the metadata, its `args` are command line arguments
+fn apply_preset(capi: &toml::Value, preset: &str) -> Option<toml::Value> {
+    let mut overrides = capi.get("preset")?.get(preset)?.clone();
+    if let Some(table) = overrides.as_table_mut() {
+        table.remove("args");
+    }
+
+    let mut merged = capi.clone();
+    merge(&mut merged, &overrides);
+    Some(merged)
+}
+
+fn apply_target_overrides(
+    capi: &toml::Value,
+    target: &target::Target,
+) -> anyhow::Result<toml::Value> {
+    let mut merged = capi.clone();
+    if let Some(targets) = capi.get("target").and_then(|v| v.as_table()) {
+        for (spec, overrides) in targets {
+            if target.matches(spec)? {
+                merge(&mut merged, overrides);
+            }
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Load the C-API configuration of a package, with the overrides of the `preset` and of
+/// `target` applied if they are given
+pub(crate) fn load_manifest_capi_config(
+    pkg: &Package,
+    target: Option<&target::Target>,
+    preset: Option<&str>,
+) -> anyhow::Result<CApiConfig> {
+    let crate_name = pkg
         .manifest()
         .targets()
         .iter()
@@ -501,6 +2022,31 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
         .get("package")
         .and_then(|v| v.get("metadata"))
         .and_then(|v| v.get("capi"));
+    let preset = capi
+        .zip(preset)
+        .and_then(|(capi, preset)| apply_preset(capi, preset));
+    let capi = preset.as_ref().or(capi);
+    let merged = match (capi, target) {
+        (Some(capi), Some(target)) => Some(apply_target_overrides(capi, target)?),
+        _ => None,
+    };
+    let capi = merged.as_ref().or(capi);
+
+    // The file names default to the crate name, with its hyphens turned into underscores
+    // unless the hyphenated style of the package name is requested. The C identifiers
+    // derived from them, e.g. the version macros, use underscores either way.
+    let name = &match capi
+        .and_then(|capi| capi.get("name_style"))
+        .map(|v| v.as_str())
+    {
+        None | Some(Some("underscore")) => crate_name,
+        // A library renamed by `[lib] name` keeps that name
+        Some(Some("hyphen")) if pkg.name().replace('-', "_") == crate_name => {
+            pkg.name().to_string()
+        }
+        Some(Some("hyphen")) => crate_name,
+        Some(_) => anyhow::bail!("name_style must be \"underscore\" or \"hyphen\""),
+    };
 
     if let Some(min_version) = capi
         .as_ref()
@@ -536,7 +2082,7 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
         })
         .unwrap_or_else(|| Ok(String::from(name)))?;
 
-    let header = if let Some(capi) = capi {
+    let mut header = if let Some(capi) = capi {
         HeaderCApiConfig {
             name: header
                 .as_ref()
@@ -555,6 +2101,11 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
                 .and_then(|h| h.get("enabled"))
                 .map(|v| v.clone().try_into())
                 .unwrap_or(Ok(true))?,
+            layout: header
+                .as_ref()
+                .and_then(|h| h.get("layout"))
+                .map(|v| v.clone().try_into::<PathBuf>())
+                .transpose()?,
         }
     } else {
         HeaderCApiConfig {
@@ -562,6 +2113,7 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
             subdirectory: String::from(name),
             generation: true,
             enabled: true,
+            layout: None,
         }
     };
 
@@ -579,6 +2131,14 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
     let mut requires = None;
     let mut requires_private = None;
     let mut strip_include_path_components = 0;
+    let mut include_cflags = true;
+    let mut requires_sys_versions = false;
+    let mut check_requires = false;
+    // Bare metal firmware is not built with pkg-config
+    let mut pc_install = target.map_or(true, |t| t.os != "none");
+    let mut modules = Vec::new();
+    let mut libs_private = None;
+    let mut variables = Vec::new();
 
     if let Some(pc) = pc {
         if let Some(override_name) = pc.get("name").and_then(|v| v.as_str()) {
@@ -599,13 +2159,99 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
         if let Some(req) = pc.get("requires_private").and_then(|v| v.as_str()) {
             requires_private = Some(String::from(req));
         }
+        for (module, dep) in pc
+            .get("dependencies")
+            .and_then(|v| v.as_table())
+            .into_iter()
+            .flatten()
+        {
+            let (version, public) = match dep {
+                toml::Value::String(version) => (version.as_str(), false),
+                toml::Value::Table(dep) => (
+                    dep.get("version").and_then(|v| v.as_str()).unwrap_or(""),
+                    dep.get("public").and_then(|v| v.as_bool()).unwrap_or(false),
+                ),
+                _ => anyhow::bail!(
+                    "pkg_config.dependencies.{module} must be a version constraint or a table"
+                ),
+            };
+            let req = match version.trim() {
+                "" | "*" => module.clone(),
+                version => format!("{module} {version}"),
+            };
+            // Only the public ones are needed by the consumers linking the shared library
+            let reqs = if public {
+                &mut requires
+            } else {
+                &mut requires_private
+            };
+            *reqs = Some(match reqs.take() {
+                Some(reqs) => format!("{reqs}, {req}"),
+                None => req,
+            });
+        }
         strip_include_path_components = pc
             .get("strip_include_path_components")
             .map(|v| v.clone().try_into())
-            .unwrap_or_else(|| Ok(0))?
+            .unwrap_or_else(|| Ok(0))?;
+        if let Some(v) = pc.get("include_cflags") {
+            include_cflags = v.clone().try_into()?;
+        }
+        if let Some(v) = pc.get("requires_sys_versions") {
+            requires_sys_versions = v.clone().try_into()?;
+        }
+        if let Some(v) = pc.get("check_requires") {
+            check_requires = v.clone().try_into()?;
+        }
+        if let Some(v) = pc.get("install").and_then(|v| v.as_bool()) {
+            pc_install = v;
+        }
+        if let Some(libs) = pc.get("libs_private").and_then(|v| v.as_str()) {
+            libs_private = Some(String::from(libs));
+        }
+        for (name, value) in pc
+            .get("variables")
+            .and_then(|v| v.as_table())
+            .into_iter()
+            .flatten()
+        {
+            if ["prefix", "exec_prefix", "libdir", "includedir"].contains(&name.as_str())
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                anyhow::bail!("pkg_config.variables.{name} is not a valid variable name");
+            }
+            let value = value
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("pkg_config.variables.{name} must be a string"))?;
+            variables.push((name.to_owned(), value.to_owned()));
+        }
+        for (filename, module) in pc
+            .get("modules")
+            .and_then(|v| v.as_table())
+            .into_iter()
+            .flatten()
+        {
+            let field = |key: &str| -> anyhow::Result<Option<String>> {
+                match module.get(key) {
+                    None => Ok(None),
+                    Some(v) => v.as_str().map(|v| Some(String::from(v))).ok_or_else(|| {
+                        anyhow::anyhow!("pkg_config.modules.{filename}.{key} must be a string")
+                    }),
+                }
+            };
+            modules.push(PkgConfigModule {
+                filename: filename.clone(),
+                name: field("name")?.unwrap_or_else(|| filename.clone()),
+                description: field("description")?,
+                requires: field("requires")?,
+                requires_private: field("requires_private")?,
+                libs: field("libs")?,
+                cflags: field("cflags")?,
+            });
+        }
     }
 
-    let pkg_config = PkgConfigCApiConfig {
+    let mut pkg_config = PkgConfigCApiConfig {
         name: pc_name,
         filename: pc_filename,
         description,
@@ -613,6 +2259,13 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
         requires,
         requires_private,
         strip_include_path_components,
+        include_cflags,
+        requires_sys_versions,
+        check_requires,
+        install: pc_install,
+        modules,
+        libs_private,
+        variables,
     };
 
     let library = capi.and_then(|v| v.get("library"));
@@ -621,6 +2274,31 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
     let mut install_subdir = None;
     let mut versioning = true;
     let mut rustflags = Vec::new();
+    let mut max_glibc_version = None;
+    let mut macos_deployment_target = None;
+    let mut dll_name = None;
+    let mut dll_prefix = None;
+    let mut dll_suffix = None;
+    let mut dll_version = false;
+    let mut codesign_identity = None;
+    let mut allocator = None;
+    let mut init = None;
+    let mut fini = None;
+    let mut staticlib_features = Vec::new();
+    let mut cdylib_features = Vec::new();
+    let mut parallel_installable = false;
+    let mut linker_script = false;
+    let mut symbol_version = None;
+    let mut framework = false;
+    let mut bundle_identifier = None;
+    let mut types = None;
+    let mut prefix = None;
+    let mut suffix = None;
+    let mut plugin = None;
+    let mut version_resource = true;
+    let mut stamp = false;
+    let mut reproducible = false;
+    let mut manifest = None;
 
     if let Some(library) = library {
         if let Some(override_name) = library.get("name").and_then(|v| v.as_str()) {
@@ -644,19 +2322,229 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
                 .map(str::to_string);
             rustflags.extend(args);
         }
+        if let Some(v) = library.get("max_glibc_version").and_then(|v| v.as_str()) {
+            max_glibc_version = Some(String::from(v));
+        }
+        if let Some(v) = library
+            .get("macos_deployment_target")
+            .and_then(|v| v.as_str())
+        {
+            macos_deployment_target = Some(String::from(v));
+        }
+        if let Some(v) = library.get("dll_name").and_then(|v| v.as_str()) {
+            dll_name = Some(String::from(v.trim_end_matches(".dll")));
+        }
+        if let Some(v) = library.get("dll_prefix").and_then(|v| v.as_str()) {
+            dll_prefix = Some(String::from(v));
+        }
+        if let Some(v) = library.get("dll_suffix").and_then(|v| v.as_str()) {
+            dll_suffix = Some(String::from(v));
+        }
+        dll_version = library
+            .get("dll_version")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if let Some(v) = library.get("codesign_identity").and_then(|v| v.as_str()) {
+            codesign_identity = Some(String::from(v));
+        }
+        allocator = match library.get("allocator") {
+            None => None,
+            Some(toml::Value::String(s)) if s == "system" => Some(Allocator::System),
+            Some(v) => match v.get("feature").and_then(|v| v.as_str()) {
+                Some(feature) => Some(Allocator::Feature(String::from(feature))),
+                None => anyhow::bail!(
+                    "library.allocator must be \"system\" or a table with a feature key"
+                ),
+            },
+        };
+        if let Some(v) = library.get("init").and_then(|v| v.as_str()) {
+            init = Some(String::from(v));
+        }
+        if let Some(v) = library.get("fini").and_then(|v| v.as_str()) {
+            fini = Some(String::from(v));
+        }
+        if let Some(features) = library.get("features") {
+            for (kind, list) in [
+                ("staticlib", &mut staticlib_features),
+                ("cdylib", &mut cdylib_features),
+            ] {
+                if let Some(v) = features.get(kind) {
+                    *list = v.clone().try_into().map_err(|_| {
+                        anyhow::anyhow!("library.features.{} must be an array of strings", kind)
+                    })?;
+                }
+            }
+        }
+        parallel_installable = library
+            .get("parallel_installable")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        linker_script = library
+            .get("linker_script")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        version_resource = library
+            .get("version_resource")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        stamp = library
+            .get("stamp")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        reproducible = library
+            .get("reproducible")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        manifest = match library.get("manifest") {
+            None | Some(toml::Value::Boolean(false)) => None,
+            Some(toml::Value::Boolean(true)) => Some(DllManifest::Generated),
+            Some(toml::Value::String(path)) => Some(DllManifest::File(PathBuf::from(path))),
+            Some(_) => anyhow::bail!("library.manifest must be a boolean or a path"),
+        };
+        if let Some(v) = library.get("symbol_version").and_then(|v| v.as_str()) {
+            symbol_version = Some(String::from(v));
+        }
+        // Frameworks are an Apple bundle format, the other targets keep the plain library
+        framework = library
+            .get("framework")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+            && target.map_or(true, |t| t.os == "macos" || t.os == "ios");
+        if let Some(v) = library.get("bundle_identifier").and_then(|v| v.as_str()) {
+            bundle_identifier = Some(String::from(v));
+        }
+        if let Some(v) = library.get("types") {
+            let kinds: Vec<String> = v
+                .clone()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("library.types must be an array of library types"))?;
+            if let Some(kind) = kinds
+                .iter()
+                .find(|k| !["staticlib", "cdylib", "module", "auto"].contains(&k.as_str()))
+            {
+                anyhow::bail!(
+                    "library.types contains {kind}, expected staticlib, cdylib, module or auto"
+                );
+            }
+            if kinds.is_empty() {
+                anyhow::bail!("library.types must contain at least one library type");
+            }
+            types = Some(kinds);
+        }
+        if let Some(v) = library.get("prefix").and_then(|v| v.as_str()) {
+            prefix = Some(String::from(v));
+        }
+        if let Some(v) = library.get("suffix").and_then(|v| v.as_str()) {
+            suffix = Some(String::from(v));
+        }
+        plugin = match library.get("plugin") {
+            None => None,
+            Some(toml::Value::String(package)) => Some(Plugin {
+                package: package.clone(),
+                dir: String::from("plugindir"),
+            }),
+            Some(v) => match v.get("package").and_then(|v| v.as_str()) {
+                Some(package) => Some(Plugin {
+                    package: String::from(package),
+                    dir: v
+                        .get("dir")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("plugindir")
+                        .to_string(),
+                }),
+                None => anyhow::bail!(
+                    "library.plugin must be a package name or a table with a package key"
+                ),
+            },
+        };
     }
 
-    let library = LibraryCApiConfig {
+    let mut library = LibraryCApiConfig {
         name: lib_name,
         version,
         install_subdir,
         versioning,
         rustflags,
+        max_glibc_version,
+        macos_deployment_target,
+        dll_name,
+        dll_prefix,
+        dll_suffix,
+        dll_version,
+        codesign_identity,
+        allocator,
+        init,
+        fini,
+        staticlib_features,
+        cdylib_features,
+        linker_script,
+        symbol_version,
+        framework,
+        bundle_identifier,
+        types,
+        prefix,
+        suffix,
+        module: false,
+        plugin,
+        version_resource,
+        manifest,
+        stamp,
+        reproducible,
+    };
+
+    // Suffix everything with the API version so multiple major versions can share a prefix
+    if parallel_installable {
+        let api_version = if library.version.major == 0 {
+            format!("0.{}", library.version.minor)
+        } else {
+            library.version.major.to_string()
+        };
+        let suffix = format!("-{api_version}");
+        for name in [
+            &mut library.name,
+            &mut pkg_config.filename,
+            &mut header.subdirectory,
+        ] {
+            if !name.is_empty() && !name.ends_with(&suffix) {
+                name.push_str(&suffix);
+            }
+        }
+        for module in pkg_config.modules.iter_mut() {
+            if !module.filename.ends_with(&suffix) {
+                module.filename.push_str(&suffix);
+            }
+        }
+    }
+
+    let install = capi.and_then(|v| v.get("install"));
+
+    let debug_info = match install.and_then(|v| v.get("debug_info")) {
+        Some(value) => value
+            .as_bool()
+            .ok_or_else(|| anyhow::anyhow!("install.debug_info must be a boolean"))?,
+        None => target.map_or(true, |t| t.vendor != "uwp"),
+    };
+
+    let rlib = match install.and_then(|v| v.get("rlib")) {
+        Some(value) => value
+            .as_bool()
+            .ok_or_else(|| anyhow::anyhow!("install.rlib must be a boolean"))?,
+        None => false,
     };
 
+    let include_subdirectory = install
+        .and_then(|v| v.get("include"))
+        .and_then(|v| v.get("subdirectory"))
+        .and_then(|v| v.as_str())
+        .map(String::from);
+    let include_to = include_subdirectory
+        .clone()
+        .unwrap_or_else(|| header.subdirectory.clone());
+
     let default_assets_include = InstallTargetPaths {
         from: "assets/capi/include/**/*".to_string(),
-        to: header.subdirectory.clone(),
+        to: include_to.clone(),
+        ..Default::default()
     };
 
     let header_name = if header.name.ends_with(".h") {
@@ -667,12 +2555,14 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
 
     let default_legacy_asset_include = InstallTargetPaths {
         from: header_name,
-        to: header.subdirectory.clone(),
+        to: include_to.clone(),
+        ..Default::default()
     };
 
     let default_generated_include = InstallTargetPaths {
         from: "capi/include/**/*".to_string(),
-        to: header.subdirectory.clone(),
+        to: include_to.clone(),
+        ..Default::default()
     };
 
     let mut include_targets = vec![
@@ -703,13 +2593,28 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
             }
         }
 
+        // The exclude patterns set for the whole category apply to all its targets
+        if let Some(exclude) = root.get("exclude") {
+            let exclude: Vec<String> = exclude.clone().try_into()?;
+            for target in targets.iter_mut() {
+                target.paths_mut().exclude.extend(exclude.iter().cloned());
+            }
+        }
+
         Ok(())
     }
 
-    let install = capi.and_then(|v| v.get("install"));
+    let mut systemd_targets = Vec::new();
+    let mut udev_targets = Vec::new();
+    let mut dbus_targets = Vec::new();
+    let mut man_targets = Vec::new();
+    let mut man_compress = false;
+    let mut doc_targets = Vec::new();
+    let mut bins = Vec::new();
+
     if let Some(install) = install {
         if let Some(includes) = install.get("include") {
-            custom_install_target_paths(includes, &header.subdirectory, &mut include_targets)?;
+            custom_install_target_paths(includes, &include_to, &mut include_targets)?;
         }
         if let Some(data) = install.get("data") {
             if let Some(subdir) = data.get("subdirectory").and_then(|v| v.as_str()) {
@@ -717,16 +2622,67 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
             }
             custom_install_target_paths(data, &data_subdirectory, &mut data_targets)?;
         }
-    }
-
-    let default_assets_data = InstallTargetPaths {
-        from: "assets/capi/share/**/*".to_string(),
-        to: data_subdirectory.clone(),
-    };
-
-    let default_generated_data = InstallTargetPaths {
-        from: "capi/share/**/*".to_string(),
+        if let Some(systemd) = install.get("systemd") {
+            custom_install_target_paths(systemd, "", &mut systemd_targets)?;
+        }
+        if let Some(udev) = install.get("udev") {
+            custom_install_target_paths(udev, "", &mut udev_targets)?;
+        }
+        if let Some(dbus) = install.get("dbus") {
+            custom_install_target_paths(dbus, "", &mut dbus_targets)?;
+        }
+        if let Some(man) = install.get("man") {
+            custom_install_target_paths(man, "", &mut man_targets)?;
+            if let Some(compress) = man.get("compress") {
+                man_compress = compress
+                    .as_bool()
+                    .ok_or_else(|| anyhow::anyhow!("install.man.compress must be a boolean"))?;
+            }
+        }
+        if let Some(doc) = install.get("doc") {
+            custom_install_target_paths(doc, "", &mut doc_targets)?;
+        }
+        if let Some(bin) = install.get("bin") {
+            let targets: Vec<_> = pkg
+                .targets()
+                .iter()
+                .filter(|t| t.is_bin())
+                .map(|t| t.name().to_string())
+                .collect();
+            bins = match bin {
+                toml::Value::Boolean(true) => targets,
+                toml::Value::Boolean(false) => Vec::new(),
+                _ => {
+                    let bins: Vec<String> = bin.clone().try_into().map_err(|_| {
+                        anyhow::anyhow!("install.bin must be a boolean or a list of bin targets")
+                    })?;
+                    if let Some(bin) = bins.iter().find(|&bin| !targets.contains(bin)) {
+                        anyhow::bail!("install.bin: {} has no bin target named {bin}", pkg.name());
+                    }
+                    bins
+                }
+            };
+        }
+    }
+
+    let data_exclude: Vec<String> = install
+        .and_then(|v| v.get("data"))
+        .and_then(|v| v.get("exclude"))
+        .map(|v| v.clone().try_into())
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+
+    let default_assets_data = InstallTargetPaths {
+        from: "assets/capi/share/**/*".to_string(),
+        to: data_subdirectory.clone(),
+        exclude: data_exclude.clone(),
+        ..Default::default()
+    };
+
+    let default_generated_data = InstallTargetPaths {
+        from: "capi/share/**/*".to_string(),
         to: data_subdirectory,
+        exclude: data_exclude,
+        ..Default::default()
     };
 
     data_targets.extend([
@@ -734,16 +2690,238 @@ fn load_manifest_capi_config(pkg: &Package) -> anyhow::Result<CApiConfig> {
         InstallTarget::Generated(default_generated_data),
     ]);
 
+    let (dataroot_targets, data_targets) = data_targets
+        .into_iter()
+        .partition(|t| t.paths().destination.is_some());
+
     let install = InstallCApiConfig {
         include: include_targets,
+        include_subdirectory,
         data: data_targets,
+        dataroot: dataroot_targets,
+        systemd: systemd_targets,
+        udev: udev_targets,
+        dbus: dbus_targets,
+        man: man_targets,
+        man_compress,
+        doc: doc_targets,
+        bin: bins,
+        debug_info,
+        rlib,
+    };
+
+    // The runtime directories and the environment may depend on the target
+    let target_vars = target.map(|t| t.variables()).unwrap_or_default();
+
+    let runtime_dirs = capi.and_then(|v| v.get("runtime_dirs"));
+    let mut dirs = Vec::new();
+    let mut dirs_header = None;
+
+    if let Some(runtime_dirs) = runtime_dirs {
+        if let Some(d) = runtime_dirs.get("dirs").and_then(|v| v.as_table()) {
+            for (name, path) in d {
+                let path = path.as_str().ok_or_else(|| {
+                    anyhow::anyhow!("runtime_dirs.dirs.{} must be a string", name)
+                })?;
+                dirs.push((name.to_owned(), substitute_template(path, &target_vars)));
+            }
+        }
+        if let Some(h) = runtime_dirs.get("header").and_then(|v| v.as_str()) {
+            dirs_header = Some(String::from(h));
+        }
+    }
+
+    let runtime_dirs = RuntimeDirsCApiConfig {
+        dirs,
+        header: dirs_header,
+    };
+
+    let mut env = Vec::new();
+    if let Some(vars) = capi.and_then(|v| v.get("env")).and_then(|v| v.as_table()) {
+        for (name, value) in vars {
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(_) | toml::Value::Boolean(_) => value.to_string(),
+                _ => anyhow::bail!("env.{} must be a string, an integer or a boolean", name),
+            };
+            env.push((name.to_owned(), substitute_template(&value, &target_vars)));
+        }
+    }
+
+    let mut def_symbols = Vec::new();
+    if let Some(symbols) = capi
+        .and_then(|v| v.get("def"))
+        .and_then(|v| v.get("symbols"))
+        .and_then(|v| v.as_table())
+    {
+        for (name, attrs) in symbols {
+            let mut symbol: DefSymbol = attrs.clone().try_into()?;
+            if symbol.noname && symbol.ordinal.is_none() {
+                anyhow::bail!("def.symbols.{} is NONAME but has no ordinal", name);
+            }
+            symbol.name = name.to_owned();
+            def_symbols.push(symbol);
+        }
+    }
+
+    let def_table = capi.and_then(|v| v.get("def"));
+    let def_flag = |key: &str, default: bool| -> anyhow::Result<bool> {
+        match def_table.and_then(|v| v.get(key)) {
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| anyhow::anyhow!("def.{} must be a boolean", key)),
+            None => Ok(default),
+        }
+    };
+
+    let mut def_exports = None;
+    if let Some(exports) = def_table.and_then(|v| v.get("exports")) {
+        let exports = exports
+            .as_array()
+            .and_then(|a| a.iter().map(|v| v.as_str().map(String::from)).collect())
+            .ok_or_else(|| anyhow::anyhow!("def.exports must be an array of strings"))?;
+        def_exports = Some(exports);
+    }
+    if let Some(file) = def_table.and_then(|v| v.get("exports_file")) {
+        let file = file
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("def.exports_file must be a string"))?;
+        // One symbol per line, the `#` and `;` lines are comments
+        let listed = read(&root_path.join(file))?;
+        def_exports.get_or_insert_with(Vec::new).extend(
+            listed
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with(['#', ';']))
+                .map(String::from),
+        );
+    }
+    if let Some(exports) = &def_exports {
+        if let Some(symbol) = def_symbols.iter().find(|s| !exports.contains(&s.name)) {
+            anyhow::bail!(
+                "def.symbols.{} is not in the def.exports allowlist",
+                symbol.name
+            );
+        }
+    }
+
+    let def = DefCApiConfig {
+        symbols: def_symbols,
+        exports: def_exports,
+        kill_at: def_flag("kill_at", false)?,
+        stdcall_alias: def_flag("stdcall_alias", false)?,
+        install: def_flag("install", true)?,
+    };
+
+    let mut swift = SwiftCApiConfig {
+        // CFoo, as the Swift modules wrapping C libraries are usually named
+        module: std::iter::once("C".to_string())
+            .chain(library.name.split(['-', '_', '.']).map(|part| {
+                let mut chars = part.chars();
+                chars
+                    .next()
+                    .map(|c| c.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            }))
+            .collect(),
+        ..Default::default()
+    };
+    if let Some(swift_table) = capi.and_then(|v| v.get("swift")) {
+        swift.enabled = swift_table
+            .get("enabled")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        if let Some(module) = swift_table.get("module").and_then(|v| v.as_str()) {
+            swift.module = String::from(module);
+        }
+        if let Some(xcframework) = swift_table.get("xcframework").and_then(|v| v.as_str()) {
+            swift.xcframework = Some(String::from(xcframework));
+        }
+        if swift.enabled && swift.xcframework.is_none() && !header.enabled {
+            anyhow::bail!("swift requires the header to be enabled to generate the module map");
+        }
+    }
+
+    let mut python = PythonCApiConfig {
+        module: format!("_{}", artifact_name(&library.name)),
+        ..Default::default()
+    };
+    if let Some(python_table) = capi.and_then(|v| v.get("python")) {
+        python.enabled = python_table
+            .get("enabled")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        if let Some(module) = python_table.get("module").and_then(|v| v.as_str()) {
+            python.module = String::from(module);
+        }
+    }
+
+    let js = JsCApiConfig {
+        enabled: capi
+            .and_then(|v| v.get("js"))
+            .map(|js| js.get("enabled").and_then(|v| v.as_bool()).unwrap_or(true))
+            .unwrap_or(false),
     };
 
+    let mut licenses = LicensesCApiConfig::default();
+    if let Some(deny) = capi
+        .and_then(|v| v.get("licenses"))
+        .and_then(|v| v.get("deny"))
+    {
+        let deny = deny
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("licenses.deny must be an array of strings"))?;
+        for pattern in deny {
+            let pattern = pattern
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("licenses.deny must be an array of strings"))?;
+            glob::Pattern::new(pattern)?;
+            licenses.deny.push(String::from(pattern));
+        }
+    }
+
+    let vendor = capi
+        .and_then(|v| v.get("pack"))
+        .and_then(|v| v.get("vendor"))
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .or_else(|| {
+            let author = pkg.manifest().metadata().authors.first()?;
+            // Drop the email
+            Some(author.split('<').next().unwrap().trim().to_string())
+        })
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| library.name.clone());
+    let pack = PackCApiConfig { vendor };
+
+    let check_cfg = toml
+        .get("lints")
+        .and_then(|v| v.get("rust"))
+        .and_then(|v| v.get("unexpected_cfgs"))
+        .and_then(|v| v.get("check-cfg"))
+        .and_then(|v| v.as_array())
+        .map(|v| {
+            v.iter()
+                .filter_map(|v| v.as_str())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
     Ok(CApiConfig {
         header,
         pkg_config,
         library,
         install,
+        runtime_dirs,
+        env,
+        def,
+        swift,
+        python,
+        js,
+        licenses,
+        pack,
+        check_cfg,
     })
 }
 
@@ -753,6 +2931,7 @@ fn compile_options(
     args: &ArgMatches,
     profile: InternedString,
     compile_mode: CompileMode,
+    target: &str,
 ) -> anyhow::Result<CompileOptions> {
     use cargo::core::compiler::CompileKind;
     let mut compile_opts =
@@ -774,13 +2953,9 @@ fn compile_options(
 
     compile_opts.build_config.unit_graph = false;
 
-    let rustc = config.load_global_rustc(Some(ws))?;
-
     // Always set the target, requested_kinds is a vec of a single element.
-    if compile_opts.build_config.requested_kinds[0].is_host() {
-        compile_opts.build_config.requested_kinds =
-            CompileKind::from_requested_targets(config, &[rustc.host.to_string()])?
-    }
+    compile_opts.build_config.requested_kinds =
+        CompileKind::from_requested_targets(config, &[target.to_string()])?;
 
     Ok(compile_opts)
 }
@@ -789,6 +2964,83 @@ fn compile_options(
 struct Exec {
     ran: AtomicBool,
     link_line: Mutex<HashMap<PackageId, String>>,
+    /// The soname cargo-c gives the shared library of each package
+    sonames: HashMap<PackageId, String>,
+    /// The linker wrapper of each package, see `symbol_version_linker` and `exports_linker`
+    linker_wrappers: Mutex<HashMap<PackageId, PathBuf>>,
+}
+
+/// The linker the wrappers call, when another one than the default is set
+const LINKER_ENV: &str = "CARGO_C_LINKER";
+
+/// The last linker set by the arguments of a rustc invocation, the one rustc uses
+fn last_linker(args: &[String]) -> Option<&str> {
+    let mut linker = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = match arg.as_str() {
+            "-C" => match args.next() {
+                Some(arg) => arg.as_str(),
+                None => break,
+            },
+            arg => match arg.strip_prefix("-C") {
+                Some(arg) => arg,
+                None => continue,
+            },
+        };
+        if let Some(arg) = arg.strip_prefix("linker=") {
+            linker = Some(arg);
+        }
+    }
+    linker
+}
+
+/// The sonames the linker arguments of a rustc invocation set
+fn linked_sonames(args: &[String]) -> Vec<String> {
+    let mut link_args = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = match arg.as_str() {
+            "-C" => match args.next() {
+                Some(arg) => arg.as_str(),
+                None => break,
+            },
+            arg => match arg.strip_prefix("-C") {
+                Some(arg) => arg,
+                None => continue,
+            },
+        };
+        if let Some(arg) = arg.strip_prefix("link-arg=") {
+            link_args.push(arg);
+        } else if let Some(arg) = arg.strip_prefix("link-args=") {
+            link_args.extend(arg.split_whitespace());
+        }
+    }
+
+    // The compiler driver forwards the `-Wl,` ones to the linker
+    let ld_args: Vec<_> = link_args
+        .iter()
+        .flat_map(|arg| match arg.strip_prefix("-Wl,") {
+            Some(args) => args.split(',').collect(),
+            None => vec![*arg],
+        })
+        .collect();
+    let mut sonames = Vec::new();
+    let mut ld_args = ld_args.into_iter();
+    while let Some(arg) = ld_args.next() {
+        match arg {
+            "-soname" | "--soname" | "-h" => sonames.extend(ld_args.next().map(String::from)),
+            _ => {
+                if let Some(soname) = arg
+                    .strip_prefix("-soname=")
+                    .or_else(|| arg.strip_prefix("--soname="))
+                {
+                    sonames.push(soname.to_string());
+                }
+            }
+        }
+    }
+    sonames
 }
 
 use cargo::core::*;
@@ -800,12 +3052,61 @@ impl Executor for Exec {
         &self,
         cmd: &ProcessBuilder,
         id: PackageId,
-        _target: &Target,
+        target: &Target,
         _mode: CompileMode,
         on_stdout_line: &mut dyn FnMut(&str) -> CargoResult<()>,
         on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
     ) -> CargoResult<()> {
         self.ran.store(true, Ordering::Relaxed);
+
+        // The installed links are named after the soname cargo-c sets, a different one set by
+        // the build script or the rustflags would leave the library unreachable through them
+        if let Some(soname) = self.sonames.get(&id).filter(|_| target.is_lib()) {
+            let args: Vec<_> = cmd
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect();
+            if let Some(other) = linked_sonames(&args).iter().find(|s| *s != soname) {
+                anyhow::bail!(
+                    "the shared library of {} is linked with the soname {other}, \
+                     but cargo-c installs it as {soname}: remove the -soname linker argument \
+                     from the build script or the rustflags, or set library.version or \
+                     library.versioning so that the sonames match",
+                    id.name()
+                );
+            }
+        }
+
+        // The check-cfg of cargo-c enable the checking, the cfgs the build script sets or
+        // declares are checked as well: the embedded cargo does not pass them to rustc
+        let mut cmd = cmd.clone();
+        if cmd.get_args().any(|a| a == "--check-cfg") {
+            let args: Vec<_> = cmd
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect();
+            let output = cmd
+                .get_env("OUT_DIR")
+                .and_then(|out_dir| {
+                    std::fs::read_to_string(Path::new(&out_dir).with_file_name("output")).ok()
+                })
+                .unwrap_or_default();
+            cmd.args(&build_script_check_cfg_args(&args, &output));
+        }
+
+        // The target linker of the configuration and the one of the rustflags are passed
+        // after the wrapper, it calls them in its place
+        if let Some(wrapper) = self.linker_wrappers.lock().unwrap().get(&id) {
+            let args: Vec<_> = cmd
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect();
+            if let Some(linker) = last_linker(&args).filter(|l| Path::new(l) != wrapper) {
+                cmd.env(LINKER_ENV, linker);
+                cmd.arg("-C").arg(format!("linker={}", wrapper.display()));
+            }
+        }
+
         cmd.exec_with_streaming(
             on_stdout_line,
             &mut |s| {
@@ -840,6 +3141,7 @@ impl Executor for Exec {
 }
 
 use cargo::core::compiler::{unit_graph, UnitInterner};
+use cargo::core::profiles::PanicStrategy;
 use cargo::ops::create_bcx;
 use cargo::util::profile;
 
@@ -859,120 +3161,1541 @@ fn set_deps_args(
     }
 }
 
-fn compile_with_exec(
-    ws: &Workspace<'_>,
-    options: &CompileOptions,
-    exec: &Arc<dyn Executor>,
+/// Whether the target links ELF shared objects with a linker reading version scripts
+fn is_elf(rustc_target: &target::Target) -> bool {
+    matches!(
+        rustc_target.os.as_str(),
+        "linux" | "android" | "freebsd" | "dragonfly" | "netbsd" | "illumos" | "haiku"
+    )
+}
+
+/// The shell script calling `linker`, or the one of `CARGO_C_LINKER`, with the version
+/// script rustc passes written to `map` under the `version` node
+fn symbol_version_linker_script(version: &str, map: &Path, linker: &Path) -> String {
+    let quote = |p: &Path| format!("'{}'", p.display().to_string().replace('\'', "'\\''"));
+
+    format!(
+        "#!/bin/sh
+# Generated by cargo-c, do not edit
+for arg
+do
+    shift
+    case \"$arg\" in
+    *--version-script=*)
+        sed '1s/^{{/{version} {{/' \"${{arg#*--version-script=}}\" > {map} || exit 1
+        arg=\"${{arg%%--version-script=*}}--version-script=\"{map}
+        ;;
+    esac
+    set -- \"$@\" \"$arg\"
+done
+{LINKER_ENV}=${{{LINKER_ENV}:-{linker}}}
+exec \"${LINKER_ENV}\" \"$@\"
+",
+        map = quote(map),
+        linker = quote(linker),
+    )
+}
+
+/// Linker wrapper naming the version node of the version script rustc passes
+///
+/// rustc restricts the exports of the shared library with an anonymous version script,
+/// which the linkers refuse to combine with a second, named, one. The wrapper rewrites it
+/// to `<version> { global: <exported symbols>; local: *; };` before calling the linker,
+/// and keeps a copy in the build directory.
+fn symbol_version_linker(
+    config: &Config,
+    capi_config: &CApiConfig,
     rustc_target: &target::Target,
     root_output: &Path,
-    args: &ArgMatches,
-) -> CargoResult<HashMap<PackageId, PathBuf>> {
-    ws.emit_warnings()?;
-    let interner = UnitInterner::new();
-    let mut bcx = create_bcx(ws, options, &interner)?;
-    let unit_graph = &bcx.unit_graph;
-    let extra_compiler_args = &mut bcx.extra_compiler_args;
+) -> anyhow::Result<Option<PathBuf>> {
+    let version = match &capi_config.library.symbol_version {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    if !is_elf(rustc_target) {
+        warn(
+            config,
+            format!(
+                "library.symbol_version is ignored for {}, it is not an ELF target",
+                rustc_target.triple
+            ),
+        )?;
+        return Ok(None);
+    }
+    if !cfg!(unix) {
+        anyhow::bail!("library.symbol_version needs a unix host to wrap the linker");
+    }
 
-    for unit in bcx.roots.iter() {
-        let pkg = &unit.pkg;
-        let capi_config = load_manifest_capi_config(pkg)?;
-        let name = &capi_config.library.name;
-        let install_paths = InstallPaths::new(name, args, &capi_config);
-        let pkg_rustflags = &capi_config.library.rustflags;
+    let linker = config
+        .get::<Option<PathBuf>>(&format!("target.{}.linker", rustc_target.triple))?
+        .unwrap_or_else(|| PathBuf::from("cc"));
+    let map = root_output.join(format!("{}.map", capi_config.library.name));
+    let wrapper = root_output.join(format!("{}-linker.sh", capi_config.library.name));
 
-        let mut leaf_args: Vec<String> = rustc_target
-            .shared_object_link_args(&capi_config, &install_paths.libdir, root_output)
-            .into_iter()
-            .flat_map(|l| vec!["-C".to_string(), format!("link-arg={l}")])
-            .collect();
+    let script = symbol_version_linker_script(version, &map, &linker);
+    cargo_util::paths::write_if_changed(&wrapper, script)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+    }
 
-        leaf_args.extend(pkg_rustflags.clone());
+    Ok(Some(wrapper))
+}
 
-        leaf_args.push("--cfg".into());
-        leaf_args.push("cargo_c".into());
+/// The linker rustc calls for a windows-gnu target
+pub(crate) fn mingw_linker(rustc_target: &target::Target) -> String {
+    let arch = match rustc_target.arch.as_str() {
+        "x86" => "i686",
+        arch => arch,
+    };
+    if rustc_target.abi == "llvm" {
+        format!("{arch}-w64-mingw32-clang")
+    } else {
+        format!("{arch}-w64-mingw32-gcc")
+    }
+}
 
-        leaf_args.push("--print".into());
-        leaf_args.push("native-static-libs".into());
+/// Module-definition file linking the DLL with the `def.exports` allowlist only, along
+/// with the ordinals and the aliases of the symbols
+fn exports_def_file(
+    capi_config: &CApiConfig,
+    rustc_target: &target::Target,
+    root_output: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let def_config = &capi_config.def;
+    let exports = match &def_config.exports {
+        Some(exports) if rustc_target.os == "windows" => exports,
+        _ => return Ok(None),
+    };
 
-        if args.flag("crt-static") {
-            leaf_args.push("-C".into());
-            leaf_args.push("target-feature=+crt-static".into());
+    let mut def = String::from("EXPORTS\n");
+    for name in exports {
+        let symbol = def_config.symbols.iter().find(|s| &s.name == name);
+        def.push_str(&format!("\t{name}"));
+        if let Some(symbol) = symbol.filter(|s| s.ordinal.is_some()) {
+            def.push_str(&format!(" @{}", symbol.ordinal.unwrap()));
+            if symbol.noname {
+                def.push_str(" NONAME");
+            }
+        }
+        def.push('\n');
+        for alias in symbol.iter().flat_map(|s| &s.aliases) {
+            def.push_str(&format!("\t{alias}={name}\n"));
         }
+    }
 
-        extra_compiler_args.insert(unit.clone(), leaf_args.to_owned());
+    let path = root_output.join(format!("{}-exports.def", capi_config.library.name));
+    create_dir_all(root_output)?;
+    cargo_util::paths::write_if_changed(&path, def)?;
+    Ok(Some(path))
+}
 
-        for dep in unit_graph[unit].iter() {
-            set_deps_args(dep, unit_graph, extra_compiler_args, pkg_rustflags);
-        }
+/// The shell script calling `linker`, or the one of `CARGO_C_LINKER`, with the `list.def`
+/// rustc passes replaced by `def`, given as an argument, as a `-Wl,` one or in the response
+/// file rustc writes when the command line is too long
+fn exports_linker_script(def: &Path, linker: &Path) -> String {
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "'\\''"));
+    let def = def.display().to_string();
+    // The response files of the GNU linkers escape the spaces and the backslashes, the sed
+    // replacement the backslashes, the ampersands and the separator
+    let escaped = def.replace('\\', "\\\\").replace(' ', "\\ ");
+    let replacement = escaped
+        .replace('\\', "\\\\")
+        .replace('&', "\\&")
+        .replace('|', "\\|");
+
+    format!(
+        "#!/bin/sh
+# Generated by cargo-c, do not edit
+for arg
+do
+    shift
+    case \"$arg\" in
+    -Wl,*/list.def)
+        arg=-Wl,{def}
+        ;;
+    */list.def)
+        arg={def}
+        ;;
+    -*)
+        ;;
+    @*)
+        rsp=\"${{arg#@}}.cargo-c\"
+        sed 's|^\\(-Wl,\\)\\{{0,1\\}}.*/list\\.def$|\\1'{replacement}'|' \"${{arg#@}}\" > \"$rsp\" || exit 1
+        arg=\"@$rsp\"
+        ;;
+    esac
+    set -- \"$@\" \"$arg\"
+done
+{LINKER_ENV}=${{{LINKER_ENV}:-{linker}}}
+exec \"${LINKER_ENV}\" \"$@\"
+",
+        def = quote(&def),
+        replacement = quote(&replacement),
+        linker = quote(&linker.display().to_string()),
+    )
+}
+
+/// Linker wrapper swapping the .def file rustc passes for the windows-gnu targets with
+/// the one of the `def.exports` allowlist
+///
+/// rustc exports every `#[no_mangle]` symbol through a `list.def` of its own, the linker
+/// would merge a second one with it.
+fn exports_linker(
+    config: &Config,
+    rustc_target: &target::Target,
+    def: &Path,
+    root_output: &Path,
+    name: &str,
+) -> anyhow::Result<Option<PathBuf>> {
+    if !cfg!(unix) {
+        warn(
+            config,
+            "def.exports needs a unix host to wrap the linker, \
+             only the import library is restricted to the allowlist",
+        )?;
+        return Ok(None);
     }
 
-    if options.build_config.unit_graph {
-        unit_graph::emit_serialized_unit_graph(&bcx.roots, &bcx.unit_graph, ws.config())?;
-        return Ok(HashMap::new());
+    let linker = config
+        .get::<Option<PathBuf>>(&format!("target.{}.linker", rustc_target.triple))?
+        .unwrap_or_else(|| PathBuf::from(mingw_linker(rustc_target)));
+    let wrapper = root_output.join(format!("{name}-linker.sh"));
+    let script = exports_linker_script(def, &linker);
+    cargo_util::paths::write_if_changed(&wrapper, script)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
     }
-    let _p = profile::start("compiling");
-    let cx = cargo::core::compiler::Context::new(&bcx)?;
 
-    let r = cx.compile(exec)?;
+    Ok(Some(wrapper))
+}
 
-    let out_dirs = r
-        .cdylibs
+/// Compiled resources linked into the DLL, its VERSIONINFO and its manifest, a `.res` for
+/// link.exe and a COFF object for the GNU linkers
+fn resource_object(
+    capi_config: &CApiConfig,
+    rustc_target: &target::Target,
+    root_output: &Path,
+) -> Option<PathBuf> {
+    let library = &capi_config.library;
+    if rustc_target.os != "windows" || !(library.version_resource || library.manifest.is_some()) {
+        return None;
+    }
+    let ext = if rustc_target.env == "msvc" {
+        "res"
+    } else {
+        "o"
+    };
+    Some(root_output.join(format!("{}-version.{ext}", capi_config.library.name)))
+}
+
+/// The VERSIONINFO resource of the DLL, from the package metadata
+fn version_resource_source(
+    capi_config: &CApiConfig,
+    metadata: Option<&cargo::core::manifest::ManifestMetadata>,
+    version: &Version,
+    dll_name: &str,
+    stamp: Option<&str>,
+) -> String {
+    let library = &capi_config.library;
+    // The resource compilers double the quotes instead of escaping them
+    let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+    let numeric = |v: &Version| {
+        let part = |n: u64| n.min(u16::MAX as u64);
+        format!("{},{},{},0", part(v.major), part(v.minor), part(v.patch))
+    };
+    // The author without the e-mail address
+    let company = metadata
+        .and_then(|m| m.authors.first())
+        .map(|a| a.split('<').next().unwrap().trim().to_string())
+        .filter(|a| !a.is_empty());
+    let description = metadata
+        .and_then(|m| m.description.as_deref())
+        .and_then(|d| d.lines().next())
+        .unwrap_or(&library.name);
+
+    let mut values = vec![
+        ("FileDescription", description.trim().to_string()),
+        ("FileVersion", library.version.to_string()),
+        ("InternalName", library.name.clone()),
+        ("OriginalFilename", dll_name.to_string()),
+        ("ProductName", library.name.clone()),
+        ("ProductVersion", version.to_string()),
+    ];
+    if let Some(company) = company {
+        values.insert(0, ("CompanyName", company));
+    }
+    if let Some(stamp) = stamp {
+        values.insert(0, ("Comments", stamp.to_string()));
+    }
+    let values: String = values
         .iter()
-        .filter_map(|l| {
-            let id = l.unit.pkg.package_id();
-            if let Some(ref m) = l.script_meta {
-                if let Some(env) = r.extra_env.get(m) {
-                    env.iter().find_map(|e| {
-                        if e.0 == "OUT_DIR" {
-                            Some((id, PathBuf::from(&e.1)))
-                        } else {
-                            None
-                        }
-                    })
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        })
+        .map(|(key, value)| format!("            VALUE {}, {}\n", quote(key), quote(value)))
         .collect();
+    // VS_FF_PRERELEASE
+    let flags = if library.version.pre.is_empty() {
+        "0x0L"
+    } else {
+        "0x2L"
+    };
 
-    Ok(out_dirs)
+    format!(
+        "1 VERSIONINFO
+FILEVERSION {file_version}
+PRODUCTVERSION {product_version}
+FILEFLAGSMASK 0x3fL
+FILEFLAGS {flags}
+FILEOS 0x40004L
+FILETYPE 0x2L
+FILESUBTYPE 0x0L
+BEGIN
+    BLOCK \"StringFileInfo\"
+    BEGIN
+        BLOCK \"040904b0\"
+        BEGIN
+{values}        END
+    END
+    BLOCK \"VarFileInfo\"
+    BEGIN
+        VALUE \"Translation\", 0x409, 1200
+    END
+END
+",
+        file_version = numeric(&library.version),
+        product_version = numeric(version),
+    )
 }
 
-#[derive(Debug)]
-pub struct CPackage {
-    pub version: Version,
-    pub root_path: PathBuf,
-    pub capi_config: CApiConfig,
-    pub build_targets: BuildTargets,
-    pub install_paths: InstallPaths,
-    finger_print: FingerPrint,
+/// The resource compilers to try for the target, `RC` overrides them
+fn resource_compilers(rustc_target: &target::Target) -> Vec<std::process::Command> {
+    if let Some(rc) = std::env::var_os("RC") {
+        return vec![std::process::Command::new(rc)];
+    }
+    if rustc_target.env == "msvc" {
+        let mut tools: Vec<_> = cc::windows_registry::find(&rustc_target.triple, "rc.exe")
+            .into_iter()
+            .collect();
+        // The rc of the other hosts is not the resource compiler
+        let names: &[&str] = if cfg!(windows) {
+            &["rc", "llvm-rc"]
+        } else {
+            &["llvm-rc"]
+        };
+        tools.extend(names.iter().map(std::process::Command::new));
+        tools
+    } else {
+        let linker = mingw_linker(rustc_target);
+        let prefix = linker.rsplit_once('-').unwrap().0;
+        // The gnullvm targets come with the LLVM tools instead of binutils
+        let mut tools = vec![format!("{prefix}-windres"), "windres".into()];
+        if rustc_target.abi == "llvm" {
+            tools.insert(1, "llvm-windres".into());
+        } else {
+            tools.push("llvm-windres".into());
+        }
+        tools.into_iter().map(std::process::Command::new).collect()
+    }
 }
 
-impl CPackage {
-    fn from_package(
-        pkg: &mut Package,
-        args: &ArgMatches,
-        libkinds: &[&str],
-        rustc_target: &target::Target,
-        root_output: &Path,
-    ) -> anyhow::Result<CPackage> {
-        let id = pkg.package_id();
-        let version = pkg.version().clone();
-        let root_path = pkg.root().to_path_buf();
-        let capi_config = load_manifest_capi_config(pkg)?;
+/// The side-by-side manifest generated for `library.manifest = true`, identifying the DLL as
+/// an assembly of its own
+fn generated_manifest(
+    capi_config: &CApiConfig,
+    rustc_target: &target::Target,
+    dll_name: &str,
+) -> String {
+    let version = &capi_config.library.version;
+    let arch = match rustc_target.arch.as_str() {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<assembly xmlns="urn:schemas-microsoft-com:asm.v1" manifestVersion="1.0">
+  <assemblyIdentity type="win32" name="{}" version="{}.{}.{}.0" processorArchitecture="{arch}"/>
+  <file name="{dll_name}"/>
+</assembly>
+"#,
+        capi_config.library.name,
+        version.major.min(u16::MAX as u64),
+        version.minor.min(u16::MAX as u64),
+        version.patch.min(u16::MAX as u64),
+    )
+}
 
-        patch_target(pkg, libkinds, &capi_config)?;
+/// Compile the resources of the DLL, with rc.exe, llvm-rc or windres
+///
+/// Without a resource compiler the DLL is linked without its version information, a
+/// manifest cannot be left out.
+fn build_resources(
+    ws: &Workspace,
+    cpkg: &CPackage,
+    rustc_target: &target::Target,
+    root_output: &Path,
+) -> anyhow::Result<()> {
+    let capi_config = &cpkg.capi_config;
+    let (obj, dll) = match (
+        resource_object(capi_config, rustc_target, root_output),
+        cpkg.build_targets.shared_lib.as_ref(),
+    ) {
+        (Some(obj), Some(dll)) => (obj, dll),
+        _ => return Ok(()),
+    };
+    let metadata = ws
+        .members()
+        .find(|m| m.root() == cpkg.root_path)
+        .map(|m| m.manifest().metadata());
+    let dll_name = dll.file_name().unwrap().to_string_lossy();
+    let library = &capi_config.library;
+    let src = obj.with_extension("rc");
+    let mut rc = String::from("/* Generated by cargo-c, do not edit */\n\n");
+    if library.version_resource {
+        rc.push_str(&version_resource_source(
+            capi_config,
+            metadata,
+            &cpkg.version,
+            &dll_name,
+            library.stamp.then(|| stamp_text(ws, cpkg)).as_deref(),
+        ));
+    }
+    if let Some(manifest) = &library.manifest {
+        let path = match manifest {
+            DllManifest::File(path) => cpkg.root_path.join(path),
+            DllManifest::Generated => {
+                let path = obj.with_extension("manifest");
+                cargo_util::paths::write_if_changed(
+                    &path,
+                    generated_manifest(capi_config, rustc_target, &dll_name),
+                )?;
+                path
+            }
+        };
+        if !path.is_file() {
+            anyhow::bail!("library.manifest {} does not exist", path.display());
+        }
+        // ISOLATIONAWARE_MANIFEST_RESOURCE_ID and RT_MANIFEST, the ones the loader reads for
+        // the DLLs
+        rc.push_str(&format!(
+            "\n2 24 \"{}\"\n",
+            path.display().to_string().replace('\\', "/")
+        ));
+    }
+    cargo_util::paths::write_if_changed(&src, rc)?;
+
+    ws.config()
+        .shell()
+        .verbose(|shell| shell.status("Compiling", src.display()))?;
+
+    for mut cmd in resource_compilers(rustc_target) {
+        if rustc_target.env == "msvc" {
+            cmd.arg("/nologo")
+                .arg(format!("/fo{}", obj.display()))
+                .arg(&src);
+        } else {
+            cmd.args(["-O", "coff", "-i"]).arg(&src).arg("-o").arg(&obj);
+        }
+        let out = match cmd.output() {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            out => out.map_err(|e| anyhow::anyhow!("Cannot run {cmd:?}: {e}"))?,
+        };
+        if !out.status.success() {
+            anyhow::bail!(
+                "Cannot compile {}:\n{}",
+                src.display(),
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+        return Ok(());
+    }
+
+    if library.manifest.is_some() {
+        anyhow::bail!(
+            "no resource compiler found to embed library.manifest in {dll_name}, \
+             install windres or llvm-rc, or set RC"
+        );
+    }
+    if obj.exists() {
+        cargo_util::paths::remove_file(&obj)?;
+    }
+    warn(
+        ws.config(),
+        format!(
+            "no resource compiler found, {dll_name} has no version information, \
+             install windres or llvm-rc, set RC or set library.version_resource = false"
+        ),
+    )
+}
+
+/// Object file running the library.init and library.fini functions when the shared library
+/// is loaded and unloaded
+fn init_fini_object(
+    capi_config: &CApiConfig,
+    rustc_target: &target::Target,
+    root_output: &Path,
+) -> Option<PathBuf> {
+    let library = &capi_config.library;
+    if library.init.is_none() && library.fini.is_none() {
+        return None;
+    }
+    let ext = if rustc_target.env == "msvc" {
+        "obj"
+    } else {
+        "o"
+    };
+    Some(root_output.join(format!("{}-init-fini.{ext}", library.name)))
+}
+
+fn init_fini_source(capi_config: &CApiConfig, rustc_target: &target::Target) -> String {
+    let init = capi_config.library.init.as_deref();
+    let fini = capi_config.library.fini.as_deref();
+    let mut buf = String::from("/* Generated by cargo-c, do not edit */\n\n");
+
+    for f in init.iter().chain(fini.iter()) {
+        buf.push_str(&format!("extern void {f}(void);\n"));
+    }
+    buf.push('\n');
+
+    if rustc_target.os == "windows" {
+        // DllMain is called by the CRT entry point of the DLL
+        buf.push_str(
+            "#include <windows.h>
+
+BOOL WINAPI DllMain(HINSTANCE instance, DWORD reason, LPVOID reserved)
+{
+    (void)instance;
+",
+        );
+        if let Some(init) = init {
+            buf.push_str(&format!(
+                "    if (reason == DLL_PROCESS_ATTACH)\n        {init}();\n"
+            ));
+        }
+        if let Some(fini) = fini {
+            // The other threads are already gone when the process is exiting
+            buf.push_str(&format!(
+                "    if (reason == DLL_PROCESS_DETACH && reserved == NULL)\n        {fini}();\n"
+            ));
+        }
+        buf.push_str("    return TRUE;\n}\n");
+    } else {
+        // Emitted in .init_array and .fini_array (or __mod_init_func on Mach-O)
+        if let Some(init) = init {
+            buf.push_str(&format!(
+                "__attribute__((constructor, used)) static void cargo_c_init(void)\n{{\n    {init}();\n}}\n"
+            ));
+        }
+        if let Some(fini) = fini {
+            buf.push_str(&format!(
+                "__attribute__((destructor, used)) static void cargo_c_fini(void)\n{{\n    {fini}();\n}}\n"
+            ));
+        }
+    }
+
+    buf
+}
+
+/// Compile the object registering the init and fini functions, it is linked in the shared
+/// library only
+fn build_init_fini_object(
+    ws: &Workspace,
+    capi_config: &CApiConfig,
+    rustc_target: &target::Target,
+    target: &str,
+    host: &str,
+    root_output: &Path,
+) -> anyhow::Result<()> {
+    let obj = match init_fini_object(capi_config, rustc_target, root_output) {
+        Some(obj) => obj,
+        None => return Ok(()),
+    };
+    let src = obj.with_extension("c");
+    cargo_util::paths::write_if_changed(&src, init_fini_source(capi_config, rustc_target))?;
+
+    compile_c_object(ws, &src, &obj, target, host)
+}
+
+/// A copy of the object linked in the shared library named after the hash of its sources
+///
+/// The link arguments are part of the fingerprint of the library, cargo relinks it when the
+/// sources change as it does when the Rust sources do.
+fn linked_object(obj: &Path, sources: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    for src in sources {
+        read_bytes(src)?.hash(&mut hasher);
+    }
+    let stem = obj.file_stem().unwrap().to_string_lossy();
+    let ext = obj.extension().unwrap().to_string_lossy();
+    let linked = obj.with_file_name(format!("{stem}-{:016x}.{ext}", hasher.finish()));
+    copy(obj, &linked)?;
+
+    // The copies of the former sources
+    for entry in std::fs::read_dir(obj.parent().unwrap())?.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let hash = name
+            .strip_prefix(&format!("{stem}-"))
+            .and_then(|name| name.strip_suffix(&format!(".{ext}")));
+        let stale = hash.map_or(false, |h| {
+            h.len() == 16 && u64::from_str_radix(h, 16).is_ok()
+        });
+        if stale && entry.path() != linked {
+            cargo_util::paths::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(linked)
+}
+
+/// The C compiler of the target, as the cc crate finds it
+pub(crate) fn c_compiler(target: &str, host: &str) -> anyhow::Result<cc::Tool> {
+    Ok(cc::Build::new()
+        .target(target)
+        .host(host)
+        .opt_level(2)
+        .debug(false)
+        .cargo_metadata(false)
+        .cargo_warnings(false)
+        .warnings(false)
+        .try_get_compiler()?)
+}
+
+/// Compile a generated C source in an object file linked into the shared library
+fn compile_c_object(
+    ws: &Workspace,
+    src: &Path,
+    obj: &Path,
+    target: &str,
+    host: &str,
+) -> anyhow::Result<()> {
+    ws.config()
+        .shell()
+        .verbose(|shell| shell.status("Compiling", src.display()))?;
+
+    let compiler = c_compiler(target, host)?;
+    let mut cmd = compiler.to_command();
+    if compiler.is_like_msvc() {
+        cmd.arg("/nologo")
+            .arg("/c")
+            .arg(src)
+            .arg(format!("/Fo{}", obj.display()));
+    } else {
+        cmd.arg("-c").arg(src).arg("-o").arg(obj);
+    }
+
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {cmd:?}: {e}"))?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "Cannot compile {}:\n{}",
+            src.display(),
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+/// The crate, its version and its repository along with the cargo-c version, as
+/// `library.stamp` records them in the shared library
+fn stamp_text(ws: &Workspace, cpkg: &CPackage) -> String {
+    let pkg = ws.members().find(|m| m.root() == cpkg.root_path);
+    let name = pkg.map_or(cpkg.capi_config.library.name.as_str(), |p| {
+        p.name().as_str()
+    });
+    let mut stamp = format!(
+        "{name} {} (cargo-c {})",
+        cpkg.version,
+        env!("CARGO_PKG_VERSION")
+    );
+    if let Some(repository) = pkg.and_then(|p| p.manifest().metadata().repository.as_deref()) {
+        stamp.push_str(&format!(" {repository}"));
+    }
+    stamp
+}
+
+/// Object file recording the `library.stamp` in the ELF or Mach-O shared library, the
+/// Windows DLLs carry it in their version resource
+fn stamp_object(
+    capi_config: &CApiConfig,
+    rustc_target: &target::Target,
+    root_output: &Path,
+) -> Option<PathBuf> {
+    let apple = rustc_target.os == "macos" || rustc_target.os == "ios";
+    if !capi_config.library.stamp || !(is_elf(rustc_target) || apple) {
+        return None;
+    }
+    Some(root_output.join(format!("{}-stamp.o", capi_config.library.name)))
+}
+
+fn stamp_source(stamp: &str, rustc_target: &target::Target) -> String {
+    let literal = format!("\"{}\"", stamp.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut buf = String::from("/* Generated by cargo-c, do not edit */\n\n");
+
+    if is_elf(rustc_target) {
+        // A note owned by `cargo-c`, kept by the linkers when collecting the unused sections,
+        // read with `readelf -n`, and the same text in .comment
+        let desc_size = stamp.len() + 1;
+        buf.push_str(&format!(
+            "#ident {literal}
+
+__attribute__((section(\".note.cargo-c\"), aligned(4), used))
+static const struct {{
+    unsigned int namesz, descsz, type;
+    char name[8];
+    char desc[{padded}];
+}} cargo_c_note = {{ 8, {desc_size}, 1, \"cargo-c\", {literal} }};
+",
+            padded = (desc_size + 3) / 4 * 4,
+        ));
+    } else {
+        // no_dead_strip keeps the section in the dylib, `otool -s __TEXT __cargo_c` reads it
+        buf.push_str(&format!(
+            "__attribute__((section(\"__TEXT,__cargo_c,regular,no_dead_strip\"), used))
+static const char cargo_c_stamp[] = {literal};
+"
+        ));
+    }
+
+    buf
+}
+
+/// Why the `library.stamp` requested cannot be recorded in the shared library of the target
+fn unrecorded_stamp(capi_config: &CApiConfig, rustc_target: &target::Target) -> Option<String> {
+    let library = &capi_config.library;
+    let apple = rustc_target.os == "macos" || rustc_target.os == "ios";
+    if !library.stamp || is_elf(rustc_target) || apple {
+        None
+    } else if rustc_target.os == "windows" {
+        (!library.version_resource).then(|| {
+            "the DLLs carry it in their version resource, set library.version_resource = true"
+                .to_string()
+        })
+    } else {
+        Some(format!(
+            "there is no stamp for the {} targets",
+            rustc_target.triple
+        ))
+    }
+}
+
+fn build_stamp_object(
+    ws: &Workspace,
+    cpkg: &CPackage,
+    rustc_target: &target::Target,
+    target: &str,
+    host: &str,
+    root_output: &Path,
+) -> anyhow::Result<()> {
+    if cpkg.build_targets.shared_lib.is_none() {
+        return Ok(());
+    }
+    let obj = match stamp_object(&cpkg.capi_config, rustc_target, root_output) {
+        Some(obj) => obj,
+        None => {
+            if let Some(reason) = unrecorded_stamp(&cpkg.capi_config, rustc_target) {
+                warn(
+                    ws.config(),
+                    format!("library.stamp is not recorded in the shared library, {reason}"),
+                )?;
+            }
+            return Ok(());
+        }
+    };
+    let src = obj.with_extension("c");
+    cargo_util::paths::write_if_changed(&src, stamp_source(&stamp_text(ws, cpkg), rustc_target))?;
+
+    compile_c_object(ws, &src, &obj, target, host)
+}
+
+/// The check-cfg arguments declaring `cargo_c`, the features and the cfgs of the manifest
+fn check_cfg_args(pkg: &Package, capi_config: &CApiConfig) -> Vec<String> {
+    let features = pkg
+        .summary()
+        .features()
+        .keys()
+        .map(|f| format!("{f:?}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // docsrs and test are declared by cargo as well
+    [
+        "cfg(cargo_c)".to_string(),
+        "cfg(docsrs, test)".to_string(),
+        format!("cfg(feature, values({features}))"),
+    ]
+    .into_iter()
+    .chain(capi_config.check_cfg.iter().cloned())
+    .flat_map(|c| ["--check-cfg".to_string(), c])
+    .collect()
+}
+
+/// The check-cfg arguments declaring the cfgs passed to rustc, e.g. the `rustc-cfg` of the
+/// build script, and the ones the build script declares with `rustc-check-cfg` in its
+/// `output`
+fn build_script_check_cfg_args(args: &[String], output: &str) -> Vec<String> {
+    let mut check_cfgs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "--cfg" {
+            continue;
+        }
+        let check_cfg = match args.next() {
+            Some(cfg) => match cfg.split_once('=') {
+                // Declared by `check_cfg_args`
+                Some(("feature", _)) => continue,
+                Some((name, value)) => format!("cfg({name}, values({value}))"),
+                None if cfg == "cargo_c" => continue,
+                None => format!("cfg({cfg})"),
+            },
+            None => break,
+        };
+        if !check_cfgs.contains(&check_cfg) {
+            check_cfgs.push(check_cfg);
+        }
+    }
+
+    for line in output.lines() {
+        if let Some(check_cfg) = line
+            .strip_prefix("cargo:rustc-check-cfg=")
+            .or_else(|| line.strip_prefix("cargo::rustc-check-cfg="))
+        {
+            check_cfgs.push(check_cfg.to_string());
+        }
+    }
+
+    check_cfgs
+        .into_iter()
+        .flat_map(|c| ["--check-cfg".to_string(), c])
+        .collect()
+}
+
+/// The library types rustc can build for the target, as `--library-type auto` picks them
+///
+/// rustc drops the crate types the target does not support, e.g. `cdylib` on bare metal or
+/// with a static C runtime, and leaves them out of `--print file-names`.
+fn supported_library_types(
+    rustc: &Path,
+    target: &str,
+    crt_static: bool,
+) -> anyhow::Result<Vec<&'static str>> {
+    let mut supported = Vec::new();
+    for kind in ["staticlib", "cdylib"] {
+        let mut cmd = std::process::Command::new(rustc);
+        cmd.args(["-", "--crate-name", "probe", "--crate-type", kind])
+            .args(["--print", "file-names", "--target", target]);
+        if crt_static {
+            cmd.args(["-C", "target-feature=+crt-static"]);
+        }
+        let out = cmd
+            .stdin(std::process::Stdio::null())
+            .output()
+            .map_err(|e| anyhow::anyhow!("Cannot run {:?}: {}", cmd, e))?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "Command failed {:?}: {}",
+                cmd,
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+        if !String::from_utf8_lossy(&out.stdout).trim().is_empty() {
+            supported.push(kind);
+        }
+    }
+    if supported.is_empty() {
+        anyhow::bail!("{target} supports neither the staticlib nor the cdylib library type");
+    }
+    Ok(supported)
+}
+
+/// The shared Rust standard library of the target, as shipped in the sysroot
+fn rust_std_dylib(rustc: &Path, target: &str) -> anyhow::Result<PathBuf> {
+    let out = std::process::Command::new(rustc)
+        .args(["--print", "target-libdir", "--target", target])
+        .output()?;
+    let libdir = PathBuf::from(String::from_utf8_lossy(&out.stdout).trim());
+
+    let entries = std::fs::read_dir(&libdir).ok().into_iter().flatten();
+    entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .find(|p| {
+            let name = p.file_name().unwrap().to_string_lossy();
+            (name.starts_with("libstd-") || name.starts_with("std-"))
+                && [".so", ".dylib", ".dll"]
+                    .iter()
+                    .any(|ext| name.ends_with(ext))
+        })
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "The Rust standard library for {} is not available as a shared library in {}",
+                target,
+                libdir.display()
+            )
+        })
+}
+
+/// The rustc arguments optimizing the whole library for size
+fn min_size_args(rustc_target: &target::Target) -> Vec<String> {
+    let mut args = vec![
+        "-C".to_string(),
+        "opt-level=z".to_string(),
+        "-C".to_string(),
+        "codegen-units=1".to_string(),
+        "-C".to_string(),
+        "panic=abort".to_string(),
+    ];
+    // Let the linker drop the unused sections
+    let gc_sections = match rustc_target.os.as_str() {
+        "macos" | "ios" => Some("-Wl,-dead_strip"),
+        "windows" if rustc_target.env == "msvc" => Some("/OPT:REF,ICF"),
+        _ if rustc_target.arch.starts_with("wasm") => None,
+        _ => Some("-Wl,--gc-sections"),
+    };
+    if let Some(gc_sections) = gc_sections {
+        args.push("-C".to_string());
+        args.push(format!("link-arg={gc_sections}"));
+    }
+    args
+}
+
+/// Collect the packages compiled into the artifact, skipping the build dependencies
+fn linked_packages(dep: &UnitDep, graph: &UnitGraph, packages: &mut Vec<Package>) {
+    if dep.unit_for.is_for_host()
+        || packages
+            .iter()
+            .any(|p| p.package_id() == dep.unit.pkg.package_id())
+    {
+        return;
+    }
+    packages.push(dep.unit.pkg.clone());
+    for dep in graph[&dep.unit].iter() {
+        linked_packages(dep, graph, packages);
+    }
+}
+
+/// The target directory of a `<target-dir>/<triple>/<profile>` build directory
+pub(crate) fn target_dir_of(root_output: &Path) -> anyhow::Result<&Path> {
+    root_output.ancestors().nth(2).ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} is not a <target-dir>/<triple>/<profile> build directory",
+            root_output.display()
+        )
+    })
+}
+
+/// The path of `to` relative to the directory `from`, both absolute
+pub(crate) fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    from.components()
+        .skip(common)
+        .map(|_| Component::ParentDir.as_os_str())
+        .chain(to.components().skip(common).map(|c| c.as_os_str()))
+        .collect()
+}
+
+/// Map the absolute paths of the sources recorded in the debug info, the workspace to `.`
+/// and the registry sources to `/cargo`
+fn remap_path_args(cargo_home: &Path, root: &Path) -> Vec<String> {
+    let mut args = Vec::new();
+    for (from, to) in [(cargo_home, "/cargo"), (root, ".")] {
+        args.push("--remap-path-prefix".into());
+        args.push(format!("{}={to}", from.display()));
+    }
+    args
+}
+
+/// Zero the timestamps and the owners of the members of the static library and give them
+/// the same mode, the archive then only depends on its contents
+///
+/// The member headers are rewritten in place, the symbol table offsets stay valid.
+fn normalize_archive(path: &Path) -> anyhow::Result<()> {
+    let mut data = read_bytes(path)?;
+    if !data.starts_with(b"!<arch>\n") {
+        anyhow::bail!("{} is not an ar archive", path.display());
+    }
+
+    let mut pos = 8;
+    while pos + 60 <= data.len() {
+        let header = &mut data[pos..pos + 60];
+        if &header[58..] != b"`\n" {
+            anyhow::bail!("{} has a malformed member at {}", path.display(), pos);
+        }
+        let size: usize = std::str::from_utf8(&header[48..58])?.trim().parse()?;
+        // mtime, uid, gid and mode, the blank ones of the string table are left as they are
+        for (field, value) in [(16..28, "0"), (28..34, "0"), (34..40, "0"), (40..48, "644")] {
+            let width = field.len();
+            let field = &mut header[field];
+            if field.iter().any(|&c| c != b' ') {
+                field.copy_from_slice(format!("{value:<width$}").as_bytes());
+            }
+        }
+        pos += 60 + size + size % 2;
+    }
+
+    write(path, data)?;
+    Ok(())
+}
+
+/// Fail if pkg-config does not find the `Requires` and `Requires.private` entries, the
+/// version constraints included, in the build environment
+fn check_requires(
+    ws: &Workspace,
+    rustc_target: &target::Target,
+    pc: &PkgConfig,
+    env: &[(&str, String)],
+) -> anyhow::Result<()> {
+    let tool = pkg_config_tool(&rustc_target.triple);
+    let mut unsatisfied = Vec::new();
+    for req in pc.all_requires() {
+        ws.config()
+            .shell()
+            .verbose(|shell| shell.status("Checking", req))?;
+        let exists = std::process::Command::new(&tool)
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .arg("--exists")
+            .arg(req)
+            .status()
+            .map_err(|e| anyhow::anyhow!("Cannot run {}: {}", tool.display(), e))?;
+        if exists.success() {
+            continue;
+        }
+        let module = req.split_whitespace().next().unwrap_or(req);
+        let found = std::process::Command::new(&tool)
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .arg("--modversion")
+            .arg(module)
+            .output()?;
+        let found = if found.status.success() {
+            format!("{} found", String::from_utf8_lossy(&found.stdout).trim())
+        } else {
+            "not found".to_string()
+        };
+        unsatisfied.push(format!("  - {req}: {found}"));
+    }
+    if !unsatisfied.is_empty() {
+        anyhow::bail!(
+            "the pkg-config requirements are not satisfied by the build environment:\n{}",
+            unsatisfied.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// Stage the rlib of the library with the rlibs and the rmetas of the crates linked into
+/// it, laid out as in the `rustlib` directory of a sysroot
+///
+/// The consumers of the Rust crate build with `--extern <crate>=<dir>/lib<crate>.rlib -L
+/// dependency=<dir>`, `<dir>` being `rustlib/<triple>/lib`. The rlibs are only loaded by the
+/// rustc that built them, `rustlib/<triple>/<name>.json` records it along with the crate.
+fn build_rustlib(
+    ws: &Workspace,
+    cpkg: &CPackage,
+    packages: &[Package],
+    rustc_version: &str,
+    root_output: &Path,
+    rustlib: &Path,
+) -> anyhow::Result<()> {
+    let capi_config = &cpkg.capi_config;
+    let target = &cpkg.build_targets.target;
+    ws.config().shell().status("Staging", "rlib")?;
+
+    if rustlib.exists() {
+        cargo_util::paths::remove_dir_all(rustlib)?;
+    }
+    let dir = rustlib.join(&target.triple).join("lib");
+    create_dir_all(&dir)?;
+
+    let crate_name = artifact_name(&capi_config.library.name);
+    let rlib = format!("lib{crate_name}.rlib");
+    copy(root_output.join(&rlib), dir.join(&rlib))?;
+
+    // Several builds of a crate may be left in deps, rustc picks the one the rlib was
+    // built against
+    let names: Vec<_> = packages
+        .iter()
+        .filter(|p| p.package_id() != cpkg.finger_print.id)
+        .filter_map(|p| p.targets().iter().find(|t| t.is_lib()))
+        .map(|t| format!("lib{}-", t.crate_name()))
+        .collect();
+    for entry in std::fs::read_dir(root_output.join("deps"))? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let crate_file = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("rlib" | "rmeta")
+        );
+        if crate_file && names.iter().any(|n| file_name.starts_with(n)) {
+            copy(&path, dir.join(&*file_name))?;
+        }
+    }
+
+    let metadata = serde_json::json!({
+        "name": cpkg.finger_print.id.name().as_str(),
+        "version": cpkg.version.to_string(),
+        "crate_name": crate_name,
+        "target": target.triple,
+        "rustc": rustc_version.lines().next().unwrap_or_default(),
+        "rlib": format!("lib/{rlib}"),
+    });
+    write(
+        rustlib
+            .join(&target.triple)
+            .join(format!("{}.json", capi_config.library.name)),
+        serde_json::to_string_pretty(&metadata)? + "\n",
+    )?;
+
+    Ok(())
+}
+
+/// The member of the AIX archive holding the shared object, versioned as the sonames are
+fn aix_member_name(library: &LibraryCApiConfig) -> String {
+    if library.versioning {
+        format!("lib{}.so.{}", library.name, library.version.major)
+    } else {
+        format!("lib{}.so", library.name)
+    }
+}
+
+/// Put the shared object rustc linked in the `lib<name>.a` archive, as the AIX shared
+/// libraries are shipped
+///
+/// The consumers record the archive and the member, e.g. `libfoo.a(libfoo.so.1)`, the
+/// member name is the counterpart of the soname. The archive is written with `ar -X64` on
+/// AIX and `llvm-ar --format=bigarchive` elsewhere, the `AR` environment variable may be
+/// used to override the tool.
+fn build_aix_archive(
+    ws: &Workspace,
+    library: &LibraryCApiConfig,
+    built: &Path,
+    archive: &Path,
+) -> anyhow::Result<()> {
+    // Already archived, by the previous build or by the linker
+    if read_bytes(built)?.starts_with(b"<bigaf>\n") {
+        if built != archive {
+            copy(built, archive)?;
+        }
+        return Ok(());
+    }
+
+    ws.config()
+        .shell()
+        .status("Building", "AIX shared library archive")?;
+    let dir = archive.parent().unwrap();
+    let member = aix_member_name(library);
+    copy(built, dir.join(&member))?;
+    if archive.exists() {
+        std::fs::remove_file(archive)?;
+    }
+
+    let mut cmd = match std::env::var_os("AR") {
+        Some(ar) => std::process::Command::new(ar),
+        None if cfg!(target_os = "aix") => std::process::Command::new("ar"),
+        None => {
+            let mut cmd = std::process::Command::new("llvm-ar");
+            cmd.arg("--format=bigarchive");
+            cmd
+        }
+    };
+    cmd.arg("-X64")
+        .arg("rc")
+        .arg(archive.file_name().unwrap())
+        .arg(&member)
+        .current_dir(dir);
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {cmd:?}: {e}"))?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "Command failed {:?}: {}",
+            cmd,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+/// The pkg-config of the target, as the pkg-config crate picks it
+pub(crate) fn pkg_config_tool(target: &str) -> PathBuf {
+    let target_var = format!("PKG_CONFIG_{}", target.replace('-', "_"));
+    std::env::var_os(target_var)
+        .or_else(|| std::env::var_os("PKG_CONFIG"))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("pkg-config"))
+}
+
+/// The versions of the system libraries the linked `-sys` crates require, from their
+/// `package.metadata.system-deps` tables
+///
+/// The versions enabled by the features of the crates are not taken into account, only
+/// the base one.
+fn sys_deps_versions(packages: &[Package]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut versions = Vec::new();
+    for pkg in packages {
+        let manifest = read(&pkg.root().join("Cargo.toml"))?.parse::<toml::Value>()?;
+        let deps = manifest
+            .get("package")
+            .and_then(|v| v.get("metadata"))
+            .and_then(|v| v.get("system-deps"))
+            .and_then(|v| v.as_table());
+        for (key, dep) in deps.into_iter().flatten() {
+            let (name, version) = match dep {
+                toml::Value::String(version) => (key.as_str(), Some(version.as_str())),
+                toml::Value::Table(dep) => (
+                    dep.get("name").and_then(|v| v.as_str()).unwrap_or(key),
+                    dep.get("version").and_then(|v| v.as_str()),
+                ),
+                _ => continue,
+            };
+            if let Some(version) = version {
+                versions.push((name.to_string(), version.to_string()));
+            }
+        }
+    }
+    Ok(versions)
+}
+
+/// The rustc arguments of the companion binaries
+///
+/// They link the rlib of the library, they find the shared libraries installed next to it,
+/// e.g. the shared Rust standard library, through an rpath relative to `{bindir}`.
+fn bin_args(
+    pkg: &Package,
+    capi_config: &CApiConfig,
+    install_paths: &InstallPaths,
+    rustc_target: &target::Target,
+    args: &ArgMatches,
+    check_cfg: bool,
+) -> Vec<String> {
+    let mut bin_args = capi_config.library.rustflags.clone();
+
+    bin_args.push("--cfg".into());
+    bin_args.push("cargo_c".into());
+    if check_cfg {
+        bin_args.extend(check_cfg_args(pkg, capi_config));
+    }
+
+    let origin = match rustc_target.os.as_str() {
+        "macos" | "ios" => Some("@loader_path"),
+        _ if is_elf(rustc_target) => Some("$ORIGIN"),
+        _ => None,
+    };
+    if let Some(origin) = origin {
+        let libdir = relative_path(&install_paths.bindir, &install_paths.libdir);
+        bin_args.push("-C".into());
+        bin_args.push(format!("link-arg=-Wl,-rpath,{origin}/{}", libdir.display()));
+    }
+
+    if args.get_flag("prefer_dynamic_std") {
+        bin_args.push("-C".into());
+        bin_args.push("prefer-dynamic".into());
+    }
+    if args.get_flag("crt_static") {
+        bin_args.push("-C".into());
+        bin_args.push("target-feature=+crt-static".into());
+    }
+    if args.get_flag("min_size") {
+        bin_args.extend(min_size_args(rustc_target));
+        bin_args.push("-C".into());
+        bin_args.push("strip=symbols".into());
+    }
+
+    bin_args
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compile_with_exec(
+    ws: &Workspace<'_>,
+    options: &CompileOptions,
+    exec: &Arc<Exec>,
+    rustc_target: &target::Target,
+    root_output: &Path,
+    args: &ArgMatches,
+    libkinds: &[&str],
+    linked: &mut HashMap<PackageId, Vec<Package>>,
+) -> CargoResult<HashMap<PackageId, PathBuf>> {
+    ws.emit_warnings()?;
+    let interner = UnitInterner::new();
+    let mut bcx = create_bcx(ws, options, &interner)?;
+    // check-cfg is stable since rustc 1.80, the embedded cargo does not pass it by itself
+    let check_cfg = bcx.rustc().version >= Version::new(1, 80, 0);
+    let unit_graph = &bcx.unit_graph;
+    let extra_compiler_args = &mut bcx.extra_compiler_args;
+
+    for unit in bcx.roots.iter() {
+        let pkg = &unit.pkg;
+        let mut capi_config =
+            load_manifest_capi_config(pkg, Some(rustc_target), selected_preset(args))?;
+        apply_library_kinds(&mut capi_config, libkinds);
+        let name = &capi_config.library.name;
+        let install_paths = InstallPaths::new(name, args, &capi_config, rustc_target);
+        let pkg_rustflags = &capi_config.library.rustflags;
+
+        if unit.target.is_bin() {
+            extra_compiler_args.insert(
+                unit.clone(),
+                bin_args(
+                    pkg,
+                    &capi_config,
+                    &install_paths,
+                    rustc_target,
+                    args,
+                    check_cfg,
+                ),
+            );
+            continue;
+        }
+
+        let mut leaf_args: Vec<String> = rustc_target
+            .shared_object_link_args(&capi_config, &install_paths.libdir, root_output)
+            .into_iter()
+            .flat_map(|l| vec!["-C".to_string(), format!("link-arg={l}")])
+            .collect();
+
+        if let Some(obj) = init_fini_object(&capi_config, rustc_target, root_output) {
+            let obj = linked_object(&obj, &[obj.with_extension("c")])?;
+            leaf_args.push("-C".into());
+            leaf_args.push(format!("link-arg={}", obj.display()));
+        }
+
+        if let Some(obj) = stamp_object(&capi_config, rustc_target, root_output) {
+            let obj = linked_object(&obj, &[obj.with_extension("c")])?;
+            leaf_args.push("-C".into());
+            leaf_args.push(format!("link-arg={}", obj.display()));
+        }
+
+        if let Some(res) =
+            resource_object(&capi_config, rustc_target, root_output).filter(|res| res.is_file())
+        {
+            let mut sources = vec![res.with_extension("rc")];
+            match &capi_config.library.manifest {
+                Some(DllManifest::File(path)) => sources.push(pkg.root().join(path)),
+                Some(DllManifest::Generated) => sources.push(res.with_extension("manifest")),
+                None => {}
+            }
+            let res = linked_object(&res, &sources)?;
+            leaf_args.push("-C".into());
+            leaf_args.push(format!("link-arg={}", res.display()));
+        }
+
+        if let Some(linker) =
+            symbol_version_linker(ws.config(), &capi_config, rustc_target, root_output)?
+        {
+            leaf_args.push("-C".into());
+            leaf_args.push(format!("linker={}", linker.display()));
+            exec.linker_wrappers
+                .lock()
+                .unwrap()
+                .insert(pkg.package_id(), linker);
+        }
+
+        if let Some(def) = exports_def_file(&capi_config, rustc_target, root_output)? {
+            if rustc_target.env == "msvc" {
+                // The linkers use the last /DEF passed, the one of rustc comes first
+                leaf_args.push("-C".into());
+                leaf_args.push(format!("link-arg=/DEF:{}", def.display()));
+            } else if let Some(linker) = exports_linker(
+                ws.config(),
+                rustc_target,
+                &def,
+                root_output,
+                &capi_config.library.name,
+            )? {
+                leaf_args.push("-C".into());
+                leaf_args.push(format!("linker={}", linker.display()));
+                exec.linker_wrappers
+                    .lock()
+                    .unwrap()
+                    .insert(pkg.package_id(), linker);
+            }
+        }
+
+        // The import library looks the stdcall exports up undecorated, the DLL must match
+        if capi_config.def.kill_at && rustc_target.arch == "x86" && rustc_target.env == "gnu" {
+            leaf_args.push("-C".into());
+            leaf_args.push("link-arg=-Wl,--kill-at".into());
+        }
+
+        leaf_args.extend(pkg_rustflags.clone());
+
+        leaf_args.push("--cfg".into());
+        leaf_args.push("cargo_c".into());
+
+        if check_cfg {
+            leaf_args.extend(check_cfg_args(pkg, &capi_config));
+        }
+
+        leaf_args.push("--print".into());
+        leaf_args.push("native-static-libs".into());
+
+        if args.get_flag("prefer_dynamic_std") {
+            // The shared std is built with panic=unwind and cannot be mixed with abort
+            let abort = unit.profile.panic == PanicStrategy::Abort
+                || args.get_flag("min_size")
+                || pkg_rustflags
+                    .iter()
+                    .any(|f| f.trim_start_matches("-C") == "panic=abort");
+            if abort {
+                anyhow::bail!(
+                    "--prefer-dynamic-std cannot be used with panic=abort, \
+                     the shared Rust standard library is built with panic=unwind"
+                );
+            }
+            leaf_args.push("-C".into());
+            leaf_args.push("prefer-dynamic".into());
+        }
+
+        if args.get_flag("crt_static") {
+            leaf_args.push("-C".into());
+            leaf_args.push("target-feature=+crt-static".into());
+        }
+
+        let mut deps_args = pkg_rustflags.clone();
+        if capi_config.library.reproducible {
+            let remap = remap_path_args(ws.config().home().as_path_unlocked(), ws.root());
+            leaf_args.extend(remap.iter().cloned());
+            deps_args.extend(remap);
+        }
+        if args.get_flag("min_size") {
+            let size_args = min_size_args(rustc_target);
+            leaf_args.extend(size_args.iter().cloned());
+            // The exported symbols are kept
+            leaf_args.push("-C".into());
+            leaf_args.push("strip=symbols".into());
+            deps_args.extend(size_args);
+        }
+
+        extra_compiler_args.insert(unit.clone(), leaf_args.to_owned());
+
+        for dep in unit_graph[unit].iter() {
+            set_deps_args(dep, unit_graph, extra_compiler_args, &deps_args);
+        }
+
+        let packages = linked.entry(pkg.package_id()).or_default();
+        if packages.is_empty() {
+            packages.push(pkg.clone());
+        }
+        for dep in unit_graph[unit].iter() {
+            linked_packages(dep, unit_graph, packages);
+        }
+    }
+
+    if options.build_config.unit_graph {
+        unit_graph::emit_serialized_unit_graph(&bcx.roots, &bcx.unit_graph, ws.config())?;
+        return Ok(HashMap::new());
+    }
+    let _p = profile::start("compiling");
+    let cx = cargo::core::compiler::Context::new(&bcx)?;
+
+    let r = cx.compile(&(exec.clone() as Arc<dyn Executor>))?;
+
+    let out_dirs = r
+        .cdylibs
+        .iter()
+        .filter_map(|l| {
+            let id = l.unit.pkg.package_id();
+            if let Some(ref m) = l.script_meta {
+                if let Some(env) = r.extra_env.get(m) {
+                    env.iter().find_map(|e| {
+                        if e.0 == "OUT_DIR" {
+                            Some((id, PathBuf::from(&e.1)))
+                        } else {
+                            None
+                        }
+                    })
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Ok(out_dirs)
+}
+
+#[derive(Debug)]
+pub struct CPackage {
+    pub version: Version,
+    pub root_path: PathBuf,
+    pub capi_config: CApiConfig,
+    pub build_targets: BuildTargets,
+    pub install_paths: InstallPaths,
+    finger_print: FingerPrint,
+}
+
+impl CPackage {
+    /// The native libraries the static library must be linked with
+    pub fn static_libs(&self) -> &str {
+        &self.finger_print.static_libs
+    }
+
+    fn from_package(
+        config: &Config,
+        pkg: &mut Package,
+        args: &ArgMatches,
+        libkinds: &[&str],
+        rustc_target: &target::Target,
+        root_output: &Path,
+    ) -> anyhow::Result<CPackage> {
+        let id = pkg.package_id();
+        let version = pkg.version().clone();
+        let root_path = pkg.root().to_path_buf();
+        let mut capi_config =
+            load_manifest_capi_config(pkg, Some(rustc_target), selected_preset(args))?;
+        check_capi_keys(config, pkg)?;
+        apply_library_kinds(&mut capi_config, libkinds);
+        // The plugins are loaded by the library they extend, they are not linked with
+        let libkinds: &[&str] = if capi_config.library.plugin.is_some() {
+            capi_config.pkg_config.install = false;
+            &["module"]
+        } else {
+            libkinds
+        };
+
+        patch_target(config, pkg, libkinds, &capi_config)?;
 
         let name = &capi_config.library.name;
 
-        let install_paths = InstallPaths::new(name, args, &capi_config);
+        let install_paths = InstallPaths::new(name, args, &capi_config, rustc_target);
         let build_targets =
             BuildTargets::new(name, rustc_target, root_output, libkinds, &capi_config)?;
 
-        let finger_print = FingerPrint::new(&id, root_output, &build_targets, &install_paths);
+        let mut finger_print = FingerPrint::new(
+            &id,
+            root_output,
+            &build_targets,
+            &install_paths,
+            &capi_config,
+        );
+        if name.contains(['-', '.']) {
+            let artifacts = BuildTargets::new(
+                &artifact_name(name),
+                rustc_target,
+                root_output,
+                libkinds,
+                &capi_config,
+            )?;
+            finger_print.artifacts.extend(artifacts.static_lib);
+        }
+        if let Some(shared_lib) = build_targets.shared_lib.as_ref() {
+            let built = rustc_shared_lib(&artifact_name(name), rustc_target, root_output);
+            if built != *shared_lib {
+                finger_print.artifacts.push(built);
+            }
+        }
 
         Ok(CPackage {
             version,
@@ -985,6 +4708,570 @@ impl CPackage {
     }
 }
 
+/// Make sure the minimum macOS version stamped in the dylib matches the deployment target
+fn check_macos_deployment_target(
+    ws: &Workspace,
+    shared_lib: &Path,
+    version: &str,
+) -> anyhow::Result<()> {
+    let mut cmd = std::process::Command::new("otool");
+    cmd.arg("-l").arg(shared_lib);
+
+    let out = match cmd.output() {
+        Ok(out) if out.status.success() => out,
+        _ => {
+            warn(
+                ws.config(),
+                format!("Cannot run {cmd:?}, the macOS deployment target is not verified"),
+            )?;
+            return Ok(());
+        }
+    };
+
+    ws.config()
+        .shell()
+        .status("Checking", format!("macOS deployment target {version}"))?;
+
+    // LC_BUILD_VERSION reports `minos`, the older LC_VERSION_MIN_MACOSX reports `version`
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut lines = stdout.lines().map(str::trim);
+    let mut found = None;
+    while let Some(line) = lines.next() {
+        let key = match line {
+            "cmd LC_BUILD_VERSION" => "minos",
+            "cmd LC_VERSION_MIN_MACOSX" => "version",
+            _ => continue,
+        };
+        found = lines
+            .by_ref()
+            .take_while(|l| !l.starts_with("Load command"))
+            .find_map(|l| l.strip_prefix(key).map(|v| v.trim().to_string()));
+        break;
+    }
+
+    let normalize = |v: &str| {
+        let v = v.trim_end_matches(".0");
+        if v.contains('.') {
+            v.to_string()
+        } else {
+            format!("{v}.0")
+        }
+    };
+
+    match found {
+        Some(found) if normalize(&found) == normalize(version) => Ok(()),
+        Some(found) => anyhow::bail!(
+            "{} has minimum macOS version {} but the deployment target is {}",
+            shared_lib.display(),
+            found,
+            version
+        ),
+        None => anyhow::bail!(
+            "{} does not record a minimum macOS version",
+            shared_lib.display()
+        ),
+    }
+}
+
+/// Make sure the shared library does not bundle a known custom allocator, so memory can
+/// be freed across the library boundary with the host free()
+fn check_system_allocator(shared_lib: &Path) -> anyhow::Result<()> {
+    use object::read::{Object, ObjectSymbol};
+
+    const ALLOCATORS: &[(&str, &str)] = &[
+        ("_rjem_malloc", "jemalloc"),
+        ("je_malloc", "jemalloc"),
+        ("mi_malloc", "mimalloc"),
+        ("tc_malloc", "tcmalloc"),
+        ("sn_malloc", "snmalloc"),
+    ];
+
+    let data = read_bytes(shared_lib)?;
+    let file = object::read::File::parse(&*data)
+        .map_err(|e| anyhow::anyhow!("Cannot parse {}: {}", shared_lib.display(), e))?;
+    for symbol in file.symbols().chain(file.dynamic_symbols()) {
+        if symbol.is_undefined() {
+            continue;
+        }
+        let name = symbol.name().unwrap_or_default();
+        // Mach-O symbols have a leading underscore
+        let name = name
+            .strip_prefix('_')
+            .filter(|_| file.format() == object::BinaryFormat::MachO)
+            .unwrap_or(name);
+        if let Some((_, allocator)) = ALLOCATORS.iter().find(|(s, _)| *s == name) {
+            anyhow::bail!(
+                "{} is built with {} as allocator while library.allocator is \"system\"",
+                shared_lib.display(),
+                allocator
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the size of the libraries, compared to the previous build if any
+fn report_size(
+    ws: &Workspace,
+    build_targets: &BuildTargets,
+    previous_sizes: &HashMap<PathBuf, u64>,
+) -> anyhow::Result<()> {
+    fn human(size: u64) -> String {
+        match size {
+            s if s >= 1 << 20 => format!("{:.1} MiB", s as f64 / f64::from(1 << 20)),
+            s if s >= 1 << 10 => format!("{:.1} KiB", s as f64 / f64::from(1 << 10)),
+            s => format!("{s} B"),
+        }
+    }
+
+    for lib in build_targets
+        .shared_lib
+        .iter()
+        .chain(build_targets.static_lib.iter())
+    {
+        let size = std::fs::metadata(lib)?.len();
+        let name = lib.file_name().unwrap().to_string_lossy();
+        let message = match previous_sizes.get(lib) {
+            Some(&previous) if previous != size => format!(
+                "{name}: {} -> {} ({:+.1}%)",
+                human(previous),
+                human(size),
+                (size as f64 - previous as f64) * 100.0 / previous as f64
+            ),
+            _ => format!("{name}: {}", human(size)),
+        };
+        ws.config().shell().status("Size", message)?;
+    }
+
+    Ok(())
+}
+
+/// Whether a SPDX license expression can only be satisfied by denied licenses
+fn license_denied(expression: &str, deny: &[glob::Pattern]) -> bool {
+    // `/` is the legacy crates.io separator for alternatives
+    let spaced = expression
+        .replace('(', " ( ")
+        .replace(')', " ) ")
+        .replace('/', " OR ");
+    let tokens: Vec<&str> = spaced.split_whitespace().collect();
+
+    // The operands are always evaluated for the tokens to be consumed
+    fn or(tokens: &[&str], pos: &mut usize, deny: &[glob::Pattern]) -> bool {
+        let mut denied = and(tokens, pos, deny);
+        while tokens.get(*pos) == Some(&"OR") {
+            *pos += 1;
+            denied &= and(tokens, pos, deny);
+        }
+        denied
+    }
+    fn and(tokens: &[&str], pos: &mut usize, deny: &[glob::Pattern]) -> bool {
+        let mut denied = term(tokens, pos, deny);
+        while tokens.get(*pos) == Some(&"AND") {
+            *pos += 1;
+            denied |= term(tokens, pos, deny);
+        }
+        denied
+    }
+    fn term(tokens: &[&str], pos: &mut usize, deny: &[glob::Pattern]) -> bool {
+        match tokens.get(*pos) {
+            Some(&"(") => {
+                *pos += 1;
+                let denied = or(tokens, pos, deny);
+                if tokens.get(*pos) == Some(&")") {
+                    *pos += 1;
+                }
+                denied
+            }
+            Some(id) => {
+                *pos += 1;
+                // Skip the license exception
+                if tokens.get(*pos) == Some(&"WITH") {
+                    *pos += 2;
+                }
+                deny.iter().any(|p| p.matches(id))
+            }
+            None => false,
+        }
+    }
+
+    or(&tokens, &mut 0, deny)
+}
+
+/// Write the licenses of the crates compiled into the library and enforce the deny-list
+fn build_license_report(
+    ws: &Workspace,
+    capi_config: &CApiConfig,
+    packages: &[Package],
+    root_output: &Path,
+) -> anyhow::Result<()> {
+    #[derive(serde::Serialize)]
+    struct Entry<'a> {
+        name: &'a str,
+        version: String,
+        license: &'a str,
+    }
+
+    let deny = capi_config
+        .licenses
+        .deny
+        .iter()
+        .map(|p| glob::Pattern::new(p))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut entries = Vec::new();
+    let mut summary = std::collections::BTreeMap::<&str, usize>::new();
+    let mut denied = Vec::new();
+    for pkg in packages {
+        let license = pkg
+            .manifest()
+            .metadata()
+            .license
+            .as_deref()
+            .unwrap_or("UNKNOWN");
+        *summary.entry(license).or_default() += 1;
+        if license_denied(license, &deny) {
+            denied.push(format!("{} {} ({})", pkg.name(), pkg.version(), license));
+        }
+        entries.push(Entry {
+            name: pkg.name().as_str(),
+            version: pkg.version().to_string(),
+            license,
+        });
+    }
+
+    // The summary is printed when the report changes, every time with --verbose
+    let report = root_output.join(format!("{}-licenses.json", capi_config.library.name));
+    let json = serde_json::to_string_pretty(&entries)?;
+    let changed = std::fs::read_to_string(&report).ok().as_ref() != Some(&json);
+
+    let mut shell = ws.config().shell();
+    if changed || shell.verbosity() == cargo::core::Verbosity::Verbose {
+        shell.status(
+            "Licenses",
+            summary
+                .iter()
+                .map(|(license, count)| format!("{license} ({count})"))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )?;
+    }
+    shell.verbose(|s| {
+        for entry in entries.iter() {
+            s.note(format!(
+                "{} {}: {}",
+                entry.name, entry.version, entry.license
+            ))?;
+        }
+        Ok(())
+    })?;
+    drop(shell);
+
+    if changed {
+        write(report, json)?;
+    }
+
+    if !denied.is_empty() {
+        anyhow::bail!(
+            "The library would link crates with denied licenses:\n  {}",
+            denied.join("\n  ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether the glibc version is newer than `max_version`, the missing components count as
+/// zeros: 2.17 and 2.17.0 are the same version
+fn glibc_version_newer(version: &str, max_version: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> { v.split('.').map(|c| c.parse().unwrap_or(0)).collect() };
+    let (mut version, mut max) = (parse(version), parse(max_version));
+    let len = version.len().max(max.len());
+    version.resize(len, 0);
+    max.resize(len, 0);
+    version > max
+}
+
+/// Make sure the shared library does not require symbols from a glibc newer than `max_version`
+fn check_glibc_version(ws: &Workspace, shared_lib: &Path, max_version: &str) -> anyhow::Result<()> {
+    ws.config().shell().status(
+        "Checking",
+        format!("glibc symbol versions <= {max_version}"),
+    )?;
+
+    let objdump = std::env::var("OBJDUMP").unwrap_or_else(|_| "objdump".into());
+    let mut cmd = std::process::Command::new(objdump);
+    cmd.arg("-T").arg(shared_lib);
+
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {:?}: {}", cmd, e))?;
+    if !out.status.success() {
+        anyhow::bail!("Command failed {:?}", cmd);
+    }
+
+    let re = regex::Regex::new(r"GLIBC_([0-9.]+)\)?\s+(\S+)$").unwrap();
+    let symbols: Vec<_> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter_map(|line| {
+            let cap = re.captures(line)?;
+            let version = cap.get(1).unwrap().as_str();
+            glibc_version_newer(version, max_version)
+                .then(|| format!("{} (GLIBC_{})", &cap[2], version))
+        })
+        .collect();
+
+    if symbols.is_empty() {
+        Ok(())
+    } else {
+        anyhow::bail!(
+            "{} requires symbols newer than GLIBC_{}: {}",
+            shared_lib.display(),
+            max_version,
+            symbols.join(", ")
+        )
+    }
+}
+
+/// Adjust the native static libraries for a fully static link against musl
+///
+/// musl provides libdl, librt, libpthread, libutil and libm as part of libc.a, so linking
+/// them explicitly is not needed, libgcc_s is available only as a shared object
+/// and would make the static-pie or fully static consumer link fail.
+fn musl_static_libs(ws: &Workspace, static_libs: &str) -> anyhow::Result<String> {
+    const IN_LIBC: &[&str] = &["-ldl", "-lrt", "-lpthread", "-lutil", "-lm"];
+    const DYNAMIC_ONLY: &[&str] = &["-lgcc_s"];
+
+    let mut libs = Vec::new();
+    for lib in static_libs.split_whitespace() {
+        if IN_LIBC.contains(&lib) {
+            continue;
+        }
+        if DYNAMIC_ONLY.contains(&lib) {
+            warn(
+                ws.config(),
+                format!(
+                    "{lib} is available only as a shared library, \
+                 the static library cannot be linked fully statically"
+                ),
+            )?;
+        }
+        libs.push(lib);
+    }
+
+    Ok(libs.join(" "))
+}
+
+/// Set the environment variable for the build unless it is already set
+fn set_env_default(ws: &Workspace, name: &str, value: &str) -> anyhow::Result<()> {
+    if let Ok(current) = std::env::var(name) {
+        if current != value {
+            ws.config().shell().verbose(|s| {
+                s.note(format!(
+                    "{name} is already set to {current:?}, ignoring the value {value:?}"
+                ))
+            })?;
+        }
+    } else {
+        std::env::set_var(name, value);
+    }
+
+    Ok(())
+}
+
+/// The value of a pkg-config variable for the target, looked up in the environment in the
+/// order of the pkg-config crate: suffixed with the target, then `TARGET_` prefixed, then
+/// as is; and the name it is set under
+fn targeted_env_var(base: &str, target: &str) -> (Option<String>, String) {
+    let names = [
+        format!("{base}_{target}"),
+        format!("{base}_{}", target.replace('-', "_")),
+        format!("TARGET_{base}"),
+        base.to_string(),
+    ];
+    for (i, name) in names.iter().enumerate() {
+        if let Ok(value) = std::env::var(name) {
+            let set_as = if i == 0 { &names[0] } else { &names[1] };
+            return (Some(value), set_as.clone());
+        }
+    }
+    (None, names[1].clone())
+}
+
+/// The pkg-config environment making the libraries of the cross target found instead of
+/// the host ones, as the name of the variable for the target and the one for pkg-config
+///
+/// The variables already set are left untouched, the `pkgconfig` directories of the target
+/// sysroot are searched before the `PKG_CONFIG_PATH` already set.
+fn cross_pkg_config_env(
+    args: &ArgMatches,
+    target: &str,
+) -> anyhow::Result<Vec<(String, &'static str, String)>> {
+    let mut env = Vec::new();
+    let (allow_cross, name) = targeted_env_var("PKG_CONFIG_ALLOW_CROSS", target);
+    if allow_cross.is_none() {
+        env.push((name, "PKG_CONFIG_ALLOW_CROSS", "1".to_string()));
+    }
+
+    let sysroot = args
+        .get_one::<PathBuf>("target_sysroot")
+        .cloned()
+        .or_else(|| std::env::var_os("CARGO_C_TARGET_SYSROOT").map(PathBuf::from));
+
+    if let Some(sysroot) = sysroot {
+        let (sysroot_dir, name) = targeted_env_var("PKG_CONFIG_SYSROOT_DIR", target);
+        if sysroot_dir.is_none() {
+            env.push((
+                name,
+                "PKG_CONFIG_SYSROOT_DIR",
+                sysroot.to_string_lossy().into_owned(),
+            ));
+        }
+
+        let (path, name) = targeted_env_var("PKG_CONFIG_PATH", target);
+        let mut dirs: Vec<_> = [
+            "usr/lib/pkgconfig",
+            "usr/share/pkgconfig",
+            "usr/local/lib/pkgconfig",
+        ]
+        .iter()
+        .map(|p| sysroot.join(p))
+        .collect();
+        dirs.extend(path.iter().flat_map(std::env::split_paths));
+        let path = std::env::join_paths(dirs)?;
+        env.push((name, "PKG_CONFIG_PATH", path.to_string_lossy().into_owned()));
+    }
+
+    Ok(env)
+}
+
+/// Make the build scripts using pkg-config look for the libraries of the cross targets
+/// instead of the host ones
+///
+/// The variables, suffixed with the target as the pkg-config crate looks them up, are added
+/// to the `[env]` table of the configuration cargo sets on the build scripts it runs. The
+/// entries of the table already configured are kept.
+pub(crate) fn setup_cross_pkg_config(config: &mut Config, args: &ArgMatches) -> anyhow::Result<()> {
+    use cargo::util::config::{ConfigValue, Definition};
+
+    let host = config.load_global_rustc(None)?.host.to_string();
+    let mut env = Vec::new();
+    for target in requested_targets(config, args, &host)? {
+        if target != host {
+            env.extend(cross_pkg_config_env(args, &target)?);
+        }
+    }
+    if env.is_empty() {
+        return Ok(());
+    }
+
+    let values = config.values_mut()?;
+    let table = values
+        .entry("env".to_string())
+        .or_insert_with(|| ConfigValue::Table(HashMap::new(), Definition::Cli(None)));
+    if let ConfigValue::Table(table, _) = table {
+        for (name, _, value) in env {
+            // Set over the value of the environment, it already holds the one of the user
+            let value = HashMap::from([
+                (
+                    "value".to_string(),
+                    ConfigValue::String(value, Definition::Cli(None)),
+                ),
+                (
+                    "force".to_string(),
+                    ConfigValue::Boolean(true, Definition::Cli(None)),
+                ),
+            ]);
+            table
+                .entry(name)
+                .or_insert(ConfigValue::Table(value, Definition::Cli(None)));
+        }
+    }
+
+    Ok(())
+}
+
+/// The targets to build for: the ones passed with `--target`, or else the `build.target` of
+/// the cargo configuration, or else the host, as cargo does
+pub(crate) fn requested_targets(
+    config: &Config,
+    args: &ArgMatches,
+    host: &str,
+) -> anyhow::Result<Vec<String>> {
+    let mut targets = args.targets();
+    if targets.is_empty() {
+        if let Some(build_target) = &config.build_config()?.target {
+            targets = build_target.values(config)?;
+        }
+    }
+    if targets.is_empty() {
+        targets.push(host.to_string());
+    }
+    let mut unique = Vec::new();
+    for target in targets {
+        if !unique.contains(&target) {
+            unique.push(target);
+        }
+    }
+    Ok(unique)
+}
+
+/// Fail before building if `command` would get the libraries of more than one target,
+/// unless they are merged in a universal library or, for `cinstall`, installed apart
+pub fn check_single_target(
+    ws: &Workspace,
+    config: &Config,
+    args: &ArgMatches,
+    command: &str,
+) -> anyhow::Result<()> {
+    let rustc = config.load_global_rustc(Some(ws))?;
+    let targets = requested_targets(config, args, &rustc.host)?
+        .iter()
+        .map(target::Target::new)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if targets.len() < 2 {
+        return Ok(());
+    }
+
+    let all: Vec<_> = targets.iter().collect();
+    let merged = !args.get_flag("target_subdir") && universal_target(&all).is_some();
+    let apart = command == "cinstall"
+        && targets
+            .iter()
+            .all(|t| crate::install::uses_target_subdir(args, t));
+    if !merged && !apart {
+        anyhow::bail!(
+            "{command} handles a single target at a time, pass a single --target to pick \
+             one of the requested targets"
+        );
+    }
+    Ok(())
+}
+
+/// The environment variables of the process, to restore after building a target
+fn env_snapshot() -> Vec<(std::ffi::OsString, std::ffi::OsString)> {
+    std::env::vars_os().collect()
+}
+
+/// Restore the environment variables of the process, so the ones set for a target,
+/// e.g. the cross pkg-config setup or the `capi.env` ones, do not leak into the next one
+fn restore_env(saved: &[(std::ffi::OsString, std::ffi::OsString)]) {
+    for (name, _) in std::env::vars_os() {
+        if !saved.iter().any(|(saved_name, _)| *saved_name == name) {
+            std::env::remove_var(name);
+        }
+    }
+    for (name, value) in saved {
+        if std::env::var_os(name).as_ref() != Some(value) {
+            std::env::set_var(name, value);
+        }
+    }
+}
+
+/// Build the C-API of the workspace members for each requested target
+///
+/// A fresh workspace is loaded for every target past the first one, as the library types
+/// built depend on the target.
 pub fn cbuild(
     ws: &mut Workspace,
     config: &Config,
@@ -992,48 +5279,309 @@ pub fn cbuild(
     default_profile: &str,
 ) -> anyhow::Result<(Vec<CPackage>, CompileOptions)> {
     let rustc = config.load_global_rustc(Some(ws))?;
-    let targets = args.targets();
-    let target = match targets.len() {
-        0 => rustc.host.to_string(),
-        1 => targets[0].to_string(),
-        _ => {
-            anyhow::bail!("Multiple targets not supported yet");
+    let targets = requested_targets(config, args, &rustc.host)?;
+
+    let env = env_snapshot();
+    let (mut members, mut compile_opts) =
+        cbuild_target(ws, config, args, default_profile, &targets[0])?;
+    for target in &targets[1..] {
+        restore_env(&env);
+        let mut target_ws = args.workspace(config)?;
+        let (target_members, target_opts) =
+            cbuild_target(&mut target_ws, config, args, default_profile, target)?;
+        members.extend(target_members);
+        compile_opts = target_opts;
+    }
+
+    // The SDK layout keeps the libraries of each target apart
+    let built: Vec<_> = members.iter().map(|m| &m.build_targets.target).collect();
+    match universal_target(&built) {
+        Some(universal) if !args.get_flag("target_subdir") => {
+            members = merge_universal(ws, &universal, members)?;
         }
-    };
+        _ => {}
+    }
 
-    let rustc_target = target::Target::new(&target)?;
+    Ok((members, compile_opts))
+}
 
-    let default_kind = || match (rustc_target.os.as_str(), rustc_target.env.as_str()) {
-        ("none", _) | (_, "musl") => vec!["staticlib"],
-        _ => vec!["staticlib", "cdylib"],
+/// The universal target the Apple targets of a single platform are merged into, e.g.
+/// `universal-apple-darwin` for `x86_64-apple-darwin` and `aarch64-apple-darwin`
+fn universal_target(targets: &[&target::Target]) -> Option<String> {
+    let first = targets.first()?;
+    let same_platform = targets
+        .iter()
+        .all(|t| t.vendor == "apple" && t.os == first.os && t.abi == first.abi);
+    if !same_platform || targets.iter().all(|t| t.triple == first.triple) {
+        return None;
+    }
+
+    let os = match first.os.as_str() {
+        "macos" => "darwin",
+        os => os,
     };
+    Some(match first.abi.as_str() {
+        "" => format!("universal-apple-{os}"),
+        abi => format!("universal-apple-{os}-{abi}"),
+    })
+}
 
-    let libkinds = args
-        .get_many::<String>("library-type")
-        .map_or_else(default_kind, |v| v.map(String::as_str).collect::<Vec<_>>());
-    let only_staticlib = !libkinds.contains(&"cdylib");
-    let only_cdylib = !libkinds.contains(&"staticlib");
+fn lipo(inputs: &[&PathBuf], output: &Path) -> anyhow::Result<()> {
+    let mut cmd =
+        std::process::Command::new(std::env::var_os("LIPO").unwrap_or_else(|| "lipo".into()));
+    cmd.arg("-create").arg("-output").arg(output).args(inputs);
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {:?}: {}", cmd, e))?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "Command failed {:?}: {}",
+            cmd,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Merge the libraries built for every architecture into universal ones with `lipo`
+///
+/// They are written in `<target-dir>/<universal>/<profile>` along with the pkg-config files
+/// and the headers of the first target, the packages returned install them all at once.
+fn merge_universal(
+    ws: &Workspace,
+    universal: &str,
+    members: Vec<CPackage>,
+) -> anyhow::Result<Vec<CPackage>> {
+    let mut groups: Vec<(CPackage, Vec<BuildTargets>)> = Vec::new();
+    for cpkg in members {
+        match groups
+            .iter_mut()
+            .find(|(g, _)| g.root_path == cpkg.root_path)
+        {
+            Some((_, slices)) => slices.push(cpkg.build_targets),
+            None => groups.push((cpkg, Vec::new())),
+        }
+    }
+
+    let mut merged = Vec::new();
+    for (mut cpkg, slices) in groups {
+        let build_targets = &mut cpkg.build_targets;
+        let root_output = build_targets.pc.parent().unwrap().to_path_buf();
+        let universal_output = target_dir_of(&root_output)?
+            .join(universal)
+            .join(root_output.file_name().unwrap());
+        create_dir_all(&universal_output)?;
+
+        ws.config().shell().status(
+            "Merging",
+            format!("{} into {universal}", cpkg.capi_config.library.name),
+        )?;
+
+        for (lib, slice_lib) in [
+            (
+                &mut build_targets.static_lib,
+                slices
+                    .iter()
+                    .map(|s| s.static_lib.as_ref())
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                &mut build_targets.shared_lib,
+                slices.iter().map(|s| s.shared_lib.as_ref()).collect(),
+            ),
+        ] {
+            if let Some(path) = lib.as_mut() {
+                let mut inputs = vec![&*path];
+                inputs.extend(slice_lib.into_iter().flatten());
+                let output = universal_output.join(path.file_name().unwrap());
+                lipo(&inputs, &output)?;
+                *path = output;
+            }
+        }
+
+        // The pkg-config files and the headers are the same for every architecture
+        let pkg_config = &cpkg.capi_config.pkg_config;
+        let filenames = std::iter::once(&pkg_config.filename)
+            .chain(pkg_config.modules.iter().map(|module| &module.filename));
+        for filename in filenames {
+            let pc = format!("{filename}.pc");
+            copy(root_output.join(&pc), universal_output.join(&pc))?;
+            let uninstalled = format!("{filename}-uninstalled.pc");
+            write(
+                universal_output.join(&uninstalled),
+                read(&root_output.join(&uninstalled))?.replace(
+                    &root_output.display().to_string(),
+                    &universal_output.display().to_string(),
+                ),
+            )?;
+        }
+        let include = root_output.join("include");
+        if include.is_dir() {
+            crate::install::copy_dir(&include, &universal_output.join("include"))?;
+        }
+
+        build_targets.pc = universal_output.join(build_targets.pc.file_name().unwrap());
+        for pc in build_targets.pc_modules.iter_mut() {
+            *pc = universal_output.join(pc.file_name().unwrap());
+        }
+        // The debug information of each architecture is left in its own directory
+        build_targets.debug_info = None;
+        build_targets.target.triple = universal.to_string();
+        merged.push(cpkg);
+    }
+
+    Ok(merged)
+}
+
+/// The library types the requested members configure for the target in `library.types`
+///
+/// The library types are shared by the whole build, the members setting them must agree.
+fn configured_library_types(
+    ws: &Workspace,
+    args: &ArgMatches,
+    requested: &[PackageId],
+    target: &target::Target,
+) -> anyhow::Result<Option<Vec<String>>> {
+    let mut configured: Option<(InternedString, Vec<String>)> = None;
+    for m in ws.members().filter(|m| {
+        m.library().is_some()
+            && m.summary().features().contains_key("capi")
+            && requested.contains(&m.package_id())
+    }) {
+        let types = match load_manifest_capi_config(m, Some(target), selected_preset(args))?
+            .library
+            .types
+        {
+            Some(types) => types,
+            None => continue,
+        };
+        match &configured {
+            Some((name, kinds)) if *kinds != types => anyhow::bail!(
+                "{name} and {} configure different library types for {}, pass --library-type",
+                m.name(),
+                target.triple
+            ),
+            Some(_) => {}
+            None => configured = Some((m.name(), types)),
+        }
+    }
+
+    Ok(configured.map(|(_, types)| types))
+}
+
+/// Install the plugins in the runtime directory of the package they extend
+fn resolve_plugin_dirs(
+    ws: &Workspace,
+    args: &ArgMatches,
+    target: &target::Target,
+    members: &mut [CPackage],
+) -> anyhow::Result<()> {
+    for cpkg in members.iter_mut() {
+        let plugin = match &cpkg.capi_config.library.plugin {
+            Some(plugin) => plugin.clone(),
+            None => continue,
+        };
+        let name = &cpkg.capi_config.library.name;
+        let main = ws
+            .members()
+            .find(|m| m.name().as_str() == plugin.package)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{name} is a plugin of {}, which is not a member of the workspace",
+                    plugin.package
+                )
+            })?;
+        let main_config = load_manifest_capi_config(main, Some(target), selected_preset(args))?;
+        let dir = main_config
+            .runtime_dirs
+            .dirs
+            .iter()
+            .find(|(dir, _)| *dir == plugin.dir)
+            .map(|(_, path)| path.clone())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{name} is installed in runtime_dirs.dirs.{} of {}, which is not set",
+                    plugin.dir,
+                    plugin.package
+                )
+            })?;
+        cpkg.capi_config.library.install_subdir = Some(dir);
+    }
+
+    Ok(())
+}
+
+/// Make sure the members do not overwrite each other's libraries, headers or pkg-config files
+///
+/// The libraries are compared through the name rustc gives them, `foo-bar` and `foo_bar`
+/// produce the same artifacts.
+fn check_name_collisions<'a>(
+    capi_configs: impl IntoIterator<Item = &'a CApiConfig>,
+) -> anyhow::Result<()> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for capi_config in capi_configs {
+        let lib_name = capi_config.library.name.as_str();
+        let mut names = vec![
+            format!("library {}", artifact_name(&capi_config.library.name)),
+            format!("pkg-config file {}.pc", capi_config.pkg_config.filename),
+        ];
+        names.extend(
+            capi_config
+                .pkg_config
+                .modules
+                .iter()
+                .map(|module| format!("pkg-config file {}.pc", module.filename)),
+        );
+        if capi_config.header.enabled {
+            names.push(format!(
+                "header {}",
+                Path::new(&capi_config.header.subdirectory)
+                    .join(&capi_config.header.name)
+                    .display()
+            ));
+        }
+        for name in names {
+            if let Some(other) = seen.insert(name.clone(), lib_name) {
+                anyhow::bail!("{other} and {lib_name} both produce the {name}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn cbuild_target(
+    ws: &mut Workspace,
+    config: &Config,
+    args: &ArgMatches,
+    default_profile: &str,
+    target: &str,
+) -> anyhow::Result<(Vec<CPackage>, CompileOptions)> {
+    let rustc = config.load_global_rustc(Some(ws))?;
+    let target = target.to_string();
+    let rustc_target = target::Target::new(&target)?;
+
+    let build_script = args
+        .get_one::<String>("emit")
+        .map(|emit| crate::superbuild::BuildScript::from_emit(emit))
+        .transpose()?;
 
     let profile = args.get_profile_name(config, default_profile, ProfileChecking::Custom)?;
 
     let profiles = Profiles::new(ws, profile)?;
 
-    let mut compile_opts = compile_options(ws, config, args, profile, CompileMode::Build)?;
+    let mut compile_opts = compile_options(ws, config, args, profile, CompileMode::Build, &target)?;
 
     // TODO: there must be a simpler way to get the right path.
     let root_output = ws
         .target_dir()
         .as_path_unlocked()
         .to_path_buf()
-        .join(PathBuf::from(target))
+        .join(PathBuf::from(&target))
         .join(profiles.get_dir_name());
 
     let capi_feature = InternedString::new("capi");
 
-    let mut members = Vec::new();
-
-    let mut pristine = false;
-
     let requested: Vec<_> = compile_opts
         .spec
         .get_packages(ws)?
@@ -1041,32 +5589,321 @@ pub fn cbuild(
         .map(|p| p.package_id())
         .collect();
 
+    let configured_kinds = configured_library_types(ws, args, &requested, &rustc_target)?;
+    let default_kind = || match configured_kinds.as_ref() {
+        Some(kinds) => kinds.iter().map(String::as_str).collect(),
+        None => match (rustc_target.os.as_str(), rustc_target.env.as_str()) {
+            // The wasi consumers link the static library, the wasm modules are not shared
+            ("none", _) | ("espidf", _) | ("wasi", _) | (_, "musl") => vec!["staticlib"],
+            ("aix", _) => vec!["cdylib"],
+            _ => vec!["staticlib", "cdylib"],
+        },
+    };
+
+    let mut libkinds = args
+        .get_many::<String>("library-type")
+        .map_or_else(default_kind, |v| v.map(String::as_str).collect::<Vec<_>>());
+    if libkinds.contains(&"auto") {
+        libkinds = supported_library_types(&rustc.path, &target, args.get_flag("crt_static"))?;
+        if !libkinds.contains(&"cdylib") {
+            warn(
+                config,
+                format!(
+                    "{target} does not support the cdylib library type, \
+                     only the static library is built"
+                ),
+            )?;
+        }
+    }
+    if libkinds.contains(&"module") && libkinds.contains(&"cdylib") {
+        anyhow::bail!("the module library type is a cdylib, they cannot be built together");
+    }
+    if rustc_target.os == "aix" && libkinds.contains(&"staticlib") && libkinds.len() > 1 {
+        anyhow::bail!(
+            "the AIX static and shared libraries are both named lib<name>.a, \
+             build them with separate --library-type invocations"
+        );
+    }
+    let only_staticlib = !libkinds.contains(&"cdylib") && !libkinds.contains(&"module");
+    let only_cdylib = !libkinds.contains(&"staticlib");
+
+    // Report the missing pieces of the toolchain at once, before cargo stops at the first
+    crate::doctor::check_toolchain(
+        ws,
+        args,
+        &rustc.path,
+        &rustc.host,
+        &rustc_target,
+        !only_staticlib,
+    )?;
+
+    let mut members = Vec::new();
+
+    let mut pristine = false;
+
     for m in ws.members_mut().filter(|m| {
         m.library().is_some()
             && m.summary().features().contains_key(&capi_feature)
             && requested.contains(&m.package_id())
     }) {
-        let cpkg = CPackage::from_package(m, args, &libkinds, &rustc_target, &root_output)?;
+        let mut cpkg =
+            CPackage::from_package(config, m, args, &libkinds, &rustc_target, &root_output)?;
+
+        if cpkg.build_targets.shared_lib.is_some() {
+            let kind = compile_opts.build_config.requested_kinds[0];
+            let profile = profiles.get_profile(
+                m.package_id(),
+                true,
+                true,
+                cargo::core::profiles::UnitFor::new_normal(kind),
+                kind,
+            );
+            cpkg.build_targets.debug_info = debug_info_artifact(
+                &profile,
+                args.get_flag("min_size"),
+                &rustc_target,
+                &root_output,
+                &cpkg.capi_config.library.name,
+            );
+        }
 
         pristine = pristine || cpkg.finger_print.load_previous().is_err();
 
         members.push(cpkg);
     }
 
-    if pristine {
+    // The plugins are modules, they cannot be built along a library without shared library
+    if only_staticlib {
+        if let Some(plugin) = members
+            .iter()
+            .find(|m| m.capi_config.library.plugin.is_some())
+        {
+            anyhow::bail!(
+                "{} is a plugin, always built as a module, it cannot be built with the staticlib \
+                 library type only, add cdylib or build the other members with --package",
+                plugin.capi_config.library.name
+            );
+        }
+    }
+    resolve_plugin_dirs(ws, args, &rustc_target, &mut members)?;
+    check_name_collisions(members.iter().map(|cpkg| &cpkg.capi_config))?;
+
+    // The companion binaries are built in the same pass, against the rlib
+    let bins: Vec<String> = members
+        .iter()
+        .flat_map(|cpkg| cpkg.capi_config.install.bin.iter().cloned())
+        .collect();
+    if !bins.is_empty() {
+        compile_opts.filter = CompileFilter::new(
+            LibRule::True,
+            FilterRule::Just(bins),
+            FilterRule::none(),
+            FilterRule::none(),
+            FilterRule::none(),
+        );
+    }
+
+    for cpkg in members.iter() {
+        if let Some(Allocator::Feature(feature)) = &cpkg.capi_config.library.allocator {
+            std::rc::Rc::get_mut(&mut compile_opts.cli_features.features)
+                .unwrap()
+                .insert(FeatureValue::new(feature.as_str().into()));
+        }
+    }
+
+    // The environment is shared by the whole build, the variables already set take
+    // precedence over the ones coming from the metadata.
+    for cpkg in members.iter() {
+        for (name, value) in cpkg.capi_config.env.iter() {
+            set_env_default(ws, name, value)?;
+        }
+        if let Some(version) = &cpkg.capi_config.library.macos_deployment_target {
+            if rustc_target.os == "macos" {
+                set_env_default(ws, "MACOSX_DEPLOYMENT_TARGET", version)?;
+            }
+        }
+    }
+
+    // Set up for the build scripts by `setup_cross_pkg_config`
+    let pkg_config_env: Vec<_> = if target != rustc.host.as_str() {
+        cross_pkg_config_env(args, &target)?
+            .into_iter()
+            .map(|(_, name, value)| (name, value))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    std::fs::create_dir_all(&root_output)?;
+    for cpkg in members.iter() {
+        build_init_fini_object(
+            ws,
+            &cpkg.capi_config,
+            &rustc_target,
+            &target,
+            rustc.host.as_str(),
+            &root_output,
+        )?;
+        build_stamp_object(
+            ws,
+            cpkg,
+            &rustc_target,
+            &target,
+            rustc.host.as_str(),
+            &root_output,
+        )?;
+        build_resources(ws, cpkg, &rustc_target, &root_output)?;
+    }
+
+    // The members are restored from the artifact cache all together or built all together
+    let artifact_cache = args
+        .get_one::<PathBuf>("artifact_cache")
+        .map(|dir| {
+            // The RUSTFLAGS and the build.rustflags and target rustflags of the configuration
+            let kinds = &compile_opts.build_config.requested_kinds;
+            let target_data = cargo::core::compiler::RustcTargetData::new(ws, kinds)?;
+            let linker = target_data
+                .target_config(kinds[0])
+                .linker
+                .as_ref()
+                .map(|linker| linker.val.raw_value().to_owned());
+            let inputs = format!(
+                "{}\n{target} {profile} {libkinds:?}\n{:?}\n{:?} {linker:?}\n{:?}",
+                rustc.verbose_version,
+                compile_opts.cli_features,
+                target_data.info(kinds[0]).rustflags,
+                [
+                    args.get_flag("crt_static"),
+                    args.get_flag("min_size"),
+                    args.get_flag("prefer_dynamic_std"),
+                ],
+            );
+            ArtifactCache::new(ws, dir, inputs)
+        })
+        .transpose()?;
+    let mut out_dirs = HashMap::new();
+    let mut restored = false;
+    if let Some(cache) = &artifact_cache {
+        let keys: Vec<_> = members.iter().map(|cpkg| cache.key(cpkg)).collect();
+        let hit = keys
+            .iter()
+            .all(|key| cache.entry_dir(key).join("entry.toml").is_file());
+        if hit {
+            for (cpkg, key) in members.iter().zip(keys.iter()) {
+                if let Some(entry) = cache.restore(key)? {
+                    if let Some(out_dir) = entry.out_dir {
+                        out_dirs.insert(cpkg.finger_print.id, cache.target_dir.join(out_dir));
+                    }
+                }
+                ws.config().shell().status(
+                    "Restored",
+                    format!("{} from the artifact cache", cpkg.capi_config.library.name),
+                )?;
+            }
+            restored = true;
+        }
+    }
+
+    if pristine && !restored {
         // If the cache is somehow missing force a full rebuild;
         compile_opts.build_config.force_rebuild = true;
     }
 
-    let exec = Arc::new(Exec::default());
-    let out_dirs = compile_with_exec(
-        ws,
-        &compile_opts,
-        &(exec.clone() as Arc<dyn Executor>),
-        &rustc_target,
-        &root_output,
-        args,
-    )?;
+    // The size of the libraries as previously built, to report the size saved
+    let previous_sizes: HashMap<PathBuf, u64> = members
+        .iter()
+        .flat_map(|cpkg| {
+            let build_targets = &cpkg.build_targets;
+            build_targets
+                .shared_lib
+                .iter()
+                .chain(build_targets.static_lib.iter())
+        })
+        .filter_map(|lib| Some((lib.clone(), std::fs::metadata(lib).ok()?.len())))
+        .collect();
+
+    // The library types configured with their own features are built separately
+    let split_features = !only_staticlib
+        && !only_cdylib
+        && members.iter().any(|cpkg| {
+            let library = &cpkg.capi_config.library;
+            !library.staticlib_features.is_empty() || !library.cdylib_features.is_empty()
+        });
+    let passes = if split_features {
+        vec![vec!["staticlib"], vec!["cdylib"]]
+    } else {
+        vec![libkinds.clone()]
+    };
+
+    let sonames = members
+        .iter()
+        .filter(|cpkg| cpkg.build_targets.shared_lib.is_some())
+        .filter_map(|cpkg| {
+            Some((
+                cpkg.finger_print.id,
+                rustc_target.soname(&cpkg.capi_config)?,
+            ))
+        })
+        .collect();
+    let exec = Arc::new(Exec {
+        sonames,
+        ..Default::default()
+    });
+    let mut linked = HashMap::new();
+    for kinds in passes.iter().filter(|_| !restored) {
+        let mut pass_opts = compile_opts.clone();
+        if split_features {
+            let ids: Vec<_> = members
+                .iter()
+                .filter(|cpkg| cpkg.capi_config.library.plugin.is_none())
+                .map(|cpkg| cpkg.finger_print.id)
+                .collect();
+            for m in ws.members_mut().filter(|m| ids.contains(&m.package_id())) {
+                set_lib_kinds(m, kinds);
+            }
+
+            let features = std::rc::Rc::make_mut(&mut pass_opts.cli_features.features);
+            for cpkg in members.iter() {
+                let library = &cpkg.capi_config.library;
+                let extra = match kinds[0] {
+                    "staticlib" => &library.staticlib_features,
+                    _ => &library.cdylib_features,
+                };
+                for feature in extra {
+                    features.insert(FeatureValue::new(feature.as_str().into()));
+                }
+            }
+        }
+
+        out_dirs.extend(compile_with_exec(
+            ws,
+            &pass_opts,
+            &exec,
+            &rustc_target,
+            &root_output,
+            args,
+            &libkinds,
+            &mut linked,
+        )?);
+    }
+
+    if split_features {
+        let ids: Vec<_> = members
+            .iter()
+            .filter(|cpkg| cpkg.capi_config.library.plugin.is_none())
+            .map(|cpkg| cpkg.finger_print.id)
+            .collect();
+        for m in ws.members_mut().filter(|m| ids.contains(&m.package_id())) {
+            set_lib_kinds(m, &libkinds);
+        }
+    }
+
+    if args.get_flag("prefer_dynamic_std") && !only_staticlib {
+        let rust_std = rust_std_dylib(&rustc.path, &target)?;
+        for cpkg in members.iter_mut() {
+            cpkg.build_targets.rust_std = Some(rust_std.clone());
+        }
+    }
 
     for cpkg in members.iter_mut() {
         let out_dir = out_dirs.get(&cpkg.finger_print.id).map(|p| p.as_path());
@@ -1082,6 +5919,23 @@ pub fn cbuild(
             let to = Path::new(&cpkg.capi_config.header.subdirectory).join(&header_name);
             cpkg.build_targets.extra.include.push((from, to));
         }
+
+        if let Some(header_name) = cpkg.capi_config.runtime_dirs.header_name() {
+            let from = root_output.join(&header_name);
+            let to = Path::new(&cpkg.capi_config.header.subdirectory).join(&header_name);
+            cpkg.build_targets.extra.include.push((from, to));
+        }
+
+        if cpkg.capi_config.swift.enabled {
+            let package_dir = Path::new("swift").join(&cpkg.capi_config.pkg_config.filename);
+            for file in cpkg.capi_config.swift.files() {
+                let from = root_output.join("swift").join(&file);
+                cpkg.build_targets
+                    .extra
+                    .dataroot
+                    .push((from, package_dir.join(file)));
+            }
+        }
     }
 
     if pristine {
@@ -1092,39 +5946,140 @@ pub fn cbuild(
     let new_build = exec.ran.load(Ordering::Relaxed);
 
     for cpkg in members.iter_mut() {
-        // it is a new build, build the additional files and update update the cache
-        // if the hash value does not match.
-        if new_build && !cpkg.finger_print.is_valid() {
+        // build the additional files and update the cache if the hash value does not match,
+        // the configuration or the install paths may have changed without rebuilding the library.
+        if !cpkg.finger_print.is_valid() {
             let name = &cpkg.capi_config.library.name;
-            let static_libs = if only_cdylib {
+            let static_libs = if !new_build {
+                ws.config().shell().status(
+                    "Regenerating",
+                    format!("{name} files, they do not match the current configuration"),
+                )?;
+                match cpkg.finger_print.load_previous() {
+                    Ok(cache) => cache.static_libs,
+                    Err(e) => {
+                        // e.g. written by an older cargo-c, the library was not rebuilt
+                        warn(
+                            ws.config(),
+                            format!(
+                                "the cached native libraries of {name} cannot be read ({e}), \
+                                 Libs.private lists none, run `cargo clean` to rebuild them"
+                            ),
+                        )?;
+                        String::new()
+                    }
+                }
+            } else if only_cdylib {
                 "".to_string()
             } else {
-                exec.link_line
+                let static_libs = exec
+                    .link_line
                     .lock()
                     .unwrap()
                     .values()
                     .next()
                     .unwrap()
-                    .to_string()
+                    .to_string();
+                if rustc_target.env == "musl" {
+                    musl_static_libs(ws, &static_libs)?
+                } else {
+                    static_libs
+                }
             };
             let capi_config = &cpkg.capi_config;
             let build_targets = &cpkg.build_targets;
 
+            if let Some(packages) = linked.get(&cpkg.finger_print.id) {
+                build_license_report(ws, capi_config, packages, &root_output)?;
+                if let Some(rustlib) = build_targets.rustlib.as_ref() {
+                    build_rustlib(
+                        ws,
+                        cpkg,
+                        packages,
+                        &rustc.verbose_version,
+                        &root_output,
+                        rustlib,
+                    )?;
+                }
+            }
+
             let mut pc = PkgConfig::from_workspace(name, &cpkg.install_paths, args, capi_config);
+            if capi_config.pkg_config.requires_sys_versions {
+                if let Some(packages) = linked.get(&cpkg.finger_print.id) {
+                    pc.constrain_requires(&sys_deps_versions(packages)?);
+                }
+            }
+            if capi_config.pkg_config.check_requires {
+                check_requires(ws, &rustc_target, &pc, &pkg_config_env)?;
+            }
+            // The objects of the wasi-sdk consumers share the memory of the threads targets
+            // only when compiled and linked with `-pthread`, wasm-ld rejects the library
+            // built with the atomics otherwise
+            if rustc_target.os == "wasi"
+                && rustc_target.matches("cfg(target_feature = \"atomics\")")?
+            {
+                pc.add_cflag("-pthread").add_lib("-pthread");
+            }
+            if let Some(rust_std) = &build_targets.rust_std {
+                let file_name = rust_std.file_name().unwrap().to_string_lossy();
+                pc.add_variable("rust_std", &file_name);
+                // Installed in libdir, the linkers resolving the dependencies of the shared
+                // libraries look it up there. The import table is enough on Windows
+                if rustc_target.os != "windows" {
+                    let name = file_name.trim_start_matches("lib");
+                    pc.add_lib(format!("-l{}", name.split('.').next().unwrap()));
+                }
+            }
+            if build_targets.rustlib.is_some() {
+                pc.add_variable(
+                    "rustlib",
+                    format!("${{libdir}}/rustlib/{}/lib", rustc_target.triple),
+                );
+            }
+            // Without a shared library every consumer links the dependencies, not only the
+            // ones calling `pkg-config --static`
+            let libs_private = capi_config.pkg_config.libs_private.as_ref();
             if only_staticlib {
                 pc.add_lib(&static_libs);
+                if let Some(libs) = libs_private {
+                    pc.add_lib(libs);
+                }
+            }
+            if capi_config.library.module && only_cdylib {
+                pc.set_libs("");
             }
             pc.add_lib_private(&static_libs);
+            // The libraries the native-static-libs of rustc miss, e.g. linked by build scripts
+            if let Some(libs) = libs_private {
+                pc.add_lib_private(libs);
+            }
 
             build_pc_files(ws, &capi_config.pkg_config.filename, &root_output, &pc)?;
+            for module in capi_config.pkg_config.modules.iter() {
+                let module_pc = pc.module(module, &capi_config.pkg_config.filename);
+                build_pc_files(ws, &module.filename, &root_output, &module_pc)?;
+            }
+            build_compile_flags_files(ws, &capi_config.pkg_config.filename, &root_output, &pc)?;
+            build_env_file(ws, capi_config, build_targets, &root_output, &static_libs)?;
 
-            if !only_staticlib {
+            // The modules are not linked with, they need no import library
+            if !only_staticlib && !capi_config.library.module {
                 let lib_name = name;
                 build_def_file(ws, lib_name, &rustc_target, &root_output)?;
 
+                if let Some(def) = build_targets.def.as_ref() {
+                    apply_def_symbols(ws, &capi_config.def, &rustc_target, def)?;
+                }
+
+                // The gnullvm targets come with the LLVM tools instead of binutils
+                let default_dlltool = if rustc_target.abi == "llvm" {
+                    "llvm-dlltool"
+                } else {
+                    "dlltool"
+                };
                 let mut dlltool = std::env::var_os("DLLTOOL")
                     .map(PathBuf::from)
-                    .unwrap_or_else(|| PathBuf::from("dlltool"));
+                    .unwrap_or_else(|| PathBuf::from(default_dlltool));
 
                 // dlltool argument overwrites environment var
                 if args.contains_id("dlltool") {
@@ -1134,7 +6089,25 @@ pub fn cbuild(
                         .unwrap();
                 }
 
-                build_implib_file(ws, lib_name, &rustc_target, &root_output, &dlltool)?;
+                let dll_name = build_targets
+                    .shared_lib
+                    .as_ref()
+                    .and_then(|lib| lib.file_name())
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+
+                if let Some(implib) = build_targets.impl_lib.as_ref() {
+                    build_implib_file(
+                        ws,
+                        lib_name,
+                        &dll_name,
+                        &capi_config.def,
+                        &rustc_target,
+                        &root_output,
+                        implib,
+                        &dlltool,
+                    )?;
+                }
             }
 
             if capi_config.header.enabled {
@@ -1149,12 +6122,31 @@ pub fn cbuild(
                     )?;
                 }
 
+                build_runtime_dirs_header(ws, capi_config, &cpkg.install_paths, &root_output)?;
+
                 copy_prebuilt_include_file(ws, build_targets, &root_output)?;
             }
 
-            if name.contains('-') {
+            build_swift_package(ws, capi_config, &cpkg.install_paths, &root_output)?;
+
+            if capi_config.python.enabled {
+                let dll = build_targets
+                    .shared_lib
+                    .as_ref()
+                    .filter(|_| rustc_target.os == "windows")
+                    .map(|lib| lib.file_name().unwrap().to_string_lossy());
+                build_python_module(
+                    ws,
+                    capi_config,
+                    &root_output,
+                    &cpkg.root_path,
+                    dll.as_deref(),
+                )?;
+            }
+
+            if name.contains(['-', '.']) {
                 let from_build_targets = BuildTargets::new(
-                    &name.replace('-', "_"),
+                    &artifact_name(name),
                     &rustc_target,
                     &root_output,
                     &libkinds,
@@ -1167,20 +6159,103 @@ pub fn cbuild(
                 ) {
                     copy(from_static_lib, to_static_lib)?;
                 }
-                if let (Some(from_shared_lib), Some(to_shared_lib)) = (
-                    from_build_targets.shared_lib.as_ref(),
-                    build_targets.shared_lib.as_ref(),
-                ) {
-                    copy(from_shared_lib, to_shared_lib)?;
+            }
+
+            if let Some(static_lib) = build_targets
+                .static_lib
+                .as_ref()
+                .filter(|_| capi_config.library.reproducible)
+            {
+                normalize_archive(static_lib)?;
+            }
+
+            // The shared library produced by rustc is named after the crate, with the prefix
+            // and the suffix of the target
+            if let Some(shared_lib) = build_targets.shared_lib.as_ref() {
+                let built = rustc_shared_lib(&artifact_name(name), &rustc_target, &root_output);
+                if rustc_target.os == "aix" {
+                    build_aix_archive(ws, &capi_config.library, &built, shared_lib)?;
+                } else if built != *shared_lib {
+                    copy(built, shared_lib)?;
+                }
+            }
+
+            if let Some(shared_lib) = build_targets.shared_lib.as_ref() {
+                if capi_config.js.enabled && rustc_target.arch.starts_with("wasm") {
+                    ws.config()
+                        .shell()
+                        .status("Building", "JavaScript loader and declarations")?;
+                    crate::wasm_gen::build_js_glue(shared_lib)?;
+                }
+            }
+
+            if let (Some(shared_lib), Some(version)) = (
+                build_targets.shared_lib.as_ref(),
+                capi_config.library.macos_deployment_target.as_ref(),
+            ) {
+                if rustc_target.os == "macos" {
+                    // A MACOSX_DEPLOYMENT_TARGET already set is the one the build used
+                    let version = std::env::var("MACOSX_DEPLOYMENT_TARGET")
+                        .unwrap_or_else(|_| version.clone());
+                    check_macos_deployment_target(ws, shared_lib, &version)?;
+                }
+            }
+
+            if let (Some(shared_lib), Some(max_version)) = (
+                build_targets.shared_lib.as_ref(),
+                capi_config.library.max_glibc_version.as_ref(),
+            ) {
+                if rustc_target.os == "linux" && rustc_target.env == "gnu" {
+                    check_glibc_version(ws, shared_lib, max_version)?;
+                }
+            }
+
+            if let (Some(shared_lib), Some(Allocator::System)) = (
+                build_targets.shared_lib.as_ref(),
+                capi_config.library.allocator.as_ref(),
+            ) {
+                // The wasm modules are not native objects, the host allocator is not shared
+                if !rustc_target.arch.starts_with("wasm") {
+                    check_system_allocator(shared_lib)?;
+                }
+            }
+
+            if let Some(shared_lib) = build_targets.shared_lib.as_ref() {
+                if capi_config.library.framework {
+                    ws.config()
+                        .shell()
+                        .status("Building", format!("{name}.framework"))?;
+                    crate::framework::assemble(
+                        capi_config,
+                        build_targets,
+                        shared_lib,
+                        &root_output,
+                    )?;
                 }
             }
 
+            if args.get_flag("min_size") {
+                report_size(ws, build_targets, &previous_sizes)?;
+            }
+
             cpkg.finger_print.static_libs = static_libs;
             cpkg.finger_print.store()?;
         } else {
             // It is not a new build, recover the static_libs value from the cache
             cpkg.finger_print.static_libs = cpkg.finger_print.load_previous()?.static_libs;
         }
+
+        if let Some(format) = build_script {
+            crate::superbuild::emit_build_script(ws, format, cpkg, &root_output)?;
+        }
+    }
+
+    if let Some(cache) = artifact_cache.as_ref().filter(|_| !restored) {
+        let env_names = build_script_env_names(&root_output);
+        for cpkg in members.iter() {
+            let out_dir = out_dirs.get(&cpkg.finger_print.id).map(|p| p.as_path());
+            cache.store(&cache.key(cpkg), &cached_files(cpkg), out_dir, &env_names)?;
+        }
     }
 
     Ok((members, compile_opts))
@@ -1193,6 +6268,9 @@ pub fn ctest(
     packages: &[CPackage],
     mut compile_opts: CompileOptions,
 ) -> CliResult {
+    let rustc = config.load_global_rustc(Some(ws))?;
+    crate::layout::check_layouts(ws, packages, &rustc.host, args.get_flag("record-layouts"))?;
+
     compile_opts.build_config.requested_profile =
         args.get_profile_name(config, "test", ProfileChecking::Custom)?;
     compile_opts.build_config.mode = CompileMode::Test;
@@ -1239,5 +6317,760 @@ pub fn ctest(
 
     std::env::set_var("INLINE_C_RS_CFLAGS", cflags);
 
+    for pkg in packages {
+        for (name, value) in artifact_env(&pkg.capi_config, &pkg.build_targets, pkg.static_libs()) {
+            std::env::set_var(name, value);
+        }
+    }
+
     ops::run_tests(ws, &ops, &test_args)
 }
+
+/// The configuration of a `foo` library with the default options, for the tests
+#[cfg(test)]
+pub(crate) fn test_capi_config() -> CApiConfig {
+    CApiConfig {
+        header: HeaderCApiConfig {
+            name: "foo".into(),
+            subdirectory: "".into(),
+            generation: true,
+            enabled: true,
+            layout: None,
+        },
+        pkg_config: PkgConfigCApiConfig {
+            name: "foo".into(),
+            filename: "foo".into(),
+            description: "".into(),
+            version: "0.1".into(),
+            requires: Some("somelib, someotherlib".into()),
+            requires_private: Some("someprivatelib >= 1.0".into()),
+            strip_include_path_components: 0,
+            include_cflags: true,
+            requires_sys_versions: false,
+            check_requires: false,
+            install: true,
+            modules: Vec::new(),
+            libs_private: None,
+            variables: Vec::new(),
+        },
+        library: LibraryCApiConfig {
+            name: "foo".into(),
+            version: Version::parse("0.1.0").unwrap(),
+            install_subdir: None,
+            versioning: true,
+            rustflags: Vec::default(),
+            max_glibc_version: None,
+            macos_deployment_target: None,
+            dll_name: None,
+            dll_prefix: None,
+            dll_suffix: None,
+            dll_version: false,
+            codesign_identity: None,
+            allocator: None,
+            init: None,
+            fini: None,
+            staticlib_features: Vec::new(),
+            cdylib_features: Vec::new(),
+            linker_script: false,
+            symbol_version: None,
+            framework: false,
+            bundle_identifier: None,
+            types: None,
+            prefix: None,
+            suffix: None,
+            module: false,
+            plugin: None,
+            version_resource: true,
+            manifest: None,
+            stamp: false,
+            reproducible: false,
+        },
+        install: Default::default(),
+        runtime_dirs: Default::default(),
+        swift: Default::default(),
+        python: Default::default(),
+        js: Default::default(),
+        licenses: Default::default(),
+        pack: Default::default(),
+        check_cfg: Vec::new(),
+        env: Vec::new(),
+        def: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[cfg(unix)]
+    #[test]
+    fn exports_linker_script() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("cargo-c-exports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let executable = |name: &str, script: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        // Print the arguments, then the content of the response files
+        let linker = executable(
+            "linker.sh",
+            "#!/bin/sh\nfor a; do echo \"$a\"; done\nfor a; do case $a in @*) cat \"${a#@}\";; esac; done\n",
+        );
+        let wrapper = executable(
+            "wrapper.sh",
+            &super::exports_linker_script(Path::new("/out/foo bar.def"), &linker),
+        );
+        let rsp = dir.join("linker-arguments");
+        std::fs::write(
+            &rsp,
+            "-Wl,/tmp/rustc1/list.def\n/tmp/rustc1/list.def\nfoo.o\n",
+        )
+        .unwrap();
+
+        let out = std::process::Command::new(&wrapper)
+            .args([
+                "-o",
+                "foo.dll",
+                "/tmp/rustc0/list.def",
+                "-Wl,/tmp/rustc0/list.def",
+            ])
+            .args(["-Wl,--gc-sections", &format!("@{}", rsp.display())])
+            .output()
+            .unwrap();
+        let out = String::from_utf8(out.stdout).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "-o",
+                "foo.dll",
+                "/out/foo bar.def",
+                "-Wl,/out/foo bar.def",
+                "-Wl,--gc-sections",
+                &format!("@{}.cargo-c", rsp.display()),
+                "-Wl,/out/foo\\ bar.def",
+                "/out/foo\\ bar.def",
+                "foo.o",
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbol_version_linker_script() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("cargo-c-symver-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let executable = |name: &str, script: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        // Print the name it is called with and the arguments
+        let script = "#!/bin/sh\necho \"${0##*/}\"\nfor a; do echo \"$a\"; done\n";
+        let linker = executable("linker.sh", script);
+        let rustflags_linker = executable("rustflags-linker.sh", script);
+        let map = dir.join("foo.map");
+        let wrapper = executable(
+            "wrapper.sh",
+            &super::symbol_version_linker_script("FOO_1", &map, &linker),
+        );
+        let version_script = dir.join("exports.map");
+        std::fs::write(
+            &version_script,
+            "{\n  global:\n    foo;\n  local:\n    *;\n};\n",
+        )
+        .unwrap();
+
+        let link = |linker_env: Option<&Path>| {
+            let mut cmd = std::process::Command::new(&wrapper);
+            cmd.args([
+                "-o",
+                "libfoo.so",
+                &format!("-Wl,--version-script={}", version_script.display()),
+            ]);
+            cmd.env_remove(super::LINKER_ENV);
+            if let Some(linker) = linker_env {
+                cmd.env(super::LINKER_ENV, linker);
+            }
+            String::from_utf8(cmd.output().unwrap().stdout).unwrap()
+        };
+
+        let version_script_arg = format!("-Wl,--version-script={}", map.display());
+        assert_eq!(
+            link(None).lines().collect::<Vec<_>>(),
+            ["linker.sh", "-o", "libfoo.so", &version_script_arg]
+        );
+        // The linker of the rustflags is called in place of the default one
+        assert_eq!(
+            link(Some(&rustflags_linker)).lines().collect::<Vec<_>>(),
+            [
+                "rustflags-linker.sh",
+                "-o",
+                "libfoo.so",
+                &version_script_arg
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(&map).unwrap(),
+            "FOO_1 {\n  global:\n    foo;\n  local:\n    *;\n};\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn last_linker() {
+        assert_eq!(super::last_linker(&strings(&["-C", "opt-level=3"])), None);
+        assert_eq!(
+            super::last_linker(&strings(&[
+                "-C",
+                "linker=/out/foo-linker.sh",
+                "-Clinker=clang",
+                "-C",
+                "link-arg=-fuse-ld=lld",
+            ])),
+            Some("clang")
+        );
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn linked_sonames() {
+        assert_eq!(
+            super::linked_sonames(&strings(&["-C", "link-arg=-Wl,-soname,libfoo.so.1"])),
+            ["libfoo.so.1"]
+        );
+        assert_eq!(
+            super::linked_sonames(&strings(&["-Clink-arg=-Wl,-h,libfoo.so.2"])),
+            ["libfoo.so.2"]
+        );
+        assert_eq!(
+            super::linked_sonames(&strings(&[
+                "-C",
+                "link-args=-Wl,--as-needed -Wl,--soname=libfoo.so.3",
+                "-C",
+                "link-arg=-soname",
+                "-C",
+                "link-arg=libfoo.so.4",
+            ])),
+            ["libfoo.so.3", "libfoo.so.4"]
+        );
+        assert!(super::linked_sonames(&strings(&[
+            "--cfg",
+            "soname",
+            "-C",
+            "opt-level=3",
+            "-C",
+            "link-arg=-Wl,--gc-sections",
+        ]))
+        .is_empty());
+    }
+
+    #[test]
+    fn glibc_version_newer() {
+        assert!(!super::glibc_version_newer("2.17", "2.17.0"));
+        assert!(!super::glibc_version_newer("2.17.0", "2.17"));
+        assert!(!super::glibc_version_newer("2.2.5", "2.17"));
+        assert!(super::glibc_version_newer("2.18", "2.17.0"));
+        assert!(super::glibc_version_newer("2.17.1", "2.17"));
+        assert!(super::glibc_version_newer("3", "2.34"));
+    }
+
+    #[test]
+    fn license_denied() {
+        let deny = [
+            glob::Pattern::new("GPL-*").unwrap(),
+            glob::Pattern::new("AGPL-3.0").unwrap(),
+        ];
+        let denied = |expression| super::license_denied(expression, &deny);
+
+        assert!(!denied("MIT"));
+        assert!(denied("GPL-3.0-only"));
+        assert!(!denied("MIT OR GPL-3.0-only"));
+        assert!(!denied("MIT/GPL-2.0"));
+        assert!(denied("MIT AND GPL-2.0-or-later"));
+        assert!(denied("AGPL-3.0 OR GPL-2.0-only"));
+        assert!(!denied("(MIT AND Apache-2.0) OR GPL-3.0-only"));
+        assert!(denied("MIT AND (GPL-2.0-only OR AGPL-3.0)"));
+        assert!(!denied("Apache-2.0 WITH LLVM-exception"));
+        assert!(denied("GPL-2.0-only WITH Classpath-exception-2.0"));
+        assert!(!denied("GPL-2.0-only WITH Classpath-exception-2.0 OR MIT"));
+    }
+
+    #[test]
+    fn build_script_check_cfg_args() {
+        let args = strings(&[
+            "--crate-name",
+            "foo",
+            "--cfg",
+            "cargo_c",
+            "--cfg",
+            "feature=\"capi\"",
+            "--cfg",
+            "has_foo",
+            "--cfg",
+            "foo_version=\"2\"",
+            "--cfg",
+            "has_foo",
+        ]);
+        let output = "cargo:rustc-cfg=has_foo\n\
+                      cargo:rustc-check-cfg=cfg(has_bar)\n\
+                      cargo::rustc-check-cfg=cfg(foo_version, values(\"1\", \"2\"))\n";
+        assert_eq!(
+            super::build_script_check_cfg_args(&args, output),
+            [
+                "--check-cfg",
+                "cfg(has_foo)",
+                "--check-cfg",
+                "cfg(foo_version, values(\"2\"))",
+                "--check-cfg",
+                "cfg(has_bar)",
+                "--check-cfg",
+                "cfg(foo_version, values(\"1\", \"2\"))",
+            ]
+        );
+        assert!(super::build_script_check_cfg_args(&strings(&["--cfg", "cargo_c"]), "").is_empty());
+    }
+
+    #[test]
+    fn target_dir_of() {
+        assert_eq!(
+            super::target_dir_of(Path::new("/foo/target/x86_64-unknown-linux-gnu/debug")).unwrap(),
+            Path::new("/foo/target")
+        );
+        assert!(super::target_dir_of(Path::new("debug")).is_err());
+    }
+
+    #[test]
+    fn install_target_subdir() {
+        let capi_config = super::test_capi_config();
+        let target = crate::target::Target::new("thumbv7em-none-eabihf").unwrap();
+        let paths = |args: &[&str]| {
+            let args = crate::cli::subcommand_install("cinstall", "")
+                .get_matches_from([&["cinstall", "--prefix", "/opt/fw"], args].concat());
+            super::InstallPaths::new("foo", &args, &capi_config, &target)
+        };
+
+        let default = paths(&[]);
+        assert_eq!(
+            default.libdir,
+            Path::new("/opt/fw/lib/thumbv7em-none-eabihf")
+        );
+        assert_eq!(
+            default.bindir,
+            Path::new("/opt/fw/bin/thumbv7em-none-eabihf")
+        );
+        assert_eq!(
+            default.pkgconfigdir,
+            Path::new("/opt/fw/lib/thumbv7em-none-eabihf/pkgconfig")
+        );
+        assert_eq!(default.includedir, Path::new("/opt/fw/include"));
+
+        let explicit = paths(&[
+            "--libdir",
+            "/opt/fw/lib/cortex-m4",
+            "--bindir",
+            "/opt/fw/bin",
+        ]);
+        assert_eq!(explicit.libdir, Path::new("/opt/fw/lib/cortex-m4"));
+        assert_eq!(explicit.bindir, Path::new("/opt/fw/bin"));
+        assert_eq!(
+            explicit.pkgconfigdir,
+            Path::new("/opt/fw/lib/cortex-m4/pkgconfig")
+        );
+    }
+
+    #[test]
+    fn version_macros() {
+        let version = semver::Version::new(1, 2, 3);
+        assert_eq!(
+            super::version_macros("foo-bar", &version),
+            "\n#define FOO_BAR_MAJOR 1\n#define FOO_BAR_MINOR 2\n#define FOO_BAR_PATCH 3\n"
+        );
+        assert_eq!(
+            super::version_macros("foo_bar", &version),
+            super::version_macros("foo-bar", &version)
+        );
+    }
+
+    #[test]
+    fn check_name_collisions() {
+        let config = |name: &str, pc: &str, header: &str| {
+            let mut capi_config = super::test_capi_config();
+            capi_config.library.name = name.into();
+            capi_config.pkg_config.filename = pc.into();
+            capi_config.header.name = header.into();
+            capi_config
+        };
+
+        let members = [config("foo", "foo", "foo"), config("bar", "bar", "bar")];
+        assert!(super::check_name_collisions(&members).is_ok());
+
+        // rustc names both libfoo_bar
+        let members = [
+            config("foo-bar", "foo-bar", "foo-bar"),
+            config("foo_bar", "foo_bar", "foo_bar"),
+        ];
+        let err = super::check_name_collisions(&members).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "foo-bar and foo_bar both produce the library foo_bar"
+        );
+
+        let members = [config("foo", "foo", "foo"), config("bar", "foo", "bar")];
+        let err = super::check_name_collisions(&members).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "foo and bar both produce the pkg-config file foo.pc"
+        );
+
+        let members = [
+            config("foo", "foo", "common"),
+            config("bar", "bar", "common"),
+        ];
+        let err = super::check_name_collisions(&members).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "foo and bar both produce the header common"
+        );
+
+        // A disabled header is not installed
+        let mut headerless = config("bar", "bar", "common");
+        headerless.header.enabled = false;
+        let members = [config("foo", "foo", "common"), headerless];
+        assert!(super::check_name_collisions(&members).is_ok());
+    }
+
+    #[test]
+    fn merge() {
+        let mut base: toml::Value = toml::from_str(
+            "[library]\nname = \"foo\"\nversioning = true\n[header]\ngeneration = true\n",
+        )
+        .unwrap();
+        let overrides: toml::Value =
+            toml::from_str("[library]\nversioning = false\n[install]\ndocs = [\"README.md\"]\n")
+                .unwrap();
+        super::merge(&mut base, &overrides);
+
+        let expected: toml::Value = toml::from_str(
+            "[library]\nname = \"foo\"\nversioning = false\n[header]\ngeneration = true\n\
+             [install]\ndocs = [\"README.md\"]\n",
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn apply_preset() {
+        let capi: toml::Value = toml::from_str(
+            "[library]\nname = \"foo\"\n\
+             [preset.distro]\nargs = [\"--release\"]\nlibrary = { versioning = false }\n",
+        )
+        .unwrap();
+
+        let merged = super::apply_preset(&capi, "distro").unwrap();
+        assert_eq!(merged["library"]["name"].as_str(), Some("foo"));
+        assert_eq!(merged["library"]["versioning"].as_bool(), Some(false));
+        // The args are command line arguments, not metadata
+        assert!(merged.get("args").is_none());
+
+        assert!(super::apply_preset(&capi, "static").is_none());
+    }
+
+    fn windows_target(arch: &str, env: &str) -> crate::target::Target {
+        crate::target::Target {
+            triple: format!("{arch}-pc-windows-{env}"),
+            arch: arch.into(),
+            vendor: "pc".into(),
+            os: "windows".into(),
+            env: env.into(),
+            abi: "".into(),
+            cfg: Vec::new(),
+        }
+    }
+
+    const DEF: &str =
+        "LIBRARY foo.dll\nEXPORTS\n\tfoo_init\n\tfoo_run\n\tfoo_version DATA\n\tfoo_internal\n";
+
+    #[test]
+    fn def_exports() {
+        let def_config = super::DefCApiConfig {
+            symbols: vec![super::DefSymbol {
+                name: "foo_run".into(),
+                aliases: vec!["foo_internal".into()],
+                ..Default::default()
+            }],
+            exports: Some(strings(&["foo_init", "foo_run", "foo_missing"])),
+            ..Default::default()
+        };
+        let (def, warnings) =
+            super::def_with_symbols(DEF, &def_config, &windows_target("x86_64", "msvc"));
+        assert_eq!(
+            def,
+            "LIBRARY foo.dll\nEXPORTS\n\tfoo_init\n\tfoo_run\n\tfoo_internal\n"
+        );
+        assert_eq!(
+            warnings,
+            ["def.exports lists foo_missing, which the library does not export"]
+        );
+    }
+
+    #[test]
+    fn def_symbols() {
+        let def_config = super::DefCApiConfig {
+            symbols: vec![
+                super::DefSymbol {
+                    name: "foo_init".into(),
+                    ordinal: Some(1),
+                    noname: true,
+                    ..Default::default()
+                },
+                super::DefSymbol {
+                    name: "foo_version".into(),
+                    ordinal: Some(2),
+                    private: true,
+                    ..Default::default()
+                },
+                super::DefSymbol {
+                    name: "foo_gone".into(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let (def, warnings) =
+            super::def_with_symbols(DEF, &def_config, &windows_target("x86_64", "gnu"));
+        assert_eq!(
+            def,
+            "LIBRARY foo.dll\nEXPORTS\n\tfoo_init @1 NONAME\n\tfoo_run\n\tfoo_version @2 DATA PRIVATE\n\tfoo_internal\n"
+        );
+        assert_eq!(
+            warnings,
+            ["def.symbols.foo_gone is not exported by the library"]
+        );
+    }
+
+    #[test]
+    fn def_stdcall() {
+        let mut def_config = super::DefCApiConfig {
+            symbols: vec![super::DefSymbol {
+                name: "foo_run".into(),
+                stdcall: Some(8),
+                ..Default::default()
+            }],
+            exports: Some(strings(&["foo_run"])),
+            stdcall_alias: true,
+            ..Default::default()
+        };
+        let (def, warnings) =
+            super::def_with_symbols(DEF, &def_config, &windows_target("x86", "msvc"));
+        assert_eq!(def, "LIBRARY foo.dll\nEXPORTS\n\tfoo_run\n\tfoo_run@8\n");
+        assert!(warnings.is_empty());
+
+        // Already decorated by the linker
+        let (def, _) = super::def_with_symbols(
+            "EXPORTS\n\tfoo_run@8\n",
+            &def_config,
+            &windows_target("x86", "msvc"),
+        );
+        assert_eq!(def, "EXPORTS\n\tfoo_run@8\n");
+
+        // Only the import library of 32-bit x86 maps the decorated name
+        let (def, _) = super::def_with_symbols(DEF, &def_config, &windows_target("x86_64", "msvc"));
+        assert_eq!(def, "LIBRARY foo.dll\nEXPORTS\n\tfoo_run\n");
+
+        def_config.stdcall_alias = false;
+        let (def, _) = super::def_with_symbols(DEF, &def_config, &windows_target("x86", "gnu"));
+        assert_eq!(def, "LIBRARY foo.dll\nEXPORTS\n\tfoo_run@8\n");
+    }
+
+    #[test]
+    fn version_resource_source() {
+        use semver::Version;
+
+        let mut capi_config = super::test_capi_config();
+        capi_config.library.version = Version::parse("1.2.70000-rc.1").unwrap();
+        let metadata = cargo::core::manifest::ManifestMetadata {
+            authors: strings(&["Jane Doe <jane@example.com>"]),
+            keywords: Vec::new(),
+            categories: Vec::new(),
+            license: None,
+            license_file: None,
+            description: Some("The \"foo\" library\nwith more details".into()),
+            readme: None,
+            homepage: None,
+            repository: None,
+            documentation: None,
+            badges: Default::default(),
+            links: None,
+        };
+        let rc = super::version_resource_source(
+            &capi_config,
+            Some(&metadata),
+            &Version::parse("0.1.0").unwrap(),
+            "foo-1.dll",
+            Some("foo 0.1.0 (cargo-c 0.9.0)"),
+        );
+        assert!(rc.starts_with("1 VERSIONINFO\nFILEVERSION 1,2,65535,0\nPRODUCTVERSION 0,1,0,0\n"));
+        assert!(rc.contains("FILEFLAGS 0x2L\n"));
+        assert!(rc.contains(
+            "            VALUE \"Comments\", \"foo 0.1.0 (cargo-c 0.9.0)\"
+            VALUE \"CompanyName\", \"Jane Doe\"
+            VALUE \"FileDescription\", \"The \"\"foo\"\" library\"
+            VALUE \"FileVersion\", \"1.2.70000-rc.1\"
+            VALUE \"InternalName\", \"foo\"
+            VALUE \"OriginalFilename\", \"foo-1.dll\"
+            VALUE \"ProductName\", \"foo\"
+            VALUE \"ProductVersion\", \"0.1.0\"
+        END"
+        ));
+
+        let capi_config = super::test_capi_config();
+        let rc = super::version_resource_source(
+            &capi_config,
+            None,
+            &capi_config.library.version,
+            "foo.dll",
+            None,
+        );
+        assert!(rc.contains("FILEFLAGS 0x0L\n"));
+        assert!(rc.contains(
+            "        BEGIN
+            VALUE \"FileDescription\", \"foo\"
+            VALUE \"FileVersion\", \"0.1.0\"
+"
+        ));
+    }
+
+    #[test]
+    fn linked_object() {
+        let dir = std::env::temp_dir().join(format!("cargo-c-linked-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (obj, src) = (dir.join("foo-init-fini.o"), dir.join("foo-init-fini.c"));
+        std::fs::write(&obj, "object").unwrap();
+
+        std::fs::write(&src, "int foo;").unwrap();
+        let first = super::linked_object(&obj, std::slice::from_ref(&src)).unwrap();
+        assert_eq!(first.parent(), Some(&*dir));
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("foo-init-fini-") && name.ends_with(".o"));
+        assert_eq!(std::fs::read(&first).unwrap(), b"object");
+        assert_eq!(
+            super::linked_object(&obj, std::slice::from_ref(&src)).unwrap(),
+            first
+        );
+
+        // Another source is linked under another name, relinking the library
+        std::fs::write(&src, "int bar;").unwrap();
+        assert_ne!(super::linked_object(&obj, &[src]).unwrap(), first);
+        assert!(!first.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stamp_source() {
+        let linux = crate::target::Target {
+            os: "linux".into(),
+            ..windows_target("x86_64", "gnu")
+        };
+        let src = super::stamp_source("foo 0.1.0 \"b\"", &linux);
+        assert!(src.contains("#ident \"foo 0.1.0 \\\"b\\\"\"\n"));
+        // The 13 bytes of the text and its terminator padded to 16
+        assert!(src.contains("    char desc[16];\n"));
+        assert!(src.contains("cargo_c_note = { 8, 14, 1, \"cargo-c\", \"foo 0.1.0 \\\"b\\\"\" };"));
+
+        let macos = crate::target::Target {
+            os: "macos".into(),
+            ..windows_target("aarch64", "")
+        };
+        let src = super::stamp_source("foo 0.1.0", &macos);
+        assert!(src.contains(
+            "__attribute__((section(\"__TEXT,__cargo_c,regular,no_dead_strip\"), used))
+static const char cargo_c_stamp[] = \"foo 0.1.0\";
+"
+        ));
+    }
+
+    #[test]
+    fn unrecorded_stamp() {
+        let mut capi_config = super::test_capi_config();
+        let windows = windows_target("x86_64", "msvc");
+        let wasm = crate::target::Target {
+            triple: "wasm32-unknown-unknown".into(),
+            os: "unknown".into(),
+            ..windows_target("wasm32", "")
+        };
+        assert_eq!(super::unrecorded_stamp(&capi_config, &wasm), None);
+
+        capi_config.library.stamp = true;
+        assert_eq!(super::unrecorded_stamp(&capi_config, &windows), None);
+        assert_eq!(
+            super::unrecorded_stamp(&capi_config, &wasm).as_deref(),
+            Some("there is no stamp for the wasm32-unknown-unknown targets")
+        );
+
+        capi_config.library.version_resource = false;
+        assert_eq!(
+            super::unrecorded_stamp(&capi_config, &windows).as_deref(),
+            Some(
+                "the DLLs carry it in their version resource, set library.version_resource = true"
+            )
+        );
+    }
+
+    #[test]
+    fn normalize_archive() {
+        fn header(name: &str, mtime: &str, uid: &str, mode: &str, size: usize) -> String {
+            format!("{name:<16}{mtime:<12}{uid:<6}{uid:<6}{mode:<8}{size:<10}`\n")
+        }
+
+        let mut archive = String::from("!<arch>\n");
+        archive.push_str(&header("//", "", "", "", 6));
+        archive.push_str("a.o/\n\n");
+        // The odd-sized member is followed by a padding byte
+        archive.push_str(&header("/0", "1700000000", "1000", "100664", 3));
+        archive.push_str("abc\n");
+        archive.push_str(&header("b.o/", "1700000001", "1000", "100600", 2));
+        archive.push_str("de");
+
+        let path = std::env::temp_dir().join(format!("cargo-c-archive-{}.a", std::process::id()));
+        std::fs::write(&path, &archive).unwrap();
+        super::normalize_archive(&path).unwrap();
+        let normalized = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut expected = String::from("!<arch>\n");
+        expected.push_str(&header("//", "", "", "", 6));
+        expected.push_str("a.o/\n\n");
+        expected.push_str(&header("/0", "0", "0", "644", 3));
+        expected.push_str("abc\n");
+        expected.push_str(&header("b.o/", "0", "0", "644", 2));
+        expected.push_str("de");
+        assert_eq!(normalized, expected);
+    }
+
+    #[test]
+    fn remap_path_args() {
+        assert_eq!(
+            super::remap_path_args(Path::new("/home/user/.cargo"), Path::new("/src/foo")),
+            [
+                "--remap-path-prefix",
+                "/home/user/.cargo=/cargo",
+                "--remap-path-prefix",
+                "/src/foo=.",
+            ]
+        );
+    }
+}
@@ -1,36 +1,99 @@
 use std::path::{Path, PathBuf};
 
-use crate::build::{CApiConfig, InstallTarget};
+use crate::build::{CApiConfig, InstallTarget, LibraryCApiConfig};
 use crate::target::Target;
 
 #[derive(Debug, Default, Clone)]
 pub struct ExtraTargets {
     pub include: Vec<(PathBuf, PathBuf)>,
     pub data: Vec<(PathBuf, PathBuf)>,
+    pub dataroot: Vec<(PathBuf, PathBuf)>,
+    pub systemd: Vec<(PathBuf, PathBuf)>,
+    pub udev: Vec<(PathBuf, PathBuf)>,
+    pub dbus: Vec<(PathBuf, PathBuf)>,
+    /// The manual pages, relative to `{mandir}`
+    pub man: Vec<(PathBuf, PathBuf)>,
+    pub doc: Vec<(PathBuf, PathBuf)>,
+    /// Source files that must go through the template substitution when installed
+    pub templates: Vec<PathBuf>,
 }
 
 impl ExtraTargets {
+    /// All the files to install that are not headers
+    pub fn non_include(&self) -> impl Iterator<Item = &(PathBuf, PathBuf)> {
+        self.data
+            .iter()
+            .chain(self.dataroot.iter())
+            .chain(self.systemd.iter())
+            .chain(self.udev.iter())
+            .chain(self.dbus.iter())
+            .chain(self.man.iter())
+            .chain(self.doc.iter())
+    }
+
     pub fn setup(
         &mut self,
         capi_config: &CApiConfig,
         root_dir: &Path,
         out_dir: Option<&Path>,
     ) -> anyhow::Result<()> {
-        self.include = extra_targets(&capi_config.install.include, root_dir, out_dir)?;
-        self.data = extra_targets(&capi_config.install.data, root_dir, out_dir)?;
+        self.include = extra_targets(capi_config.install.include.iter(), root_dir, out_dir)?;
+        self.data = extra_targets(capi_config.install.data.iter(), root_dir, out_dir)?;
+        self.dataroot = extra_targets(capi_config.install.dataroot.iter(), root_dir, out_dir)?;
+        self.systemd = extra_targets(capi_config.install.systemd.iter(), root_dir, out_dir)?;
+        self.udev = extra_targets(capi_config.install.udev.iter(), root_dir, out_dir)?;
+        self.dbus = extra_targets(capi_config.install.dbus.iter(), root_dir, out_dir)?;
+        self.man = extra_targets(capi_config.install.man.iter(), root_dir, out_dir)?
+            .into_iter()
+            .map(|(from, to)| Ok((from, man_page_path(&to)?)))
+            .collect::<anyhow::Result<_>>()?;
+        self.doc = extra_targets(capi_config.install.doc.iter(), root_dir, out_dir)?;
+        self.templates = extra_targets(
+            capi_config
+                .install
+                .non_include()
+                .filter(|t| t.paths().template),
+            root_dir,
+            out_dir,
+        )?
+        .into_iter()
+        .map(|(from, _)| from)
+        .collect();
 
         Ok(())
     }
 }
 
-fn extra_targets(
-    targets: &[InstallTarget],
+/// Put the manual page in the `man<section>` directory matching its extension, e.g.
+/// `foo.3` or `foo.3ssl` in `man3`
+fn man_page_path(to: &Path) -> anyhow::Result<PathBuf> {
+    let file_name = to.file_name().unwrap();
+    let section = Path::new(file_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| ext.chars().next())
+        .filter(|c| c.is_ascii_digit() || *c == 'n')
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Cannot tell the manual section of {}, its extension must start with it",
+                to.display()
+            )
+        })?;
+
+    Ok(to
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(format!("man{section}"))
+        .join(file_name))
+}
+
+fn extra_targets<'a>(
+    targets: impl Iterator<Item = &'a InstallTarget>,
     root_path: &Path,
     root_output: Option<&Path>,
 ) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
     use itertools::*;
     targets
-        .iter()
         .filter_map(|t| match t {
             InstallTarget::Asset(paths) => Some(paths.install_paths(root_path)),
             InstallTarget::Generated(paths) => {
@@ -49,10 +112,83 @@ pub struct BuildTargets {
     pub impl_lib: Option<PathBuf>,
     pub def: Option<PathBuf>,
     pub pc: PathBuf,
+    /// The pkg-config files of the modules, next to the main one
+    pub pc_modules: Vec<PathBuf>,
+    /// The shared Rust standard library the shared library links to
+    pub rust_std: Option<PathBuf>,
+    /// Separate debug information of the shared library, a `.pdb` or a `.dSYM` bundle
+    pub debug_info: Option<PathBuf>,
+    /// The `rustlib` directory holding the rlibs, with `install.rlib`
+    pub rustlib: Option<PathBuf>,
+    /// The companion binaries
+    pub bins: Vec<PathBuf>,
     pub target: Target,
     pub extra: ExtraTargets,
 }
 
+/// The `target_os`, `target_env` and `target_arch` combinations the libraries are built for,
+/// `*` matching any value, as handled by `BuildTargets::new`
+pub const SUPPORTED_TARGETS: &[(&str, &str, &str)] = &[
+    ("none", "*", "*"),
+    ("espidf", "*", "*"),
+    ("linux", "*", "*"),
+    ("freebsd", "*", "*"),
+    ("dragonfly", "*", "*"),
+    ("netbsd", "*", "*"),
+    ("android", "*", "*"),
+    ("haiku", "*", "*"),
+    ("illumos", "*", "*"),
+    ("aix", "*", "powerpc*"),
+    ("macos", "*", "*"),
+    ("ios", "*", "*"),
+    ("windows", "*", "*"),
+    ("emscripten", "*", "wasm*"),
+    ("wasi", "*", "wasm*"),
+    ("unknown", "*", "wasm*"),
+];
+
+/// Prefix and suffix rustc gives the shared library on the target
+fn default_shared_lib_affixes(target: &Target) -> (&'static str, &'static str) {
+    match target.os.as_str() {
+        "macos" | "ios" => ("lib", ".dylib"),
+        // The AIX shared objects are named as the archives that hold them
+        "aix" => ("lib", ".a"),
+        "windows" => ("", ".dll"),
+        _ if target.arch.starts_with("wasm") => ("", ".wasm"),
+        _ => ("lib", ".so"),
+    }
+}
+
+/// Prefix and suffix of the shared library file name, `library.prefix` and `library.suffix`
+/// replace the ones of the target, `library.dll_prefix` and `library.dll_suffix` the ones of
+/// the Windows DLLs
+pub fn shared_lib_affixes<'a>(
+    library: &'a LibraryCApiConfig,
+    target: &Target,
+) -> (&'a str, &'a str) {
+    let (mut prefix, suffix) = default_shared_lib_affixes(target);
+    // The MinGW DLLs are named `libfoo.dll`, as libtool and meson do, unless named explicitly
+    if target.os == "windows" && target.env == "gnu" && library.dll_name.is_none() {
+        prefix = "lib";
+    }
+    let prefix = library.prefix.as_deref().unwrap_or(prefix);
+    let suffix = library.suffix.as_deref().unwrap_or(suffix);
+    if target.os == "windows" {
+        (
+            library.dll_prefix.as_deref().unwrap_or(prefix),
+            library.dll_suffix.as_deref().unwrap_or(suffix),
+        )
+    } else {
+        (prefix, suffix)
+    }
+}
+
+/// The shared library as rustc writes it in `targetdir`, `artifact` being the crate name
+pub fn rustc_shared_lib(artifact: &str, target: &Target, targetdir: &Path) -> PathBuf {
+    let (prefix, suffix) = default_shared_lib_affixes(target);
+    targetdir.join(format!("{prefix}{artifact}{suffix}"))
+}
+
 impl BuildTargets {
     pub fn new(
         name: &str,
@@ -62,6 +198,12 @@ impl BuildTargets {
         capi_config: &CApiConfig,
     ) -> anyhow::Result<BuildTargets> {
         let pc = targetdir.join(format!("{}.pc", &capi_config.pkg_config.filename));
+        let pc_modules = capi_config
+            .pkg_config
+            .modules
+            .iter()
+            .map(|module| targetdir.join(format!("{}.pc", module.filename)))
+            .collect();
         let include = if capi_config.header.enabled {
             let mut header_name = PathBuf::from(&capi_config.header.name);
             header_name.set_extension("h");
@@ -75,22 +217,28 @@ impl BuildTargets {
         let os = &target.os;
         let env = &target.env;
 
+        let (prefix, suffix) = shared_lib_affixes(&capi_config.library, target);
+        let shared_lib = targetdir.join(format!("{prefix}{lib_name}{suffix}"));
+
         let (shared_lib, static_lib, impl_lib, def) = match (os.as_str(), env.as_str()) {
             ("none", _)
+            | ("espidf", _)
             | ("linux", _)
             | ("freebsd", _)
             | ("dragonfly", _)
             | ("netbsd", _)
             | ("android", _)
             | ("haiku", _)
-            | ("illumos", _) => {
+            | ("illumos", _)
+            | ("macos", _)
+            | ("ios", _) => {
                 let static_lib = targetdir.join(format!("lib{lib_name}.a"));
-                let shared_lib = targetdir.join(format!("lib{lib_name}.so"));
                 (shared_lib, static_lib, None, None)
             }
-            ("macos", _) | ("ios", _) => {
+            // The shared library is the `libfoo.a` archive holding the shared object, the
+            // static library cannot be built along with it
+            ("aix", _) if target.arch.starts_with("powerpc") => {
                 let static_lib = targetdir.join(format!("lib{lib_name}.a"));
-                let shared_lib = targetdir.join(format!("lib{lib_name}.dylib"));
                 (shared_lib, static_lib, None, None)
             }
             ("windows", env) => {
@@ -99,16 +247,29 @@ impl BuildTargets {
                 } else {
                     targetdir.join(format!("lib{lib_name}.a"))
                 };
-                let shared_lib = targetdir.join(format!("{lib_name}.dll"));
+                let library = &capi_config.library;
+                let mut dll_name = library.dll_name.as_deref().unwrap_or(lib_name).to_string();
+                // `libfoo-2.dll`, the Windows counterpart of the soname
+                if library.dll_version && library.versioning {
+                    dll_name = format!("{dll_name}-{}", library.version.major);
+                }
+                let shared_lib = targetdir.join(format!("{prefix}{dll_name}{suffix}"));
+                // llvm-mingw names the import libraries `libfoo.dll.a`, as rustc does
                 let impl_lib = if env == "msvc" {
                     targetdir.join(format!("{lib_name}.dll.lib"))
+                } else if target.abi == "llvm" {
+                    targetdir.join(format!("lib{lib_name}.dll.a"))
                 } else {
                     targetdir.join(format!("{lib_name}.dll.a"))
                 };
                 let def = targetdir.join(format!("{lib_name}.def"));
                 (shared_lib, static_lib, Some(impl_lib), Some(def))
             }
-            _ => unimplemented!("The target {}-{} is not supported yet", os, env),
+            ("emscripten", _) | ("wasi", _) | ("unknown", _) if target.arch.starts_with("wasm") => {
+                let static_lib = targetdir.join(format!("lib{lib_name}.a"));
+                (shared_lib, static_lib, None, None)
+            }
+            _ => unimplemented!("The target {} is not supported yet", target.triple),
         };
 
         let static_lib = if libkinds.contains(&"staticlib") {
@@ -117,22 +278,99 @@ impl BuildTargets {
             None
         };
 
-        // Bare metal does not support shared objects
-        let shared_lib = if libkinds.contains(&"cdylib") && os.as_str() != "none" {
-            Some(shared_lib)
+        let module = libkinds.contains(&"module");
+
+        // Bare metal and ESP-IDF do not support shared objects
+        let shared_lib =
+            if (libkinds.contains(&"cdylib") || module) && os != "none" && os != "espidf" {
+                Some(shared_lib)
+            } else {
+                None
+            };
+
+        // The modules are only loaded with dlopen, they have no import library
+        let (impl_lib, def) = if module {
+            (None, None)
         } else {
-            None
+            (impl_lib, def)
+        };
+
+        let exe_suffix = match os.as_str() {
+            "windows" => ".exe",
+            _ if target.arch.starts_with("wasm") => ".wasm",
+            _ => "",
         };
+        let bins = capi_config
+            .install
+            .bin
+            .iter()
+            .map(|bin| targetdir.join(format!("{bin}{exe_suffix}")))
+            .collect();
 
         Ok(BuildTargets {
             pc,
+            pc_modules,
             include,
             static_lib,
             shared_lib,
             impl_lib,
             def,
+            rust_std: None,
+            debug_info: None,
+            rustlib: capi_config.install.rlib.then(|| targetdir.join("rustlib")),
+            bins,
             target: target.clone(),
             extra: Default::default(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::target::Target;
+
+    fn matches(pattern: &str, value: &str) -> bool {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => pattern == value,
+        }
+    }
+
+    #[test]
+    fn supported_targets() {
+        let capi_config = crate::build::test_capi_config();
+        let mut oses: Vec<_> = super::SUPPORTED_TARGETS.iter().map(|t| t.0).collect();
+        oses.extend(["openbsd", "solaris", "cuda"]);
+        for os in oses {
+            for (arch, env) in [("x86_64", "gnu"), ("powerpc64", ""), ("wasm32", "p1")] {
+                let target = Target {
+                    triple: format!("{arch}-unknown-{os}"),
+                    arch: arch.into(),
+                    vendor: "unknown".into(),
+                    os: os.into(),
+                    env: env.into(),
+                    abi: "".into(),
+                    cfg: Vec::new(),
+                };
+                let supported = super::SUPPORTED_TARGETS
+                    .iter()
+                    .any(|(o, e, a)| matches(o, os) && matches(e, env) && matches(a, arch));
+                let built = matches!(
+                    std::panic::catch_unwind(|| {
+                        super::BuildTargets::new(
+                            "foo",
+                            &target,
+                            Path::new("/target"),
+                            &["cdylib", "staticlib"],
+                            &capi_config,
+                        )
+                    }),
+                    Ok(Ok(_))
+                );
+                assert_eq!(built, supported, "{}", target.triple);
+            }
+        }
+    }
+}
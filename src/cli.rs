@@ -16,10 +16,22 @@ struct Common {
     /// Path to directory where target should be copied to
     #[clap(long = "destdir")]
     destdir: Option<PathBuf>,
+    /// Path to directory where the headers, pkg-config files and link-time libraries
+    /// should be copied to, instead of destdir
+    #[clap(long = "devel-prefix")]
+    devel_prefix: Option<PathBuf>,
+    /// Path to directory where the shared libraries and data files should be copied to,
+    /// instead of destdir
+    #[clap(long = "runtime-prefix")]
+    runtime_prefix: Option<PathBuf>,
     /// Directory path used to construct default values of
     /// includedir, libdir, bindir, pkgconfigdir
     #[clap(long = "prefix")]
     prefix: Option<PathBuf>,
+    /// Default to the prefix of the platform rather than /usr/local, when installing
+    /// for the host: %ProgramFiles%\<name> on Windows, the Homebrew one on macOS
+    #[clap(long = "platform-prefix")]
+    platform_prefix: bool,
     /// Path to directory for installing generated library files
     #[clap(long = "libdir")]
     libdir: Option<PathBuf>,
@@ -39,12 +51,141 @@ struct Common {
     /// (defaults to {datarootdir})
     #[clap(long = "datadir")]
     datadir: Option<PathBuf>,
+    /// Path to directory for installing systemd units
+    /// (defaults to {prefix}/lib/systemd/system)
+    #[clap(long = "systemdsystemunitdir")]
+    systemdsystemunitdir: Option<PathBuf>,
+    /// Path to directory for installing udev rules
+    /// (defaults to {prefix}/lib/udev/rules.d)
+    #[clap(long = "udevrulesdir")]
+    udevrulesdir: Option<PathBuf>,
+    /// Path to directory for installing dbus service files
+    /// (defaults to {datarootdir}/dbus-1/services)
+    #[clap(long = "dbusservicedir")]
+    dbusservicedir: Option<PathBuf>,
+    /// Path to directory for installing manual pages (defaults to {datarootdir}/man)
+    #[clap(long = "mandir")]
+    mandir: Option<PathBuf>,
+    /// Path to directory for installing documentation (defaults to {datadir}/doc/{name})
+    #[clap(long = "docdir")]
+    docdir: Option<PathBuf>,
     #[clap(long = "dlltool")]
     /// Use the provided dlltool when building for the windows-gnu targets.
     dlltool: Option<PathBuf>,
     #[clap(long = "crt-static")]
     /// Build the library embedding the C runtime
     crt_static: bool,
+    #[clap(long = "prefer-dynamic-std")]
+    /// Link the shared library to the shared Rust standard library and install it
+    prefer_dynamic_std: bool,
+    #[clap(long = "min-size")]
+    /// Optimize the library for size and report the size saved
+    min_size: bool,
+    #[clap(long = "deny-warnings")]
+    /// Turn the warnings of cargo-c itself into errors
+    deny_warnings: bool,
+    #[clap(
+        long = "emit",
+        value_name = "build-script=ninja|make|cmake|msbuild|zephyr"
+    )]
+    /// Write a Ninja, Make, CMake, MSBuild or Zephyr fragment to consume the library from another build
+    emit: Option<String>,
+    #[clap(long = "target-sysroot")]
+    /// Sysroot of the target used to set up pkg-config when cross compiling
+    target_sysroot: Option<PathBuf>,
+    #[clap(long = "allow-outside-prefix")]
+    /// Allow installing files outside of the prefix and of their install directory
+    allow_outside_prefix: bool,
+    #[clap(long = "target-subdir")]
+    /// Install the libraries and pkg-config files of each target in a subdirectory
+    /// of libdir and bindir named after the target triple
+    target_subdir: bool,
+    #[clap(long = "dir-mode", value_name = "MODE", value_parser = parse_mode)]
+    /// Permissions of the directories created by cinstall, in octal (defaults to 755)
+    dir_mode: Option<u32>,
+    #[clap(long = "relocatable")]
+    /// Make the installed tree work from any location: the shared libraries are found
+    /// through the rpath and the pkg-config files relative to their directory
+    relocatable: bool,
+    #[clap(long = "preset", value_name = "NAME")]
+    /// Use the options of the named preset of package.metadata.capi.preset
+    preset: Option<String>,
+    #[clap(long = "artifact-cache", value_name = "PATH")]
+    /// Restore the libraries, headers and pkg-config files from this directory when built
+    /// before with the same sources, toolchain, target, profile and features, store them there
+    /// otherwise
+    artifact_cache: Option<PathBuf>,
+}
+
+/// The values of `--library-type`
+pub const LIBRARY_TYPES: [&str; 4] = ["cdylib", "staticlib", "module", "auto"];
+
+/// The values of `cpackage --format`
+pub const PACKAGE_FORMATS: [&str; 5] = ["tar.gz", "zip", "pack", "deb", "rpm"];
+
+/// The cargo subcommands installed, and the ones of `cargo capi`
+const SUBCOMMANDS: &[&str] = &[
+    "cbuild",
+    "cinstall",
+    "ctest",
+    "cpackage",
+    "cuninstall",
+    "capi",
+];
+const CAPI_SUBCOMMANDS: &[&str] = &[
+    "build",
+    "install",
+    "uninstall",
+    "package",
+    "test",
+    "doctor",
+    "abi-check",
+    "init",
+    "import-autotools",
+    "packaging-template",
+    "nuget",
+    "meson",
+    "vcpkg",
+];
+
+/// Print the version, with `--verbose` as a JSON report of the targets, subcommands and
+/// features supported, for the build systems detecting cargo-c at configure time
+pub fn print_version(args: &ArgMatches) -> anyhow::Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+    if args.get_count("verbose") == 0 {
+        println!("{} {version}", env!("CARGO_PKG_NAME"));
+        return Ok(());
+    }
+
+    let targets: Vec<_> = crate::build_targets::SUPPORTED_TARGETS
+        .iter()
+        .map(|(os, env, arch)| serde_json::json!({ "os": os, "env": env, "arch": arch }))
+        .collect();
+    let mut features = Vec::new();
+    if cfg!(feature = "vendored-openssl") {
+        features.push("vendored-openssl");
+    }
+    let report = serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": version,
+        "cargo": cargo::version().to_string(),
+        "targets": targets,
+        "subcommands": SUBCOMMANDS,
+        "capi_subcommands": CAPI_SUBCOMMANDS,
+        "library_types": LIBRARY_TYPES,
+        "package_formats": PACKAGE_FORMATS,
+        "features": features,
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("{mode} is not an octal permission mode"))
 }
 
 fn base_cli() -> Command {
@@ -110,13 +251,17 @@ pub fn subcommand_build(name: &'static str, about: &'static str) -> Command {
             multi_opt(
                 "library-type",
                 "LIBRARY-TYPE",
-                "Build only a type of library",
+                "Build only a type of library, auto builds the ones the target supports",
             )
             .global(true)
             .ignore_case(true)
-            .value_parser(["cdylib", "staticlib"]),
+            .value_parser(LIBRARY_TYPES),
         )
         .arg_release("Build artifacts in release mode, with optimizations")
+        .arg(flag(
+            "watch",
+            "Build again every time a file of the crate changes",
+        ))
         .arg_package_spec_no_all(
             "Package to build (see `cargo help pkgid`)",
             "Build all packages in the workspace",
@@ -139,16 +284,24 @@ pub fn subcommand_install(name: &'static str, about: &'static str) -> Command {
             multi_opt(
                 "library-type",
                 "LIBRARY-TYPE",
-                "Build only a type of library",
+                "Build only a type of library, auto builds the ones the target supports",
             )
             .global(true)
             .ignore_case(true)
-            .value_parser(["cdylib", "staticlib"]),
+            .value_parser(LIBRARY_TYPES),
         )
         .arg(flag("debug", "Build in debug mode instead of release mode"))
         .arg_release(
             "Build artifacts in release mode, with optimizations. This is the default behavior.",
         )
+        .arg(flag(
+            "watch",
+            "Build and install again every time a file of the crate changes",
+        ))
+        .arg(flag(
+            "print-default-prefix",
+            "Print the prefix used without --prefix and exit",
+        ))
         .arg_package_spec_no_all(
             "Package to install (see `cargo help pkgid`)",
             "Install all packages in the workspace",
@@ -163,6 +316,80 @@ the --debug flag will use the `dev` profile instead.
         )
 }
 
+pub fn subcommand_abi_check(name: &'static str) -> Command {
+    subcommand_build(
+        name,
+        "Check the symbols exported by the shared library against a baseline",
+    )
+    .arg(
+        opt(
+            "baseline",
+            "Symbol list to compare against (defaults to abi/<name>.symbols in the package)",
+        )
+        .value_name("PATH")
+        .value_parser(clap::value_parser!(PathBuf)),
+    )
+    .arg(flag(
+        "update-baseline",
+        "Write the symbols currently exported as the new baseline",
+    ))
+    .arg(
+        opt(
+            "abidiff",
+            "Previous release of the shared library to compare against with abidiff",
+        )
+        .value_name("PATH")
+        .value_parser(clap::value_parser!(PathBuf)),
+    )
+}
+
+pub fn subcommand_uninstall(name: &'static str, about: &'static str) -> Command {
+    base_cli()
+        .name(name)
+        .about(about)
+        .arg(flag(
+            "dry-run",
+            "Print the files that would be removed without removing them",
+        ))
+        .arg_package_spec_no_all(
+            "Package to uninstall (see `cargo help pkgid`)",
+            "Uninstall all packages in the workspace",
+            "Exclude packages from being uninstalled",
+        )
+        .after_help(
+            "
+The files removed are the ones listed in the install manifest written by cinstall,
+pass the same --prefix, --libdir and --destdir options used to install.
+",
+        )
+}
+
+pub fn subcommand_package(name: &'static str, about: &'static str) -> Command {
+    subcommand_install(name, about)
+        .arg(
+            opt(
+                "format",
+                "Format of the archive, pack assembles a CMSIS-Pack of the static libraries, \
+                 deb and rpm the runtime and the development packages",
+            )
+            .value_name("FORMAT")
+            .value_parser(PACKAGE_FORMATS)
+            .default_value("tar.gz"),
+        )
+        .arg(
+            opt(
+                "output-dir",
+                "Directory to write the archive to (defaults to the target directory)",
+            )
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(flag(
+            "spec-only",
+            "Write the rpm spec without running rpmbuild, keeping the staged trees it installs",
+        ))
+}
+
 pub fn subcommand_test(name: &'static str) -> Command {
     base_cli()
         .trailing_var_arg(true)
@@ -182,6 +409,265 @@ pub fn subcommand_test(name: &'static str) -> Command {
         )
         .arg(flag("no-run", "Compile, but don't run tests"))
         .arg(flag("no-fail-fast", "Run all tests regardless of failure"))
+        .arg(flag(
+            "record-layouts",
+            "Record the layouts of the new targets and types in header.layout",
+        ))
+}
+
+pub fn subcommand_doctor(name: &'static str) -> Command {
+    base_cli()
+        .name(name)
+        .about("Check the environment for problems before building or installing")
+}
+
+pub fn subcommand_init(name: &'static str) -> Command {
+    Command::new(name)
+        .about("Add the C-API metadata and skeleton to an existing crate")
+        .arg(
+            opt(
+                "library-name",
+                "Name of the C library (defaults to the crate name)",
+            )
+            .value_name("NAME"),
+        )
+        .arg(
+            opt(
+                "header-name",
+                "Name of the C header (defaults to the library name)",
+            )
+            .value_name("NAME"),
+        )
+        .arg_manifest_path()
+}
+
+pub fn subcommand_import_autotools(name: &'static str) -> Command {
+    Command::new(name)
+        .about("Generate the C-API metadata from an existing .pc.in and configure.ac")
+        .arg(
+            opt(
+                "pc-in",
+                "Path to the .pc.in file (defaults to the one in the crate root)",
+            )
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            opt("configure-ac", "Path to the configure.ac file")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg_manifest_path()
+}
+
+pub fn subcommand_packaging_template(name: &'static str) -> Command {
+    subcommand_install(
+        name,
+        "Write a distribution packaging skeleton in packaging/<KIND>",
+    )
+    .arg(
+        Arg::new("kind")
+            .value_name("KIND")
+            .required(true)
+            .value_parser(["rpm", "deb", "arch", "alpine"]),
+    )
+}
+
+pub fn subcommand_nuget(name: &'static str) -> Command {
+    Command::new(name)
+        .about("Package the windows-msvc builds of the target directory in a native .nupkg")
+        .arg(
+            opt(
+                "output-dir",
+                "Directory to write the package to (defaults to the target directory)",
+            )
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg_target_dir()
+        .arg_manifest_path()
+}
+
+pub fn subcommand_meson(name: &'static str) -> Command {
+    Command::new(name)
+        .about("Write a meson wrap and subproject to consume the library with dependency()")
+        .arg(
+            opt(
+                "prebuilt",
+                "Installed prefix to declare the library from, instead of building the sources",
+            )
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            opt(
+                "libdir",
+                "Directory of the prebuilt library, relative to the prefix (defaults to lib)",
+            )
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            opt(
+                "output-dir",
+                "Directory to write the subprojects to (defaults to <target-dir>/meson)",
+            )
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg_target_triple("Target triple the library is built for")
+        .arg_target_dir()
+        .arg_manifest_path()
+}
+
+pub fn subcommand_vcpkg(name: &'static str) -> Command {
+    Command::new(name)
+        .about("Write a vcpkg port building the library with cargo cinstall")
+        .arg(
+            opt(
+                "output-dir",
+                "Overlay ports directory to write the port to (defaults to <target-dir>/vcpkg)",
+            )
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg_target_dir()
+        .arg_manifest_path()
+}
+
+/// The `args` of a preset table
+fn preset_table_args(table: &toml::Value, preset: &str) -> anyhow::Result<Vec<OsString>> {
+    match table.get("args") {
+        None => Ok(Vec::new()),
+        Some(toml::Value::Array(args)) => args
+            .iter()
+            .map(|arg| {
+                arg.as_str().map(OsString::from).ok_or_else(|| {
+                    anyhow::anyhow!("the args of the preset {preset} must be strings")
+                })
+            })
+            .collect(),
+        Some(_) => anyhow::bail!("the args of the preset {preset} must be an array of strings"),
+    }
+}
+
+/// The arguments a preset puts on the command line, from the root manifest of the workspace
+/// and the manifests of the members selected, the ones the preset tables are read from
+fn preset_args(args: &ArgMatches, preset: &str) -> anyhow::Result<Vec<OsString>> {
+    let cwd = std::env::current_dir()?;
+    let manifest = match args.try_get_one::<String>("manifest-path").ok().flatten() {
+        Some(path) => cwd.join(path),
+        None => cargo::util::important_paths::find_root_manifest_for_wd(&cwd)?,
+    };
+    let config = cargo::Config::default()?;
+    let ws = cargo::core::Workspace::new(&manifest, &config)?;
+    let packages: Vec<&String> = args
+        .try_get_many::<String>("package")
+        .ok()
+        .flatten()
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let mut manifests = vec![ws.root_manifest().to_path_buf()];
+    for m in ws.members() {
+        let selected = packages.is_empty() || packages.iter().any(|p| *p == m.name().as_str());
+        if selected && !manifests.contains(&m.manifest_path().to_path_buf()) {
+            manifests.push(m.manifest_path().to_path_buf());
+        }
+    }
+
+    let mut found = false;
+    let mut preset_args = Vec::new();
+    for manifest in manifests {
+        let toml = cargo_util::paths::read(&manifest)?.parse::<toml::Value>()?;
+        let table = ["package", "workspace"].iter().find_map(|section| {
+            toml.get(section)
+                .and_then(|v| v.get("metadata"))
+                .and_then(|v| v.get("capi"))
+                .and_then(|v| v.get("preset"))
+                .and_then(|v| v.get(preset))
+        });
+        let table = match table {
+            Some(table) => table,
+            None => continue,
+        };
+        found = true;
+
+        let args = preset_table_args(table, preset)?;
+        if args.is_empty() {
+            continue;
+        }
+        if !preset_args.is_empty() && preset_args != args {
+            anyhow::bail!(
+                "the members set different args for the preset {preset}, set them once in \
+                 [workspace.metadata.capi.preset.{preset}] or select one with --package"
+            );
+        }
+        preset_args = args;
+    }
+
+    if !found {
+        anyhow::bail!(
+            "there is no preset named {preset} in the workspace of {}",
+            manifest.display()
+        );
+    }
+    Ok(preset_args)
+}
+
+/// Parse the command line, with the arguments of the `--preset` selected inserted right
+/// after the subcommand so that the ones given explicitly take precedence
+pub fn get_matches_with_preset(app: &Command) -> anyhow::Result<ArgMatches> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let matches = app.clone().get_matches_from(&argv);
+
+    let mut subcommands = Vec::new();
+    let mut args = &matches;
+    while let Some((name, sub_args)) = args.subcommand() {
+        subcommands.push(name.to_owned());
+        args = sub_args;
+    }
+    let preset = match args.try_get_one::<String>("preset") {
+        Ok(Some(preset)) => preset.clone(),
+        _ => return Ok(matches),
+    };
+
+    let extra = preset_args(args, &preset)?;
+    if extra.is_empty() {
+        return Ok(matches);
+    }
+
+    let argv = insert_preset_args(&argv, &subcommands, &extra, &preset)?;
+    Ok(app.clone().get_matches_from(argv))
+}
+
+/// The command line with `extra` inserted after the last of the `subcommands`
+fn insert_preset_args(
+    argv: &[OsString],
+    subcommands: &[String],
+    extra: &[OsString],
+    preset: &str,
+) -> anyhow::Result<Vec<OsString>> {
+    let mut pos = 0;
+    for name in subcommands {
+        // An alias or an abbreviation of the subcommand is not looked up
+        pos += 1 + argv[pos + 1..]
+            .iter()
+            .position(|arg| arg == name.as_str())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "cannot find the {name} subcommand on the command line to insert the args \
+                     of the preset {preset}, spell it out"
+                )
+            })?;
+    }
+    Ok(argv[..=pos]
+        .iter()
+        .chain(extra)
+        .chain(&argv[pos + 1..])
+        .cloned()
+        .collect())
 }
 
 pub fn run_cargo_fallback(subcommand: &str, subcommand_args: &ArgMatches) -> CliResult {
@@ -206,3 +692,80 @@ pub fn run_cargo_fallback(subcommand: &str, subcommand_args: &ArgMatches) -> Cli
     }
     Err(CliError::new(err, 101))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    fn os_strings(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn preset_table_args() {
+        let table: toml::Value =
+            toml::from_str("args = [\"--release\", \"--prefix=/usr\"]").unwrap();
+        assert_eq!(
+            super::preset_table_args(&table, "distro").unwrap(),
+            os_strings(&["--release", "--prefix=/usr"])
+        );
+
+        let table: toml::Value =
+            toml::from_str("library = { rustflags = \"-Cpanic=abort\" }").unwrap();
+        assert!(super::preset_table_args(&table, "distro")
+            .unwrap()
+            .is_empty());
+
+        let table: toml::Value = toml::from_str("args = [\"--release\", 1]").unwrap();
+        assert_eq!(
+            super::preset_table_args(&table, "distro")
+                .unwrap_err()
+                .to_string(),
+            "the args of the preset distro must be strings"
+        );
+
+        let table: toml::Value = toml::from_str("args = \"--release\"").unwrap();
+        assert_eq!(
+            super::preset_table_args(&table, "distro")
+                .unwrap_err()
+                .to_string(),
+            "the args of the preset distro must be an array of strings"
+        );
+    }
+
+    #[test]
+    fn insert_preset_args() {
+        let extra = os_strings(&["--release", "--prefix=/usr"]);
+        let subcommands = ["capi".to_string(), "install".to_string()];
+
+        // The explicit arguments come last and take precedence
+        assert_eq!(
+            super::insert_preset_args(
+                &os_strings(&["cargo-capi", "capi", "install", "--prefix=/opt"]),
+                &subcommands,
+                &extra,
+                "distro",
+            )
+            .unwrap(),
+            os_strings(&[
+                "cargo-capi",
+                "capi",
+                "install",
+                "--release",
+                "--prefix=/usr",
+                "--prefix=/opt"
+            ])
+        );
+
+        let err = super::insert_preset_args(
+            &os_strings(&["cargo-capi", "capi", "i", "--prefix=/opt"]),
+            &subcommands,
+            &extra,
+            "distro",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("cannot find the install subcommand"));
+    }
+}
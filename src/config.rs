@@ -1,4 +1,5 @@
 use std::env;
+use std::path::PathBuf;
 
 use cargo::util::command_prelude::{ArgMatches, ArgMatchesExt};
 use cargo::{CliResult, Config};
@@ -37,5 +38,11 @@ pub fn config_configure(config: &mut Config, args: &ArgMatches) -> CliResult {
 
     // Make sure that the env-vars are correctly set at this point.
     setup_env();
+
+    // Only the build commands take a target sysroot
+    if args.try_get_one::<PathBuf>("target_sysroot").is_ok() {
+        crate::build::setup_cross_pkg_config(config, args)?;
+    }
+
     Ok(())
 }
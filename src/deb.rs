@@ -0,0 +1,405 @@
+use std::path::{Path, PathBuf};
+
+use cargo::core::Workspace;
+use cargo_util::paths::{create_dir_all, read_bytes, remove_dir_all, write};
+use semver::Version;
+
+use crate::archive::{package_summary, stage_split_install, top_level};
+use crate::build::{warn, CPackage, LibraryCApiConfig};
+use crate::build_targets::shared_lib_affixes;
+use crate::target::Target;
+
+/// The Debian architecture of a Linux target
+fn deb_arch(target: &Target) -> Option<&'static str> {
+    let arch = target.triple.split('-').next().unwrap_or_default();
+    let deb_arch = match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "i386" | "i586" | "i686" => "i386",
+        _ if arch.starts_with("arm") && target.abi == "eabihf" => "armhf",
+        _ if arch.starts_with("arm") => "armel",
+        "powerpc64le" => "ppc64el",
+        "powerpc64" => "ppc64",
+        "powerpc" => "powerpc",
+        "riscv64gc" | "riscv64" => "riscv64",
+        "s390x" => "s390x",
+        "mips64el" => "mips64el",
+        "mipsel" => "mipsel",
+        "loongarch64" => "loong64",
+        "sparc64" => "sparc64",
+        _ => return None,
+    };
+    Some(deb_arch)
+}
+
+/// The Debian package names allow lowercase alphanumeric characters, `+`, `-` and `.`
+fn deb_name(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '+' | '-' | '.' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+/// The Debian version, dpkg sorts the versions with a `~` before the release ones, as
+/// semver does with a `-`
+fn deb_version(version: &Version) -> String {
+    version.to_string().replace('-', "~")
+}
+
+/// The names of the runtime `lib<name><soversion>` and the development `lib<name>-dev`
+/// packages
+pub(crate) fn package_names(library: &LibraryCApiConfig) -> (String, String) {
+    let lib = deb_name(&format!("lib{}", library.name));
+    // A package name ending with a digit is separated from the soversion by a hyphen
+    let runtime_name = match (
+        library.versioning,
+        lib.ends_with(|c: char| c.is_ascii_digit()),
+    ) {
+        (false, _) => lib.clone(),
+        (true, true) => format!("{lib}-{}", library.version.major),
+        (true, false) => format!("{lib}{}", library.version.major),
+    };
+    (runtime_name, format!("{lib}-dev"))
+}
+
+/// The libraries the shared library is linked to, its `DT_NEEDED` entries
+fn elf_needed<Elf: object::read::elf::FileHeader<Endian = object::Endianness>>(
+    data: &[u8],
+) -> anyhow::Result<Vec<String>> {
+    use object::read::elf::Dyn;
+
+    let header = Elf::parse(data)?;
+    let endian = header.endian()?;
+    let sections = header.sections(endian, data)?;
+    let mut needed = Vec::new();
+    if let Some((entries, link)) = sections.dynamic(endian, data)? {
+        let strings = sections.strings(endian, data, link)?;
+        for entry in entries {
+            if entry.tag32(endian) == Some(object::elf::DT_NEEDED) {
+                let name = entry.string(endian, strings)?;
+                needed.push(String::from_utf8_lossy(name).into_owned());
+            }
+        }
+    }
+    Ok(needed)
+}
+
+/// The Depends of the runtime package, from the libraries the shared library needs
+fn runtime_depends(ws: &Workspace, pkg: &CPackage, shared_lib: &Path) -> anyhow::Result<String> {
+    let data = read_bytes(shared_lib)?;
+    let needed = match object::FileKind::parse(&*data)? {
+        object::FileKind::Elf32 => elf_needed::<object::elf::FileHeader32<_>>(&data)?,
+        object::FileKind::Elf64 => elf_needed::<object::elf::FileHeader64<_>>(&data)?,
+        _ => anyhow::bail!("{} is not an ELF shared library", shared_lib.display()),
+    };
+
+    let libc6 = match &pkg.capi_config.library.max_glibc_version {
+        Some(version) => format!("libc6 (>= {version})"),
+        None => "libc6".to_string(),
+    };
+    let mut depends: Vec<String> = Vec::new();
+    for soname in needed {
+        let dep = match soname.as_str() {
+            "libc.so.6" | "libm.so.6" | "libdl.so.2" | "libpthread.so.0" | "librt.so.1"
+            | "libutil.so.1" => libc6.clone(),
+            _ if soname.starts_with("ld-linux") => libc6.clone(),
+            "libgcc_s.so.1" => "libgcc-s1".to_string(),
+            "libstdc++.so.6" => "libstdc++6".to_string(),
+            // The shared Rust standard library is installed next to the library
+            _ if soname.starts_with("libstd-") => continue,
+            _ => {
+                warn(
+                    ws.config(),
+                    format!(
+                        "the Debian package providing {soname}, needed by {}, is not known, \
+                         add it to the Depends of the runtime package",
+                        pkg.capi_config.library.name
+                    ),
+                )?;
+                continue;
+            }
+        };
+        if !depends.contains(&dep) {
+            depends.push(dep);
+        }
+    }
+
+    Ok(depends.join(", "))
+}
+
+/// The size of the installed files, in KiB, as dpkg reports it
+fn installed_size(dir: &Path) -> anyhow::Result<u64> {
+    fn size(dir: &Path) -> anyhow::Result<u64> {
+        let mut total = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let metadata = std::fs::symlink_metadata(&path)?;
+            total += if metadata.is_dir() {
+                size(&path)?
+            } else {
+                metadata.len()
+            };
+        }
+        Ok(total)
+    }
+
+    Ok((size(dir)? + 1023) / 1024)
+}
+
+fn tar_gz(entries: &[(String, Vec<u8>)], tree: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    // Owned by root, the links of the shared library kept as links
+    builder.mode(tar::HeaderMode::Deterministic);
+    builder.follow_symlinks(false);
+    for (name, contents) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, format!("./{name}"), contents.as_slice())?;
+    }
+    if let Some(tree) = tree {
+        for (name, path) in top_level(tree)? {
+            builder.append_dir_all(format!("./{name}"), &path)?;
+        }
+    }
+    Ok(builder.into_inner()?.finish()?)
+}
+
+/// The `ar` archive of a `.deb`
+fn ar(members: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut ar = b"!<arch>\n".to_vec();
+    for (name, contents) in members {
+        let header = format!(
+            "{name:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            0,
+            0,
+            0,
+            "100644",
+            contents.len()
+        );
+        ar.extend(header.as_bytes());
+        ar.extend(contents);
+        if contents.len() % 2 == 1 {
+            ar.push(b'\n');
+        }
+    }
+    ar
+}
+
+/// A binary package of the files installed in `tree`
+struct Deb {
+    name: String,
+    section: &'static str,
+    description: String,
+    depends: String,
+    /// The control files besides `control`
+    control_files: Vec<(String, Vec<u8>)>,
+    tree: PathBuf,
+}
+
+/// Stage the install of the package and assemble the runtime `lib<name><soversion>` and
+/// the development `lib<name>-dev` Debian packages from it
+///
+/// The runtime package holds the shared library and the data files, along with its
+/// `shlibs` file and the `ldconfig` trigger, the development package the rest.
+pub(crate) fn deb_packages(
+    ws: &Workspace,
+    pkg: &mut CPackage,
+    output_dir: &Path,
+) -> anyhow::Result<()> {
+    let target = pkg.build_targets.target.clone();
+    let arch = match (target.os.as_str(), deb_arch(&target)) {
+        ("linux", Some(arch)) => arch,
+        _ => anyhow::bail!("there is no Debian architecture for {}", target.triple),
+    };
+    if pkg.install_paths.prefix != Path::new("/usr") {
+        warn(
+            ws.config(),
+            format!(
+                "the Debian packages install in {}, pass --prefix=/usr \
+                 --libdir=/usr/lib/<multiarch-triplet> to follow the Debian layout",
+                pkg.install_paths.prefix.display()
+            ),
+        )?;
+    }
+
+    let (staging, runtime, devel) = stage_split_install(ws, pkg)?;
+
+    let capi_config = &pkg.capi_config;
+    let library = &capi_config.library;
+    let version = &deb_version(&library.version);
+    let (runtime_name, devel_name) = package_names(library);
+    let summary = package_summary(capi_config);
+
+    let mut debs = Vec::new();
+    if runtime.is_dir() {
+        let mut control_files = Vec::new();
+        let mut depends = String::new();
+        if let Some(shared_lib) = pkg.build_targets.shared_lib.as_ref() {
+            depends = runtime_depends(ws, pkg, shared_lib)?;
+            if library.versioning {
+                let (prefix, _) = shared_lib_affixes(library, &target);
+                control_files.push((
+                    "shlibs".to_string(),
+                    format!(
+                        "{prefix}{} {} {runtime_name} (>= {version})\n",
+                        library.name, library.version.major
+                    )
+                    .into_bytes(),
+                ));
+            }
+            control_files.push((
+                "triggers".to_string(),
+                b"activate-noawait ldconfig\n".to_vec(),
+            ));
+        }
+        debs.push(Deb {
+            name: runtime_name.clone(),
+            section: "libs",
+            description: summary.clone(),
+            depends,
+            control_files,
+            tree: runtime,
+        });
+    }
+    if devel.is_dir() {
+        let depends = if debs.is_empty() {
+            String::new()
+        } else {
+            format!("{runtime_name} (= {version})")
+        };
+        debs.push(Deb {
+            name: devel_name,
+            section: "libdevel",
+            description: format!("{summary} - development files"),
+            depends,
+            control_files: Vec::new(),
+            tree: devel,
+        });
+    }
+
+    let metadata = ws
+        .members()
+        .find(|m| m.root() == pkg.root_path)
+        .map(|m| m.manifest().metadata().clone());
+    let maintainer = match metadata.as_ref().and_then(|m| m.authors.first()) {
+        Some(author) => author.clone(),
+        None => {
+            warn(
+                ws.config(),
+                format!(
+                    "{} has no package.authors, the Debian packages have no maintainer",
+                    library.name
+                ),
+            )?;
+            "Unknown <unknown@localhost>".to_string()
+        }
+    };
+    let homepage = metadata
+        .as_ref()
+        .and_then(|m| m.homepage.clone().or_else(|| m.repository.clone()));
+
+    create_dir_all(output_dir)?;
+    for deb in debs {
+        let mut control = format!(
+            "Package: {}
+Version: {version}
+Architecture: {arch}
+Multi-Arch: same
+Maintainer: {maintainer}
+Installed-Size: {}
+Section: {}
+Priority: optional
+",
+            deb.name,
+            installed_size(&deb.tree)?,
+            deb.section,
+        );
+        if !deb.depends.is_empty() {
+            control += &format!("Depends: {}\n", deb.depends);
+        }
+        if let Some(homepage) = &homepage {
+            control += &format!("Homepage: {homepage}\n");
+        }
+        control += &format!("Description: {}\n", deb.description);
+
+        let mut control_files = vec![("control".to_string(), control.into_bytes())];
+        control_files.extend(deb.control_files);
+
+        let file_name = format!("{}_{version}_{arch}.deb", deb.name);
+        ws.config().shell().status("Packaging", &file_name)?;
+        let contents = ar(&[
+            ("debian-binary", b"2.0\n".to_vec()),
+            ("control.tar.gz", tar_gz(&control_files, None)?),
+            ("data.tar.gz", tar_gz(&[], Some(&deb.tree))?),
+        ]);
+
+        let path = output_dir.join(file_name);
+        write(&path, contents)?;
+        ws.config().shell().status("Writing", path.display())?;
+    }
+    remove_dir_all(&staging)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+
+    use crate::target::Target;
+
+    #[test]
+    fn deb_name() {
+        assert_eq!(super::deb_name("libfoo_bar"), "libfoo-bar");
+        assert_eq!(super::deb_name("libFoo+1.0"), "libfoo+1.0");
+    }
+
+    #[test]
+    fn deb_arch() {
+        let arch = |triple: &str| super::deb_arch(&Target::new(triple).unwrap());
+        assert_eq!(arch("x86_64-unknown-linux-gnu"), Some("amd64"));
+        assert_eq!(arch("i686-unknown-linux-gnu"), Some("i386"));
+        assert_eq!(arch("armv7-unknown-linux-gnueabihf"), Some("armhf"));
+        assert_eq!(arch("arm-unknown-linux-gnueabi"), Some("armel"));
+        assert_eq!(arch("riscv64gc-unknown-linux-gnu"), Some("riscv64"));
+        assert_eq!(arch("wasm32-unknown-unknown"), None);
+    }
+
+    #[test]
+    fn deb_version() {
+        assert_eq!(
+            super::deb_version(&Version::parse("1.2.3").unwrap()),
+            "1.2.3"
+        );
+        assert_eq!(
+            super::deb_version(&Version::parse("1.2.3-rc.1+git").unwrap()),
+            "1.2.3~rc.1+git"
+        );
+    }
+
+    #[test]
+    fn ar() {
+        let ar = super::ar(&[
+            ("debian-binary", b"2.0\n".to_vec()),
+            ("odd", b"abc".to_vec()),
+        ]);
+
+        let header = |name: &str, size: usize| {
+            format!("{name:<16}0           0     0     100644  {size:<10}`\n")
+        };
+        let mut expected = b"!<arch>\n".to_vec();
+        expected.extend(header("debian-binary", 4).as_bytes());
+        expected.extend(b"2.0\n");
+        expected.extend(header("odd", 3).as_bytes());
+        // The members are aligned on even offsets
+        expected.extend(b"abc\n");
+        assert_eq!(ar, expected);
+        assert_eq!(header("odd", 3).len(), 60);
+    }
+}
@@ -0,0 +1,474 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use cargo::core::{Package, Workspace};
+use cargo::util::config::StringList;
+use cargo::util::interning::InternedString;
+use clap::ArgMatches;
+
+use crate::build::{
+    load_manifest_capi_config, mingw_linker, pkg_config_tool, requested_targets, selected_preset,
+    warn,
+};
+use crate::install::{append_to_destdir, InstallPaths};
+use crate::target::Target;
+
+/// Collect the problems found, each warning tells how to address it
+struct Doctor<'a> {
+    ws: &'a Workspace<'a>,
+    problems: usize,
+}
+
+impl<'a> Doctor<'a> {
+    fn ok(&self, what: &str, detail: impl std::fmt::Display) -> anyhow::Result<()> {
+        self.ws.config().shell().status(what, detail)
+    }
+
+    fn problem(&mut self, message: impl std::fmt::Display) -> anyhow::Result<()> {
+        self.problems += 1;
+        warn(self.ws.config(), message)
+    }
+}
+
+/// First line printed by `tool --version`, if it can be run
+fn tool_version(tool: &Path) -> Option<String> {
+    let out = Command::new(tool).arg("--version").output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    Some(stdout.lines().next().unwrap_or_default().trim().to_string())
+}
+
+/// The closest existing ancestor of `dir`, the one the install would have to write into
+fn existing_ancestor(dir: &Path) -> Option<&Path> {
+    dir.ancestors().find(|d| d.is_dir())
+}
+
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".cargo-c-doctor-{}", std::process::id()));
+    let writable = std::fs::File::create(&probe).is_ok();
+    let _ = std::fs::remove_file(&probe);
+    writable
+}
+
+/// The Rust standard library of the target is missing, unless `-Zbuild-std` builds it
+fn missing_rust_std(ws: &Workspace, rustc: &Path, target: &str) -> anyhow::Result<Option<String>> {
+    // The custom targets have no prebuilt standard library to install
+    if target.ends_with(".json") || ws.config().cli_unstable().build_std.is_some() {
+        return Ok(None);
+    }
+    let out = Command::new(rustc).args(["--print", "sysroot"]).output()?;
+    let sysroot = PathBuf::from(String::from_utf8_lossy(&out.stdout).trim());
+    let target_libdir = sysroot.join("lib").join("rustlib").join(target).join("lib");
+
+    Ok((!target_libdir.is_dir()).then(|| {
+        format!(
+            "the Rust standard library for {target} is not installed in {}, \
+             run `rustup target add {target}`",
+            sysroot.display()
+        )
+    }))
+}
+
+fn check_rust_target(
+    doctor: &mut Doctor,
+    ws: &Workspace,
+    rustc: &Path,
+    target: &str,
+) -> anyhow::Result<()> {
+    match missing_rust_std(ws, rustc, target)? {
+        None => doctor.ok("Found", format!("Rust standard library for {target}")),
+        Some(problem) => doctor.problem(problem),
+    }
+}
+
+/// The tool can be spawned, whatever its `--version` exits with
+fn can_run(tool: &Path) -> bool {
+    Command::new(tool).arg("--version").output().is_ok()
+}
+
+/// The rustflags pass a linker to rustc, overriding the configured and the default ones
+fn rustflags_set_linker(ws: &Workspace, target: &str) -> anyhow::Result<bool> {
+    let target_var = format!("CARGO_TARGET_{}_RUSTFLAGS", target.replace(['-', '.'], "_"));
+    let mut flags: Vec<String> = [
+        "RUSTFLAGS",
+        "CARGO_ENCODED_RUSTFLAGS",
+        &target_var.to_uppercase(),
+    ]
+    .iter()
+    .filter_map(|var| std::env::var(var).ok())
+    .collect();
+    for key in [
+        format!("target.{target}.rustflags"),
+        "build.rustflags".to_string(),
+    ] {
+        if let Some(list) = ws.config().get::<Option<StringList>>(&key)? {
+            flags.extend(list.as_slice().iter().cloned());
+        }
+    }
+    Ok(flags.iter().any(|f| f.contains("linker=")))
+}
+
+/// The prefix of the GNU cross toolchain of a Linux target, as Debian names it, e.g.
+/// `arm-linux-gnueabihf`
+fn gnu_cross_prefix(target: &Target) -> String {
+    let arch = match target.arch.as_str() {
+        "x86" => target.triple.split('-').next().unwrap_or("i686"),
+        arch => arch,
+    };
+    format!("{arch}-linux-{}{}", target.env, target.abi)
+}
+
+/// What may prevent rustc from linking for a target, with the way to address it
+enum LinkerProblem {
+    /// The linker cannot be run
+    Missing(String),
+    /// No linker is configured and the default one, which can be run, may link for the
+    /// host only, e.g. without the multilib packages
+    Unconfigured(String),
+}
+
+impl std::fmt::Display for LinkerProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkerProblem::Missing(problem) | LinkerProblem::Unconfigured(problem) => {
+                f.write_str(problem)
+            }
+        }
+    }
+}
+
+/// The linker rustc would use for the target is missing or may not link for it, with the
+/// way to install it
+fn missing_linker(
+    ws: &Workspace,
+    target: &Target,
+    host: &Target,
+) -> anyhow::Result<Option<LinkerProblem>> {
+    let triple = &target.triple;
+    let configured = ws
+        .config()
+        .get::<Option<PathBuf>>(&format!("target.{triple}.linker"))?;
+    if let Some(linker) = configured {
+        return Ok((!can_run(&linker)).then(|| {
+            LinkerProblem::Missing(format!(
+                "the linker {} set in target.{triple}.linker cannot be run",
+                linker.display()
+            ))
+        }));
+    }
+    if rustflags_set_linker(ws, triple)? {
+        return Ok(None);
+    }
+
+    // rustc falls back to `cc`
+    let unconfigured = |hint: &str| {
+        let problem = format!(
+            "no linker is configured for {triple}, {hint}and set target.{triple}.linker \
+             in .cargo/config.toml"
+        );
+        Some(if can_run(Path::new("cc")) {
+            LinkerProblem::Unconfigured(problem)
+        } else {
+            LinkerProblem::Missing(problem)
+        })
+    };
+    let missing = |problem: String| Some(LinkerProblem::Missing(problem));
+    let problem = match (target.os.as_str(), target.env.as_str()) {
+        // rustc links them with the rust-lld it ships
+        _ if target.arch.starts_with("wasm") && target.os != "emscripten" => None,
+        ("none" | "uefi", _) => None,
+        ("emscripten", _) if !can_run(Path::new("emcc")) => missing(
+            "emcc cannot be run, install the emsdk and run `source ./emsdk_env.sh`".to_string(),
+        ),
+        ("emscripten", _) => None,
+        ("macos" | "ios" | "tvos" | "watchos", _) if host.vendor == "apple" => {
+            let sdk = Command::new("xcrun").arg("--show-sdk-path").output();
+            match sdk {
+                Ok(out) if out.status.success() => None,
+                _ => missing(
+                    "the Xcode command line tools are not installed, \
+                     run `xcode-select --install`"
+                        .to_string(),
+                ),
+            }
+        }
+        ("macos" | "ios" | "tvos" | "watchos", _) => {
+            unconfigured("install a cross linker for the Apple targets, e.g. osxcross, ")
+        }
+        ("windows", "msvc") if host.os == "windows" => {
+            match cc::windows_registry::find_tool(triple, "link.exe") {
+                Some(_) => None,
+                None => missing(
+                    "link.exe is not installed, install the Visual Studio Build Tools \
+                     with the Desktop development with C++ workload"
+                        .to_string(),
+                ),
+            }
+        }
+        // rustc calls link.exe, that is not available outside of Windows
+        ("windows", "msvc") => missing(format!(
+            "no linker is configured for {triple}, install lld-link (`apt install lld`) \
+             and set target.{triple}.linker in .cargo/config.toml"
+        )),
+        ("windows", "gnu") if host.os != "windows" => {
+            let linker = mingw_linker(target);
+            let hint = if target.abi == "llvm" {
+                "install llvm-mingw".to_string()
+            } else {
+                let arch = linker.split('-').next().unwrap_or_default();
+                format!("run `apt install gcc-mingw-w64-{}`", arch.replace('_', "-"))
+            };
+            if can_run(Path::new(&linker)) {
+                None
+            } else {
+                missing(format!("{linker} cannot be run, {hint}"))
+            }
+        }
+        ("windows", _) => None,
+        // rustc calls `cc`, that links for the host only
+        _ if target.arch == host.arch && target.os == host.os && !can_run(Path::new("cc")) => {
+            missing("cc cannot be run, install a C toolchain, e.g. `apt install gcc`".to_string())
+        }
+        _ if target.arch == host.arch && target.os == host.os => None,
+        ("linux", "gnu") => {
+            let prefix = gnu_cross_prefix(target);
+            unconfigured(&format!(
+                "run `apt install gcc-{prefix}` to install a cross linker, e.g. {prefix}-gcc, "
+            ))
+        }
+        ("android", _) => unconfigured("install the Android NDK to use its clang "),
+        _ => unconfigured(&format!("install a cross linker for {triple} ")),
+    };
+
+    Ok(problem)
+}
+
+/// The dlltool passed with `--dlltool` or `DLLTOOL` is missing, the builtin generator is
+/// used only when none is requested
+fn missing_dlltool(args: &ArgMatches, target: &Target) -> Option<String> {
+    if target.os != "windows" || target.env != "gnu" {
+        return None;
+    }
+    let dlltool = args
+        .get_one::<PathBuf>("dlltool")
+        .cloned()
+        .or_else(|| std::env::var_os("DLLTOOL").map(PathBuf::from))?;
+    let package = if target.abi == "llvm" {
+        "`apt install llvm`"
+    } else {
+        "`apt install binutils-mingw-w64`"
+    };
+
+    (!can_run(&dlltool)).then(|| {
+        format!(
+            "the dlltool {} cannot be run, run {package} or unset it to use \
+             the builtin import library generator",
+            dlltool.display()
+        )
+    })
+}
+
+/// Fail with every toolchain component the build for `target` misses, before cargo or the
+/// linker would fail on the first one
+///
+/// Linking the target is only checked when building a shared library.
+pub(crate) fn check_toolchain(
+    ws: &Workspace,
+    args: &ArgMatches,
+    rustc: &Path,
+    host: &str,
+    target: &Target,
+    links: bool,
+) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    problems.extend(missing_rust_std(ws, rustc, &target.triple)?);
+    if links {
+        match missing_linker(ws, target, &Target::new(host)?)? {
+            Some(LinkerProblem::Missing(problem)) => problems.push(problem),
+            // The default linker may still link for the target, only the linker can tell
+            Some(LinkerProblem::Unconfigured(problem)) => warn(ws.config(), problem)?,
+            None => {}
+        }
+        problems.extend(missing_dlltool(args, target));
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let list: Vec<_> = problems.iter().map(|p| format!("  - {p}")).collect();
+    anyhow::bail!(
+        "the toolchain to build for {} is incomplete:\n{}",
+        target.triple,
+        list.join("\n")
+    )
+}
+
+fn check_pkg_config(
+    doctor: &mut Doctor,
+    args: &ArgMatches,
+    target: &str,
+    cross: bool,
+) -> anyhow::Result<()> {
+    let tool = pkg_config_tool(target);
+
+    match tool_version(&tool) {
+        Some(version) => doctor.ok("Found", format!("{} {version}", tool.display()))?,
+        None => doctor.problem(format!(
+            "{} cannot be run, install pkg-config (or pkgconf) or point PKG_CONFIG to it \
+             to use the generated .pc files",
+            tool.display()
+        ))?,
+    }
+
+    let sysroot = args.get_one::<PathBuf>("target_sysroot").is_some()
+        || ["CARGO_C_TARGET_SYSROOT", "PKG_CONFIG_SYSROOT_DIR"]
+            .iter()
+            .any(|v| std::env::var_os(v).is_some());
+    if cross && !sysroot {
+        doctor.problem(format!(
+            "pkg-config would look up the host libraries when building for {target}, \
+             pass --target-sysroot or set PKG_CONFIG_SYSROOT_DIR"
+        ))?;
+    }
+
+    Ok(())
+}
+
+fn check_cross_toolchain(
+    doctor: &mut Doctor,
+    ws: &Workspace,
+    target: &str,
+    host: &str,
+) -> anyhow::Result<()> {
+    let compiler = cc::Build::new()
+        .target(target)
+        .host(host)
+        .opt_level(0)
+        .cargo_metadata(false)
+        .cargo_warnings(false)
+        .warnings(false)
+        .try_get_compiler();
+
+    match compiler {
+        Ok(compiler) if compiler.to_command().arg("--version").output().is_ok() => doctor.ok(
+            "Found",
+            format!("C compiler {} for {target}", compiler.path().display()),
+        )?,
+        _ => doctor.problem(format!(
+            "no C compiler found for {target}, install the cross toolchain or set CC_{}",
+            target.replace('-', "_")
+        ))?,
+    }
+
+    match missing_linker(ws, &Target::new(target)?, &Target::new(host)?)? {
+        None => doctor.ok("Found", format!("linker for {target}"))?,
+        Some(problem) => doctor.problem(problem)?,
+    }
+
+    Ok(())
+}
+
+/// Check that the member can be installed for the target
+fn check_member(
+    doctor: &mut Doctor,
+    pkg: &Package,
+    args: &ArgMatches,
+    target: &Target,
+    checked: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let capi_config = load_manifest_capi_config(pkg, Some(target), selected_preset(args))?;
+    let paths = InstallPaths::new(&capi_config.library.name, args, &capi_config, target);
+
+    for dir in [&paths.libdir, &paths.includedir, &paths.pkgconfigdir] {
+        let dir = append_to_destdir(paths.destdir.as_deref(), dir);
+        let existing = match existing_ancestor(&dir) {
+            Some(existing) => existing.to_path_buf(),
+            None => continue,
+        };
+        if checked.contains(&existing) {
+            continue;
+        }
+        if is_writable(&existing) {
+            doctor.ok("Writable", existing.display())?;
+        } else {
+            doctor.problem(format!(
+                "{} is not writable, pass --destdir or another --prefix, \
+                 or install with the needed permissions",
+                existing.display()
+            ))?;
+        }
+        checked.push(existing);
+    }
+
+    let header = &capi_config.header;
+    if header.enabled && header.generation {
+        let cbindgen_toml = pkg.root().join("cbindgen.toml");
+        if cbindgen_toml.is_file() {
+            // The header is generated by the cbindgen cargo-c is built with, the options
+            // of a newer one are rejected
+            match cbindgen::Config::from_file(&cbindgen_toml) {
+                Ok(_) => doctor.ok(
+                    "Found",
+                    format!(
+                        "cbindgen.toml of {}, read by cbindgen {}",
+                        capi_config.library.name,
+                        cbindgen::VERSION
+                    ),
+                )?,
+                Err(e) => doctor.problem(format!(
+                    "cbindgen {} cannot read the cbindgen.toml of {}, fix it or update \
+                     cargo-c to a version built with a newer cbindgen: {e}",
+                    cbindgen::VERSION,
+                    pkg.name()
+                ))?,
+            }
+        } else {
+            doctor.problem(format!(
+                "{} has no cbindgen.toml, the header would be generated as C++, \
+                 add one setting language = \"C\"",
+                pkg.name()
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Inspect the environment and report what would make the build or the install fail
+pub fn cdoctor(ws: &Workspace, args: &ArgMatches) -> anyhow::Result<()> {
+    let rustc = ws.config().load_global_rustc(Some(ws))?;
+    let host = rustc.host.to_string();
+
+    let mut doctor = Doctor { ws, problems: 0 };
+
+    let targets = requested_targets(ws.config(), args, &host)?;
+    for target in &targets {
+        let target = target.as_str();
+        let cross = target != host;
+        check_rust_target(&mut doctor, ws, &rustc.path, target)?;
+        check_pkg_config(&mut doctor, args, target, cross)?;
+        if cross {
+            check_cross_toolchain(&mut doctor, ws, target, &host)?;
+        }
+    }
+
+    let capi_feature = InternedString::new("capi");
+    for pkg in ws
+        .members()
+        .filter(|m| m.library().is_some() && m.summary().features().contains_key(&capi_feature))
+    {
+        let mut checked = Vec::new();
+        for target in &targets {
+            let target = Target::new(target)?;
+            check_member(&mut doctor, pkg, args, &target, &mut checked)?;
+        }
+    }
+
+    match doctor.problems {
+        0 => doctor.ok("Finished", "no problems found"),
+        1 => anyhow::bail!("1 problem found"),
+        n => anyhow::bail!("{n} problems found"),
+    }
+}
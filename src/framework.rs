@@ -0,0 +1,158 @@
+use std::path::{Path, PathBuf};
+
+use cargo_util::paths::{copy, create_dir_all, remove_dir_all, write};
+
+use crate::build::CApiConfig;
+use crate::build_targets::BuildTargets;
+use crate::nuget::xml_escape;
+use crate::target::Target;
+
+/// macOS and Mac Catalyst frameworks use the versioned layout, iOS the shallow one
+fn is_versioned(target: &Target) -> bool {
+    target.os == "macos" || target.abi == "macabi"
+}
+
+/// Path of the library inside the bundle, relative to the directory holding the bundle
+pub fn binary_path(capi_config: &CApiConfig, target: &Target) -> PathBuf {
+    let name = &capi_config.library.name;
+    let bundle = PathBuf::from(format!("{name}.framework"));
+    if is_versioned(target) {
+        bundle.join("Versions").join("A").join(name)
+    } else {
+        bundle.join(name)
+    }
+}
+
+/// The install name of the framework library
+///
+/// The iOS frameworks are embedded in the applications, they are looked up through the
+/// rpath of the executable.
+pub fn install_name(capi_config: &CApiConfig, target: &Target, libdir: &Path) -> String {
+    let binary = binary_path(capi_config, target);
+    if target.os == "ios" && target.abi != "macabi" {
+        format!("@rpath/{}", binary.display())
+    } else {
+        libdir.join(binary).display().to_string()
+    }
+}
+
+fn supported_platform(target: &Target) -> &'static str {
+    match (
+        target.os.as_str(),
+        target.abi.as_str(),
+        target.arch.as_str(),
+    ) {
+        ("macos", _, _) | (_, "macabi", _) => "MacOSX",
+        (_, "sim", _) | (_, _, "x86_64") => "iPhoneSimulator",
+        _ => "iPhoneOS",
+    }
+}
+
+fn info_plist(capi_config: &CApiConfig, target: &Target) -> String {
+    let library = &capi_config.library;
+    let name = xml_escape(&library.name);
+    let identifier = xml_escape(
+        library
+            .bundle_identifier
+            .as_deref()
+            .unwrap_or(&library.name),
+    );
+    // The bundle versions are made of numbers only
+    let version = format!(
+        "{}.{}.{}",
+        library.version.major, library.version.minor, library.version.patch
+    );
+    let platform = supported_platform(target);
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>CFBundleDevelopmentRegion</key>
+	<string>en</string>
+	<key>CFBundleExecutable</key>
+	<string>{name}</string>
+	<key>CFBundleIdentifier</key>
+	<string>{identifier}</string>
+	<key>CFBundleInfoDictionaryVersion</key>
+	<string>6.0</string>
+	<key>CFBundleName</key>
+	<string>{name}</string>
+	<key>CFBundlePackageType</key>
+	<string>FMWK</string>
+	<key>CFBundleShortVersionString</key>
+	<string>{version}</string>
+	<key>CFBundleVersion</key>
+	<string>{version}</string>
+	<key>CFBundleSupportedPlatforms</key>
+	<array>
+		<string>{platform}</string>
+	</array>
+</dict>
+</plist>
+"#
+    )
+}
+
+#[cfg(unix)]
+fn symlink(original: &str, link: &Path) -> anyhow::Result<()> {
+    std::os::unix::fs::symlink(original, link)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn symlink(_original: &str, _link: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("the macOS frameworks can only be assembled on unix hosts")
+}
+
+/// Assemble `<name>.framework` in `dir` from the shared library and the headers, replacing
+/// the bundle already there, and return the path of the library in the bundle
+pub fn assemble(
+    capi_config: &CApiConfig,
+    build_targets: &BuildTargets,
+    shared_lib: &Path,
+    dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let target = &build_targets.target;
+    let name = &capi_config.library.name;
+    let binary = dir.join(binary_path(capi_config, target));
+    let contents = binary.parent().unwrap();
+
+    let bundle = dir.join(format!("{name}.framework"));
+    if bundle.exists() {
+        remove_dir_all(&bundle)?;
+    }
+    create_dir_all(contents)?;
+    copy(shared_lib, &binary)?;
+
+    // The headers are included as <name/header.h>, the header subdirectory is dropped
+    let subdirectory = Path::new(&capi_config.header.subdirectory);
+    for (from, to) in build_targets.extra.include.iter() {
+        let to = contents
+            .join("Headers")
+            .join(to.strip_prefix(subdirectory).unwrap_or(to));
+        create_dir_all(to.parent().unwrap())?;
+        copy(from, to)?;
+    }
+
+    let plist = if is_versioned(target) {
+        let resources = contents.join("Resources");
+        create_dir_all(&resources)?;
+        resources.join("Info.plist")
+    } else {
+        contents.join("Info.plist")
+    };
+    write(plist, info_plist(capi_config, target))?;
+
+    if is_versioned(target) {
+        symlink("A", &bundle.join("Versions").join("Current"))?;
+        symlink(&format!("Versions/Current/{name}"), &bundle.join(name))?;
+        symlink("Versions/Current/Resources", &bundle.join("Resources"))?;
+        if contents.join("Headers").is_dir() {
+            symlink("Versions/Current/Headers", &bundle.join("Headers"))?;
+        }
+    }
+
+    Ok(binary)
+}
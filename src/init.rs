@@ -0,0 +1,446 @@
+use std::path::{Path, PathBuf};
+
+use cargo::util::command_prelude::ArgMatchesExt;
+use cargo::Config;
+use cargo_util::paths::{create_dir_all, read, write};
+use clap::ArgMatches;
+use toml_edit::{value, Array, Document, Item, Table};
+
+/// Get the table under `key`, adding it if missing
+fn table_mut<'a>(parent: &'a mut Table, key: &str) -> anyhow::Result<&'a mut Table> {
+    parent
+        .entry(key)
+        .or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        })
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("`{}` is not a table", key))
+}
+
+/// Write a file of the skeleton unless it exists already
+pub(crate) fn create_file(config: &Config, path: &Path, contents: &str) -> anyhow::Result<()> {
+    if path.exists() {
+        config.shell().warn(format!(
+            "{} already exists, leaving it untouched",
+            path.display()
+        ))?;
+        return Ok(());
+    }
+    config
+        .shell()
+        .status("Creating", path.display().to_string())?;
+    create_dir_all(path.parent().unwrap())?;
+    write(path, contents)
+}
+
+fn cbindgen_toml(header_name: &str) -> String {
+    format!(
+        "include_guard = \"{}_H\"\ninclude_version = true\nlanguage = \"C\"\ncpp_compat = true\n",
+        header_name.to_uppercase()
+    )
+}
+
+fn capi_rs(prefix: &str, edition: &str) -> String {
+    let no_mangle = if edition >= "2024" {
+        "#[unsafe(no_mangle)]"
+    } else {
+        "#[no_mangle]"
+    };
+    format!(
+        "//! C-API of the crate, built and installed by cargo-c
+
+/// Return the major version of the library
+{no_mangle}
+pub extern \"C\" fn {prefix}_version_major() -> u32 {{
+    env!(\"CARGO_PKG_VERSION_MAJOR\").parse().unwrap()
+}}
+"
+    )
+}
+
+fn capi_test_rs(prefix: &str, header_name: &str) -> String {
+    format!(
+        "#[cfg(feature = \"capi\")]
+mod capi {{
+    use inline_c::assert_c;
+
+    #[test]
+    fn test_capi() {{
+        (assert_c! {{
+            #include <{header_name}.h>
+
+            int main() {{
+                return {prefix}_version_major() == {}_MAJOR ? 0 : 1;
+            }}
+        }})
+        .success();
+    }}
+}}
+",
+        header_name.to_uppercase()
+    )
+}
+
+/// Add the C-API metadata and a skeleton C-API to an existing crate
+pub fn cinit(config: &Config, args: &ArgMatches) -> anyhow::Result<()> {
+    let manifest_path = args.root_manifest(config)?;
+    let root = manifest_path.parent().unwrap();
+    let mut doc = read(&manifest_path)?.parse::<Document>()?;
+
+    let package = doc
+        .get("package")
+        .ok_or_else(|| anyhow::anyhow!("{} has no [package]", manifest_path.display()))?;
+    let crate_name = package
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("The package has no name"))?
+        .to_owned();
+    let edition = package
+        .get("edition")
+        .and_then(|v| v.as_str())
+        .unwrap_or("2015")
+        .to_owned();
+
+    let lib_name = args
+        .get_one::<String>("library-name")
+        .cloned()
+        .unwrap_or_else(|| crate_name.clone());
+    let header_name = args
+        .get_one::<String>("header-name")
+        .cloned()
+        .unwrap_or_else(|| lib_name.replace('-', "_"));
+    let prefix = lib_name.replace(['-', '.'], "_");
+
+    let manifest = doc.as_table_mut();
+
+    let capi = table_mut(
+        table_mut(table_mut(manifest, "package")?, "metadata")?,
+        "capi",
+    )?;
+    if !capi.is_empty() {
+        config
+            .shell()
+            .warn("package.metadata.capi is already present, leaving it untouched")?;
+    } else {
+        config
+            .shell()
+            .status("Adding", "package.metadata.capi to the manifest")?;
+        let mut header = Table::new();
+        header["name"] = value(&header_name);
+        header["subdirectory"] = value(&lib_name);
+        let mut pkg_config = Table::new();
+        pkg_config["name"] = value(&lib_name);
+        let mut library = Table::new();
+        library["name"] = value(&lib_name);
+        capi.insert("header", Item::Table(header));
+        capi.insert("pkg_config", Item::Table(pkg_config));
+        capi.insert("library", Item::Table(library));
+    }
+
+    let features = table_mut(manifest, "features")?;
+    if !features.contains_key("capi") {
+        features.insert("capi", value(Array::new()));
+    }
+
+    let dev_dependencies = table_mut(manifest, "dev-dependencies")?;
+    if !dev_dependencies.contains_key("inline-c") {
+        dev_dependencies.insert("inline-c", value("0.1"));
+    }
+
+    // cargo-c sets the library types by itself
+    if let Some(lib) = manifest.get_mut("lib").and_then(|v| v.as_table_like_mut()) {
+        if lib.remove("crate-type").is_some() {
+            config.shell().status(
+                "Removing",
+                "the lib crate-type, cargo-c sets it when building",
+            )?;
+        }
+        if lib.is_empty() {
+            manifest.remove("lib");
+        }
+    }
+
+    write(&manifest_path, doc.to_string())?;
+
+    create_file(
+        config,
+        &root.join("cbindgen.toml"),
+        &cbindgen_toml(&header_name),
+    )?;
+    create_file(
+        config,
+        &root.join("src").join("capi.rs"),
+        &capi_rs(&prefix, &edition),
+    )?;
+    create_file(
+        config,
+        &root.join("tests").join("capi.rs"),
+        &capi_test_rs(&prefix, &header_name),
+    )?;
+
+    let lib_rs = root.join("src").join("lib.rs");
+    if lib_rs.exists() {
+        let contents = read(&lib_rs)?;
+        if !contents.contains("mod capi;") {
+            config
+                .shell()
+                .status("Adding", "the capi module to src/lib.rs")?;
+            write(
+                &lib_rs,
+                format!("{contents}\n#[cfg(feature = \"capi\")]\nmod capi;\n"),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The capi metadata recovered from the autotools files
+#[derive(Debug, Default, PartialEq, Eq)]
+struct AutotoolsMetadata {
+    pc_filename: Option<String>,
+    pc_name: Option<String>,
+    description: Option<String>,
+    version: Option<String>,
+    requires: Option<String>,
+    requires_private: Option<String>,
+    header_subdirectory: Option<String>,
+    library_name: Option<String>,
+    library_version: Option<String>,
+}
+
+/// Parse the `Key: value` fields of a .pc.in file
+fn parse_pc_in(metadata: &mut AutotoolsMetadata, pc_in: &str, warnings: &mut Vec<String>) {
+    // Values still referring to configure substitutions cannot be carried over
+    let mut keep = |key: &str, value: &str| {
+        if value.contains('@') {
+            warnings.push(format!("{key} refers to a configure substitution: {value}"));
+            None
+        } else {
+            Some(value.to_owned())
+        }
+    };
+
+    let mut extra_libs = Vec::new();
+    for line in pc_in.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) if !key.contains('=') => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        match key {
+            "Name" => metadata.pc_name = keep(key, value),
+            "Description" => metadata.description = keep(key, value),
+            "Requires" => metadata.requires = keep(key, value),
+            "Requires.private" => metadata.requires_private = keep(key, value),
+            "Cflags" => {
+                metadata.header_subdirectory = value
+                    .split_whitespace()
+                    .find_map(|flag| flag.strip_prefix("-I${includedir}/"))
+                    .map(str::to_owned)
+            }
+            "Libs" => {
+                let libs: Vec<_> = value
+                    .split_whitespace()
+                    .filter_map(|flag| flag.strip_prefix("-l"))
+                    .collect();
+                metadata.library_name = libs.first().and_then(|l| keep(key, l));
+                extra_libs.extend(libs.into_iter().skip(1));
+            }
+            _ => {}
+        }
+    }
+
+    for lib in extra_libs {
+        warnings.push(format!("Libs links {lib} as well, it is not carried over"));
+    }
+}
+
+/// Parse the package version and the libtool version-info from the configure script
+/// and the makefiles
+fn parse_configure(metadata: &mut AutotoolsMetadata, configure: &str) {
+    let m4_define =
+        regex::Regex::new(r"m4_define\(\s*\[?(\w+)\]?\s*,\s*\[?([^\]\)]*)\]?\s*\)").unwrap();
+    let defines: Vec<(String, String)> = m4_define
+        .captures_iter(configure)
+        .map(|c| (c[1].to_owned(), c[2].trim().to_owned()))
+        .collect();
+    let expand = |value: &str| {
+        defines.iter().fold(value.to_owned(), |value, (name, def)| {
+            value.replace(name, def)
+        })
+    };
+
+    let ac_init = regex::Regex::new(r"AC_INIT\(\s*\[?[^\],]*\]?\s*,\s*\[?([^\],\)]+)\]?").unwrap();
+    if let Some(c) = ac_init.captures(configure) {
+        let version = expand(c[1].trim());
+        if semver::Version::parse(&version).is_ok() || version.split('.').count() == 2 {
+            metadata.version = Some(version);
+        }
+    }
+
+    // libtool computes the soname major as current - age
+    let version_info = regex::Regex::new(r"-version-info\s+(\d+):(\d+):(\d+)").unwrap();
+    let lt_var = |name: &str| {
+        regex::Regex::new(&format!(r"(?m)^\s*\w*LT_{name}=\[?(\d+)"))
+            .unwrap()
+            .captures(configure)
+            .and_then(|c| c[1].parse::<u64>().ok())
+    };
+    let (current, revision, age) = match version_info.captures(configure) {
+        Some(c) => (c[1].parse().ok(), c[2].parse().ok(), c[3].parse().ok()),
+        None => (lt_var("CURRENT"), lt_var("REVISION"), lt_var("AGE")),
+    };
+    if let (Some(current), Some(revision), Some(age)) = (current, revision, age) {
+        if current >= age {
+            metadata.library_version = Some(format!("{}.{age}.{revision}", current - age));
+        }
+    }
+}
+
+/// Set `key` in `table` unless it is already set
+fn set_if_missing(table: &mut Table, key: &str, v: &Option<String>) -> bool {
+    match v {
+        Some(v) if !table.contains_key(key) => {
+            table[key] = value(v);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Generate the capi metadata matching an existing .pc.in and configure.ac
+pub fn cimport_autotools(config: &Config, args: &ArgMatches) -> anyhow::Result<()> {
+    let manifest_path = args.root_manifest(config)?;
+    let root = manifest_path.parent().unwrap();
+
+    let pc_in = match args.get_one::<PathBuf>("pc-in") {
+        Some(pc_in) => pc_in.clone(),
+        None => std::fs::read_dir(root)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .find(|path| path.to_string_lossy().ends_with(".pc.in"))
+            .ok_or_else(|| anyhow::anyhow!("No .pc.in file found, use --pc-in"))?,
+    };
+    let configure_ac = args
+        .get_one::<PathBuf>("configure-ac")
+        .cloned()
+        .unwrap_or_else(|| root.join("configure.ac"));
+
+    let mut metadata = AutotoolsMetadata {
+        pc_filename: pc_in
+            .file_name()
+            .and_then(|f| f.to_str())
+            .and_then(|f| f.strip_suffix(".pc.in"))
+            .map(str::to_owned),
+        ..Default::default()
+    };
+    let mut warnings = Vec::new();
+    parse_pc_in(&mut metadata, &read(&pc_in)?, &mut warnings);
+
+    let mut configure = if configure_ac.exists() {
+        read(&configure_ac)?
+    } else {
+        String::new()
+    };
+    // The version-info is usually passed in Makefile.am
+    for makefile in ["Makefile.am", "src/Makefile.am"] {
+        let makefile = root.join(makefile);
+        if makefile.exists() {
+            configure.push_str(&read(&makefile)?);
+        }
+    }
+    parse_configure(&mut metadata, &configure);
+
+    for warning in warnings {
+        config.shell().warn(warning)?;
+    }
+
+    let mut doc = read(&manifest_path)?.parse::<Document>()?;
+    let capi = table_mut(
+        table_mut(table_mut(doc.as_table_mut(), "package")?, "metadata")?,
+        "capi",
+    )?;
+
+    let mut changed = false;
+    let pkg_config = table_mut(capi, "pkg_config")?;
+    changed |= set_if_missing(pkg_config, "filename", &metadata.pc_filename);
+    changed |= set_if_missing(pkg_config, "name", &metadata.pc_name);
+    changed |= set_if_missing(pkg_config, "description", &metadata.description);
+    changed |= set_if_missing(pkg_config, "version", &metadata.version);
+    changed |= set_if_missing(pkg_config, "requires", &metadata.requires);
+    changed |= set_if_missing(pkg_config, "requires_private", &metadata.requires_private);
+    pkg_config.set_implicit(pkg_config.is_empty());
+    let header = table_mut(capi, "header")?;
+    changed |= set_if_missing(header, "subdirectory", &metadata.header_subdirectory);
+    header.set_implicit(header.is_empty());
+    let library = table_mut(capi, "library")?;
+    changed |= set_if_missing(library, "name", &metadata.library_name);
+    changed |= set_if_missing(library, "version", &metadata.library_version);
+    library.set_implicit(library.is_empty());
+
+    if changed {
+        config.shell().status(
+            "Importing",
+            format!("{} into the manifest", pc_in.display()),
+        )?;
+        write(&manifest_path, doc.to_string())?;
+    } else {
+        config
+            .shell()
+            .status("Fresh", "the manifest has all the imported metadata")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn autotools() {
+        let mut metadata = AutotoolsMetadata::default();
+        let mut warnings = Vec::new();
+        parse_pc_in(
+            &mut metadata,
+            "prefix=@prefix@
+includedir=${prefix}/include
+
+Name: foo
+Description: The foo library
+Version: @VERSION@
+Requires: glib-2.0 >= 2.56
+Requires.private: @FOO_REQUIRES@
+Libs: -L${libdir} -lfoo -lm
+Cflags: -I${includedir}/foo-1.0
+",
+            &mut warnings,
+        );
+        parse_configure(
+            &mut metadata,
+            "m4_define([foo_version], [1.4.2])
+AC_INIT([foo], [foo_version], [https://example.com/foo/issues])
+LT_CURRENT=5
+LT_REVISION=2
+LT_AGE=3
+",
+        );
+
+        assert_eq!(
+            metadata,
+            AutotoolsMetadata {
+                pc_filename: None,
+                pc_name: Some("foo".into()),
+                description: Some("The foo library".into()),
+                version: Some("1.4.2".into()),
+                requires: Some("glib-2.0 >= 2.56".into()),
+                requires_private: None,
+                header_subdirectory: Some("foo-1.0".into()),
+                library_name: Some("foo".into()),
+                library_version: Some("2.3.2".into()),
+            }
+        );
+        assert_eq!(warnings.len(), 2);
+    }
+}
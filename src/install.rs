@@ -2,13 +2,13 @@ use clap::ArgMatches;
 use std::path::{Component, Path, PathBuf};
 
 use cargo::core::Workspace;
-use cargo_util::paths::{copy, create_dir_all};
-use semver::Version;
+use cargo_util::paths::{copy, create_dir_all, read, read_bytes, write};
 
 use crate::build::*;
-use crate::build_targets::BuildTargets;
+use crate::build_targets::{shared_lib_affixes, BuildTargets};
+use crate::target::Target;
 
-fn append_to_destdir(destdir: Option<&Path>, path: &Path) -> PathBuf {
+pub(crate) fn append_to_destdir(destdir: Option<&Path>, path: &Path) -> PathBuf {
     if let Some(destdir) = destdir {
         let mut joined = destdir.to_path_buf();
         for component in path.components() {
@@ -23,10 +23,171 @@ fn append_to_destdir(destdir: Option<&Path>, path: &Path) -> PathBuf {
     }
 }
 
+/// Check if the relative path resolves outside the directory it is joined to
+fn escapes_install_dir(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return true,
+            Component::ParentDir => {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+        }
+    }
+    false
+}
+
+/// Make sure nothing gets installed outside the prefix by accident
+fn check_install_paths(
+    ws: &Workspace,
+    paths: &InstallPaths,
+    build_targets: &BuildTargets,
+) -> anyhow::Result<()> {
+    let extra = &build_targets.extra;
+
+    for (from, to) in extra.include.iter().chain(extra.non_include()) {
+        if escapes_install_dir(to) {
+            if paths.allow_outside_prefix {
+                warn(
+                    ws.config(),
+                    format!(
+                        "{} is installed outside its install directory as {}",
+                        from.display(),
+                        to.display()
+                    ),
+                )?;
+            } else {
+                anyhow::bail!(
+                    "{} would be installed outside its install directory as {}, \
+                     pass --allow-outside-prefix to allow it",
+                    from.display(),
+                    to.display()
+                );
+            }
+        }
+    }
+
+    if !paths.allow_outside_prefix {
+        for (name, dir) in paths.dirs() {
+            if !dir.starts_with(&paths.prefix) {
+                anyhow::bail!(
+                    "{name} {} is outside the prefix {}, pass --allow-outside-prefix to allow it",
+                    dir.display(),
+                    paths.prefix.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Variables available to the data file templates as `@NAME@`
+fn template_variables(
+    paths: &InstallPaths,
+    capi_config: &CApiConfig,
+    target: &Target,
+) -> Vec<(String, String)> {
+    let mut vars = vec![("PREFIX".to_string(), paths.prefix.display().to_string())];
+
+    vars.extend(
+        paths
+            .dirs()
+            .iter()
+            .map(|(name, path)| (name.to_uppercase(), path.display().to_string())),
+    );
+
+    vars.push(("NAME".into(), capi_config.library.name.clone()));
+    vars.push(("VERSION".into(), capi_config.library.version.to_string()));
+    vars.extend(target.variables());
+
+    vars
+}
+
+fn gzip(contents: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(contents)?;
+    Ok(encoder.finish()?)
+}
+
+pub(crate) fn substitute_template(buf: &str, vars: &[(String, String)]) -> String {
+    vars.iter().fold(buf.to_string(), |buf, (name, value)| {
+        buf.replace(&format!("@{name}@"), value)
+    })
+}
+
+/// Whether the linker of the target reads GNU ld scripts, ld64 and link.exe do not
+fn uses_gnu_ld(target: &Target) -> bool {
+    !matches!(target.os.as_str(), "macos" | "ios") && target.env != "msvc"
+}
+
+/// GNU ld script fragment adding the static library and the system libraries it needs to
+/// the link, meant to be included by the linker script of the firmware or passed in place
+/// of the library
+fn linker_script(static_lib: &str, libdir: &Path, static_libs: &str) -> String {
+    let system_libs: Vec<_> = static_libs
+        .split_whitespace()
+        .filter(|l| l.starts_with("-l"))
+        .collect();
+    let mut script = format!(
+        "/* Generated by cargo-c, INCLUDE it from the linker script */
+SEARCH_DIR(\"{}\")
+INPUT({static_lib})
+",
+        libdir.display()
+    );
+    if !system_libs.is_empty() {
+        script += &format!("INPUT({})\n", system_libs.join(" "));
+    }
+    script
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::{Path, PathBuf};
 
+    #[test]
+    fn escapes_install_dir() {
+        assert!(!super::escapes_install_dir(Path::new("foo/bar.h")));
+        assert!(!super::escapes_install_dir(Path::new("foo/../bar.h")));
+        assert!(!super::escapes_install_dir(Path::new("./bar.h")));
+        assert!(super::escapes_install_dir(Path::new("../bar.h")));
+        assert!(super::escapes_install_dir(Path::new("foo/../../bar.h")));
+        assert!(super::escapes_install_dir(Path::new("/etc/bar.conf")));
+    }
+
+    #[test]
+    fn linker_script() {
+        assert_eq!(
+            super::linker_script("libfoo.a", Path::new("/usr/lib"), "-lgcc_s -lutil -lc"),
+            "/* Generated by cargo-c, INCLUDE it from the linker script */
+SEARCH_DIR(\"/usr/lib\")
+INPUT(libfoo.a)
+INPUT(-lgcc_s -lutil -lc)
+"
+        );
+    }
+
+    #[test]
+    fn substitute_template() {
+        let vars = vec![
+            ("PREFIX".to_string(), "/usr".to_string()),
+            ("VERSION".to_string(), "1.2.3".to_string()),
+        ];
+
+        assert_eq!(
+            super::substitute_template("ExecStart=@PREFIX@/bin/foo @VERSION@ @UNKNOWN@", &vars),
+            "ExecStart=/usr/bin/foo 1.2.3 @UNKNOWN@"
+        );
+    }
+
     #[test]
     fn append_to_destdir() {
         assert_eq!(
@@ -69,12 +230,49 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn default_prefix() {
+        let capi_config = crate::build::test_capi_config();
+        let windows = crate::target::Target::new("x86_64-pc-windows-msvc").unwrap();
+        let linux = crate::target::Target::new("x86_64-unknown-linux-gnu").unwrap();
+
+        // The platform prefixes are opt-in
+        for target in [&windows, &linux] {
+            assert_eq!(
+                super::default_prefix(&capi_config, target, false),
+                Path::new("/usr/local")
+            );
+        }
+        assert_eq!(
+            super::default_prefix(&capi_config, &linux, true),
+            Path::new("/usr/local")
+        );
+
+        // The same prefix is printed and installed to, an explicit one wins
+        let paths = |args: &[&str]| {
+            let args = crate::cli::subcommand_install("cinstall", "")
+                .get_matches_from([&["cinstall"], args].concat());
+            super::InstallPaths::new("foo", &args, &capi_config, &windows)
+        };
+        assert_eq!(
+            paths(&["--platform-prefix"]).prefix,
+            super::default_prefix(&capi_config, &windows, true)
+        );
+        assert_eq!(
+            paths(&["--platform-prefix", "--prefix", "/opt/foo"]).prefix,
+            Path::new("/opt/foo")
+        );
+    }
 }
 
 pub(crate) enum LibType {
     So,
     Dylib,
     Windows,
+    Wasm,
+    /// The AIX archive holding the shared object
+    Aix,
 }
 
 impl LibType {
@@ -93,6 +291,10 @@ impl LibType {
             | ("illumos", _) => LibType::So,
             ("macos", _) | ("ios", _) => LibType::Dylib,
             ("windows", _) => LibType::Windows,
+            ("aix", _) => LibType::Aix,
+            ("emscripten", _) | ("wasi", _) | ("unknown", _) if target.arch.starts_with("wasm") => {
+                LibType::Wasm
+            }
             _ => unimplemented!("The target {}-{} is not supported yet", os, env),
         }
     }
@@ -105,10 +307,17 @@ pub(crate) struct UnixLibNames {
 }
 
 impl UnixLibNames {
-    pub(crate) fn new(lib_type: LibType, lib_name: &str, lib_version: &Version) -> Option<Self> {
+    pub(crate) fn new(
+        lib_type: LibType,
+        library: &LibraryCApiConfig,
+        target: &Target,
+    ) -> Option<Self> {
+        let lib_name = &library.name;
+        let lib_version = &library.version;
+        let (prefix, suffix) = shared_lib_affixes(library, target);
         match lib_type {
             LibType::So => {
-                let lib = format!("lib{lib_name}.so");
+                let lib = format!("{prefix}{lib_name}{suffix}");
                 let lib_with_major_ver = format!("{}.{}", lib, lib_version.major);
                 let lib_with_full_ver = format!(
                     "{}.{}.{}",
@@ -121,17 +330,17 @@ impl UnixLibNames {
                 })
             }
             LibType::Dylib => {
-                let lib = format!("lib{lib_name}.dylib");
+                let lib = format!("{prefix}{lib_name}{suffix}");
                 let lib_with_major_ver = if lib_version.major == 0 {
                     format!(
-                        "lib{}.{}.{}.dylib",
+                        "{prefix}{}.{}.{}{suffix}",
                         lib_name, lib_version.major, lib_version.minor
                     )
                 } else {
-                    format!("lib{}.{}.dylib", lib_name, lib_version.major)
+                    format!("{prefix}{}.{}{suffix}", lib_name, lib_version.major)
                 };
                 let lib_with_full_ver = format!(
-                    "lib{}.{}.{}.{}.dylib",
+                    "{prefix}{}.{}.{}.{}{suffix}",
                     lib_name, lib_version.major, lib_version.minor, lib_version.patch
                 );
                 Some(Self {
@@ -140,40 +349,362 @@ impl UnixLibNames {
                     with_full_ver: lib_with_full_ver,
                 })
             }
-            LibType::Windows => None,
+            LibType::Windows | LibType::Wasm | LibType::Aix => None,
         }
     }
 
-    fn links(&self, install_path_lib: &Path) {
+    fn links(&self, runtime_lib: &Path, devel_lib: &Path) {
         let mut ln_sf = std::process::Command::new("ln");
         ln_sf.arg("-sf");
         ln_sf
             .arg(&self.with_full_ver)
-            .arg(install_path_lib.join(&self.with_major_ver));
+            .arg(runtime_lib.join(&self.with_major_ver));
         let _ = ln_sf.status().unwrap();
 
         let mut ln_sf = std::process::Command::new("ln");
         ln_sf.arg("-sf");
         ln_sf
             .arg(&self.with_full_ver)
-            .arg(install_path_lib.join(&self.canonical));
+            .arg(devel_lib.join(&self.canonical));
         let _ = ln_sf.status().unwrap();
     }
 
+    /// Install the shared library in `runtime_lib`, the unversioned link used only when
+    /// linking goes in `devel_lib`
     pub(crate) fn install(
         &self,
         capi_config: &CApiConfig,
         shared_lib: &Path,
-        install_path_lib: &Path,
-    ) -> anyhow::Result<()> {
+        runtime_lib: &Path,
+        devel_lib: &Path,
+    ) -> anyhow::Result<PathBuf> {
         if capi_config.library.versioning {
-            copy(shared_lib, install_path_lib.join(&self.with_full_ver))?;
-            self.links(install_path_lib);
+            let installed = runtime_lib.join(&self.with_full_ver);
+            copy(shared_lib, &installed)?;
+            if devel_lib != runtime_lib {
+                create_dir_all(devel_lib)?;
+            }
+            self.links(runtime_lib, devel_lib);
+            Ok(installed)
         } else {
-            copy(shared_lib, install_path_lib.join(&self.canonical))?;
+            let installed = runtime_lib.join(&self.canonical);
+            copy(shared_lib, &installed)?;
+            Ok(installed)
         }
+    }
+}
+
+/// Run a tool rewriting an installed binary, `var` overrides the program
+fn run_tool(var: &str, program: &str, args: &[&std::ffi::OsStr]) -> anyhow::Result<()> {
+    let mut cmd =
+        std::process::Command::new(std::env::var_os(var).unwrap_or_else(|| program.into()));
+    cmd.args(args);
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {:?}: {}", cmd, e))?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "Command failed {:?}: {}",
+            cmd,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Make the installed shared library loadable from any location
+///
+/// The Mach-O install name becomes `@rpath/<name>`, as delocate does, the executables find
+/// the library through their own rpath. The ELF libraries only carry their soname, unless
+/// they link the shared Rust standard library, which is then looked up next to them.
+fn relocate_shared_lib(
+    ws: &Workspace,
+    capi_config: &CApiConfig,
+    build_targets: &BuildTargets,
+    installed_lib: &Path,
+    install_name: &Path,
+) -> anyhow::Result<()> {
+    let target = &build_targets.target;
+    if capi_config.library.module {
+        return Ok(());
+    }
+
+    if target.os == "macos" || target.os == "ios" {
+        ws.config()
+            .shell()
+            .status("Relocating", installed_lib.display())?;
+        let id = Path::new("@rpath").join(install_name);
+        run_tool(
+            "INSTALL_NAME_TOOL",
+            "install_name_tool",
+            &["-id".as_ref(), id.as_os_str(), installed_lib.as_os_str()],
+        )?;
+    } else if build_targets.rust_std.is_some() {
+        ws.config()
+            .shell()
+            .status("Relocating", installed_lib.display())?;
+        run_tool(
+            "PATCHELF",
+            "patchelf",
+            &[
+                "--set-rpath".as_ref(),
+                "$ORIGIN".as_ref(),
+                installed_lib.as_os_str(),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Point the `prefix` of a pkg-config file to the prefix relative to `${pcfiledir}`
+fn relocatable_pc(contents: &str, paths: &InstallPaths) -> String {
+    let prefix = Path::new("${pcfiledir}").join(relative_path(&paths.pkgconfigdir, &paths.prefix));
+    contents
+        .lines()
+        .map(|line| {
+            if line.starts_with("prefix=") {
+                format!("prefix={}\n", prefix.display())
+            } else {
+                format!("{line}\n")
+            }
+        })
+        .collect()
+}
+
+/// Re-apply the code signature of an installed Mach-O binary
+///
+/// The kernel kills arm64 processes loading a library whose signature got
+/// invalidated by editing the binary, so it is re-signed ad-hoc unless an
+/// identity is configured for real signing.
+fn codesign(
+    ws: &Workspace,
+    capi_config: &CApiConfig,
+    build_targets: &BuildTargets,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let identity = capi_config.library.codesign_identity.as_deref();
+    // `codesign` is only available on macOS hosts
+    if identity.is_none() && (build_targets.target.arch != "aarch64" || !cfg!(target_os = "macos"))
+    {
+        return Ok(());
+    }
+
+    ws.config()
+        .shell()
+        .status("Signing", path.file_name().unwrap().to_string_lossy())?;
+
+    let mut cmd = std::process::Command::new(
+        std::env::var_os("CODESIGN").unwrap_or_else(|| "codesign".into()),
+    );
+    cmd.arg("--force")
+        .arg("--sign")
+        .arg(identity.unwrap_or("-"))
+        .arg(path);
+    let out = cmd.output()?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "Command failed {:?}: {}",
+            cmd,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+pub(crate) fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let to = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            copy(entry.path(), to)?;
+        }
+    }
+    Ok(())
+}
+
+/// The files and directories created by `cinstall`, listed in the install manifest
+/// `cuninstall` reads back
+struct Installed<'a> {
+    paths: &'a InstallPaths,
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+}
+
+impl<'a> Installed<'a> {
+    fn new(paths: &'a InstallPaths) -> Self {
+        Installed {
+            paths,
+            files: Vec::new(),
+            dirs: Vec::new(),
+        }
+    }
+
+    /// Create the missing directories of `dir` with the configured permissions
+    fn create_dir_all(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let missing: Vec<_> = dir.ancestors().take_while(|d| !d.exists()).collect();
+        for dir in missing.into_iter().rev() {
+            std::fs::create_dir(dir)
+                .map_err(|e| anyhow::anyhow!("Cannot create {}: {}", dir.display(), e))?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let permissions = std::fs::Permissions::from_mode(self.paths.dir_mode);
+                std::fs::set_permissions(dir, permissions)?;
+            }
+            self.dirs.push(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, from: &Path, to: PathBuf) -> anyhow::Result<()> {
+        copy(from, &to)?;
+        self.record(to);
         Ok(())
     }
+
+    /// Copy a directory tree, merging it with the one already installed, and record only
+    /// the files and the directories it adds
+    fn copy_tree(&mut self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        self.create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            let to = to.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                self.copy_tree(&entry.path(), &to)?;
+            } else {
+                self.copy(&entry.path(), to)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, to: PathBuf, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        write(&to, contents)?;
+        self.record(to);
+        Ok(())
+    }
+
+    fn record(&mut self, path: PathBuf) {
+        if !self.files.contains(&path) {
+            self.files.push(path);
+        }
+    }
+
+    /// Record the files and the links of a directory tree, e.g. a bundle
+    fn record_tree(&mut self, dir: &Path) -> anyhow::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.record_tree(&entry.path())?;
+            } else {
+                self.record(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the manifest in `<libdir>/cargo-c/<name>.json`
+    ///
+    /// The paths are the ones of the installed system, the staging directory they were
+    /// written to is recorded as `devel` or `runtime`.
+    fn write_manifest(mut self, capi_config: &CApiConfig, target: &Target) -> anyhow::Result<()> {
+        let paths = self.paths;
+        let unstage = |path: &Path| {
+            for (kind, destdir) in [
+                ("runtime", paths.runtime_destdir()),
+                ("devel", paths.devel_destdir()),
+            ] {
+                if let Some(rel) = destdir.and_then(|d| path.strip_prefix(d).ok()) {
+                    return (kind, Path::new("/").join(rel));
+                }
+            }
+            ("devel", path.to_path_buf())
+        };
+
+        let manifest = install_manifest(paths, &capi_config.library.name);
+        self.create_dir_all(manifest.parent().unwrap())?;
+
+        let entries = |paths: &[PathBuf]| -> Vec<_> {
+            paths
+                .iter()
+                .map(|f| {
+                    let (kind, path) = unstage(f);
+                    serde_json::json!({ "path": path, "kind": kind })
+                })
+                .collect()
+        };
+        let json = serde_json::json!({
+            "name": capi_config.library.name,
+            "version": capi_config.library.version.to_string(),
+            "target": target.triple,
+            "files": entries(&self.files),
+            // The staging directories themselves are not part of the install
+            "directories": entries(
+                &self
+                    .dirs
+                    .iter()
+                    .filter(|d| {
+                        ![paths.runtime_destdir(), paths.devel_destdir()]
+                            .iter()
+                            .flatten()
+                            .any(|destdir| destdir.starts_with(d))
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>()
+            ),
+        });
+
+        write(manifest, serde_json::to_string_pretty(&json)? + "\n")
+    }
+}
+
+/// Where the install manifest of the library is written, in the staging directory
+fn install_manifest(paths: &InstallPaths, name: &str) -> PathBuf {
+    append_to_destdir(paths.devel_destdir(), &paths.libdir)
+        .join("cargo-c")
+        .join(format!("{name}.json"))
+}
+
+/// Install the separate debug information next to the installed shared library
+///
+/// The PDB keeps its name, it is recorded in the DLL; the dSYM bundle is named after the
+/// library for the debuggers to find it. Nothing is installed if the profile did not
+/// produce any.
+fn install_debug_info(
+    ws: &Workspace,
+    capi_config: &CApiConfig,
+    build_targets: &BuildTargets,
+    installed_lib: &Path,
+    installed: &mut Installed,
+) -> anyhow::Result<()> {
+    let debug_info = match &build_targets.debug_info {
+        Some(debug_info) if capi_config.install.debug_info && debug_info.exists() => debug_info,
+        _ => return Ok(()),
+    };
+
+    ws.config()
+        .shell()
+        .status("Installing", "debug information")?;
+    let dir = installed_lib.parent().unwrap();
+    if debug_info.is_dir() {
+        let name = format!(
+            "{}.dSYM",
+            installed_lib.file_name().unwrap().to_string_lossy()
+        );
+        let to = dir.join(name);
+        if to.exists() {
+            cargo_util::paths::remove_dir_all(&to)?;
+        }
+        copy_dir(debug_info, &to)?;
+        installed.record_tree(&to)
+    } else {
+        installed.copy(debug_info, dir.join(debug_info.file_name().unwrap()))
+    }
 }
 
 pub fn cinstall(ws: &Workspace, packages: &[CPackage]) -> anyhow::Result<()> {
@@ -182,54 +713,126 @@ pub fn cinstall(ws: &Workspace, packages: &[CPackage]) -> anyhow::Result<()> {
         let capi_config = &pkg.capi_config;
         let build_targets = &pkg.build_targets;
 
-        let destdir = &paths.destdir;
+        let devel = paths.devel_destdir();
+        let runtime = paths.runtime_destdir();
+
+        check_install_paths(ws, paths, build_targets)?;
+
+        let mut installed = Installed::new(paths);
 
-        let mut install_path_lib = paths.libdir.clone();
+        if paths.relocatable && capi_config.runtime_dirs.header.is_some() {
+            warn(
+                ws.config(),
+                format!(
+                    "the paths {} defines in {} stay absolute in a relocatable install",
+                    capi_config.library.name,
+                    capi_config.runtime_dirs.header.as_deref().unwrap()
+                ),
+            )?;
+        }
+
+        let mut libdir = paths.libdir.clone();
         if let Some(subdir) = &capi_config.library.install_subdir {
-            install_path_lib.push(subdir);
+            libdir.push(subdir);
         }
 
-        let install_path_lib = append_to_destdir(destdir.as_deref(), &install_path_lib);
-        let install_path_pc = append_to_destdir(destdir.as_deref(), &paths.pkgconfigdir);
-        let install_path_include = append_to_destdir(destdir.as_deref(), &paths.includedir);
-        let install_path_data = append_to_destdir(destdir.as_deref(), &paths.datadir);
+        let install_path_lib = append_to_destdir(devel, &libdir);
+        let runtime_path_lib = append_to_destdir(runtime, &libdir);
+        let install_path_pc = append_to_destdir(devel, &paths.pkgconfigdir);
 
-        create_dir_all(&install_path_lib)?;
-        create_dir_all(&install_path_pc)?;
+        if capi_config.pkg_config.install {
+            installed.create_dir_all(&install_path_pc)?;
 
-        ws.config()
-            .shell()
-            .status("Installing", "pkg-config file")?;
+            ws.config()
+                .shell()
+                .status("Installing", "pkg-config file")?;
 
-        copy(
-            &build_targets.pc,
-            install_path_pc.join(build_targets.pc.file_name().unwrap()),
-        )?;
+            for pc in std::iter::once(&build_targets.pc).chain(build_targets.pc_modules.iter()) {
+                let to = install_path_pc.join(pc.file_name().unwrap());
+                if paths.relocatable {
+                    installed.write(to, relocatable_pc(&read(pc)?, paths))?;
+                } else {
+                    installed.copy(pc, to)?;
+                }
+            }
+        }
 
         if capi_config.header.enabled {
             ws.config().shell().status("Installing", "header file")?;
             for (from, to) in build_targets.extra.include.iter() {
-                let to = install_path_include.join(to);
-                create_dir_all(to.parent().unwrap())?;
-                copy(from, to)?;
+                let to = append_to_destdir(devel, &paths.includedir.join(to));
+                installed.create_dir_all(to.parent().unwrap())?;
+                installed.copy(from, to)?;
             }
         }
 
-        if !build_targets.extra.data.is_empty() {
-            ws.config().shell().status("Installing", "data file")?;
-            for (from, to) in build_targets.extra.data.iter() {
-                let to = install_path_data.join(to);
-                create_dir_all(to.parent().unwrap())?;
-                copy(from, to)?;
+        let vars = template_variables(paths, capi_config, &build_targets.target);
+        let extra = &build_targets.extra;
+        for (what, files, dir, compress) in [
+            ("data file", &extra.data, &paths.datadir, false),
+            ("data file", &extra.dataroot, &paths.datarootdir, false),
+            (
+                "systemd unit",
+                &extra.systemd,
+                &paths.systemdsystemunitdir,
+                false,
+            ),
+            ("udev rule", &extra.udev, &paths.udevrulesdir, false),
+            (
+                "dbus service file",
+                &extra.dbus,
+                &paths.dbusservicedir,
+                false,
+            ),
+            (
+                "manual page",
+                &extra.man,
+                &paths.mandir,
+                capi_config.install.man_compress,
+            ),
+            ("documentation", &extra.doc, &paths.docdir, false),
+        ] {
+            if files.is_empty() {
+                continue;
+            }
+            ws.config().shell().status("Installing", what)?;
+            for (from, to) in files.iter() {
+                // An absolute destination, allowed by --allow-outside-prefix, is staged too
+                let to = append_to_destdir(runtime, &dir.join(to));
+                installed.create_dir_all(to.parent().unwrap())?;
+                if compress {
+                    let contents = if extra.templates.contains(from) {
+                        substitute_template(&read(from)?, &vars).into_bytes()
+                    } else {
+                        read_bytes(from)?
+                    };
+                    let mut to = to.into_os_string();
+                    to.push(".gz");
+                    installed.write(PathBuf::from(to), gzip(&contents)?)?;
+                } else if extra.templates.contains(from) {
+                    installed.write(to, substitute_template(&read(from)?, &vars))?;
+                } else {
+                    installed.copy(from, to)?;
+                }
             }
         }
 
         if let Some(ref static_lib) = build_targets.static_lib {
             ws.config().shell().status("Installing", "static library")?;
-            copy(
+            installed.create_dir_all(&install_path_lib)?;
+            installed.copy(
                 static_lib,
                 install_path_lib.join(static_lib.file_name().unwrap()),
             )?;
+
+            if capi_config.library.linker_script && uses_gnu_ld(&build_targets.target) {
+                ws.config().shell().status("Installing", "linker script")?;
+                let file_name = static_lib.file_name().unwrap().to_string_lossy();
+                installed.write(
+                    install_path_lib.join(format!("lib{}.ld", capi_config.library.name)),
+                    linker_script(&file_name, &libdir, pkg.static_libs()),
+                )?;
+            }
         }
 
         if let Some(ref shared_lib) = build_targets.shared_lib {
@@ -237,36 +840,354 @@ pub fn cinstall(ws: &Workspace, packages: &[CPackage]) -> anyhow::Result<()> {
 
             let lib_name = &capi_config.library.name;
             let lib_type = LibType::from_build_targets(build_targets);
+            installed.create_dir_all(&runtime_path_lib)?;
             match lib_type {
+                LibType::Dylib if capi_config.library.framework => {
+                    let binary = crate::framework::assemble(
+                        capi_config,
+                        build_targets,
+                        shared_lib,
+                        &runtime_path_lib,
+                    )?;
+                    if paths.relocatable {
+                        let install_name =
+                            crate::framework::binary_path(capi_config, &build_targets.target);
+                        relocate_shared_lib(
+                            ws,
+                            capi_config,
+                            build_targets,
+                            &binary,
+                            &install_name,
+                        )?;
+                    }
+                    codesign(ws, capi_config, build_targets, &binary)?;
+                    let bundle = runtime_path_lib.join(format!("{lib_name}.framework"));
+                    installed.record_tree(&bundle)?;
+                    install_debug_info(ws, capi_config, build_targets, &bundle, &mut installed)?;
+                }
                 LibType::So | LibType::Dylib => {
-                    let lib = UnixLibNames::new(lib_type, lib_name, &capi_config.library.version)
-                        .unwrap();
-                    lib.install(capi_config, shared_lib, &install_path_lib)?;
+                    let is_dylib = matches!(lib_type, LibType::Dylib);
+                    let lib =
+                        UnixLibNames::new(lib_type, &capi_config.library, &build_targets.target)
+                            .unwrap();
+                    if capi_config.library.versioning {
+                        installed.create_dir_all(&install_path_lib)?;
+                    }
+                    let installed_lib = lib.install(
+                        capi_config,
+                        shared_lib,
+                        &runtime_path_lib,
+                        &install_path_lib,
+                    )?;
+                    installed.record(installed_lib.clone());
+                    if capi_config.library.versioning {
+                        installed.record(runtime_path_lib.join(&lib.with_major_ver));
+                        installed.record(install_path_lib.join(&lib.canonical));
+                    }
+                    if paths.relocatable {
+                        let install_name = if capi_config.library.versioning {
+                            &lib.with_major_ver
+                        } else {
+                            &lib.canonical
+                        };
+                        relocate_shared_lib(
+                            ws,
+                            capi_config,
+                            build_targets,
+                            &installed_lib,
+                            Path::new(install_name),
+                        )?;
+                    }
+                    if is_dylib {
+                        codesign(ws, capi_config, build_targets, &installed_lib)?;
+                        install_debug_info(
+                            ws,
+                            capi_config,
+                            build_targets,
+                            &installed_lib,
+                            &mut installed,
+                        )?;
+                    }
                 }
                 LibType::Windows => {
                     let lib_name = shared_lib.file_name().unwrap();
 
-                    if capi_config.library.install_subdir.is_none() {
-                        let install_path_bin = append_to_destdir(destdir.as_deref(), &paths.bindir);
-                        create_dir_all(&install_path_bin)?;
+                    let installed_lib = if capi_config.library.install_subdir.is_none()
+                        && !capi_config.library.module
+                    {
+                        let install_path_bin = append_to_destdir(runtime, &paths.bindir);
+                        installed.create_dir_all(&install_path_bin)?;
 
-                        copy(shared_lib, install_path_bin.join(lib_name))?;
+                        install_path_bin.join(lib_name)
                     } else {
                         // We assume they are plugins, install them in the custom libdir path
-                        copy(shared_lib, install_path_lib.join(lib_name))?;
+                        runtime_path_lib.join(lib_name)
+                    };
+                    installed.copy(shared_lib, installed_lib.clone())?;
+                    install_debug_info(
+                        ws,
+                        capi_config,
+                        build_targets,
+                        &installed_lib,
+                        &mut installed,
+                    )?;
+
+                    installed.create_dir_all(&install_path_lib)?;
+
+                    let def = build_targets.def.iter().filter(|_| capi_config.def.install);
+                    for file in build_targets.impl_lib.iter().chain(def) {
+                        installed.copy(file, install_path_lib.join(file.file_name().unwrap()))?;
+                    }
+                }
+                // The consumers link the archive, the loader finds the member it records
+                LibType::Aix => {
+                    installed.copy(
+                        shared_lib,
+                        runtime_path_lib.join(shared_lib.file_name().unwrap()),
+                    )?;
+                }
+                LibType::Wasm => {
+                    installed.copy(
+                        shared_lib,
+                        runtime_path_lib.join(shared_lib.file_name().unwrap()),
+                    )?;
+                    if capi_config.js.enabled {
+                        for ext in ["js", "d.ts"] {
+                            let glue = shared_lib.with_extension(ext);
+                            installed
+                                .copy(&glue, runtime_path_lib.join(glue.file_name().unwrap()))?;
+                        }
                     }
+                }
+            }
+        }
 
-                    let impl_lib = build_targets.impl_lib.as_ref().unwrap();
-                    let impl_lib_name = impl_lib.file_name().unwrap();
-                    copy(impl_lib, install_path_lib.join(impl_lib_name))?;
-                    let def = build_targets.def.as_ref().unwrap();
-                    let def_name = def.file_name().unwrap();
-                    copy(def, install_path_lib.join(def_name))?;
+        if !build_targets.bins.is_empty() {
+            ws.config().shell().status("Installing", "binaries")?;
+            // Next to the DLL on Windows, the other targets find the libraries through
+            // the rpath relative to bindir
+            let install_path_bin = append_to_destdir(runtime, &paths.bindir);
+            installed.create_dir_all(&install_path_bin)?;
+            for bin in build_targets.bins.iter() {
+                installed.copy(bin, install_path_bin.join(bin.file_name().unwrap()))?;
+            }
+        }
+
+        if let Some(ref rust_std) = build_targets.rust_std {
+            ws.config()
+                .shell()
+                .status("Installing", "Rust standard library")?;
+            // Next to the shared library, which the loader finds there
+            let dir = match LibType::from_build_targets(build_targets) {
+                LibType::Windows if capi_config.library.install_subdir.is_none() => {
+                    append_to_destdir(runtime, &paths.bindir)
                 }
+                _ => runtime_path_lib.clone(),
+            };
+            installed.copy(rust_std, dir.join(rust_std.file_name().unwrap()))?;
+        }
+
+        if let Some(ref rustlib) = build_targets.rustlib {
+            ws.config().shell().status("Installing", "rlib")?;
+            // Merged with the rustlib directories of the other targets
+            let dir = append_to_destdir(devel, &paths.libdir).join("rustlib");
+            installed.copy_tree(rustlib, &dir)?;
+        }
+
+        installed.write_manifest(capi_config, &build_targets.target)?;
+    }
+
+    Ok(())
+}
+
+/// Remove the directories left empty by removing `files`, up to the install directories
+fn remove_empty_dirs(files: &[PathBuf], install_dirs: &[PathBuf]) {
+    let mut dirs: Vec<&Path> = files.iter().filter_map(|f| f.parent()).collect();
+    // The deepest directories first
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    dirs.dedup();
+    for dir in dirs {
+        for dir in dir.ancestors() {
+            if install_dirs.iter().any(|d| d.starts_with(dir)) || std::fs::remove_dir(dir).is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// Remove the files `cinstall` installed, as listed in the install manifests
+pub fn cuninstall(ws: &Workspace, args: &ArgMatches) -> anyhow::Result<()> {
+    use cargo::util::command_prelude::ArgMatchesExt;
+    use cargo::util::interning::InternedString;
+
+    let config = ws.config();
+    let rustc = config.load_global_rustc(Some(ws))?;
+    let dry_run = args.get_flag("dry-run");
+    let capi_feature = InternedString::new("capi");
+    let requested: Vec<_> = args
+        .packages_from_flags()?
+        .get_packages(ws)?
+        .iter()
+        .map(|p| p.package_id())
+        .collect();
+
+    for target in requested_targets(config, args, &rustc.host)? {
+        let target = Target::new(&target)?;
+        for pkg in ws.members().filter(|m| {
+            m.library().is_some()
+                && m.summary().features().contains_key(&capi_feature)
+                && requested.contains(&m.package_id())
+        }) {
+            let capi_config = load_manifest_capi_config(pkg, Some(&target), selected_preset(args))?;
+            let name = &capi_config.library.name;
+            let paths = InstallPaths::new(name, args, &capi_config, &target);
+            let manifest = install_manifest(&paths, name);
+            if !manifest.is_file() {
+                warn(
+                    config,
+                    format!(
+                        "{name} is not installed, there is no install manifest in {}",
+                        manifest.display()
+                    ),
+                )?;
+                continue;
+            }
+
+            let json: serde_json::Value = serde_json::from_str(&read(&manifest)?)?;
+            let entries = |key: &str| -> anyhow::Result<Vec<PathBuf>> {
+                let mut entries = Vec::new();
+                for entry in json[key].as_array().into_iter().flatten() {
+                    let (path, kind) = match (entry["path"].as_str(), entry["kind"].as_str()) {
+                        (Some(path), Some(kind)) => (path, kind),
+                        _ => anyhow::bail!("Malformed install manifest {}", manifest.display()),
+                    };
+                    let destdir = match kind {
+                        "runtime" => paths.runtime_destdir(),
+                        _ => paths.devel_destdir(),
+                    };
+                    entries.push(append_to_destdir(destdir, Path::new(path)));
+                }
+                Ok(entries)
+            };
+            let mut files = entries("files")?;
+            // The manifests written before the directories were recorded have none
+            let mut dirs = entries("directories")?;
+            files.push(manifest);
+
+            let status = if dry_run { "Would remove" } else { "Removing" };
+            for file in files.iter() {
+                // The links are removed as well, dangling or not
+                if file.symlink_metadata().is_err() {
+                    continue;
+                }
+                config.shell().status(status, file.display())?;
+                if !dry_run {
+                    std::fs::remove_file(file)?;
+                }
+            }
+
+            if !dry_run {
+                let install_dirs: Vec<_> = paths
+                    .dirs()
+                    .into_iter()
+                    .flat_map(|(_, dir)| {
+                        [
+                            append_to_destdir(paths.devel_destdir(), dir),
+                            append_to_destdir(paths.runtime_destdir(), dir),
+                        ]
+                    })
+                    .chain([
+                        append_to_destdir(paths.devel_destdir(), &paths.datarootdir),
+                        append_to_destdir(paths.runtime_destdir(), &paths.datarootdir),
+                    ])
+                    .collect();
+                remove_empty_dirs(&files, &install_dirs);
+
+                // The directories cinstall created, the deepest first, if nothing else
+                // was installed in them
+                dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+                for dir in dirs.iter() {
+                    if dir.is_dir() && std::fs::remove_dir(dir).is_ok() {
+                        config.shell().status(status, dir.display())?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The prefix used without `--prefix`, `/usr/local` unless `platform` is set
+///
+/// When installing for the host, Windows gets `%ProgramFiles%\<name>` (or the per-user
+/// `%LOCALAPPDATA%\Programs\<name>`) and macOS the Homebrew prefix if there is one, where
+/// the Homebrew pkg-config looks. Everything else, cross builds included, gets `/usr/local`.
+pub fn default_prefix(capi_config: &CApiConfig, target: &Target, platform: bool) -> PathBuf {
+    let name = &capi_config.library.name;
+    if !platform {
+        return PathBuf::from("/usr/local");
+    }
+
+    if cfg!(windows) && target.os == "windows" {
+        if let Some(dir) = std::env::var_os("ProgramFiles") {
+            return PathBuf::from(dir).join(name);
+        }
+        if let Some(dir) = std::env::var_os("LOCALAPPDATA") {
+            return PathBuf::from(dir).join("Programs").join(name);
+        }
+    }
+
+    if cfg!(target_os = "macos") && target.os == "macos" {
+        if let Some(dir) = std::env::var_os("HOMEBREW_PREFIX") {
+            return PathBuf::from(dir);
+        }
+        // The default prefix of Homebrew on Apple silicon, /usr/local on Intel
+        let homebrew = Path::new("/opt/homebrew");
+        if target.arch == "aarch64" && homebrew.join("bin").join("brew").exists() {
+            return homebrew.to_path_buf();
+        }
+    }
+
+    PathBuf::from("/usr/local")
+}
+
+/// Print the default prefix of the requested packages and targets
+pub fn cprint_default_prefix(ws: &Workspace, args: &ArgMatches) -> anyhow::Result<()> {
+    use cargo::util::command_prelude::ArgMatchesExt;
+    use cargo::util::interning::InternedString;
+
+    let config = ws.config();
+    let rustc = config.load_global_rustc(Some(ws))?;
+    let capi_feature = InternedString::new("capi");
+    let requested: Vec<_> = args
+        .packages_from_flags()?
+        .get_packages(ws)?
+        .iter()
+        .map(|p| p.package_id())
+        .collect();
+
+    let mut prefixes = Vec::new();
+    for target in requested_targets(config, args, &rustc.host)? {
+        let target = Target::new(&target)?;
+        for pkg in ws.members().filter(|m| {
+            m.library().is_some()
+                && m.summary().features().contains_key(&capi_feature)
+                && requested.contains(&m.package_id())
+        }) {
+            let capi_config = load_manifest_capi_config(pkg, Some(&target), selected_preset(args))?;
+            let prefix = default_prefix(&capi_config, &target, args.get_flag("platform_prefix"));
+            if !prefixes.contains(&prefix) {
+                prefixes.push(prefix);
             }
         }
     }
 
+    for prefix in prefixes {
+        println!("{}", prefix.display());
+    }
+
     Ok(())
 }
 
@@ -274,25 +1195,63 @@ pub fn cinstall(ws: &Workspace, packages: &[CPackage]) -> anyhow::Result<()> {
 pub struct InstallPaths {
     pub subdir_name: PathBuf,
     pub destdir: Option<PathBuf>,
+    /// Staging directory of the headers, pkg-config files and link-time libraries
+    pub devel_prefix: Option<PathBuf>,
+    /// Staging directory of the shared libraries and the data files
+    pub runtime_prefix: Option<PathBuf>,
     pub prefix: PathBuf,
     pub libdir: PathBuf,
     pub includedir: PathBuf,
+    pub datarootdir: PathBuf,
     pub datadir: PathBuf,
     pub bindir: PathBuf,
     pub pkgconfigdir: PathBuf,
+    pub systemdsystemunitdir: PathBuf,
+    pub udevrulesdir: PathBuf,
+    pub dbusservicedir: PathBuf,
+    pub mandir: PathBuf,
+    pub docdir: PathBuf,
+    pub allow_outside_prefix: bool,
+    /// The libraries of each target go in `<libdir>/<triple>`, to install several targets
+    /// in the same prefix
+    pub target_subdir: bool,
+    /// Permissions of the directories created when installing
+    pub dir_mode: u32,
+    /// Rewrite the absolute paths of the installed files, see `relocate_shared_lib`
+    pub relocatable: bool,
+}
+
+/// Whether the libraries of the target are installed in `<libdir>/<triple>`
+pub(crate) fn uses_target_subdir(args: &ArgMatches, target: &Target) -> bool {
+    // Bare metal SDKs and the wasi-sdk sysroots ship the libraries of each target side by
+    // side
+    args.get_flag("target_subdir") || target.os == "none" || target.os == "wasi"
 }
 
 impl InstallPaths {
-    pub fn new(_name: &str, args: &ArgMatches, capi_config: &CApiConfig) -> Self {
+    pub fn new(name: &str, args: &ArgMatches, capi_config: &CApiConfig, target: &Target) -> Self {
+        let target_subdir = uses_target_subdir(args, target);
+        // The explicit directories are used as passed, only the default ones get the subdirectory
+        let per_target = |dir: PathBuf| {
+            if target_subdir {
+                dir.join(&target.triple)
+            } else {
+                dir
+            }
+        };
         let destdir = args.get_one::<PathBuf>("destdir").map(PathBuf::from);
+        let devel_prefix = args.get_one::<PathBuf>("devel_prefix").map(PathBuf::from);
+        let runtime_prefix = args.get_one::<PathBuf>("runtime_prefix").map(PathBuf::from);
         let prefix = args
             .get_one::<PathBuf>("prefix")
             .map(PathBuf::from)
-            .unwrap_or_else(|| "/usr/local".into());
+            .unwrap_or_else(|| {
+                default_prefix(capi_config, target, args.get_flag("platform_prefix"))
+            });
         let libdir = args
             .get_one::<PathBuf>("libdir")
             .map(PathBuf::from)
-            .unwrap_or_else(|| prefix.join("lib"));
+            .unwrap_or_else(|| per_target(prefix.join("lib")));
         let includedir = args
             .get_one::<PathBuf>("includedir")
             .map(PathBuf::from)
@@ -311,21 +1270,79 @@ impl InstallPaths {
         let bindir = args
             .get_one::<PathBuf>("bindir")
             .map(PathBuf::from)
-            .unwrap_or_else(|| prefix.join("bin"));
+            .unwrap_or_else(|| per_target(prefix.join("bin")));
         let pkgconfigdir = args
             .get_one::<PathBuf>("pkgconfigdir")
             .map(PathBuf::from)
             .unwrap_or_else(|| libdir.join("pkgconfig"));
+        let systemdsystemunitdir = args
+            .get_one::<PathBuf>("systemdsystemunitdir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| prefix.join("lib/systemd/system"));
+        let udevrulesdir = args
+            .get_one::<PathBuf>("udevrulesdir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| prefix.join("lib/udev/rules.d"));
+        let dbusservicedir = args
+            .get_one::<PathBuf>("dbusservicedir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| datarootdir.join("dbus-1/services"));
+        let mandir = args
+            .get_one::<PathBuf>("mandir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| datarootdir.join("man"));
+        let docdir = args
+            .get_one::<PathBuf>("docdir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| datadir.join("doc").join(name));
 
         InstallPaths {
             subdir_name,
             destdir,
+            devel_prefix,
+            runtime_prefix,
             prefix,
             libdir,
             includedir,
+            datarootdir,
             datadir,
             bindir,
             pkgconfigdir,
+            systemdsystemunitdir,
+            udevrulesdir,
+            dbusservicedir,
+            mandir,
+            docdir,
+            allow_outside_prefix: args.get_flag("allow_outside_prefix"),
+            target_subdir,
+            dir_mode: args.get_one::<u32>("dir_mode").copied().unwrap_or(0o755),
+            relocatable: args.get_flag("relocatable"),
         }
     }
+
+    /// Where the development files are staged, `destdir` unless split
+    pub fn devel_destdir(&self) -> Option<&Path> {
+        self.devel_prefix.as_deref().or(self.destdir.as_deref())
+    }
+
+    /// Where the runtime files are staged, `destdir` unless split
+    pub fn runtime_destdir(&self) -> Option<&Path> {
+        self.runtime_prefix.as_deref().or(self.destdir.as_deref())
+    }
+
+    /// The install directories, by name
+    pub fn dirs(&self) -> Vec<(&'static str, &Path)> {
+        vec![
+            ("libdir", &self.libdir),
+            ("includedir", &self.includedir),
+            ("bindir", &self.bindir),
+            ("datadir", &self.datadir),
+            ("pkgconfigdir", &self.pkgconfigdir),
+            ("systemdsystemunitdir", &self.systemdsystemunitdir),
+            ("udevrulesdir", &self.udevrulesdir),
+            ("dbusservicedir", &self.dbusservicedir),
+            ("mandir", &self.mandir),
+            ("docdir", &self.docdir),
+        ]
+    }
 }
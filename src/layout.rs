@@ -0,0 +1,436 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use cargo::core::Workspace;
+use cargo::util::config::PathAndArgs;
+use cargo::Config;
+use cargo_platform::CfgExpr;
+use cargo_util::paths::{read, write};
+
+use crate::build::{c_compiler, warn, CPackage};
+use crate::target::Target;
+
+/// The structs and unions the header defines, spelled as C refers to them
+///
+/// cbindgen writes the definitions starting and ending at the first column, the opaque
+/// types only declared are left out, as their size is not known.
+fn defined_types(header: &str) -> Vec<String> {
+    let mut types = Vec::new();
+    // The tag of the definition in progress, `None` for a typedef
+    let mut open: Option<Option<String>> = None;
+    for line in header.lines() {
+        match &open {
+            None => {
+                let decl = match line.strip_suffix('{') {
+                    Some(decl) => decl.trim_end(),
+                    None => continue,
+                };
+                if let Some(decl) = decl.strip_prefix("typedef ") {
+                    if decl.starts_with("struct") || decl.starts_with("union") {
+                        open = Some(None);
+                    }
+                } else if decl.starts_with("struct ") || decl.starts_with("union ") {
+                    open = Some(Some(decl.to_string()));
+                }
+            }
+            Some(tag) => {
+                if let Some(rest) = line.strip_prefix('}') {
+                    let name = rest
+                        .trim()
+                        .trim_end_matches(';')
+                        .split_whitespace()
+                        .last()
+                        .map(str::to_string);
+                    if let Some(name) = name.or_else(|| tag.clone()) {
+                        types.push(name);
+                    }
+                    open = None;
+                }
+            }
+        }
+    }
+    types
+}
+
+/// The macro guarding the layouts of a target
+fn target_macro(target: &str) -> String {
+    let target = target
+        .to_uppercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    format!("CARGO_C_LAYOUT_{target}")
+}
+
+/// The layouts recorded in the `header.layout` file, as the assertions of the targets
+#[derive(Default)]
+struct Layouts {
+    sections: Vec<(String, Vec<String>)>,
+}
+
+impl Layouts {
+    fn parse(contents: &str) -> Self {
+        let mut sections = Vec::new();
+        let mut current = None;
+        for line in contents.lines() {
+            let line = line.trim();
+            if let Some(name) = line
+                .strip_prefix("#if defined(")
+                .and_then(|l| l.strip_suffix(')'))
+            {
+                sections.push((name.to_string(), Vec::new()));
+                current = Some(sections.len() - 1);
+            } else if line.starts_with("#endif") {
+                current = None;
+            } else if let (Some(idx), true) = (current, line.starts_with("_Static_assert(")) {
+                sections[idx].1.push(line.to_string());
+            }
+        }
+        Layouts { sections }
+    }
+
+    /// The types the assertions of the target are about
+    fn types(&self, target_macro: &str) -> Option<Vec<String>> {
+        let (_, asserts) = self.sections.iter().find(|(m, _)| m == target_macro)?;
+        let mut types = Vec::new();
+        for assert in asserts {
+            let ty = assert
+                .strip_prefix("_Static_assert(sizeof(")
+                .and_then(|a| a.split(')').next());
+            if let Some(ty) = ty {
+                types.push(ty.to_string());
+            }
+        }
+        Some(types)
+    }
+
+    fn add(&mut self, target_macro: &str, asserts: Vec<String>) {
+        match self.sections.iter_mut().find(|(m, _)| m == target_macro) {
+            Some((_, section)) => section.extend(asserts),
+            None => self.sections.push((target_macro.to_string(), asserts)),
+        }
+    }
+
+    fn render(&self, header_name: &str) -> String {
+        let mut buf = format!(
+            "/* The layouts of the types of {header_name}, recorded by cargo ctest */\n\
+             /* Define the CARGO_C_LAYOUT_<TARGET> macro of the target to check them */\n"
+        );
+        for (target_macro, asserts) in &self.sections {
+            buf += &format!("\n#if defined({target_macro})\n");
+            for assert in asserts {
+                buf += assert;
+                buf.push('\n');
+            }
+            buf += "#endif\n";
+        }
+        buf
+    }
+}
+
+/// A path as an `#include` spells it
+fn include_path(path: &Path) -> String {
+    path.display().to_string().replace('\\', "/")
+}
+
+/// Compile the C source, to an executable or to an object, returning the diagnostics of
+/// the compiler on failure
+fn compile(
+    compiler: &cc::Tool,
+    src: &Path,
+    out: &Path,
+    executable: bool,
+) -> anyhow::Result<Result<(), String>> {
+    let mut cmd = compiler.to_command();
+    if compiler.is_like_msvc() {
+        // _Static_assert and _Alignof are C11
+        cmd.arg("/nologo").arg("/std:c11");
+        if executable {
+            cmd.arg(src).arg(format!("/Fe{}", out.display()));
+        } else {
+            cmd.arg("/c").arg(src).arg(format!("/Fo{}", out.display()));
+        }
+        // The objects of the executables are written in the working directory
+        cmd.current_dir(out.parent().unwrap());
+    } else {
+        if !executable {
+            cmd.arg("-c");
+        }
+        cmd.arg(src).arg("-o").arg(out);
+    }
+
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {cmd:?}: {e}"))?;
+    if out.status.success() {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(String::from_utf8_lossy(&out.stderr).into_owned()))
+    }
+}
+
+/// The runner configured for the target, as `cargo test` looks it up
+fn target_runner(
+    config: &Config,
+    target: &Target,
+) -> anyhow::Result<Option<(PathBuf, Vec<String>)>> {
+    let key = format!("target.{}.runner", target.triple);
+    if let Some(v) = config.get::<Option<PathAndArgs>>(&key)? {
+        return Ok(Some((v.path.resolve_program(config), v.args)));
+    }
+
+    let mut cfgs = config
+        .target_cfgs()?
+        .iter()
+        .filter_map(|(key, cfg)| cfg.runner.as_ref().map(|runner| (key, runner)))
+        .filter(|(key, _runner)| CfgExpr::matches_key(key, &target.cfg));
+    let matching = cfgs.next();
+    if let Some((key, runner)) = cfgs.next() {
+        let (first, first_runner) = matching.unwrap();
+        anyhow::bail!(
+            "several matching instances of `target.'cfg(..)'.runner` in configurations\n\
+             first match `{}` located in {}\n\
+             second match `{}` located in {}",
+            first,
+            first_runner.definition,
+            key,
+            runner.definition
+        );
+    }
+    Ok(matching.map(|(_key, runner)| {
+        (
+            runner.val.path.clone().resolve_program(config),
+            runner.val.args.clone(),
+        )
+    }))
+}
+
+/// Measure the size and the alignment of the types with a probe built for the target
+///
+/// The probe runs through the `target.<triple>.runner` when one is configured, an emulator
+/// for instance when cross compiling.
+fn measure(
+    config: &Config,
+    compiler: &cc::Tool,
+    header: &Path,
+    target: &Target,
+    types: &[&String],
+) -> anyhow::Result<Vec<String>> {
+    let builddir = header.parent().unwrap();
+    let src = builddir.join("layout-probe.c");
+    let exe = if target.os == "windows" {
+        builddir.join("layout-probe.exe")
+    } else {
+        builddir.join("layout-probe")
+    };
+
+    let mut buf = format!(
+        "#include <stdio.h>\n#include \"{}\"\n\nint main(void) {{\n",
+        include_path(header)
+    );
+    for ty in types {
+        buf += &format!(
+            "    printf(\"%u %u\\n\", (unsigned) sizeof({ty}), (unsigned) _Alignof({ty}));\n"
+        );
+    }
+    buf += "    return 0;\n}\n";
+    write(&src, buf)?;
+
+    if let Err(stderr) = compile(compiler, &src, &exe, true)? {
+        anyhow::bail!("Cannot compile {}:\n{}", src.display(), stderr);
+    }
+    let mut cmd = match target_runner(config, target)? {
+        Some((runner, args)) => {
+            let mut cmd = Command::new(runner);
+            cmd.args(args).arg(&exe);
+            cmd
+        }
+        None => Command::new(&exe),
+    };
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {cmd:?}: {e}"))?;
+    if !out.status.success() {
+        anyhow::bail!("{} failed", exe.display());
+    }
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut asserts = Vec::new();
+    for (ty, line) in types.iter().zip(stdout.lines()) {
+        let (size, align) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("Unexpected output of {}", exe.display()))?;
+        asserts.push(format!(
+            "_Static_assert(sizeof({ty}) == {size}, \"the size of {ty} changed\");"
+        ));
+        asserts.push(format!(
+            "_Static_assert(_Alignof({ty}) == {align}, \"the alignment of {ty} changed\");"
+        ));
+    }
+    Ok(asserts)
+}
+
+/// Check the layouts of the structs and unions of the generated header against the ones
+/// recorded in `header.layout`
+///
+/// The file holds the `_Static_assert`s of every target the tests ran on, the layouts of
+/// the new targets and of the new types are measured and added to it with `record`, they
+/// fail the check otherwise.
+pub(crate) fn check_layouts(
+    ws: &Workspace,
+    packages: &[CPackage],
+    host: &str,
+    record: bool,
+) -> anyhow::Result<()> {
+    for pkg in packages {
+        let capi_config = &pkg.capi_config;
+        let layout = match &capi_config.header.layout {
+            Some(layout) => pkg.root_path.join(layout),
+            None => continue,
+        };
+        let header = match &pkg.build_targets.include {
+            Some(header) => header,
+            None => {
+                warn(
+                    ws.config(),
+                    format!(
+                        "header.layout is set but {} has no header",
+                        capi_config.library.name
+                    ),
+                )?;
+                continue;
+            }
+        };
+        let header_name = header.file_name().unwrap().to_string_lossy();
+        let target = pkg.build_targets.target.triple.as_str();
+        let target_macro = target_macro(target);
+        let compiler = c_compiler(target, host)?;
+
+        let mut layouts = if layout.exists() {
+            Layouts::parse(&read(&layout)?)
+        } else {
+            Layouts::default()
+        };
+
+        let recorded = layouts.types(&target_macro);
+        if recorded.is_some() {
+            ws.config().shell().status(
+                "Checking",
+                format!("the layouts of the {header_name} types"),
+            )?;
+            let src = header.with_file_name("layout-check.c");
+            let obj = src.with_extension(if compiler.is_like_msvc() { "obj" } else { "o" });
+            write(
+                &src,
+                format!(
+                    "#define {target_macro}\n#include \"{}\"\n#include \"{}\"\n",
+                    include_path(header),
+                    include_path(&layout)
+                ),
+            )?;
+            if let Err(stderr) = compile(&compiler, &src, &obj, false)? {
+                anyhow::bail!(
+                    "the layouts of the {header_name} types do not match the ones recorded \
+                     for {target} in {}:\n{stderr}",
+                    layout.display()
+                );
+            }
+        }
+
+        let recorded = recorded.unwrap_or_default();
+        let types = defined_types(&read(header)?);
+        let new_types: Vec<_> = types.iter().filter(|t| !recorded.contains(t)).collect();
+        if new_types.is_empty() {
+            continue;
+        }
+        let new_types_list = new_types
+            .iter()
+            .map(|t| t.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !record {
+            anyhow::bail!(
+                "the layouts of {new_types_list} are not recorded for {target} in {}, \
+                 run `cargo ctest --record-layouts` and commit the file",
+                layout.display()
+            );
+        }
+
+        ws.config().shell().status(
+            "Recording",
+            format!(
+                "the layouts of {new_types_list} for {target} in {}",
+                layout.display()
+            ),
+        )?;
+        let asserts = measure(
+            ws.config(),
+            &compiler,
+            header,
+            &pkg.build_targets.target,
+            &new_types,
+        )?;
+        layouts.add(&target_macro, asserts);
+        if let Some(dir) = layout.parent() {
+            cargo_util::paths::create_dir_all(dir)?;
+        }
+        write(&layout, layouts.render(&header_name))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn types_of_the_styles() {
+        let header = "\
+typedef struct Opaque Opaque;
+
+typedef struct Both {
+  int32_t a;
+  union {
+    struct {
+      uint8_t b;
+    };
+  };
+} Both;
+
+typedef struct {
+  uint64_t a;
+} Type;
+
+struct Tag {
+  float a;
+};
+
+typedef union Union {
+  int32_t a;
+  float b;
+} Union;
+
+typedef enum Enum {
+  A,
+} Enum;
+";
+        assert_eq!(
+            defined_types(header),
+            ["Both", "Type", "struct Tag", "Union"]
+        );
+    }
+
+    #[test]
+    fn sections() {
+        let mut layouts = Layouts::default();
+        layouts.add(
+            "CARGO_C_LAYOUT_A",
+            vec!["_Static_assert(sizeof(struct Tag) == 4, \"\");".into()],
+        );
+        let layouts = Layouts::parse(&layouts.render("foo.h"));
+        assert_eq!(
+            layouts.types("CARGO_C_LAYOUT_A"),
+            Some(vec!["struct Tag".to_string()])
+        );
+        assert_eq!(layouts.types("CARGO_C_LAYOUT_B"), None);
+    }
+}
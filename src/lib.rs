@@ -1,7 +1,22 @@
+pub mod abi;
+pub mod archive;
 pub mod build;
 pub mod build_targets;
 pub mod cli;
 pub mod config;
+pub mod deb;
+pub mod doctor;
+pub mod framework;
+pub mod init;
 pub mod install;
+pub mod layout;
+pub mod meson;
+pub mod nuget;
+pub mod packaging;
 pub mod pkg_config_gen;
+pub mod rpm;
+pub mod superbuild;
 pub mod target;
+pub mod vcpkg;
+pub mod wasm_gen;
+pub mod watch;
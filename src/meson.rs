@@ -0,0 +1,275 @@
+use std::path::{Path, PathBuf};
+
+use cargo::core::{Package, Workspace};
+use cargo::util::command_prelude::ArgMatchesExt;
+use cargo::util::interning::InternedString;
+use cargo_util::paths::{create_dir_all, write};
+use clap::ArgMatches;
+
+use crate::build::{load_manifest_capi_config, requested_targets, selected_preset, CApiConfig};
+use crate::target::Target;
+
+/// The meson identifier of the dependency variable, e.g. `foo_dep`
+fn dep_variable(capi_config: &CApiConfig) -> String {
+    let name: String = capi_config
+        .pkg_config
+        .filename
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{name}_dep")
+}
+
+/// The include directory the consumers add, relative to `includedir`, as in the pkg-config
+/// file
+fn include_subdirectory(capi_config: &CApiConfig) -> PathBuf {
+    Path::new(&capi_config.header.subdirectory)
+        .ancestors()
+        .nth(capi_config.pkg_config.strip_include_path_components)
+        .unwrap_or_else(|| Path::new(""))
+        .to_path_buf()
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// The wrap file, a git checkout of the released sources or the prebuilt tree in place
+fn wrap_file(pkg: &Package, capi_config: &CApiConfig, prebuilt: bool) -> anyhow::Result<String> {
+    let filename = &capi_config.pkg_config.filename;
+    let wrap = if prebuilt {
+        format!("[wrap-file]\ndirectory = {filename}\n")
+    } else {
+        let repository = pkg
+            .manifest()
+            .metadata()
+            .repository
+            .as_deref()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{} has no package.repository, the meson wrap cannot fetch its sources, \
+                     pass --prebuilt to consume an installed tree",
+                    pkg.name()
+                )
+            })?;
+        format!(
+            "[wrap-git]\nurl = {repository}\nrevision = v{}\ndepth = 1\npatch_directory = {filename}\n",
+            pkg.version()
+        )
+    };
+
+    Ok(format!(
+        "{wrap}\n[provide]\ndependency_names = {filename}\n"
+    ))
+}
+
+/// The relative path with `/` separators, as meson takes them on every platform
+fn meson_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A `meson.build` declaring the library installed in `prefix`, in its `libdir`
+fn prebuilt_meson_build(capi_config: &CApiConfig, prefix: &Path, libdir: &Path) -> String {
+    let name = &capi_config.pkg_config.filename;
+    let dep = dep_variable(capi_config);
+    let prefix = quote(&prefix.display().to_string());
+    // An absolute libdir is taken as it is by the `/` operator
+    let libdir = quote(&libdir.display().to_string());
+    let library = quote(&capi_config.library.name);
+    let include = quote(&include_subdirectory(capi_config).display().to_string());
+    let version = quote(&capi_config.library.version.to_string());
+
+    format!(
+        "project({}, 'c', version: {version})
+
+prefix = {prefix}
+cc = meson.get_compiler('c')
+
+{dep} = declare_dependency(
+  dependencies: cc.find_library({library}, dirs: prefix / {libdir}),
+  include_directories: include_directories(prefix / 'include' / {include}),
+  version: meson.project_version(),
+)
+meson.override_dependency({}, {dep})
+",
+        quote(name),
+        quote(name),
+    )
+}
+
+/// A `meson.build` building the static library with `cargo cinstall` in the build directory,
+/// `manifest` being the path of the member manifest in the checkout of the repository
+fn source_meson_build(capi_config: &CApiConfig, manifest: &Path) -> String {
+    let name = &capi_config.pkg_config.filename;
+    let dep = dep_variable(capi_config);
+    let library = &capi_config.library.name;
+    let include = quote(&meson_path(
+        &Path::new("include").join(include_subdirectory(capi_config)),
+    ));
+    let version = quote(&capi_config.library.version.to_string());
+
+    format!(
+        "project({}, 'c', version: {version}, meson_version: '>= 0.60')
+
+cargo = find_program('cargo')
+cc = meson.get_compiler('c')
+
+if cc.get_argument_syntax() == 'msvc'
+  static_lib = {}
+else
+  static_lib = {}
+endif
+
+# The library and the headers are installed in the build directory
+{library_var} = custom_target({},
+  output: static_lib,
+  command: [cargo, 'cinstall', '--release', '--library-type', 'staticlib',
+    '--manifest-path', meson.current_source_dir() / {manifest},
+    '--target-dir', meson.current_build_dir() / 'target',
+    '--destdir', meson.current_build_dir(), '--prefix', '/',
+    '--libdir', '/', '--includedir', '/include'],
+  console: true,
+  build_always_stale: true,
+)
+
+# The native libraries the Rust standard library links to
+native_deps = [dependency('threads')]
+if host_machine.system() == 'windows'
+  foreach lib : ['ws2_32', 'userenv', 'ntdll', 'bcrypt', 'advapi32']
+    native_deps += cc.find_library(lib)
+  endforeach
+else
+  foreach lib : ['dl', 'm']
+    native_deps += cc.find_library(lib, required: false)
+  endforeach
+endif
+
+{dep} = declare_dependency(
+  sources: {library_var},
+  link_with: {library_var},
+  dependencies: native_deps,
+  include_directories: include_directories({include}),
+  version: meson.project_version(),
+)
+meson.override_dependency({}, {dep})
+",
+        quote(name),
+        quote(&format!("{library}.lib")),
+        quote(&format!("lib{library}.a")),
+        quote(library),
+        quote(name),
+        library_var = format!("{}_lib", dep.trim_end_matches("_dep")),
+        manifest = quote(&meson_path(manifest)),
+    )
+}
+
+/// Write `<name>.wrap` and the `meson.build` of the subproject, for meson projects to
+/// consume the library with `dependency('<name>')`
+pub fn cmeson(ws: &Workspace, args: &ArgMatches) -> anyhow::Result<()> {
+    let output_dir = args
+        .get_one::<PathBuf>("output-dir")
+        .cloned()
+        .unwrap_or_else(|| {
+            args.value_of_path("target-dir", ws.config())
+                .unwrap_or_else(|| ws.target_dir().into_path_unlocked())
+                .join("meson")
+        });
+    let prebuilt = args.get_one::<PathBuf>("prebuilt");
+    let rustc = ws.config().load_global_rustc(Some(ws))?;
+    let target = match requested_targets(ws.config(), args, &rustc.host)?.as_slice() {
+        [target] => Target::new(target)?,
+        _ => anyhow::bail!("a meson subproject is written for a single target, pass one --target"),
+    };
+    let capi_feature = InternedString::new("capi");
+
+    for pkg in ws
+        .members()
+        .filter(|m| m.library().is_some() && m.summary().features().contains_key(&capi_feature))
+    {
+        let capi_config = load_manifest_capi_config(pkg, Some(&target), selected_preset(args))?;
+        let filename = &capi_config.pkg_config.filename;
+
+        // The prebuilt tree is used in place, the sources get the build file as a patch
+        let (meson_build, dir) = match prebuilt {
+            Some(prefix) => {
+                let prefix = if prefix.is_absolute() {
+                    prefix.clone()
+                } else {
+                    ws.config().cwd().join(prefix)
+                };
+                let libdir = args
+                    .get_one::<PathBuf>("libdir")
+                    .cloned()
+                    .unwrap_or_else(|| PathBuf::from("lib"));
+                (
+                    prebuilt_meson_build(&capi_config, &prefix, &libdir),
+                    output_dir.join(filename),
+                )
+            }
+            // The repository is checked out at the root of the workspace
+            None => (
+                source_meson_build(
+                    &capi_config,
+                    pkg.manifest_path()
+                        .strip_prefix(ws.root())
+                        .unwrap_or_else(|_| Path::new("Cargo.toml")),
+                ),
+                output_dir.join("packagefiles").join(filename),
+            ),
+        };
+
+        for (path, contents) in [
+            (
+                output_dir.join(format!("{filename}.wrap")),
+                wrap_file(pkg, &capi_config, prebuilt.is_some())?,
+            ),
+            (dir.join("meson.build"), meson_build),
+        ] {
+            ws.config().shell().status("Writing", path.display())?;
+            create_dir_all(path.parent().unwrap())?;
+            write(&path, contents)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn source_meson_build() {
+        let capi_config = crate::build::test_capi_config();
+        let meson_build =
+            super::source_meson_build(&capi_config, &Path::new("crates").join("foo/Cargo.toml"));
+        assert!(meson_build.contains(
+            "    '--manifest-path', meson.current_source_dir() / 'crates/foo/Cargo.toml',\n"
+        ));
+        assert!(meson_build.contains("  static_lib = 'libfoo.a'\n"));
+        assert!(meson_build.contains("  include_directories: include_directories('include'),\n"));
+        assert!(meson_build.contains("meson.override_dependency('foo', foo_dep)\n"));
+    }
+
+    #[test]
+    fn prebuilt_meson_build() {
+        let capi_config = crate::build::test_capi_config();
+        let meson_build =
+            super::prebuilt_meson_build(&capi_config, Path::new("/opt/foo"), Path::new("lib64"));
+        assert!(meson_build.contains("prefix = '/opt/foo'\n"));
+        assert!(meson_build
+            .contains("  dependencies: cc.find_library('foo', dirs: prefix / 'lib64'),\n"));
+
+        let meson_build = super::prebuilt_meson_build(
+            &capi_config,
+            Path::new("/opt/foo"),
+            Path::new("/usr/lib/x86_64-linux-gnu"),
+        );
+        assert!(meson_build.contains(
+            "  dependencies: cc.find_library('foo', dirs: prefix / '/usr/lib/x86_64-linux-gnu'),\n"
+        ));
+    }
+}
@@ -0,0 +1,417 @@
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+
+use cargo::core::{Package, Workspace};
+use cargo::util::command_prelude::ArgMatchesExt;
+use cargo::util::interning::InternedString;
+use cargo_util::paths::{create_dir_all, read_bytes, write};
+use clap::ArgMatches;
+use zip::write::SimpleFileOptions;
+use zip::CompressionMethod;
+
+use crate::build::{load_manifest_capi_config, selected_preset, CApiConfig};
+use crate::build_targets::BuildTargets;
+use crate::superbuild::{msbuild_condition, msbuild_configuration, msbuild_platform};
+use crate::target::Target;
+
+/// Zip writer for the Open Packaging Conventions container of a .nupkg and the binary
+/// archives, the entries are dated 1980-01-01 so the archives are reproducible
+pub(crate) struct Zip {
+    writer: zip::ZipWriter<Cursor<Vec<u8>>>,
+    names: Vec<String>,
+}
+
+impl Default for Zip {
+    fn default() -> Self {
+        Zip {
+            writer: zip::ZipWriter::new(Cursor::new(Vec::new())),
+            names: Vec::new(),
+        }
+    }
+}
+
+impl Zip {
+    pub(crate) fn add(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            // zip64 extra fields for the files over 4 GiB
+            .large_file(u32::try_from(contents.len()).is_err());
+        self.writer.start_file(name, options)?;
+        self.writer.write_all(contents)?;
+
+        self.names.push(name.to_string());
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.writer.finish()?.into_inner())
+    }
+}
+
+pub(crate) fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The runtime identifier matching the target architecture
+fn runtime_id(target: &Target) -> String {
+    // The UWP runtimes are the Windows 10 ones
+    let os = if target.vendor == "uwp" {
+        "win10"
+    } else {
+        "win"
+    };
+    let arch = match target.arch.as_str() {
+        "x86_64" => "x64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    format!("{os}-{arch}")
+}
+
+/// A windows-msvc build of the library found in the target directory
+struct Build {
+    configuration: String,
+    /// The directory of the build in the package, below `lib` and `bin`
+    dir: String,
+    condition: String,
+    runtime_id: String,
+    root_output: PathBuf,
+    targets: BuildTargets,
+    /// With the overrides of the target applied
+    capi_config: CApiConfig,
+}
+
+fn find_builds(
+    target_dir: &Path,
+    pkg: &Package,
+    preset: Option<&str>,
+) -> anyhow::Result<Vec<Build>> {
+    let mut builds = Vec::new();
+
+    let mut triples: Vec<_> = match std::fs::read_dir(target_dir) {
+        Ok(entries) => entries.flatten().map(|e| e.path()).collect(),
+        Err(_) => return Ok(builds),
+    };
+    triples.sort();
+
+    for triple_dir in triples {
+        let triple = triple_dir.file_name().unwrap().to_string_lossy();
+        if !triple.ends_with("-windows-msvc") {
+            continue;
+        }
+        let target = Target::new(triple.as_ref())?;
+
+        let mut profiles: Vec<_> = std::fs::read_dir(&triple_dir)?
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+        profiles.sort();
+
+        for root_output in profiles {
+            let capi_config = load_manifest_capi_config(pkg, Some(&target), preset)?;
+            let mut targets = BuildTargets::new(
+                &capi_config.library.name,
+                &target,
+                &root_output,
+                &["staticlib", "cdylib"],
+                &capi_config,
+            )?;
+            targets.static_lib = targets.static_lib.filter(|l| l.is_file());
+            targets.shared_lib = targets.shared_lib.filter(|l| l.is_file());
+            targets.impl_lib = targets.impl_lib.filter(|l| l.is_file());
+            if targets.static_lib.is_none() && targets.shared_lib.is_none() {
+                continue;
+            }
+
+            let profile_dir = root_output.file_name().unwrap().to_string_lossy();
+            let platform = msbuild_platform(&target.arch).to_string();
+            let configuration = msbuild_configuration(&profile_dir);
+            let dir = if target.vendor == "uwp" {
+                format!("uwp/{platform}/{configuration}")
+            } else {
+                format!("{platform}/{configuration}")
+            };
+            builds.push(Build {
+                condition: msbuild_condition(&target, &configuration),
+                configuration,
+                dir,
+                runtime_id: runtime_id(&target),
+                root_output,
+                targets,
+                capi_config,
+            });
+        }
+    }
+
+    Ok(builds)
+}
+
+pub(crate) fn add_dir(zip: &mut Zip, dir: &Path, prefix: &str) -> anyhow::Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let name = format!("{prefix}/{}", path.file_name().unwrap().to_string_lossy());
+        if path.is_dir() {
+            add_dir(zip, &path, &name)?;
+        } else {
+            zip.add(&name, &read_bytes(&path)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn nuspec(pkg: &Package, id: &str) -> String {
+    let metadata = pkg.manifest().metadata();
+    let mut optional = String::new();
+    if let Some(license) = &metadata.license {
+        optional += &format!(
+            "\n    <license type=\"expression\">{}</license>",
+            xml_escape(&license.replace('/', " OR "))
+        );
+    }
+    if let Some(url) = metadata.homepage.as_ref().or(metadata.repository.as_ref()) {
+        optional += &format!("\n    <projectUrl>{}</projectUrl>", xml_escape(url));
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<package xmlns=\"http://schemas.microsoft.com/packaging/2013/05/nuspec.xsd\">
+  <metadata>
+    <id>{id}</id>
+    <version>{version}</version>
+    <authors>{authors}</authors>
+    <description>{description}</description>{optional}
+    <tags>native</tags>
+  </metadata>
+</package>
+",
+        id = xml_escape(id),
+        version = pkg.version(),
+        authors = xml_escape(&if metadata.authors.is_empty() {
+            id.to_string()
+        } else {
+            metadata.authors.join(", ")
+        }),
+        description = xml_escape(metadata.description.as_deref().unwrap_or(id)),
+    )
+}
+
+/// The .targets adding the include directory, the libraries and the DLL of the build
+/// matching the `Platform` and the `Configuration` of the consumer project
+fn targets_file(capi_config: &CApiConfig, builds: &[Build]) -> String {
+    let ident = capi_config
+        .library
+        .name
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let file_name = |p: &Path| p.file_name().unwrap().to_string_lossy().into_owned();
+
+    let includedir = Path::new("include").join(&capi_config.header.subdirectory);
+    let includedir = includedir
+        .ancestors()
+        .nth(capi_config.pkg_config.strip_include_path_components)
+        .unwrap_or_else(|| Path::new("include"))
+        .display()
+        .to_string()
+        .replace('/', "\\");
+
+    let mut groups = String::new();
+    for build in builds {
+        let condition = &build.condition;
+        let dir = build.dir.replace('/', "\\");
+        let name = format!(
+            "{ident}CopyDll{}",
+            build.dir.replace(|c: char| !c.is_ascii_alphanumeric(), "")
+        );
+
+        let mut libs = Vec::new();
+        match (&build.targets.shared_lib, &build.targets.impl_lib) {
+            (Some(_), Some(impl_lib)) => libs.push(file_name(impl_lib)),
+            _ => libs.extend(build.targets.static_lib.as_deref().map(file_name)),
+        }
+
+        groups += &format!(
+            "
+  <ItemDefinitionGroup Condition=\"{condition}\">
+    <Link>
+      <AdditionalLibraryDirectories>$(MSBuildThisFileDirectory)lib\\{dir};%(AdditionalLibraryDirectories)</AdditionalLibraryDirectories>
+      <AdditionalDependencies>{libs};%(AdditionalDependencies)</AdditionalDependencies>
+    </Link>
+  </ItemDefinitionGroup>",
+            libs = libs.join(";"),
+        );
+
+        if let Some(shared_lib) = &build.targets.shared_lib {
+            groups += &format!(
+                "
+  <Target Name=\"{name}\" AfterTargets=\"Build\" Condition=\"{condition}\">
+    <Copy SourceFiles=\"$(MSBuildThisFileDirectory)bin\\{dir}\\{dll}\" DestinationFolder=\"$(OutDir)\" SkipUnchangedFiles=\"true\" />
+  </Target>",
+                dll = file_name(shared_lib),
+            );
+        }
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<!-- Generated by cargo-c, do not edit -->
+<Project xmlns=\"http://schemas.microsoft.com/developer/msbuild/2003\">
+  <ItemDefinitionGroup>
+    <ClCompile>
+      <AdditionalIncludeDirectories>$(MSBuildThisFileDirectory){includedir};%(AdditionalIncludeDirectories)</AdditionalIncludeDirectories>
+    </ClCompile>
+  </ItemDefinitionGroup>{groups}
+</Project>
+"
+    )
+}
+
+fn package(pkg: &Package, capi_config: &CApiConfig, builds: &[Build]) -> anyhow::Result<Vec<u8>> {
+    let id = &capi_config.pkg_config.name;
+    let mut zip = Zip::default();
+
+    zip.add(&format!("{id}.nuspec"), nuspec(pkg, id).as_bytes())?;
+    zip.add(
+        &format!("build/native/{id}.targets"),
+        targets_file(capi_config, builds).as_bytes(),
+    )?;
+
+    // The headers are the same for every build
+    let include = builds[0].root_output.join("include");
+    if include.is_dir() {
+        add_dir(&mut zip, &include, "build/native/include")?;
+    }
+
+    for build in builds {
+        let dir = &build.dir;
+        let targets = &build.targets;
+        for lib in targets.impl_lib.iter().chain(targets.static_lib.iter()) {
+            let name = lib.file_name().unwrap().to_string_lossy();
+            zip.add(&format!("build/native/lib/{dir}/{name}"), &read_bytes(lib)?)?;
+        }
+
+        if let Some(dll) = &targets.shared_lib {
+            let name = dll.file_name().unwrap().to_string_lossy();
+            let contents = read_bytes(dll)?;
+            zip.add(&format!("build/native/bin/{dir}/{name}"), &contents)?;
+            if let Some(pdb) = targets.debug_info.as_ref().filter(|p| p.is_file()) {
+                let pdb_name = pdb.file_name().unwrap().to_string_lossy();
+                zip.add(
+                    &format!("build/native/bin/{dir}/{pdb_name}"),
+                    &read_bytes(pdb)?,
+                )?;
+            }
+            // The runtimes are picked by the .NET consumers, the release build is preferred
+            let preferred = builds
+                .iter()
+                .filter(|b| b.runtime_id == build.runtime_id && b.targets.shared_lib.is_some())
+                .max_by_key(|b| b.configuration == "Release")
+                .map(|b| std::ptr::eq(b, build))
+                .unwrap_or(false);
+            if preferred {
+                zip.add(
+                    &format!("runtimes/{}/native/{name}", build.runtime_id),
+                    &contents,
+                )?;
+            }
+        }
+    }
+
+    // Every extension needs a content type, the relationships are added below
+    let mut extensions: Vec<_> = zip
+        .names
+        .iter()
+        .filter_map(|name| Path::new(name).extension())
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .chain(["rels".to_string()])
+        .collect();
+    extensions.sort();
+    extensions.dedup();
+    let defaults: String = extensions
+        .iter()
+        .map(|ext| {
+            let content_type = match ext.as_str() {
+                "rels" => "application/vnd.openxmlformats-package.relationships+xml",
+                _ => "application/octet",
+            };
+            format!("\n  <Default Extension=\"{ext}\" ContentType=\"{content_type}\" />")
+        })
+        .collect();
+    zip.add(
+        "[Content_Types].xml",
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\">{defaults}
+</Types>
+"
+        )
+        .as_bytes(),
+    )?;
+    zip.add(
+        "_rels/.rels",
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">
+  <Relationship Type=\"http://schemas.microsoft.com/packaging/2010/07/manifest\" Target=\"/{id}.nuspec\" Id=\"R0\" />
+</Relationships>
+"
+        )
+        .as_bytes(),
+    )?;
+
+    zip.finish()
+}
+
+/// Package the windows-msvc builds found in the target directory in a native .nupkg
+pub fn cnuget(ws: &Workspace, args: &ArgMatches) -> anyhow::Result<()> {
+    let target_dir = args
+        .value_of_path("target-dir", ws.config())
+        .unwrap_or_else(|| ws.target_dir().into_path_unlocked());
+    let output_dir = args
+        .get_one::<PathBuf>("output-dir")
+        .cloned()
+        .unwrap_or_else(|| target_dir.clone());
+    let capi_feature = InternedString::new("capi");
+
+    for pkg in ws
+        .members()
+        .filter(|m| m.library().is_some() && m.summary().features().contains_key(&capi_feature))
+    {
+        let builds = find_builds(&target_dir, pkg, selected_preset(args))?;
+        if builds.is_empty() {
+            anyhow::bail!(
+                "No windows-msvc build of {} found in {}, build it first with \
+                 `cargo cbuild --target x86_64-pc-windows-msvc`",
+                pkg.name(),
+                target_dir.display()
+            );
+        }
+        // The package metadata is the one of the first target
+        let capi_config = &builds[0].capi_config;
+
+        let id = &capi_config.pkg_config.name;
+        let nupkg = output_dir.join(format!("{id}.{}.nupkg", pkg.version()));
+        let configurations = builds
+            .iter()
+            .map(|b| b.dir.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        ws.config().shell().status(
+            "Packaging",
+            format!("{id} {} ({configurations})", pkg.version()),
+        )?;
+
+        let contents = package(pkg, capi_config, &builds)?;
+        create_dir_all(&output_dir)?;
+        write(&nupkg, contents)?;
+        ws.config().shell().status("Writing", nupkg.display())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,362 @@
+use std::path::{Path, PathBuf};
+
+use cargo::core::{Package, Workspace};
+use cargo_util::paths::remove_dir_all;
+use clap::ArgMatches;
+
+use crate::archive::stage_split_install;
+use crate::build::{CApiConfig, CPackage};
+use crate::deb::package_names;
+use crate::init::create_file;
+
+/// The install directories as spelled by the packaging tool
+struct Dirs {
+    prefix: &'static str,
+    bindir: &'static str,
+    libdir: &'static str,
+    includedir: &'static str,
+    datadir: &'static str,
+}
+
+/// Files installed by cinstall, split between the runtime and the development package
+struct FileLists {
+    runtime: Vec<String>,
+    devel: Vec<String>,
+}
+
+impl FileLists {
+    /// The files of the staged runtime and development trees, with the install directories
+    /// spelled as the packaging tool does
+    fn new(pkg: &CPackage, runtime: &Path, devel: &Path, dirs: &Dirs) -> anyhow::Result<Self> {
+        let paths = &pkg.install_paths;
+        let library = &pkg.capi_config.library;
+        // The versioned shared library is matched across the minor releases
+        let versioned = pkg.build_targets.shared_lib.as_ref().map(|lib| {
+            format!(
+                "{}.{}",
+                lib.file_name().unwrap().to_string_lossy(),
+                library.version.major
+            )
+        });
+
+        let spell = |installed: &Path| {
+            let subst = [
+                (&paths.libdir, dirs.libdir),
+                (&paths.includedir, dirs.includedir),
+                (&paths.bindir, dirs.bindir),
+                (&paths.datarootdir, dirs.datadir),
+                (&paths.prefix, dirs.prefix),
+            ];
+            for (dir, spelled) in subst {
+                if let Ok(rest) = installed.strip_prefix(dir) {
+                    return format!("{spelled}/{}", rest.display());
+                }
+            }
+            installed.display().to_string()
+        };
+
+        let mut lists = Self {
+            runtime: Vec::new(),
+            devel: Vec::new(),
+        };
+        for (tree, list) in [(runtime, &mut lists.runtime), (devel, &mut lists.devel)] {
+            let mut files = Vec::new();
+            if tree.is_dir() {
+                staged_files(tree, tree, &mut files)?;
+            }
+            for installed in files {
+                let mut file = spell(&installed);
+                if let Some(versioned) = versioned.as_ref().filter(|_| library.versioning) {
+                    let name = installed.file_name().unwrap().to_string_lossy();
+                    if name.starts_with(versioned.as_str()) {
+                        file = format!("{}*", spell(&installed.with_file_name(versioned)));
+                    }
+                }
+                if !list.contains(&file) {
+                    list.push(file);
+                }
+            }
+        }
+
+        Ok(lists)
+    }
+}
+
+/// The files and the links of a staged tree, as installed
+fn staged_files(tree: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            staged_files(tree, &path, files)?;
+        } else {
+            files.push(Path::new("/").join(path.strip_prefix(tree)?));
+        }
+    }
+    Ok(())
+}
+
+const RPM_DIRS: Dirs = Dirs {
+    prefix: "%{_prefix}",
+    bindir: "%{_bindir}",
+    libdir: "%{_libdir}",
+    includedir: "%{_includedir}",
+    datadir: "%{_datadir}",
+};
+
+const DEB_DIRS: Dirs = Dirs {
+    prefix: "usr",
+    bindir: "usr/bin",
+    libdir: "usr/lib/*",
+    includedir: "usr/include",
+    datadir: "usr/share",
+};
+
+fn rpm_spec(pkg: &Package, capi_config: &CApiConfig, files: &FileLists) -> String {
+    let metadata = pkg.manifest().metadata();
+    let dirs = &RPM_DIRS;
+    let lib_name = &capi_config.library.name;
+    format!(
+        "Name:           {lib_name}
+Version:        {version}
+Release:        1%{{?dist}}
+Summary:        {summary}
+License:        {license}
+URL:            {url}
+Source0:        %{{name}}-%{{version}}.tar.gz
+
+BuildRequires:  cargo
+BuildRequires:  cargo-c
+
+%description
+{summary}
+
+%package        devel
+Summary:        Development files for %{{name}}
+Requires:       %{{name}}%{{?_isa}} = %{{version}}-%{{release}}
+
+%description    devel
+Development files for %{{name}}.
+
+%prep
+%autosetup
+
+%build
+cargo cbuild --release --prefix={prefix} --libdir={libdir}
+
+%install
+cargo cinstall --release --destdir=%{{buildroot}} --prefix={prefix} --libdir={libdir}
+
+%check
+cargo ctest --release
+
+%files
+{runtime}
+
+%files devel
+{devel}
+
+%changelog
+",
+        version = pkg.version(),
+        summary = metadata.description.as_deref().unwrap_or("TODO"),
+        license = metadata.license.as_deref().unwrap_or("TODO"),
+        url = metadata.homepage.as_deref().unwrap_or("TODO"),
+        prefix = dirs.prefix,
+        libdir = dirs.libdir,
+        runtime = files.runtime.join("\n"),
+        devel = files.devel.join("\n"),
+    )
+}
+
+fn deb_files(pkg: &Package, capi_config: &CApiConfig, files: &FileLists) -> Vec<(String, String)> {
+    let metadata = pkg.manifest().metadata();
+    let lib_name = &capi_config.library.name;
+    let (runtime_pkg, devel_pkg) = package_names(&capi_config.library);
+    let summary = metadata.description.as_deref().unwrap_or("TODO");
+    let cargo_args = "--release --prefix=/usr --libdir=/usr/lib/$(DEB_HOST_MULTIARCH)";
+
+    let control = format!(
+        "Source: {lib_name}
+Section: libs
+Priority: optional
+Maintainer: TODO
+Build-Depends: debhelper-compat (= 13), cargo, cargo-c
+Standards-Version: 4.6.2
+Homepage: {homepage}
+
+Package: {runtime_pkg}
+Architecture: any
+Multi-Arch: same
+Depends: ${{shlibs:Depends}}, ${{misc:Depends}}
+Description: {summary}
+
+Package: {devel_pkg}
+Section: libdevel
+Architecture: any
+Multi-Arch: same
+Depends: {runtime_pkg} (= ${{binary:Version}}), ${{misc:Depends}}
+Description: {summary} - development files
+",
+        homepage = metadata.homepage.as_deref().unwrap_or("TODO"),
+    );
+    let rules = format!(
+        "#!/usr/bin/make -f
+
+include /usr/share/dpkg/architecture.mk
+
+%:
+\tdh $@
+
+override_dh_auto_configure:
+
+override_dh_auto_build:
+\tcargo cbuild {cargo_args}
+
+override_dh_auto_test:
+\tcargo ctest --release
+
+override_dh_auto_install:
+\tcargo cinstall {cargo_args} --destdir=debian/tmp
+"
+    );
+
+    vec![
+        ("debian/control".into(), control),
+        ("debian/rules".into(), rules),
+        (
+            format!("debian/{runtime_pkg}.install"),
+            files.runtime.join("\n") + "\n",
+        ),
+        (
+            format!("debian/{devel_pkg}.install"),
+            files.devel.join("\n") + "\n",
+        ),
+    ]
+}
+
+fn pkgbuild(pkg: &Package, capi_config: &CApiConfig) -> String {
+    let metadata = pkg.manifest().metadata();
+    format!(
+        "pkgname={lib_name}
+pkgver={version}
+pkgrel=1
+pkgdesc='{summary}'
+arch=('x86_64')
+url='{url}'
+license=('{license}')
+depends=('gcc-libs')
+makedepends=('cargo' 'cargo-c')
+source=(\"$pkgname-$pkgver.tar.gz\")
+sha256sums=('SKIP')
+
+build() {{
+  cd \"$pkgname-$pkgver\"
+  cargo cbuild --release --prefix=/usr
+}}
+
+check() {{
+  cd \"$pkgname-$pkgver\"
+  cargo ctest --release
+}}
+
+package() {{
+  cd \"$pkgname-$pkgver\"
+  cargo cinstall --release --destdir=\"$pkgdir\" --prefix=/usr
+}}
+",
+        lib_name = capi_config.library.name,
+        version = pkg.version(),
+        summary = metadata.description.as_deref().unwrap_or("TODO"),
+        url = metadata.homepage.as_deref().unwrap_or("TODO"),
+        license = metadata.license.as_deref().unwrap_or("TODO"),
+    )
+}
+
+fn apkbuild(pkg: &Package, capi_config: &CApiConfig) -> String {
+    let metadata = pkg.manifest().metadata();
+    format!(
+        "pkgname={lib_name}
+pkgver={version}
+pkgrel=0
+pkgdesc=\"{summary}\"
+url=\"{url}\"
+arch=\"all\"
+license=\"{license}\"
+makedepends=\"cargo cargo-c\"
+subpackages=\"$pkgname-dev\"
+source=\"$pkgname-$pkgver.tar.gz\"
+
+build() {{
+\tcargo cbuild --release --prefix=/usr
+}}
+
+check() {{
+\tcargo ctest --release
+}}
+
+package() {{
+\tcargo cinstall --release --destdir=\"$pkgdir\" --prefix=/usr
+}}
+",
+        lib_name = capi_config.library.name,
+        version = pkg.version(),
+        summary = metadata.description.as_deref().unwrap_or("TODO"),
+        url = metadata.homepage.as_deref().unwrap_or("TODO"),
+        license = metadata.license.as_deref().unwrap_or("TODO"),
+    )
+}
+
+/// Write the packaging skeleton of every C-API package built, the file lists of the rpm
+/// and the Debian packages taken from their staged install
+pub fn cpackaging_template(
+    ws: &Workspace,
+    args: &ArgMatches,
+    packages: &mut [CPackage],
+) -> anyhow::Result<()> {
+    let kind = args.get_one::<String>("kind").unwrap();
+
+    for cpkg in packages.iter_mut() {
+        let pkg = match ws.members().find(|m| m.root() == cpkg.root_path) {
+            Some(pkg) => pkg,
+            None => continue,
+        };
+        let out_dir = cpkg.root_path.join("packaging").join(kind);
+
+        let dirs = match kind.as_str() {
+            "rpm" => Some(&RPM_DIRS),
+            "deb" => Some(&DEB_DIRS),
+            _ => None,
+        };
+        let lists = match dirs {
+            Some(dirs) => {
+                let (staging, runtime, devel) = stage_split_install(ws, cpkg)?;
+                let lists = FileLists::new(cpkg, &runtime, &devel, dirs)?;
+                remove_dir_all(&staging)?;
+                Some(lists)
+            }
+            None => None,
+        };
+
+        let capi_config = &cpkg.capi_config;
+        let files = match (kind.as_str(), lists) {
+            ("rpm", Some(lists)) => vec![(
+                format!("{}.spec", capi_config.library.name),
+                rpm_spec(pkg, capi_config, &lists),
+            )],
+            ("deb", Some(lists)) => deb_files(pkg, capi_config, &lists),
+            ("arch", _) => vec![("PKGBUILD".into(), pkgbuild(pkg, capi_config))],
+            ("alpine", _) => vec![("APKBUILD".into(), apkbuild(pkg, capi_config))],
+            _ => unreachable!(),
+        };
+
+        for (path, contents) in files {
+            create_file(ws.config(), &out_dir.join(path), &contents)?;
+        }
+    }
+
+    Ok(())
+}
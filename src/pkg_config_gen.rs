@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use crate::build::CApiConfig;
+use crate::build::{CApiConfig, PkgConfigModule};
 use crate::install::InstallPaths;
 use std::path::{Component, Path, PathBuf};
 
@@ -29,6 +29,17 @@ fn canonicalize<P: AsRef<Path>>(path: P) -> String {
     }
 }
 
+/// Express the path relative to `${prefix}` if possible
+fn relative_to_prefix(path: &Path, prefix: &Path) -> PathBuf {
+    if let Ok(suffix) = path.strip_prefix(prefix) {
+        let mut path = PathBuf::from("${prefix}");
+        path.push(suffix);
+        path
+    } else {
+        path.to_path_buf()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PkgConfig {
     prefix: PathBuf,
@@ -36,6 +47,8 @@ pub struct PkgConfig {
     includedir: PathBuf,
     libdir: PathBuf,
 
+    variables: Vec<(String, String)>,
+
     name: String,
     description: String,
     version: String,
@@ -82,23 +95,64 @@ impl PkgConfig {
             libdir.push(subdir);
         }
 
-        let libs = vec![
-            format!("-L{}", libdir.display()),
-            format!("-l{}", capi_config.library.name),
-        ];
+        // The directory the modules are loaded from
+        let mut variables = Vec::new();
+        if capi_config.library.module {
+            variables.push((String::from("moduledir"), canonicalize(&libdir)));
+        }
 
-        let cflags = if capi_config.header.enabled {
-            let includedir = Path::new("${includedir}").join(&capi_config.header.subdirectory);
+        let libs = if capi_config.library.framework {
+            vec![
+                format!("-F{}", libdir.display()),
+                format!("-framework {}", capi_config.library.name),
+            ]
+        } else {
+            vec![
+                format!("-L{}", libdir.display()),
+                format!("-l{}", capi_config.library.name),
+            ]
+        };
+
+        let include_cflag = |subdirectory: &str| {
+            let includedir = Path::new("${includedir}").join(subdirectory);
             let includedir = includedir
                 .ancestors()
                 .nth(capi_config.pkg_config.strip_include_path_components)
                 .unwrap_or_else(|| Path::new(""));
 
             format!("-I{}", canonicalize(includedir))
-        } else {
-            String::from("")
         };
 
+        let mut cflags = Vec::new();
+        if capi_config.header.enabled {
+            cflags.push(include_cflag(&capi_config.header.subdirectory));
+        }
+
+        let mut subdirectories: Vec<&str> = capi_config
+            .install
+            .include_subdirectory
+            .iter()
+            .map(String::as_str)
+            .collect();
+        // The headers installed in other subdirectories are found the same way
+        if capi_config.pkg_config.include_cflags {
+            subdirectories.extend(
+                capi_config
+                    .install
+                    .include
+                    .iter()
+                    .map(|target| target.paths().to.as_str())
+                    // Found with the -I of includedir
+                    .filter(|to| !to.is_empty()),
+            );
+        }
+        for subdirectory in subdirectories {
+            let cflag = include_cflag(subdirectory);
+            if !cflags.contains(&cflag) {
+                cflags.push(cflag);
+            }
+        }
+
         PkgConfig {
             name: capi_config.pkg_config.name.clone(),
             description: capi_config.pkg_config.description.clone(),
@@ -109,13 +163,15 @@ impl PkgConfig {
             includedir: "${prefix}/include".into(),
             libdir: "${exec_prefix}/lib".into(),
 
+            variables,
+
             libs,
             libs_private: Vec::new(),
 
             requires,
             requires_private,
 
-            cflags: vec![cflags],
+            cflags,
 
             conflicts: Vec::new(),
         }
@@ -132,23 +188,40 @@ impl PkgConfig {
         pc.prefix = install_paths.prefix.clone();
         // TODO: support exec_prefix
         if args.contains_id("includedir") {
-            if let Ok(suffix) = install_paths.includedir.strip_prefix(&pc.prefix) {
-                let mut includedir = PathBuf::from("${prefix}");
-                includedir.push(suffix);
-                pc.includedir = includedir;
-            } else {
-                pc.includedir = install_paths.includedir.clone();
-            }
+            pc.includedir = relative_to_prefix(&install_paths.includedir, &pc.prefix);
+        }
+        if args.contains_id("libdir") || install_paths.target_subdir {
+            pc.libdir = relative_to_prefix(&install_paths.libdir, &pc.prefix);
         }
-        if args.contains_id("libdir") {
-            if let Ok(suffix) = install_paths.libdir.strip_prefix(&pc.prefix) {
-                let mut libdir = PathBuf::from("${prefix}");
-                libdir.push(suffix);
-                pc.libdir = libdir;
-            } else {
-                pc.libdir = install_paths.libdir.clone();
+
+        let install = &capi_config.install;
+        for (name, targets, dir) in [
+            (
+                "systemdsystemunitdir",
+                &install.systemd,
+                &install_paths.systemdsystemunitdir,
+            ),
+            ("udevrulesdir", &install.udev, &install_paths.udevrulesdir),
+            (
+                "dbusservicedir",
+                &install.dbus,
+                &install_paths.dbusservicedir,
+            ),
+        ] {
+            if !targets.is_empty() {
+                let dir = relative_to_prefix(dir, &pc.prefix);
+                pc.add_variable(name, canonicalize(dir));
             }
         }
+
+        for (name, path) in capi_config.runtime_dirs.dirs.iter() {
+            pc.add_variable(name, canonicalize(Path::new("${libdir}").join(path)));
+        }
+
+        for (name, value) in capi_config.pkg_config.variables.iter() {
+            pc.add_variable(name, value);
+        }
+
         pc
     }
 
@@ -157,12 +230,114 @@ impl PkgConfig {
         uninstalled.prefix = output.to_path_buf();
         uninstalled.includedir = "${prefix}/include".into();
         uninstalled.libdir = "${prefix}".into();
-        // First libs item is the search path
-        uninstalled.libs[0] = "-L${prefix}".into();
+        // First libs item is the search path, the modules may not have one
+        match self.libs.first() {
+            Some(path) if path.starts_with("-F") => uninstalled.libs[0] = "-F${prefix}".into(),
+            Some(path) if path.starts_with("-L") => uninstalled.libs[0] = "-L${prefix}".into(),
+            _ => {}
+        }
 
         uninstalled
     }
 
+    /// The pkg-config file of a module of the library, sharing the variables and the version
+    /// of the main one, `main`
+    pub(crate) fn module(&self, module: &PkgConfigModule, main: &str) -> Self {
+        let list = |value: &Option<String>| -> Vec<String> {
+            value
+                .iter()
+                .flat_map(|v| v.split(','))
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        };
+        let flags = |value: &Option<String>| -> Vec<String> {
+            value
+                .iter()
+                .flat_map(|v| v.split_whitespace())
+                .map(String::from)
+                .collect()
+        };
+
+        let mut pc = self.clone();
+        pc.name = module.name.clone();
+        if let Some(description) = &module.description {
+            pc.description = description.clone();
+        }
+        pc.requires = match &module.requires {
+            Some(_) => list(&module.requires),
+            None => vec![main.to_string()],
+        };
+        pc.requires_private = list(&module.requires_private);
+        pc.libs = flags(&module.libs);
+        pc.libs_private = Vec::new();
+        pc.cflags = flags(&module.cflags);
+
+        pc
+    }
+
+    /// Expand the variables the way pkg-config would when printing the flags
+    fn expand(&self, value: &str) -> String {
+        let builtin = [
+            ("prefix", canonicalize(&self.prefix)),
+            ("exec_prefix", canonicalize(&self.exec_prefix)),
+            ("includedir", canonicalize(&self.includedir)),
+            ("libdir", canonicalize(&self.libdir)),
+        ];
+        let variables = builtin
+            .iter()
+            .map(|(n, v)| (*n, v.as_str()))
+            .chain(self.variables.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+
+        let mut value = value.to_string();
+        // The variables are defined in terms of each other, a few passes resolve them all
+        for _ in 0..4 {
+            for (name, v) in variables.clone() {
+                value = value.replace(&format!("${{{name}}}"), v);
+            }
+        }
+        value
+    }
+
+    /// The `Cflags` with the variables expanded
+    pub(crate) fn expanded_cflags(&self) -> Vec<String> {
+        self.cflags
+            .iter()
+            .flat_map(|flag| {
+                self.expand(flag)
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// The `Requires` and the `Requires.private` entries
+    pub(crate) fn all_requires(&self) -> impl Iterator<Item = &String> {
+        self.requires.iter().chain(self.requires_private.iter())
+    }
+
+    /// Add a `>= <version>` constraint to the `Requires` and `Requires.private` entries
+    /// without one, from the versions of the modules given
+    pub(crate) fn constrain_requires(&mut self, versions: &[(String, String)]) -> &mut Self {
+        for req in self
+            .requires
+            .iter_mut()
+            .chain(self.requires_private.iter_mut())
+        {
+            if let Some((_, version)) = versions.iter().find(|(name, _)| name == req) {
+                *req = format!("{req} >= {version}");
+            }
+        }
+        self
+    }
+
+    pub fn add_variable<N: AsRef<str>, V: AsRef<str>>(&mut self, name: N, value: V) -> &mut Self {
+        self.variables
+            .push((name.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
+    }
+
     pub fn set_description<S: AsRef<str>>(&mut self, descr: S) -> &mut Self {
         self.description = descr.as_ref().to_owned();
         self
@@ -208,12 +383,18 @@ impl PkgConfig {
     }
 
     pub fn render(&self) -> String {
+        let variables: String = self
+            .variables
+            .iter()
+            .map(|(name, value)| format!("{name}={value}\n"))
+            .collect();
+
         let mut base = format!(
             "prefix={}
 exec_prefix={}
 libdir={}
 includedir={}
-
+{}
 Name: {}
 Description: {}
 Version: {}
@@ -223,6 +404,7 @@ Cflags: {}",
             canonicalize(&self.exec_prefix),
             canonicalize(&self.libdir),
             canonicalize(&self.includedir),
+            variables,
             self.name,
             // avoid endlines
             self.description.replace('\n', " "),
@@ -273,6 +455,76 @@ mod test {
     use super::*;
     use semver::Version;
 
+    fn capi_config() -> CApiConfig {
+        CApiConfig {
+            header: crate::build::HeaderCApiConfig {
+                name: "foo".into(),
+                subdirectory: "".into(),
+                generation: true,
+                enabled: true,
+                layout: None,
+            },
+            pkg_config: crate::build::PkgConfigCApiConfig {
+                name: "foo".into(),
+                filename: "foo".into(),
+                description: "".into(),
+                version: "0.1".into(),
+                requires: Some("somelib, someotherlib".into()),
+                requires_private: Some("someprivatelib >= 1.0".into()),
+                strip_include_path_components: 0,
+                include_cflags: true,
+                requires_sys_versions: false,
+                check_requires: false,
+                install: true,
+                modules: Vec::new(),
+                libs_private: None,
+                variables: Vec::new(),
+            },
+            library: crate::build::LibraryCApiConfig {
+                name: "foo".into(),
+                version: Version::parse("0.1.0").unwrap(),
+                install_subdir: None,
+                versioning: true,
+                rustflags: Vec::default(),
+                max_glibc_version: None,
+                macos_deployment_target: None,
+                dll_name: None,
+                dll_prefix: None,
+                dll_suffix: None,
+                dll_version: false,
+                codesign_identity: None,
+                allocator: None,
+                init: None,
+                fini: None,
+                staticlib_features: Vec::new(),
+                cdylib_features: Vec::new(),
+                linker_script: false,
+                symbol_version: None,
+                framework: false,
+                bundle_identifier: None,
+                types: None,
+                prefix: None,
+                suffix: None,
+                module: false,
+                plugin: None,
+                version_resource: true,
+                manifest: None,
+                stamp: false,
+                reproducible: false,
+            },
+            install: Default::default(),
+            runtime_dirs: Default::default(),
+            swift: Default::default(),
+            python: Default::default(),
+            js: Default::default(),
+            licenses: Default::default(),
+            pack: Default::default(),
+            check_cfg: Vec::new(),
+            env: Vec::new(),
+            def: Default::default(),
+        }
+    }
+
     #[test]
     fn simple() {
         let mut pkg = PkgConfig::new(
@@ -283,6 +535,7 @@ mod test {
                     subdirectory: "".into(),
                     generation: true,
                     enabled: true,
+                    layout: None,
                 },
                 pkg_config: crate::build::PkgConfigCApiConfig {
                     name: "foo".into(),
@@ -292,6 +545,13 @@ mod test {
                     requires: Some("somelib, someotherlib".into()),
                     requires_private: Some("someprivatelib >= 1.0".into()),
                     strip_include_path_components: 0,
+                    include_cflags: true,
+                    requires_sys_versions: false,
+                    check_requires: false,
+                    install: true,
+                    modules: Vec::new(),
+                    libs_private: None,
+                    variables: Vec::new(),
                 },
                 library: crate::build::LibraryCApiConfig {
                     name: "foo".into(),
@@ -299,12 +559,110 @@ mod test {
                     install_subdir: None,
                     versioning: true,
                     rustflags: Vec::default(),
+                    max_glibc_version: None,
+                    macos_deployment_target: None,
+                    dll_name: None,
+                    dll_prefix: None,
+                    dll_suffix: None,
+                    dll_version: false,
+                    codesign_identity: None,
+                    allocator: None,
+                    init: None,
+                    fini: None,
+                    staticlib_features: Vec::new(),
+                    cdylib_features: Vec::new(),
+                    linker_script: false,
+                    symbol_version: None,
+                    framework: false,
+                    bundle_identifier: None,
+                    types: None,
+                    prefix: None,
+                    suffix: None,
+                    module: false,
+                    plugin: None,
+                    version_resource: true,
+                    manifest: None,
+                    stamp: false,
+                    reproducible: false,
                 },
                 install: Default::default(),
+                runtime_dirs: Default::default(),
+                swift: Default::default(),
+                python: Default::default(),
+                js: Default::default(),
+                licenses: Default::default(),
+                pack: Default::default(),
+                check_cfg: Vec::new(),
+                env: Vec::new(),
+                def: Default::default(),
             },
         );
         pkg.add_lib("-lbar").add_cflag("-DFOO");
 
         println!("{:?}\n{}", pkg, pkg.render());
     }
+
+    #[test]
+    fn module() {
+        let mut pkg = PkgConfig::new("foo", &capi_config());
+        pkg.add_lib("-lbar");
+
+        let module = pkg.module(
+            &PkgConfigModule {
+                filename: "foo-extras".into(),
+                name: "foo-extras".into(),
+                description: None,
+                requires: None,
+                requires_private: None,
+                libs: Some("-L${libdir} -lfoo_extras".into()),
+                cflags: None,
+            },
+            "foo",
+        );
+        let render = module.uninstalled(Path::new("/build")).render();
+        assert!(render.contains("\nLibs: -L${prefix} -lfoo_extras\n"));
+        assert!(render.contains("\nRequires: foo\n"));
+        // The library is not part of the libs of the module
+        assert!(!render.contains("-lbar"));
+    }
+
+    #[test]
+    fn expanded_cflags() {
+        let mut pkg = PkgConfig::new("foo", &capi_config());
+        pkg.add_cflag("-DFOO");
+
+        let flags = pkg.uninstalled(Path::new("/build")).expanded_cflags();
+        assert_eq!(flags, ["-I/build/include", "-DFOO"]);
+    }
+
+    #[test]
+    fn include_subdirectory_without_header() {
+        let mut capi_config = capi_config();
+        capi_config.header.enabled = false;
+        capi_config.install.include_subdirectory = Some("foo-1".into());
+
+        let pkg = PkgConfig::new("foo", &capi_config);
+        assert_eq!(pkg.cflags, ["-I${includedir}/foo-1"]);
+    }
+
+    #[test]
+    fn install_include_cflags() {
+        let mut capi_config = capi_config();
+        capi_config.header.enabled = false;
+        for to in ["", "foo/extra"] {
+            capi_config
+                .install
+                .include
+                .push(crate::build::InstallTarget::Asset(
+                    crate::build::InstallTargetPaths {
+                        from: "include/**/*.h".into(),
+                        to: to.into(),
+                        ..Default::default()
+                    },
+                ));
+        }
+
+        let pkg = PkgConfig::new("foo", &capi_config);
+        assert_eq!(pkg.cflags, ["-I${includedir}/foo/extra"]);
+    }
 }
@@ -0,0 +1,248 @@
+use std::path::{Path, PathBuf};
+
+use cargo::core::manifest::ManifestMetadata;
+use cargo::core::Workspace;
+use cargo_util::paths::{create_dir_all, remove_dir_all, write};
+
+use crate::archive::{package_summary, stage_split_install};
+use crate::build::CPackage;
+use crate::install::InstallPaths;
+use crate::target::Target;
+
+/// The rpm architecture of a Linux target
+fn rpm_arch(target: &Target) -> Option<&'static str> {
+    let arch = target.triple.split('-').next().unwrap_or_default();
+    let rpm_arch = match arch {
+        "x86_64" => "x86_64",
+        "aarch64" => "aarch64",
+        "i586" => "i586",
+        "i686" => "i686",
+        _ if arch.starts_with("armv7") && target.abi == "eabihf" => "armv7hl",
+        "powerpc64le" => "ppc64le",
+        "powerpc64" => "ppc64",
+        "riscv64gc" | "riscv64" => "riscv64",
+        "s390x" => "s390x",
+        "loongarch64" => "loongarch64",
+        _ => return None,
+    };
+    Some(rpm_arch)
+}
+
+/// The directories the system owns: the install directories and their parents
+fn system_dirs(paths: &InstallPaths) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for dir in [
+        &paths.prefix,
+        &paths.libdir,
+        &paths.includedir,
+        &paths.datarootdir,
+        &paths.datadir,
+        &paths.bindir,
+        &paths.pkgconfigdir,
+        &paths.systemdsystemunitdir,
+        &paths.udevrulesdir,
+        &paths.dbusservicedir,
+        &paths.mandir,
+    ] {
+        dirs.extend(dir.ancestors().map(Path::to_path_buf));
+    }
+    dirs
+}
+
+/// A `%files` path, quoted for the spaces and with the macros escaped
+fn files_path(path: &Path) -> String {
+    format!("\"{}\"", path.display().to_string().replace('%', "%%"))
+}
+
+/// The `%files` entries of a staged tree: its files and links, and the directories it
+/// creates besides the system ones
+fn files_section(
+    tree: &Path,
+    dir: &Path,
+    paths: &InstallPaths,
+    files: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let installed = Path::new("/").join(path.strip_prefix(tree)?);
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            // The manual pages are in the man<section> directories of the system
+            let man_section = installed.parent() == Some(&*paths.mandir);
+            if !man_section && !system_dirs(paths).contains(&installed) {
+                files.push(format!("%dir {}", files_path(&installed)));
+            }
+            files_section(tree, &path, paths, files)?;
+        } else {
+            files.push(files_path(&installed));
+        }
+    }
+    Ok(())
+}
+
+/// The spec of the binary packages, installing the staged trees as they are
+fn binary_spec(
+    pkg: &CPackage,
+    metadata: Option<&ManifestMetadata>,
+    runtime: &Path,
+    devel: &Path,
+) -> anyhow::Result<String> {
+    let capi_config = &pkg.capi_config;
+    let library = &capi_config.library;
+    let summary = package_summary(capi_config);
+    // rpm sorts the versions with a `~` before the release ones, as semver does with a `-`
+    let version = library.version.to_string().replace('-', "~");
+
+    let license = metadata
+        .and_then(|m| m.license.as_deref())
+        .map(|l| l.replace('/', " OR "))
+        .unwrap_or_else(|| "NOASSERTION".to_string());
+    let url = metadata
+        .and_then(|m| m.homepage.as_deref().or(m.repository.as_deref()))
+        .map(|url| format!("URL:            {url}\n"))
+        .unwrap_or_default();
+
+    // Without the shared library there may be no runtime package to require
+    let requires = if runtime.is_dir() {
+        "Requires:       %{name}%{?_isa} = %{version}-%{release}\n"
+    } else {
+        ""
+    };
+
+    let mut install = String::new();
+    let mut runtime_files = Vec::new();
+    let mut devel_files = Vec::new();
+    for (tree, files) in [(runtime, &mut runtime_files), (devel, &mut devel_files)] {
+        if tree.is_dir() {
+            install += &format!("cp -a '{}'/. %{{buildroot}}/\n", tree.display());
+            files_section(tree, tree, &pkg.install_paths, files)?;
+        }
+    }
+
+    Ok(format!(
+        "# The libraries are prebuilt by cargo cpackage
+%global debug_package %{{nil}}
+
+Name:           {name}
+Version:        {version}
+Release:        1%{{?dist}}
+Summary:        {summary}
+License:        {license}
+{url}
+%description
+{summary}
+
+%package        devel
+Summary:        Development files for %{{name}}
+{requires}
+%description    devel
+Development files for %{{name}}.
+
+%install
+{install}
+%files
+{runtime_files}
+
+%files devel
+{devel_files}
+",
+        name = library.name,
+        runtime_files = runtime_files.join("\n"),
+        devel_files = devel_files.join("\n"),
+    ))
+}
+
+/// Stage the install of the package and build the `<name>` and `<name>-devel` binary rpms
+/// with `rpmbuild`, the `RPMBUILD` environment variable may be used to override the tool
+///
+/// The spec is written along with the packages, as `<name>.spec`. With `spec_only` the
+/// packages are not built and the staged trees are kept for the spec to install.
+pub(crate) fn rpm_packages(
+    ws: &Workspace,
+    pkg: &mut CPackage,
+    output_dir: &Path,
+    spec_only: bool,
+) -> anyhow::Result<()> {
+    let target = pkg.build_targets.target.clone();
+    let arch = match (target.os.as_str(), rpm_arch(&target)) {
+        ("linux", Some(arch)) => arch,
+        _ => anyhow::bail!("there is no rpm architecture for {}", target.triple),
+    };
+
+    let (staging, runtime, devel) = stage_split_install(ws, pkg)?;
+
+    create_dir_all(output_dir)?;
+    let spec = output_dir.join(format!("{}.spec", pkg.capi_config.library.name));
+    let metadata = ws
+        .members()
+        .find(|m| m.root() == pkg.root_path)
+        .map(|m| m.manifest().metadata());
+    write(&spec, binary_spec(pkg, metadata, &runtime, &devel)?)?;
+    ws.config().shell().status("Writing", spec.display())?;
+    if spec_only {
+        return Ok(());
+    }
+
+    ws.config().shell().status(
+        "Packaging",
+        format!("{} for {arch}", pkg.capi_config.library.name),
+    )?;
+    let mut cmd = std::process::Command::new(
+        std::env::var_os("RPMBUILD").unwrap_or_else(|| "rpmbuild".into()),
+    );
+    cmd.arg("-bb")
+        .arg("--target")
+        .arg(arch)
+        .arg("--define")
+        .arg(format!("_topdir {}", staging.join("rpmbuild").display()))
+        .arg("--define")
+        .arg(format!("_rpmdir {}", output_dir.display()))
+        .arg("--define")
+        .arg("_build_name_fmt %%{NAME}-%%{VERSION}-%%{RELEASE}.%%{ARCH}.rpm")
+        .arg(&spec);
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Cannot run {:?}: {}", cmd, e))?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "Command failed {:?}: {}",
+            cmd,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    remove_dir_all(&staging)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::target::Target;
+
+    #[test]
+    fn rpm_arch() {
+        let arch = |triple: &str| super::rpm_arch(&Target::new(triple).unwrap());
+        assert_eq!(arch("x86_64-unknown-linux-gnu"), Some("x86_64"));
+        assert_eq!(arch("armv7-unknown-linux-gnueabihf"), Some("armv7hl"));
+        assert_eq!(arch("armv7-unknown-linux-gnueabi"), None);
+        assert_eq!(arch("powerpc64le-unknown-linux-gnu"), Some("ppc64le"));
+        assert_eq!(arch("riscv64gc-unknown-linux-gnu"), Some("riscv64"));
+    }
+
+    #[test]
+    fn files_path() {
+        assert_eq!(
+            super::files_path(Path::new("/usr/lib64/libfoo.so.1")),
+            "\"/usr/lib64/libfoo.so.1\""
+        );
+        assert_eq!(
+            super::files_path(Path::new("/usr/share/foo bar/100%.txt")),
+            "\"/usr/share/foo bar/100%%.txt\""
+        );
+    }
+}
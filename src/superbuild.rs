@@ -0,0 +1,880 @@
+use std::path::{Path, PathBuf};
+
+use cargo::core::Workspace;
+
+use crate::build::{CApiConfig, CPackage};
+use crate::target::Target;
+
+/// Build system fragment written by `--emit build-script=<format>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildScript {
+    Ninja,
+    Make,
+    CMake,
+    MsBuild,
+    Zephyr,
+    EspIdf,
+}
+
+impl BuildScript {
+    pub fn from_emit(emit: &str) -> anyhow::Result<Self> {
+        match emit.split_once('=') {
+            Some(("build-script", "ninja")) => Ok(BuildScript::Ninja),
+            Some(("build-script", "make")) => Ok(BuildScript::Make),
+            Some(("build-script", "cmake")) => Ok(BuildScript::CMake),
+            Some(("build-script", "msbuild")) => Ok(BuildScript::MsBuild),
+            Some(("build-script", "zephyr")) => Ok(BuildScript::Zephyr),
+            Some(("build-script", "esp-idf")) => Ok(BuildScript::EspIdf),
+            _ => anyhow::bail!(
+                "Unsupported --emit {}, expected build-script=ninja, make, cmake, msbuild, \
+                 zephyr or esp-idf",
+                emit
+            ),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            BuildScript::Ninja => "ninja",
+            BuildScript::Make => "mk",
+            BuildScript::CMake | BuildScript::Zephyr | BuildScript::EspIdf => "cmake",
+            BuildScript::MsBuild => "props",
+        }
+    }
+
+    /// Escape a path for the fragment
+    fn path(self, path: &Path) -> String {
+        let path = path.display().to_string();
+        match self {
+            BuildScript::Ninja => path
+                .replace('$', "$$")
+                .replace(' ', "$ ")
+                .replace(':', "$:"),
+            BuildScript::Make => path.replace('$', "$$").replace(' ', "\\ "),
+            BuildScript::CMake | BuildScript::Zephyr | BuildScript::EspIdf => {
+                format!("\"{}\"", path.replace('\\', "/"))
+            }
+            BuildScript::MsBuild => path
+                .replace('%', "%25")
+                .replace('$', "%24")
+                .replace(';', "%3B"),
+        }
+    }
+}
+
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// The arguments of the current invocation, without the subcommand and the options cargo-c
+/// sets by itself, so they can be replayed by cbuild and cinstall
+struct Invocation {
+    args: Vec<String>,
+    /// cbuild defaults to the dev profile, cinstall to release
+    release: bool,
+    /// A custom profile is kept in `args`
+    profile: bool,
+}
+
+impl Invocation {
+    fn current(skip: &[&str]) -> Self {
+        let mut args = std::env::args().skip(1).peekable();
+        let mut release = false;
+        match args.peek().map(String::as_str) {
+            Some("cinstall") => {
+                release = true;
+                args.next();
+            }
+            Some("cbuild" | "ctest") => {
+                args.next();
+            }
+            Some("capi") => {
+                args.next();
+                release = args.next().as_deref() == Some("install");
+            }
+            _ => {}
+        }
+
+        let mut replayed = Vec::new();
+        let mut profile = false;
+        while let Some(arg) = args.next() {
+            let option = arg.split_once('=').map_or(arg.as_str(), |(o, _)| o);
+            if skip.contains(&option) {
+                if !arg.contains('=') {
+                    args.next();
+                }
+            } else if arg == "--release" || arg == "-r" {
+                release = true;
+            } else if arg == "--debug" {
+                release = false;
+            } else {
+                profile = profile || option == "--profile";
+                replayed.push(arg);
+            }
+        }
+
+        Invocation {
+            args: replayed,
+            release,
+            profile,
+        }
+    }
+
+    fn subcommand_args(&self, subcommand: &str) -> Vec<String> {
+        let mut args = self.args.clone();
+        if !self.profile {
+            match (subcommand, self.release) {
+                ("cbuild", true) => args.push("--release".into()),
+                ("cinstall", false) => args.push("--debug".into()),
+                _ => {}
+            }
+        }
+        args
+    }
+}
+
+fn command(root: &Path, subcommand: &str, invocation: &Invocation) -> String {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let mut cmd = format!(
+        "cd {} && {} {subcommand}",
+        shell_quote(&root.display().to_string()),
+        shell_quote(&cargo)
+    );
+    for arg in invocation.subcommand_args(subcommand) {
+        cmd.push(' ');
+        cmd.push_str(&shell_quote(&arg));
+    }
+    cmd.replace('$', "$$")
+}
+
+/// Write a depfile listing what the outputs of cbuild depend on
+fn write_depfile(cpkg: &CPackage, root_output: &Path, depfile: &Path) -> anyhow::Result<()> {
+    // The dep-info of the library as written by cargo, named after the rustc artifact
+    let artifact = cpkg.capi_config.library.name.replace(['-', '.'], "_");
+    let dep_info = root_output.join(format!("lib{artifact}.d"));
+    let mut deps: Vec<String> = std::fs::read_to_string(dep_info)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.split_once(": ").map(|(_, deps)| deps.trim().to_string()))
+        .collect();
+
+    for file in ["Cargo.toml", "Cargo.lock", "cbindgen.toml"] {
+        let path = cpkg.root_path.join(file);
+        if path.exists() {
+            deps.push(BuildScript::Make.path(&path));
+        }
+    }
+
+    cargo_util::paths::write(
+        depfile,
+        format!(
+            "{}: {}\n",
+            BuildScript::Make.path(&primary_output(cpkg)),
+            deps.join(" ")
+        ),
+    )
+}
+
+/// The output recorded in the depfile, ninja supports only one
+fn primary_output(cpkg: &CPackage) -> PathBuf {
+    let build_targets = &cpkg.build_targets;
+    build_targets
+        .shared_lib
+        .clone()
+        .or_else(|| build_targets.static_lib.clone())
+        .unwrap_or_else(|| build_targets.pc.clone())
+}
+
+fn outputs(cpkg: &CPackage) -> Vec<PathBuf> {
+    let build_targets = &cpkg.build_targets;
+    let mut outputs = vec![primary_output(cpkg)];
+    for path in build_targets
+        .static_lib
+        .iter()
+        .chain(build_targets.shared_lib.iter())
+        .chain(build_targets.impl_lib.iter())
+        .chain(std::iter::once(&build_targets.pc))
+        .chain(build_targets.pc_modules.iter())
+        .chain(build_targets.include.iter())
+    {
+        if !outputs.contains(path) {
+            outputs.push(path.clone());
+        }
+    }
+    outputs
+}
+
+fn render_ninja(cpkg: &CPackage, depfile: &Path, invocation: &Invocation) -> String {
+    let format = BuildScript::Ninja;
+    let name = &cpkg.capi_config.library.name;
+    let ident = name.replace(['-', '.'], "_");
+    let outputs = outputs(cpkg)
+        .iter()
+        .map(|p| format.path(p))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let primary = format.path(&primary_output(cpkg));
+
+    format!(
+        "# Generated by cargo-c, do not edit
+
+rule cargo_cbuild_{ident}
+  command = {build}
+  description = cargo cbuild {name}
+  depfile = {depfile}
+  deps = gcc
+  restat = 1
+  pool = console
+
+rule cargo_cinstall_{ident}
+  command = {install}
+  description = cargo cinstall {name}
+  pool = console
+
+build {outputs}: cargo_cbuild_{ident}
+build {name}: phony {primary}
+build {name}-install: cargo_cinstall_{ident} | {primary}
+",
+        build = command(&cpkg.root_path, "cbuild", invocation),
+        install = command(&cpkg.root_path, "cinstall", invocation),
+        depfile = format.path(depfile),
+    )
+}
+
+fn render_make(cpkg: &CPackage, depfile: &Path, invocation: &Invocation) -> String {
+    let format = BuildScript::Make;
+    let name = &cpkg.capi_config.library.name;
+    let primary = format.path(&primary_output(cpkg));
+    // The other outputs are produced along with the primary one
+    let others = outputs(cpkg)
+        .iter()
+        .skip(1)
+        .map(|p| format!("{}: {primary}\n", format.path(p)))
+        .collect::<String>();
+
+    format!(
+        "# Generated by cargo-c, do not edit
+
+.PHONY: {name} {name}-install
+
+{name}: {primary}
+
+{primary}:
+\t{build}
+
+{others}
+-include {depfile}
+
+{name}-install: {primary}
+\t{install} $(if $(DESTDIR),--destdir=$(DESTDIR))
+",
+        build = command(&cpkg.root_path, "cbuild", invocation),
+        install = command(&cpkg.root_path, "cinstall", invocation),
+        depfile = format.path(depfile),
+    )
+}
+
+fn cmake_quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains([' ', ';', '"', '$', '\\', '#', '(', ')']) {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.to_string()
+    }
+}
+
+fn render_cmake(cpkg: &CPackage, invocation: &Invocation) -> anyhow::Result<String> {
+    let format = BuildScript::CMake;
+    let capi_config = &cpkg.capi_config;
+    let build_targets = &cpkg.build_targets;
+    let name = &capi_config.library.name;
+    let ident = name.replace(['-', '.'], "_").to_uppercase();
+    let windows = build_targets.target.os == "windows";
+
+    let cmake_args = |subcommand: &str| {
+        invocation
+            .subcommand_args(subcommand)
+            .iter()
+            .map(|a| format!(" {}", cmake_quote(a)))
+            .collect::<String>()
+    };
+
+    let mut libdir = PathBuf::from("lib");
+    if let Some(subdir) = &capi_config.library.install_subdir {
+        libdir.push(subdir);
+    }
+    let libdir = libdir.display().to_string();
+
+    let includedir = Path::new("include").join(&capi_config.header.subdirectory);
+    let includedir = includedir
+        .ancestors()
+        .nth(capi_config.pkg_config.strip_include_path_components)
+        .unwrap_or_else(|| Path::new("include"))
+        .display()
+        .to_string();
+
+    let file_name = |p: &Path| p.file_name().unwrap().to_string_lossy().into_owned();
+
+    // The shared library is preferred, as the pkg-config file does
+    let (kind, location, implib) = match (&build_targets.shared_lib, &build_targets.static_lib) {
+        (Some(shared_lib), _) => {
+            let dir = if windows { "bin" } else { libdir.as_str() };
+            (
+                "SHARED",
+                format!("${{{ident}_PREFIX}}/{dir}/{}", file_name(shared_lib)),
+                build_targets
+                    .impl_lib
+                    .as_ref()
+                    .map(|l| format!("${{{ident}_PREFIX}}/{libdir}/{}", file_name(l))),
+            )
+        }
+        (None, Some(static_lib)) => (
+            "STATIC",
+            format!("${{{ident}_PREFIX}}/{libdir}/{}", file_name(static_lib)),
+            None,
+        ),
+        (None, None) => {
+            anyhow::bail!("{name} builds no library, the CMake imported target cannot be written")
+        }
+    };
+
+    let mut properties = vec![
+        format!("IMPORTED_LOCATION \"{location}\""),
+        format!("INTERFACE_INCLUDE_DIRECTORIES \"${{{ident}_PREFIX}}/{includedir}\""),
+    ];
+    let mut byproducts = vec![format!("\"{location}\"")];
+    if let Some(implib) = implib {
+        properties.push(format!("IMPORTED_IMPLIB \"{implib}\""));
+        byproducts.push(format!("\"{implib}\""));
+    }
+    if kind == "STATIC" {
+        // The native libraries the static library needs, as reported by rustc
+        let mut libs = Vec::new();
+        let mut tokens = cpkg.static_libs().split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "-framework" => libs.push(format!("-framework {}", tokens.next().unwrap_or(""))),
+                lib => libs.push(lib.trim_start_matches("-l").to_string()),
+            }
+        }
+        let libs = libs.join(";");
+        if !libs.is_empty() {
+            properties.push(format!("INTERFACE_LINK_LIBRARIES \"{libs}\""));
+        }
+    }
+
+    Ok(format!(
+        "# Generated by cargo-c, do not edit
+#
+# include() this file to build {name} with cargo-c, then link to the
+# {name}::{name} imported target.
+
+include(ExternalProject)
+
+find_program(CARGO_EXECUTABLE cargo HINTS {cargo} REQUIRED)
+set({ident}_PREFIX \"${{CMAKE_CURRENT_BINARY_DIR}}/{name}\" CACHE PATH
+    \"Installation prefix of {name} in the build tree\")
+
+ExternalProject_Add({name}-cargo
+    SOURCE_DIR {source_dir}
+    BUILD_IN_SOURCE TRUE
+    CONFIGURE_COMMAND \"\"
+    BUILD_COMMAND ${{CARGO_EXECUTABLE}} cbuild{build_args}
+        --prefix=${{{ident}_PREFIX}} --libdir=${{{ident}_PREFIX}}/{libdir}
+    INSTALL_COMMAND ${{CARGO_EXECUTABLE}} cinstall{install_args}
+        --prefix=${{{ident}_PREFIX}} --libdir=${{{ident}_PREFIX}}/{libdir}
+    # cargo decides what needs to be rebuilt
+    BUILD_ALWAYS TRUE
+    BUILD_BYPRODUCTS {byproducts}
+    USES_TERMINAL_BUILD TRUE
+    USES_TERMINAL_INSTALL TRUE
+)
+
+# The imported target requires its include directory to exist at configure time
+file(MAKE_DIRECTORY \"${{{ident}_PREFIX}}/{includedir}\")
+
+add_library({name}::{name} {kind} IMPORTED GLOBAL)
+set_target_properties({name}::{name} PROPERTIES
+    {properties}
+)
+add_dependencies({name}::{name} {name}-cargo)
+",
+        cargo = format.path(
+            Path::new(&std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+        ),
+        source_dir = format.path(&cpkg.root_path),
+        build_args = cmake_args("cbuild"),
+        install_args = cmake_args("cinstall"),
+        byproducts = byproducts.join(" "),
+        properties = properties.join("\n    "),
+    ))
+}
+
+/// The MSBuild `Platform` matching the target architecture
+pub(crate) fn msbuild_platform(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "x64",
+        "x86" => "Win32",
+        "aarch64" => "ARM64",
+        "arm" => "ARM",
+        arch => arch,
+    }
+}
+
+/// The MSBuild condition selecting a build, the UWP builds are only picked by the app
+/// container projects and the desktop ones by the others
+pub(crate) fn msbuild_condition(target: &Target, configuration: &str) -> String {
+    let platform = msbuild_platform(&target.arch);
+    let app_container = if target.vendor == "uwp" { "==" } else { "!=" };
+    format!(
+        "'$(Platform)' == '{platform}' And '$(Configuration)' == '{configuration}' \
+         And '$(AppContainerApplication)' {app_container} 'true'"
+    )
+}
+
+/// The MSBuild `Configuration` matching the profile directory
+pub(crate) fn msbuild_configuration(profile_dir: &str) -> String {
+    match profile_dir {
+        "debug" => "Debug".into(),
+        "release" => "Release".into(),
+        profile => profile.into(),
+    }
+}
+
+/// Property sheet of a single build, imported by the one of the target directory when the
+/// `Platform` and the `Configuration` of the consumer project match
+fn render_msbuild(cpkg: &CPackage, root_output: &Path) -> String {
+    let format = BuildScript::MsBuild;
+    let build_targets = &cpkg.build_targets;
+    let capi_config = &cpkg.capi_config;
+    let name = &capi_config.library.name;
+    let file_name = |p: &Path| format.path(Path::new(p.file_name().unwrap()));
+
+    let target = &build_targets.target;
+    let profile_dir = root_output.file_name().unwrap().to_string_lossy();
+    let configuration = msbuild_configuration(&profile_dir);
+    let condition = msbuild_condition(target, &configuration);
+    let ident = format!(
+        "{}CopyDll{}{}{configuration}",
+        name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"),
+        if target.vendor == "uwp" { "Uwp" } else { "" },
+        msbuild_platform(&target.arch),
+    );
+
+    let includedir =
+        Path::new("$(MSBuildThisFileDirectory)include").join(&capi_config.header.subdirectory);
+    let includedir = includedir
+        .ancestors()
+        .nth(capi_config.pkg_config.strip_include_path_components)
+        .unwrap_or_else(|| Path::new("$(MSBuildThisFileDirectory)include"))
+        .display()
+        .to_string();
+
+    // The shared library is preferred, as the pkg-config file does
+    let mut libs = Vec::new();
+    let mut copy = String::new();
+    match (&build_targets.shared_lib, &build_targets.impl_lib) {
+        (Some(shared_lib), Some(impl_lib)) => {
+            libs.push(file_name(impl_lib));
+            copy = format!(
+                "
+  <Target Name=\"{ident}\" AfterTargets=\"Build\" Condition=\"{condition}\">
+    <Copy SourceFiles=\"$(MSBuildThisFileDirectory){dll}\" DestinationFolder=\"$(OutDir)\" SkipUnchangedFiles=\"true\" />
+  </Target>
+",
+                dll = file_name(shared_lib),
+            );
+        }
+        _ => {
+            if let Some(static_lib) = &build_targets.static_lib {
+                libs.push(file_name(static_lib));
+                // The native libraries the static library needs, as reported by rustc
+                libs.extend(
+                    cpkg.static_libs()
+                        .split_whitespace()
+                        .filter(|l| l.ends_with(".lib"))
+                        .map(String::from),
+                );
+            }
+        }
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<!-- Generated by cargo-c, do not edit -->
+<Project xmlns=\"http://schemas.microsoft.com/developer/msbuild/2003\">
+  <ItemDefinitionGroup Condition=\"{condition}\">
+    <ClCompile>
+      <AdditionalIncludeDirectories>{includedir};%(AdditionalIncludeDirectories)</AdditionalIncludeDirectories>
+    </ClCompile>
+    <Link>
+      <AdditionalLibraryDirectories>$(MSBuildThisFileDirectory);%(AdditionalLibraryDirectories)</AdditionalLibraryDirectories>
+      <AdditionalDependencies>{libs};%(AdditionalDependencies)</AdditionalDependencies>
+    </Link>
+  </ItemDefinitionGroup>{copy}</Project>
+",
+        libs = libs.join(";"),
+    )
+}
+
+/// Property sheet of the target directory importing the ones of every target and profile
+/// built so far, the only one the consumer project has to reference
+fn render_msbuild_index(name: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<!-- Generated by cargo-c, do not edit -->
+<Project xmlns=\"http://schemas.microsoft.com/developer/msbuild/2003\">
+  <Import Project=\"$(MSBuildThisFileDirectory)*\\*\\{name}.props\" />
+</Project>
+"
+    )
+}
+
+/// Write the fragment wrapping cbuild and cinstall next to the built library
+pub fn emit_build_script(
+    ws: &Workspace,
+    format: BuildScript,
+    cpkg: &CPackage,
+    root_output: &Path,
+) -> anyhow::Result<()> {
+    let name = &cpkg.capi_config.library.name;
+    let depfile = root_output.join(format!("{name}-cbuild.d"));
+    let fragment = root_output.join(format!("{name}.{}", format.extension()));
+
+    let contents = match format {
+        BuildScript::Ninja => {
+            write_depfile(cpkg, root_output, &depfile)?;
+            render_ninja(cpkg, &depfile, &Invocation::current(&["--emit"]))
+        }
+        BuildScript::Make => {
+            write_depfile(cpkg, root_output, &depfile)?;
+            render_make(cpkg, &depfile, &Invocation::current(&["--emit"]))
+        }
+        // The prefix is the one of the build tree
+        BuildScript::CMake => render_cmake(
+            cpkg,
+            &Invocation::current(&["--emit", "--prefix", "--libdir", "--destdir"]),
+        )?,
+        // The modules are directories of files rather than a single fragment
+        BuildScript::Zephyr => {
+            let static_lib = module_static_lib(cpkg, format)?;
+            let files = render_zephyr(&cpkg.capi_config, static_lib, cpkg.static_libs());
+            return write_module(ws, &root_output.join("zephyr"), &files);
+        }
+        BuildScript::EspIdf => {
+            let static_lib = module_static_lib(cpkg, format)?;
+            let files = render_esp_idf(
+                &cpkg.capi_config,
+                &cpkg.build_targets.target.triple,
+                static_lib,
+                cpkg.static_libs(),
+            );
+            return write_module(ws, &root_output.join("esp-idf").join(name), &files);
+        }
+        BuildScript::MsBuild => {
+            let target = &cpkg.build_targets.target;
+            if target.os != "windows" || target.env != "msvc" {
+                anyhow::bail!("--emit build-script=msbuild requires a windows-msvc target");
+            }
+            let target_dir = crate::build::target_dir_of(root_output)?;
+            let index = target_dir.join(format!("{name}.props"));
+            ws.config().shell().status("Writing", index.display())?;
+            cargo_util::paths::write_if_changed(&index, render_msbuild_index(name))?;
+            render_msbuild(cpkg, root_output)
+        }
+    };
+
+    ws.config().shell().status("Writing", fragment.display())?;
+    cargo_util::paths::write_if_changed(&fragment, contents)
+}
+
+/// The static library imported by the Zephyr module or the ESP-IDF component
+fn module_static_lib(cpkg: &CPackage, format: BuildScript) -> anyhow::Result<&Path> {
+    match &cpkg.build_targets.static_lib {
+        Some(static_lib) => Ok(static_lib),
+        None => {
+            let format = match format {
+                BuildScript::EspIdf => "esp-idf",
+                _ => "zephyr",
+            };
+            anyhow::bail!("--emit build-script={format} requires the staticlib library type")
+        }
+    }
+}
+
+/// The uninstalled include directory, relative to the directory of the library
+fn module_includedir(capi_config: &CApiConfig) -> PathBuf {
+    let includedir = Path::new("include").join(&capi_config.header.subdirectory);
+    includedir
+        .ancestors()
+        .nth(capi_config.pkg_config.strip_include_path_components)
+        .unwrap_or_else(|| Path::new("include"))
+        // Drop the trailing separator an empty subdirectory leaves
+        .components()
+        .collect()
+}
+
+/// The `zephyr/` directory of a Zephyr module, rooted in the build tree, importing the
+/// static library and the include directory once its Kconfig option is enabled, linked with
+/// the native `static_libs` it needs
+fn render_zephyr(
+    capi_config: &CApiConfig,
+    static_lib: &Path,
+    static_libs: &str,
+) -> [(&'static str, String); 3] {
+    let name = &capi_config.library.name;
+    let ident = name
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+        .to_uppercase();
+
+    let module_path =
+        |p: &Path| BuildScript::Zephyr.path(&Path::new("${CMAKE_CURRENT_LIST_DIR}/..").join(p));
+
+    // The native libraries the static library needs, as reported by rustc
+    let link_libraries = match static_libs.trim() {
+        "" => String::new(),
+        libs => format!("  zephyr_link_libraries({libs})\n"),
+    };
+
+    let module = format!(
+        "# Generated by cargo-c, do not edit
+name: {name}
+build:
+  cmake: zephyr
+  kconfig: zephyr/Kconfig
+"
+    );
+
+    let cmake = format!(
+        "# Generated by cargo-c, do not edit
+
+if(CONFIG_{ident})
+  zephyr_library_import({name} {static_lib})
+  zephyr_include_directories({includedir})
+{link_libraries}endif()
+",
+        static_lib = module_path(Path::new(static_lib.file_name().unwrap())),
+        includedir = module_path(&module_includedir(capi_config)),
+    );
+
+    let kconfig = format!(
+        "# Generated by cargo-c, do not edit
+
+config {ident}
+\tbool \"{name}\"
+\thelp
+\t  Link the static library {name} built by cargo-c and add its
+\t  include directory.
+"
+    );
+
+    [
+        ("module.yml", module),
+        ("CMakeLists.txt", cmake),
+        ("Kconfig", kconfig),
+    ]
+}
+
+/// The chips an ESP-IDF target triple builds for, as named by `idf.py set-target`
+fn idf_targets(triple: &str) -> &'static [&'static str] {
+    match triple {
+        "xtensa-esp32-espidf" => &["esp32"],
+        "xtensa-esp32s2-espidf" => &["esp32s2"],
+        "xtensa-esp32s3-espidf" => &["esp32s3"],
+        "riscv32imc-esp-espidf" => &["esp32c2", "esp32c3"],
+        "riscv32imac-esp-espidf" => &["esp32c6", "esp32h2"],
+        "riscv32imafc-esp-espidf" => &["esp32p4"],
+        _ => &[],
+    }
+}
+
+/// An ESP-IDF component, rooted in the build tree, exposing the static library and the
+/// include directory to the components requiring it, linked with the native `static_libs`
+/// it needs
+fn render_esp_idf(
+    capi_config: &CApiConfig,
+    triple: &str,
+    static_lib: &Path,
+    static_libs: &str,
+) -> [(&'static str, String); 2] {
+    let name = &capi_config.library.name;
+
+    // The component is in esp-idf/<name>, two levels below the library
+    let component_path =
+        |p: &Path| BuildScript::EspIdf.path(&Path::new("${CMAKE_CURRENT_LIST_DIR}/../..").join(p));
+
+    let link_libraries = match static_libs.trim() {
+        "" => String::new(),
+        libs => format!("target_link_libraries(${{COMPONENT_LIB}} INTERFACE {libs})\n"),
+    };
+
+    let mut manifest = format!(
+        "# Generated by cargo-c, do not edit
+version: \"{}\"
+",
+        capi_config.library.version
+    );
+    if !capi_config.pkg_config.description.is_empty() {
+        manifest.push_str(&format!(
+            "description: \"{}\"\n",
+            capi_config.pkg_config.description.replace('"', "\\\"")
+        ));
+    }
+    let targets = idf_targets(triple);
+    if !targets.is_empty() {
+        manifest.push_str("targets:\n");
+        for target in targets {
+            manifest.push_str(&format!("  - {target}\n"));
+        }
+    }
+
+    let cmake = format!(
+        "# Generated by cargo-c, do not edit
+
+idf_component_register(INCLUDE_DIRS {includedir})
+
+add_prebuilt_library({name}-prebuilt {static_lib})
+target_link_libraries(${{COMPONENT_LIB}} INTERFACE {name}-prebuilt)
+{link_libraries}",
+        static_lib = component_path(Path::new(static_lib.file_name().unwrap())),
+        includedir = component_path(&module_includedir(capi_config)),
+    );
+
+    [("idf_component.yml", manifest), ("CMakeLists.txt", cmake)]
+}
+
+fn write_module(ws: &Workspace, dir: &Path, files: &[(&str, String)]) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    ws.config().shell().status("Writing", dir.display())?;
+    for (file, contents) in files {
+        cargo_util::paths::write_if_changed(dir.join(file), contents)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn zephyr() {
+        let mut capi_config = crate::build::test_capi_config();
+        capi_config.library.name = "foo-bar".into();
+        let [module, cmake, kconfig] =
+            super::render_zephyr(&capi_config, Path::new("/build/libfoo_bar.a"), " -lm -lc ");
+
+        assert_eq!(
+            module,
+            (
+                "module.yml",
+                "# Generated by cargo-c, do not edit
+name: foo-bar
+build:
+  cmake: zephyr
+  kconfig: zephyr/Kconfig
+"
+                .to_string()
+            )
+        );
+        assert_eq!(
+            cmake,
+            (
+                "CMakeLists.txt",
+                "# Generated by cargo-c, do not edit
+
+if(CONFIG_FOO_BAR)
+  zephyr_library_import(foo-bar \"${CMAKE_CURRENT_LIST_DIR}/../libfoo_bar.a\")
+  zephyr_include_directories(\"${CMAKE_CURRENT_LIST_DIR}/../include\")
+  zephyr_link_libraries(-lm -lc)
+endif()
+"
+                .to_string()
+            )
+        );
+        assert_eq!(
+            kconfig,
+            (
+                "Kconfig",
+                "# Generated by cargo-c, do not edit
+
+config FOO_BAR
+\tbool \"foo-bar\"
+\thelp
+\t  Link the static library foo-bar built by cargo-c and add its
+\t  include directory.
+"
+                .to_string()
+            )
+        );
+
+        // Without native libraries there is nothing more to link
+        let [_, (_, cmake), _] =
+            super::render_zephyr(&capi_config, Path::new("/build/libfoo_bar.a"), "");
+        assert!(!cmake.contains("zephyr_link_libraries"));
+    }
+
+    #[test]
+    fn esp_idf() {
+        let mut capi_config = crate::build::test_capi_config();
+        capi_config.pkg_config.description = "The \"foo\" library".into();
+        let [manifest, cmake] = super::render_esp_idf(
+            &capi_config,
+            "riscv32imc-esp-espidf",
+            Path::new("/build/libfoo.a"),
+            "-lm",
+        );
+
+        assert_eq!(
+            manifest,
+            (
+                "idf_component.yml",
+                "# Generated by cargo-c, do not edit
+version: \"0.1.0\"
+description: \"The \\\"foo\\\" library\"
+targets:
+  - esp32c2
+  - esp32c3
+"
+                .to_string()
+            )
+        );
+        assert_eq!(
+            cmake,
+            (
+                "CMakeLists.txt",
+                "# Generated by cargo-c, do not edit
+
+idf_component_register(INCLUDE_DIRS \"${CMAKE_CURRENT_LIST_DIR}/../../include\")
+
+add_prebuilt_library(foo-prebuilt \"${CMAKE_CURRENT_LIST_DIR}/../../libfoo.a\")
+target_link_libraries(${COMPONENT_LIB} INTERFACE foo-prebuilt)
+target_link_libraries(${COMPONENT_LIB} INTERFACE -lm)
+"
+                .to_string()
+            )
+        );
+
+        // An unknown triple builds for any chip
+        capi_config.pkg_config.description = String::new();
+        let [(_, manifest), _] = super::render_esp_idf(
+            &capi_config,
+            "xtensa-esp32c9-espidf",
+            Path::new("/build/libfoo.a"),
+            "",
+        );
+        assert_eq!(
+            manifest,
+            "# Generated by cargo-c, do not edit\nversion: \"0.1.0\"\n"
+        );
+    }
+}
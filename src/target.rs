@@ -4,16 +4,23 @@ use anyhow::*;
 
 use crate::build::CApiConfig;
 
-/// Split a target string to its components
+/// The components of a target, as rustc sees them
 ///
-/// Because of https://github.com/rust-lang/rust/issues/61558
-/// It uses internally `rustc` to validate the string.
+/// The triple itself is not split as its components do not map to the ones rustc uses,
+/// e.g. `thumbv7em-none-eabihf` or `x86_64-uwp-windows-msvc`, because of
+/// https://github.com/rust-lang/rust/issues/61558
+/// They are read from `rustc --print cfg`, which validates the target as well.
 #[derive(Clone, Debug)]
 pub struct Target {
+    /// The target as passed to rustc
+    pub triple: String,
     pub arch: String,
-    // pub vendor: String,
+    pub vendor: String,
     pub os: String,
     pub env: String,
+    pub abi: String,
+    /// The `cfg` values of the target, as printed by rustc
+    pub cfg: Vec<cargo_platform::Cfg>,
 }
 
 impl Target {
@@ -22,31 +29,101 @@ impl Target {
         let mut cmd = std::process::Command::new(rustc);
 
         cmd.arg("--print").arg("cfg");
-        cmd.arg("--target").arg(target);
-
-        let out = cmd.output()?;
-        if out.status.success() {
-            fn match_re(re: regex::Regex, s: &str) -> String {
-                re.captures(s)
-                    .map_or("", |cap| cap.get(1).unwrap().as_str())
-                    .to_owned()
-            }
+        cmd.arg("--target").arg(target.as_ref());
+
+        let out = cmd
+            .output()
+            .with_context(|| format!("Cannot run {:?}", cmd))?;
+        if !out.status.success() {
+            bail!(
+                "Cannot run {:?}: {}",
+                cmd,
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
 
-            let arch_re = regex::Regex::new(r#"target_arch="(.+)""#).unwrap();
-            // let vendor_re = regex::Regex::new(r#"target_vendor="(.+)""#).unwrap();
-            let os_re = regex::Regex::new(r#"target_os="(.+)""#).unwrap();
-            let env_re = regex::Regex::new(r#"target_env="(.+)""#).unwrap();
+        let cfg = String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(|l| l.parse())
+            .collect::<Result<Vec<cargo_platform::Cfg>, _>>()
+            .with_context(|| format!("Cannot parse the output of {:?}", cmd))?;
 
-            let s = std::str::from_utf8(&out.stdout).unwrap();
+        let mut target = Target {
+            triple: target.as_ref().to_string_lossy().into_owned(),
+            arch: String::new(),
+            vendor: String::new(),
+            os: String::new(),
+            env: String::new(),
+            abi: String::new(),
+            cfg,
+        };
+        target.arch = target.cfg_value("target_arch").to_owned();
+        target.vendor = target.cfg_value("target_vendor").to_owned();
+        target.os = target.cfg_value("target_os").to_owned();
+        target.env = target.cfg_value("target_env").to_owned();
+        target.abi = target.cfg_value("target_abi").to_owned();
 
-            Ok(Target {
-                arch: match_re(arch_re, s),
-                // vendor: match_re(vendor_re, s),
-                os: match_re(os_re, s),
-                env: match_re(env_re, s),
+        Ok(target)
+    }
+
+    /// The value of a `cfg` key of the target, e.g. `target_endian`
+    fn cfg_value(&self, name: &str) -> &str {
+        self.cfg
+            .iter()
+            .find_map(|cfg| match cfg {
+                cargo_platform::Cfg::KeyPair(key, value) if key == name => Some(value.as_str()),
+                _ => None,
             })
-        } else {
-            Err(anyhow!("Cannot run {:?}", cmd))
+            .unwrap_or_default()
+    }
+
+    /// Variables describing the target, available as `@NAME@` placeholders
+    pub fn variables(&self) -> Vec<(String, String)> {
+        vec![
+            ("TARGET".into(), self.triple.clone()),
+            ("TARGET_ARCH".into(), self.arch.clone()),
+            ("TARGET_OS".into(), self.os.clone()),
+            ("TARGET_ENV".into(), self.env.clone()),
+            ("TARGET_ABI".into(), self.abi.clone()),
+            (
+                "TARGET_POINTER_WIDTH".into(),
+                self.cfg_value("target_pointer_width").into(),
+            ),
+            (
+                "TARGET_ENDIAN".into(),
+                self.cfg_value("target_endian").into(),
+            ),
+        ]
+    }
+
+    /// Whether a target triple or a `cfg()` expression matches the target
+    pub fn matches(&self, spec: &str) -> anyhow::Result<bool> {
+        let platform = spec
+            .parse::<cargo_platform::Platform>()
+            .map_err(|e| anyhow!("invalid target condition `{}`: {}", spec, e))?;
+        Ok(platform.matches(&self.triple, &self.cfg))
+    }
+
+    /// The soname of the ELF shared library, the name of the link cinstall creates to it
+    pub fn soname(&self, capi_config: &CApiConfig) -> Option<String> {
+        let library = &capi_config.library;
+        let (prefix, suffix) = crate::build_targets::shared_lib_affixes(library, self);
+        let lib_name = &library.name;
+
+        match self.os.as_str() {
+            _ if library.module => None,
+            "android" => Some(format!("{prefix}{lib_name}{suffix}")),
+            "linux" | "freebsd" | "dragonfly" | "netbsd" | "haiku" | "illumos" => {
+                if library.versioning {
+                    Some(format!(
+                        "{prefix}{lib_name}{suffix}.{}",
+                        library.version.major
+                    ))
+                } else {
+                    Some(format!("{prefix}{lib_name}{suffix}"))
+                }
+            }
+            _ => None,
         }
     }
 
@@ -69,33 +146,29 @@ impl Target {
         let os = &self.os;
         let env = &self.env;
 
-        if os == "android" {
-            lines.push(format!("-Wl,-soname,lib{lib_name}.so"));
-        } else if os == "linux"
-            || os == "freebsd"
-            || os == "dragonfly"
-            || os == "netbsd"
-            || os == "haiku"
-            || os == "illumos"
-        {
-            if capi_config.library.versioning {
-                lines.push(format!("-Wl,-soname,lib{lib_name}.so.{major}"));
-            } else {
-                lines.push(format!("-Wl,-soname,lib{lib_name}.so"));
-            }
+        let (prefix, suffix) = crate::build_targets::shared_lib_affixes(&capi_config.library, self);
+
+        if capi_config.library.module {
+            // The modules are loaded by path, without soname, install name or import library
+        } else if let Some(soname) = self.soname(capi_config) {
+            lines.push(format!("-Wl,-soname,{soname}"));
         } else if os == "macos" || os == "ios" {
-            let line = if capi_config.library.versioning {
+            let line = if capi_config.library.framework {
+                format!(
+                    "-Wl,-install_name,{},-current_version,{major}.{minor}.{patch}",
+                    crate::framework::install_name(capi_config, self, libdir)
+                )
+            } else if capi_config.library.versioning {
                 let install_ver = if major == 0 {
                     format!("{major}.{minor}")
                 } else {
                     format!("{major}")
                 };
-                format!("-Wl,-install_name,{1}/lib{0}.{5}.dylib,-current_version,{2}.{3}.{4},-compatibility_version,{5}",
-                        lib_name, libdir.display(), major, minor, patch, install_ver)
+                format!("-Wl,-install_name,{1}/{6}{0}.{5}{7},-current_version,{2}.{3}.{4},-compatibility_version,{5}",
+                        lib_name, libdir.display(), major, minor, patch, install_ver, prefix, suffix)
             } else {
                 format!(
-                    "-Wl,-install_name,{1}/lib{0}.dylib",
-                    lib_name,
+                    "-Wl,-install_name,{}/{prefix}{lib_name}{suffix}",
                     libdir.display()
                 )
             };
@@ -110,6 +183,12 @@ impl Target {
             ));
         }
 
+        if os == "windows" && env == "msvc" && self.vendor == "uwp" {
+            // rustc sets /APPCONTAINER already, the Store certification requires ASLR and DEP
+            lines.push("/DYNAMICBASE".to_string());
+            lines.push("/NXCOMPAT".to_string());
+        }
+
         lines
     }
 }
@@ -0,0 +1,330 @@
+use std::path::{Path, PathBuf};
+
+use cargo::core::{Package, Workspace};
+use cargo::util::command_prelude::ArgMatchesExt;
+use cargo::util::interning::InternedString;
+use cargo_util::paths::{create_dir_all, write};
+use clap::ArgMatches;
+
+use crate::build::warn;
+
+/// The port name, vcpkg allows lowercase alphanumeric characters and hyphens only
+fn port_name(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// The `vcpkg.json` manifest of the port, versioned as the crate whose `v<version>` tag
+/// the sources are fetched from
+fn vcpkg_json(pkg: &Package) -> anyhow::Result<String> {
+    let metadata = pkg.manifest().metadata();
+    // In the order of the vcpkg format-manifest
+    let fields = [
+        ("name", Some(port_name(&pkg.name()))),
+        ("version-semver", Some(pkg.version().to_string())),
+        ("description", metadata.description.clone()),
+        (
+            "homepage",
+            metadata.homepage.clone().or_else(|| {
+                let repository = metadata.repository.as_deref()?;
+                Some(repository.trim_end_matches(".git").to_owned())
+            }),
+        ),
+        (
+            "license",
+            metadata.license.as_ref().map(|l| l.replace('/', " OR ")),
+        ),
+    ];
+    let mut lines = Vec::new();
+    for (key, value) in fields {
+        if let Some(value) = value {
+            lines.push(format!(
+                "  \"{key}\": {}",
+                serde_json::to_string(value.trim())?
+            ));
+        }
+    }
+
+    Ok(format!("{{\n{}\n}}\n", lines.join(",\n")))
+}
+
+/// The license files of the package, relative to its root
+fn license_files(pkg: &Package) -> anyhow::Result<Vec<PathBuf>> {
+    if let Some(license_file) = &pkg.manifest().metadata().license_file {
+        return Ok(vec![PathBuf::from(license_file)]);
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(pkg.root())? {
+        let name = entry?.file_name();
+        let upper = name.to_string_lossy().to_uppercase();
+        if ["LICENSE", "LICENCE", "COPYING", "UNLICENSE"]
+            .iter()
+            .any(|prefix| upper.starts_with(prefix))
+        {
+            files.push(PathBuf::from(name));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Fetch the sources of the `v<version>` tag, the SHA512 is to be filled in with the one
+/// vcpkg reports on the first install
+fn source_fetch(pkg: &Package) -> anyhow::Result<String> {
+    pkg.manifest()
+        .metadata()
+        .repository
+        .as_deref()
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} has no package.repository, the vcpkg port cannot fetch its sources",
+                pkg.name()
+            )
+        })
+        .map(repository_fetch)
+}
+
+/// Fetch the sources of the `v<version>` tag from `repository`, an archive for GitHub
+fn repository_fetch(repository: &str) -> String {
+    let repository = repository.trim_end_matches('/');
+    let repository = repository.strip_suffix(".git").unwrap_or(repository);
+
+    match repository.strip_prefix("https://github.com/") {
+        Some(repo) => format!(
+            "vcpkg_from_github(
+    OUT_SOURCE_PATH SOURCE_PATH
+    REPO {repo}
+    REF \"v${{VERSION}}\"
+    SHA512 0
+)"
+        ),
+        None => format!(
+            "# REF is the commit of the v${{VERSION}} tag
+vcpkg_from_git(
+    OUT_SOURCE_PATH SOURCE_PATH
+    URL {repository}
+    REF 0
+    FETCH_REF \"v${{VERSION}}\"
+)"
+        ),
+    }
+}
+
+/// The rust target of the vcpkg triplet
+const TRIPLET_MAPPING: &str = r#"set(rust_arch_x86 i686)
+set(rust_arch_x64 x86_64)
+set(rust_arch_arm armv7)
+set(rust_arch_arm64 aarch64)
+set(rust_arch_wasm32 wasm32)
+set(rust_arch "${rust_arch_${VCPKG_TARGET_ARCHITECTURE}}")
+if(NOT rust_arch)
+    message(FATAL_ERROR "Unsupported architecture ${VCPKG_TARGET_ARCHITECTURE}")
+endif()
+
+# The 32-bit ARM Windows targets of Rust are Thumb-2 ones
+if(VCPKG_TARGET_IS_UWP AND rust_arch STREQUAL "armv7")
+    set(rust_target "thumbv7a-uwp-windows-msvc")
+elseif(VCPKG_TARGET_IS_UWP)
+    set(rust_target "${rust_arch}-uwp-windows-msvc")
+elseif(VCPKG_TARGET_IS_MINGW)
+    set(rust_target "${rust_arch}-pc-windows-gnu")
+elseif(VCPKG_TARGET_IS_WINDOWS AND rust_arch STREQUAL "armv7")
+    set(rust_target "thumbv7a-pc-windows-msvc")
+elseif(VCPKG_TARGET_IS_WINDOWS)
+    set(rust_target "${rust_arch}-pc-windows-msvc")
+elseif(VCPKG_TARGET_IS_OSX)
+    set(rust_target "${rust_arch}-apple-darwin")
+elseif(VCPKG_TARGET_IS_IOS)
+    set(rust_target "${rust_arch}-apple-ios")
+elseif(VCPKG_TARGET_IS_ANDROID AND rust_arch STREQUAL "armv7")
+    set(rust_target "armv7-linux-androideabi")
+elseif(VCPKG_TARGET_IS_ANDROID)
+    set(rust_target "${rust_arch}-linux-android")
+elseif(VCPKG_TARGET_IS_EMSCRIPTEN)
+    set(rust_target "wasm32-unknown-emscripten")
+elseif(VCPKG_TARGET_IS_FREEBSD)
+    set(rust_target "${rust_arch}-unknown-freebsd")
+elseif(VCPKG_TARGET_IS_LINUX AND rust_arch STREQUAL "armv7")
+    set(rust_target "armv7-unknown-linux-gnueabihf")
+elseif(VCPKG_TARGET_IS_LINUX)
+    set(rust_target "${rust_arch}-unknown-linux-gnu")
+else()
+    message(FATAL_ERROR "Unsupported triplet ${TARGET_TRIPLET}")
+endif()
+
+if(VCPKG_LIBRARY_LINKAGE STREQUAL "dynamic")
+    set(library_type cdylib)
+else()
+    set(library_type staticlib)
+endif()
+if(VCPKG_TARGET_IS_WINDOWS AND VCPKG_CRT_LINKAGE STREQUAL "static")
+    set(ENV{RUSTFLAGS} "$ENV{RUSTFLAGS} -Ctarget-feature=+crt-static")
+endif()
+"#;
+
+/// A path in the sources, with the forward slashes cmake expects
+fn source_path(path: &Path) -> String {
+    let components: Vec<_> = path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    format!("\"${{SOURCE_PATH}}/{}\"", components.join("/"))
+}
+
+/// The `portfile.cmake` installing the release and the debug builds with `cargo cinstall`
+fn portfile(fetch: &str, manifest_dir: &Path, licenses: &[PathBuf]) -> String {
+    let manifest_path = source_path(&manifest_dir.join("Cargo.toml"));
+    let copyright = if licenses.is_empty() {
+        "# TODO: list the license files of the sources\nvcpkg_install_copyright(FILE_LIST)".into()
+    } else {
+        let files: Vec<_> = licenses
+            .iter()
+            .map(|l| source_path(&manifest_dir.join(l)))
+            .collect();
+        format!("vcpkg_install_copyright(FILE_LIST {})", files.join(" "))
+    };
+
+    format!(
+        "{fetch}
+
+{TRIPLET_MAPPING}
+find_program(CARGO cargo REQUIRED)
+
+set(build_types release)
+if(NOT VCPKG_BUILD_TYPE)
+    list(APPEND build_types debug)
+endif()
+foreach(build_type IN LISTS build_types)
+    if(build_type STREQUAL \"release\")
+        set(prefix \"${{CURRENT_PACKAGES_DIR}}\")
+        set(profile release)
+    else()
+        set(prefix \"${{CURRENT_PACKAGES_DIR}}/debug\")
+        set(profile dev)
+    endif()
+    vcpkg_execute_build_process(
+        COMMAND \"${{CARGO}}\" cinstall --profile ${{profile}}
+            --target ${{rust_target}} --library-type ${{library_type}}
+            --manifest-path {manifest_path}
+            --target-dir \"${{CURRENT_BUILDTREES_DIR}}/target\"
+            --prefix \"${{prefix}}\"
+        WORKING_DIRECTORY \"${{SOURCE_PATH}}\"
+        LOGNAME \"cinstall-${{TARGET_TRIPLET}}-${{build_type}}\"
+    )
+endforeach()
+
+# The install manifests of cargo cuninstall record the absolute paths of the build
+file(REMOVE_RECURSE
+    \"${{CURRENT_PACKAGES_DIR}}/lib/cargo-c\"
+    \"${{CURRENT_PACKAGES_DIR}}/debug/lib/cargo-c\"
+    \"${{CURRENT_PACKAGES_DIR}}/debug/include\"
+    \"${{CURRENT_PACKAGES_DIR}}/debug/share\"
+)
+vcpkg_fixup_pkgconfig()
+
+{copyright}
+"
+    )
+}
+
+/// Write the `vcpkg.json` and the `portfile.cmake` of a port building the library with
+/// `cargo cinstall`, in an overlay ports directory
+pub fn cvcpkg(ws: &Workspace, args: &ArgMatches) -> anyhow::Result<()> {
+    let output_dir = args
+        .get_one::<PathBuf>("output-dir")
+        .cloned()
+        .unwrap_or_else(|| {
+            args.value_of_path("target-dir", ws.config())
+                .unwrap_or_else(|| ws.target_dir().into_path_unlocked())
+                .join("vcpkg")
+        });
+    let capi_feature = InternedString::new("capi");
+
+    for pkg in ws
+        .members()
+        .filter(|m| m.library().is_some() && m.summary().features().contains_key(&capi_feature))
+    {
+        let manifest_dir = pkg.root().strip_prefix(ws.root()).unwrap_or(Path::new(""));
+        let licenses = license_files(pkg)?;
+        if licenses.is_empty() {
+            warn(
+                ws.config(),
+                format!(
+                    "no license file found for {}, list it in the portfile",
+                    pkg.name()
+                ),
+            )?;
+        }
+
+        let port_dir = output_dir.join(port_name(&pkg.name()));
+        for (path, contents) in [
+            (port_dir.join("vcpkg.json"), vcpkg_json(pkg)?),
+            (
+                port_dir.join("portfile.cmake"),
+                portfile(&source_fetch(pkg)?, manifest_dir, &licenses),
+            ),
+        ] {
+            ws.config().shell().status("Writing", path.display())?;
+            create_dir_all(path.parent().unwrap())?;
+            write(&path, contents)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn port_name() {
+        assert_eq!(super::port_name("foo"), "foo");
+        assert_eq!(super::port_name("Foo_Bar-sys"), "foo-bar-sys");
+    }
+
+    #[test]
+    fn repository_fetch() {
+        let github = super::repository_fetch("https://github.com/foo/bar.git/");
+        assert!(github.starts_with("vcpkg_from_github(\n"));
+        assert!(github.contains("\n    REPO foo/bar\n"));
+
+        let git = super::repository_fetch("https://example.com/foo.git");
+        assert!(git.contains("vcpkg_from_git(\n"));
+        assert!(git.contains("\n    URL https://example.com/foo\n"));
+    }
+
+    #[test]
+    fn portfile() {
+        let portfile = super::portfile(
+            "FETCH",
+            Path::new("crates").join("foo").as_path(),
+            &[
+                PathBuf::from("LICENSE-MIT"),
+                PathBuf::from("LICENSE-APACHE"),
+            ],
+        );
+        assert!(portfile.starts_with("FETCH\n"));
+        assert!(portfile
+            .contains("            --manifest-path \"${SOURCE_PATH}/crates/foo/Cargo.toml\"\n"));
+        assert!(portfile.contains(
+            "vcpkg_install_copyright(FILE_LIST \"${SOURCE_PATH}/crates/foo/LICENSE-MIT\" \
+             \"${SOURCE_PATH}/crates/foo/LICENSE-APACHE\")\n"
+        ));
+        assert!(portfile.contains(
+            "if(VCPKG_TARGET_IS_UWP AND rust_arch STREQUAL \"armv7\")\n    \
+             set(rust_target \"thumbv7a-uwp-windows-msvc\")\n"
+        ));
+        assert!(portfile.contains(
+            "elseif(VCPKG_TARGET_IS_WINDOWS AND rust_arch STREQUAL \"armv7\")\n    \
+             set(rust_target \"thumbv7a-pc-windows-msvc\")\n"
+        ));
+
+        let portfile = super::portfile("FETCH", Path::new(""), &[]);
+        assert!(portfile.contains("            --manifest-path \"${SOURCE_PATH}/Cargo.toml\"\n"));
+        assert!(portfile.contains("vcpkg_install_copyright(FILE_LIST)"));
+    }
+}
@@ -0,0 +1,374 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+    Other,
+}
+
+impl ValType {
+    fn from_byte(b: u8) -> Self {
+        match b {
+            0x7f => ValType::I32,
+            0x7e => ValType::I64,
+            0x7d => ValType::F32,
+            0x7c => ValType::F64,
+            _ => ValType::Other,
+        }
+    }
+
+    fn ts_type(self) -> &'static str {
+        match self {
+            ValType::I32 | ValType::F32 | ValType::F64 => "number",
+            ValType::I64 => "bigint",
+            ValType::Other => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FuncType {
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Export {
+    Func(String, FuncType),
+    Table(String),
+    Memory(String),
+    Global(String),
+    /// An exception tag
+    Tag(String),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        let b = *self
+            .data
+            .get(self.pos)
+            .context("Unexpected end of the wasm module")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .context("Unexpected end of the wasm module")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let mut result = 0u32;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            result |= u32::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+            if shift > 28 {
+                anyhow::bail!("Invalid LEB128 integer in the wasm module");
+            }
+        }
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            result |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+            if shift > 63 {
+                anyhow::bail!("Invalid LEB128 integer in the wasm module");
+            }
+        }
+    }
+
+    fn name(&mut self) -> anyhow::Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+
+    fn limits(&mut self) -> anyhow::Result<()> {
+        let flags = self.byte()?;
+        // The memory64 limits are 64-bit
+        let memory64 = flags & 4 != 0;
+        let count = if flags & 1 != 0 { 2 } else { 1 };
+        for _ in 0..count {
+            if memory64 {
+                self.u64()?;
+            } else {
+                self.u32()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the exports of a wasm module, along with the signature of the functions
+fn parse_exports(data: &[u8]) -> anyhow::Result<Vec<Export>> {
+    if data.get(0..4) != Some(b"\0asm") {
+        anyhow::bail!("Not a wasm module");
+    }
+    if data.len() < 8 {
+        anyhow::bail!("Unexpected end of the wasm module");
+    }
+
+    let mut reader = Reader::new(&data[8..]);
+    let mut types = Vec::new();
+    // Type index of every function, the imported ones come first
+    let mut functions = Vec::new();
+    let mut exports = Vec::new();
+
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let len = reader.u32()? as usize;
+        let mut section = Reader::new(reader.bytes(len)?);
+        match id {
+            // type
+            1 => {
+                for _ in 0..section.u32()? {
+                    if section.byte()? != 0x60 {
+                        anyhow::bail!("Unsupported type in the wasm module");
+                    }
+                    let params = (0..section.u32()?)
+                        .map(|_| section.byte().map(ValType::from_byte))
+                        .collect::<anyhow::Result<_>>()?;
+                    let results = (0..section.u32()?)
+                        .map(|_| section.byte().map(ValType::from_byte))
+                        .collect::<anyhow::Result<_>>()?;
+                    types.push(FuncType { params, results });
+                }
+            }
+            // import
+            2 => {
+                for _ in 0..section.u32()? {
+                    section.name()?;
+                    section.name()?;
+                    match section.byte()? {
+                        0 => functions.push(section.u32()?),
+                        1 => {
+                            section.byte()?;
+                            section.limits()?;
+                        }
+                        2 => section.limits()?,
+                        3 => {
+                            section.bytes(2)?;
+                        }
+                        // tag: attribute and type index
+                        4 => {
+                            section.byte()?;
+                            section.u32()?;
+                        }
+                        kind => {
+                            anyhow::bail!("Unsupported import kind {} in the wasm module", kind)
+                        }
+                    }
+                }
+            }
+            // function
+            3 => {
+                for _ in 0..section.u32()? {
+                    functions.push(section.u32()?);
+                }
+            }
+            // export
+            7 => {
+                for _ in 0..section.u32()? {
+                    let name = section.name()?;
+                    let kind = section.byte()?;
+                    let index = section.u32()? as usize;
+                    exports.push(match kind {
+                        0 => {
+                            let ty = functions
+                                .get(index)
+                                .and_then(|&t| types.get(t as usize))
+                                .context("Invalid function index in the wasm module")?;
+                            Export::Func(name, ty.clone())
+                        }
+                        1 => Export::Table(name),
+                        2 => Export::Memory(name),
+                        3 => Export::Global(name),
+                        4 => Export::Tag(name),
+                        kind => {
+                            anyhow::bail!("Unsupported export kind {} in the wasm module", kind)
+                        }
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(exports)
+}
+
+fn render_js(wasm_name: &str) -> String {
+    format!(
+        r#"// Generated by cargo-c, do not edit
+
+async function fetchBytes(url) {{
+    if (url.protocol === "file:") {{
+        const {{ readFile }} = await import("node:fs/promises");
+        return readFile(url);
+    }}
+    const response = await fetch(url);
+    return response.arrayBuffer();
+}}
+
+export default async function init(imports = {{}}, input = new URL("{wasm_name}", import.meta.url)) {{
+    const bytes = input instanceof URL ? await fetchBytes(input) : input;
+    const {{ instance }} = await WebAssembly.instantiate(bytes, imports);
+    return instance.exports;
+}}
+"#
+    )
+}
+
+fn render_dts(exports: &[Export]) -> String {
+    let mut buf =
+        String::from("// Generated by cargo-c, do not edit\n\nexport interface Exports {\n");
+    for export in exports {
+        let line = match export {
+            Export::Func(name, ty) => {
+                let params = ty
+                    .params
+                    .iter()
+                    .enumerate()
+                    .map(|(i, p)| format!("arg{i}: {}", p.ts_type()))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let result = match ty.results.as_slice() {
+                    [] => "void".to_string(),
+                    [r] => r.ts_type().to_string(),
+                    rs => format!(
+                        "[{}]",
+                        rs.iter()
+                            .map(|r| r.ts_type())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                };
+                format!("{name}({params}): {result};")
+            }
+            Export::Table(name) => format!("readonly {name}: WebAssembly.Table;"),
+            Export::Memory(name) => format!("readonly {name}: WebAssembly.Memory;"),
+            Export::Global(name) => format!("readonly {name}: WebAssembly.Global;"),
+            Export::Tag(name) => format!("readonly {name}: WebAssembly.Tag;"),
+        };
+        buf.push_str(&format!("    {line}\n"));
+    }
+    buf.push_str(
+        "}\n\nexport default function init(imports?: WebAssembly.Imports, input?: URL | BufferSource): Promise<Exports>;\n",
+    );
+    buf
+}
+
+/// Write the JavaScript loader and its TypeScript declarations next to the wasm module
+pub fn build_js_glue(wasm: &Path) -> anyhow::Result<()> {
+    let data = cargo_util::paths::read_bytes(wasm)?;
+    let exports =
+        parse_exports(&data).with_context(|| format!("Cannot read {}", wasm.display()))?;
+    let wasm_name = wasm.file_name().unwrap().to_string_lossy();
+
+    cargo_util::paths::write(wasm.with_extension("js"), render_js(&wasm_name))?;
+    cargo_util::paths::write(wasm.with_extension("d.ts"), render_dts(&exports))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exports() {
+        #[rustfmt::skip]
+        let module = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            // type: (i32, i32) -> i32, () -> ()
+            0x01, 0x0a, 0x02, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x00, 0x00,
+            // import: env.log () -> ()
+            0x02, 0x0b, 0x01, 0x03, b'e', b'n', b'v', 0x03, b'l', b'o', b'g', 0x00, 0x01,
+            // function: add
+            0x03, 0x02, 0x01, 0x00,
+            // memory
+            0x05, 0x03, 0x01, 0x00, 0x01,
+            // export: memory, add
+            0x07, 0x10, 0x02,
+            0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+            0x03, b'a', b'd', b'd', 0x00, 0x01,
+        ];
+
+        let exports = parse_exports(&module).unwrap();
+        assert_eq!(
+            exports,
+            vec![
+                Export::Memory("memory".into()),
+                Export::Func(
+                    "add".into(),
+                    FuncType {
+                        params: vec![ValType::I32, ValType::I32],
+                        results: vec![ValType::I32],
+                    }
+                ),
+            ]
+        );
+
+        assert!(render_dts(&exports).contains("    add(arg0: number, arg1: number): number;\n"));
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(parse_exports(b"\0asm").is_err());
+        assert!(parse_exports(b"\0asm\x01\0").is_err());
+        assert!(parse_exports(b"\0asm\x01\0\0\0\x07\x05").is_err());
+    }
+
+    #[test]
+    fn tags_and_memory64() {
+        #[rustfmt::skip]
+        let module = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            // type: (i32) -> ()
+            0x01, 0x05, 0x01, 0x60, 0x01, 0x7f, 0x00,
+            // import: env.mem memory64, min 2^35 pages
+            0x02, 0x11, 0x01, 0x03, b'e', b'n', b'v', 0x03, b'm', b'e', b'm', 0x02,
+            0x04, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01,
+            // tag: attribute 0, type 0
+            0x0d, 0x03, 0x01, 0x00, 0x00,
+            // export: error
+            0x07, 0x09, 0x01,
+            0x05, b'e', b'r', b'r', b'o', b'r', 0x04, 0x00,
+        ];
+
+        let exports = parse_exports(&module).unwrap();
+        assert_eq!(exports, vec![Export::Tag("error".into())]);
+        assert!(render_dts(&exports).contains("readonly error: WebAssembly.Tag;"));
+    }
+}
@@ -0,0 +1,97 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use cargo::core::Workspace;
+use cargo::util::command_prelude::ArgMatchesExt;
+use cargo::Config;
+use clap::ArgMatches;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The files of a directory tree as (path, modification time), skipping the hidden
+/// directories, the cargo target directories and the `excluded` ones
+fn collect_files(dir: &Path, excluded: &[PathBuf], files: &mut Vec<(PathBuf, SystemTime)>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            let is_excluded = excluded.contains(&path) || path.join("CACHEDIR.TAG").is_file();
+            if !hidden && !is_excluded {
+                collect_files(&path, excluded, files);
+            }
+        } else if let Ok(modified) = metadata.modified() {
+            files.push((path, modified));
+        }
+    }
+}
+
+/// Snapshot of the sources, manifests and C-API configuration files the build depends on
+fn snapshot(roots: &[PathBuf], excluded: &[PathBuf]) -> Vec<(PathBuf, SystemTime)> {
+    let mut files = Vec::new();
+    for root in roots {
+        collect_files(root, excluded, &mut files);
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Run `build` again every time a file of the workspace changes, until interrupted
+///
+/// The workspace is loaded again before every run so changes to the manifests, including
+/// the `package.metadata.capi` tables, are picked up. A failed run is reported and the
+/// watch goes on. The files are compared to the ones before the run, so an edit made
+/// while building triggers another run, and the install directories are not watched.
+pub fn watch<F>(config: &Config, args: &ArgMatches, mut build: F) -> anyhow::Result<()>
+where
+    F: FnMut(&mut Workspace) -> anyhow::Result<()>,
+{
+    let root_manifest = args.root_manifest(config)?;
+    let root_dir = root_manifest.parent().unwrap().to_path_buf();
+
+    // A staged install into the workspace must not trigger a rebuild
+    let install_dirs: Vec<PathBuf> = ["destdir", "prefix", "devel_prefix", "runtime_prefix"]
+        .iter()
+        .filter_map(|id| args.try_get_one::<PathBuf>(id).ok().flatten())
+        .map(|dir| config.cwd().join(dir))
+        .collect();
+
+    loop {
+        let mut roots = vec![root_dir.clone()];
+        let mut excluded = install_dirs.clone();
+
+        let ws = args.workspace(config);
+        if let Ok(ws) = &ws {
+            roots.extend(ws.members().map(|m| m.root().to_path_buf()));
+            excluded.push(ws.target_dir().into_path_unlocked());
+        }
+        let before = snapshot(&roots, &excluded);
+
+        match ws {
+            Ok(mut ws) => {
+                if let Err(e) = build(&mut ws) {
+                    cargo::display_error(&e, &mut config.shell());
+                }
+            }
+            Err(e) => cargo::display_error(&e, &mut config.shell()),
+        }
+
+        config.shell().status(
+            "Watching",
+            format!("{} files for changes, press Ctrl-C to stop", before.len()),
+        )?;
+
+        while snapshot(&roots, &excluded) == before {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}